@@ -0,0 +1,196 @@
+//! Parsing `Accept`-style headers for content negotiation.
+
+use std::slice;
+
+/// The values a client accepts, parsed from an `Accept`, `Accept-Encoding`
+/// or `Accept-Language` header, most preferred first.
+///
+/// Get one from `Request::accepts`, `Request::accepts_encodings` or
+/// `Request::accepts_languages`. Values are ordered by their `q` parameter,
+/// which defaults to 1, keeping the header's order among equal ones.
+/// Other parameters are dropped.
+///
+/// `best` picks what to send from the representations a handler has, e.g.
+/// `request.accepts().best(&["application/json", "text/html"])`, leaving
+/// `None` to be answered with `406 Not Acceptable`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accept {
+    kind: Kind,
+    // header がなければ None。何でも受け取れるという意味になる。
+    items: Option<Vec<(String, f32)>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Media,
+    Encoding,
+    Language,
+}
+
+impl Accept {
+    pub(crate) fn media<'a, I: Iterator<Item = &'a str>>(values: I) -> Accept {
+        Accept::parse(Kind::Media, values)
+    }
+
+    pub(crate) fn encodings<'a, I: Iterator<Item = &'a str>>(values: I) -> Accept {
+        Accept::parse(Kind::Encoding, values)
+    }
+
+    pub(crate) fn languages<'a, I: Iterator<Item = &'a str>>(values: I) -> Accept {
+        Accept::parse(Kind::Language, values)
+    }
+
+    fn parse<'a, I: Iterator<Item = &'a str>>(kind: Kind, values: I) -> Accept {
+        let mut present = false;
+        let mut items = Vec::new();
+        for value in values {
+            present = true;
+            for item in value.split(',') {
+                let mut params = item.split(';');
+                let name = params.next().unwrap_or("").trim();
+                if name.is_empty() {
+                    continue;
+                }
+                // 読めない q は 0 として、その値を受け取らないものとする。
+                let quality = params
+                    .find_map(|p| {
+                        let (key, value) = p.split_once('=')?;
+                        if key.trim().eq_ignore_ascii_case("q") {
+                            Some(value.trim().parse::<f32>().unwrap_or(0.0))
+                        } else {
+                            None
+                        }
+                    })
+                    .unwrap_or(1.0)
+                    .clamp(0.0, 1.0);
+                let mut name = name.to_ascii_lowercase();
+                // x-gzip は gzip の古い名前 (RFC 7230 4.2.3)。
+                if kind == Kind::Encoding && name == "x-gzip" {
+                    name = "gzip".to_string();
+                }
+                items.push((name, quality));
+            }
+        }
+        // sort_by は安定なので、同じ q の間では header の順が残る。
+        items.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Accept {
+            kind,
+            items: if present { Some(items) } else { None },
+        }
+    }
+
+    /// Return `true` if the header was not sent, so anything is acceptable.
+    pub fn is_missing(&self) -> bool {
+        self.items.is_none()
+    }
+
+    /// Iterate over the listed values and their qualities, most preferred
+    /// first. Values are lowercased.
+    pub fn iter(&self) -> AcceptIter<'_> {
+        AcceptIter(self.items.as_deref().unwrap_or(&[]).iter())
+    }
+
+    /// Return how much the client wants `value`, from 0 (not acceptable)
+    /// to 1.
+    ///
+    /// The most specific matching entry counts: for media types `text/html`
+    /// over `text/*` over `*/*`, and for languages the longest matching
+    /// prefix, so `en` matches `en-US`. `*` matches anything not listed,
+    /// and the `identity` encoding is acceptable unless excluded.
+    pub fn quality(&self, value: &str) -> f32 {
+        let items = match self.items {
+            Some(ref items) => items,
+            None => return 1.0,
+        };
+        let value = value.to_ascii_lowercase();
+        let mut best: Option<(usize, f32)> = None;
+        for &(ref name, quality) in items {
+            let specificity = match self.kind {
+                Kind::Media => media_specificity(name, &value),
+                Kind::Encoding => encoding_specificity(name, &value),
+                Kind::Language => language_specificity(name, &value),
+            };
+            if let Some(specificity) = specificity {
+                if best.is_none_or(|(s, _)| specificity > s) {
+                    best = Some((specificity, quality));
+                }
+            }
+        }
+        match best {
+            Some((_, quality)) => quality,
+            None if self.kind == Kind::Encoding && value == "identity" => 1.0,
+            None => 0.0,
+        }
+    }
+
+    /// Return the acceptable value in `available` the client wants most,
+    /// or `None` if it accepts none of them. Ties go to the one listed
+    /// first, so list in the order the handler prefers.
+    pub fn best<'a>(&self, available: &[&'a str]) -> Option<&'a str> {
+        let mut best: Option<(&'a str, f32)> = None;
+        for &value in available {
+            let quality = self.quality(value);
+            if quality > 0.0 && best.is_none_or(|(_, b)| quality > b) {
+                best = Some((value, quality));
+            }
+        }
+        best.map(|(value, _)| value)
+    }
+}
+
+impl<'a> IntoIterator for &'a Accept {
+    type Item = (&'a str, f32);
+    type IntoIter = AcceptIter<'a>;
+
+    fn into_iter(self) -> AcceptIter<'a> {
+        self.iter()
+    }
+}
+
+/// An iterator over accepted values and their qualities, returned by
+/// `Accept::iter`.
+#[derive(Debug)]
+pub struct AcceptIter<'a>(slice::Iter<'a, (String, f32)>);
+
+impl<'a> Iterator for AcceptIter<'a> {
+    type Item = (&'a str, f32);
+
+    fn next(&mut self) -> Option<(&'a str, f32)> {
+        self.0.next().map(|item| (&*item.0, item.1))
+    }
+}
+
+// 一致しなければ None。数が大きいほど具体的。
+fn media_specificity(range: &str, media: &str) -> Option<usize> {
+    // text/html;charset=utf-8 のような parameter は比べない。
+    let media = media.split(';').next().unwrap_or("").trim();
+    if range == "*/*" {
+        return Some(0);
+    }
+    let (kind, subtype) = range.split_once('/')?;
+    let (media_kind, _) = media.split_once('/')?;
+    if subtype == "*" {
+        return (kind == media_kind).then_some(1);
+    }
+    (range == media).then_some(2)
+}
+
+fn encoding_specificity(coding: &str, encoding: &str) -> Option<usize> {
+    if coding == "*" {
+        Some(0)
+    } else {
+        (coding == encoding).then_some(1)
+    }
+}
+
+// RFC 4647 の basic filtering。
+fn language_specificity(range: &str, tag: &str) -> Option<usize> {
+    if range == "*" {
+        return Some(0);
+    }
+    let matches = tag == range
+        || tag
+            .strip_prefix(range)
+            .is_some_and(|rest| rest.starts_with('-'));
+    matches.then_some(range.len())
+}
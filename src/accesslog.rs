@@ -0,0 +1,195 @@
+//! Access logs in the Common Log Format or as JSON.
+
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use super::date::{rfc3339, DateTime};
+use super::jsonlog::quote;
+use super::{Method, Request, Status, Version};
+
+/// The line format of an `AccessLog`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The Common Log Format:
+    /// `host - - [time] "request line" status bytes`.
+    #[default]
+    Common,
+    /// The Combined Log Format, which adds the `Referer` and `User-Agent`
+    /// headers to `Common`.
+    Combined,
+    /// One JSON object per line with the fields of `Combined`, named
+    /// `time`, `remote_addr`, `method`, `target`, `version`, `status`,
+    /// `bytes`, `referer`, `user_agent`, `latency_ms` and `request_id`.
+    /// Missing values are `null`.
+    Json,
+}
+
+/// Writes a line for every request a `Server` answers, set with
+/// `ServerBuilder::access_log`.
+///
+/// Lines in the Common and Combined formats end with the time taken to
+/// answer the request in seconds, with millisecond precision, like nginx's
+/// `$request_time`, followed by the `X-Request-Id` of the response if it
+/// has one (see `RequestId`). Requests too malformed to parse are logged
+/// with `"-"` as the request line.
+///
+/// Each line is written with a single `write_all`, so a writer shared
+/// between threads gets whole lines. Wrap a file in a `BufWriter` to write
+/// less often; lines then show up as its buffer fills.
+pub struct AccessLog {
+    writer: Mutex<Box<dyn Write + Send>>,
+    format: LogFormat,
+}
+
+impl AccessLog {
+    /// Create an access log that writes lines in the Common Log Format to
+    /// `writer`.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> AccessLog {
+        AccessLog {
+            writer: Mutex::new(Box::new(writer)),
+            format: LogFormat::default(),
+        }
+    }
+
+    /// Set the line format. Defaults to `LogFormat::Common`.
+    pub fn format(mut self, format: LogFormat) -> AccessLog {
+        self.format = format;
+        self
+    }
+
+    pub(crate) fn log(&self, entry: &Entry) {
+        let line = self.line(entry);
+        // 他の worker が panic して poison されていても書ける。
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(err) = writer.write_all(line.as_bytes()) {
+            warn!("Failed to write an access log: {}", err);
+        }
+    }
+
+    fn line(&self, entry: &Entry) -> String {
+        if self.format == LogFormat::Json {
+            return json_line(entry);
+        }
+        let host = match entry.remote_addr {
+            Some(addr) => addr.ip().to_string(),
+            None => "-".to_owned(),
+        };
+        let request_line = match entry.head {
+            Some(ref head) => escape(&format!("{} {} {}", head.method, head.target, head.version)),
+            None => "-".to_owned(),
+        };
+        // CLF では body がないときは 0 ではなく - にする。
+        let bytes = match entry.bytes {
+            0 => "-".to_owned(),
+            bytes => bytes.to_string(),
+        };
+        let mut line = format!(
+            "{} - - [{}] \"{}\" {} {}",
+            host,
+            DateTime::from_system_time(entry.time).clf_format(),
+            request_line,
+            entry.status.code(),
+            bytes
+        );
+        if self.format == LogFormat::Combined {
+            let head = entry.head.as_ref();
+            let referer = head.and_then(|head| head.referer.as_deref());
+            let user_agent = head.and_then(|head| head.user_agent.as_deref());
+            line.push_str(&format!(
+                " \"{}\" \"{}\"",
+                escape(referer.unwrap_or("-")),
+                escape(user_agent.unwrap_or("-"))
+            ));
+        }
+        line.push_str(&format!(" {:.3}", entry.latency.as_secs_f64()));
+        if let Some(ref id) = entry.request_id {
+            line.push(' ');
+            line.push_str(&escape(id));
+        }
+        line.push('\n');
+        line
+    }
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+// 1 行分の記録。 request は handler に渡すと無くなるので、要る分を先に写す。
+pub(crate) struct Entry {
+    pub(crate) time: SystemTime,
+    pub(crate) remote_addr: Option<SocketAddr>,
+    pub(crate) head: Option<Head>,
+    pub(crate) status: Status,
+    pub(crate) bytes: u64,
+    pub(crate) latency: Duration,
+    pub(crate) request_id: Option<String>,
+}
+
+pub(crate) struct Head {
+    method: Method,
+    target: String,
+    version: Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Head {
+    pub(crate) fn new(request: &Request) -> Head {
+        Head {
+            method: request.method().clone(),
+            target: request.target().to_owned(),
+            version: request.version(),
+            referer: request.header("Referer").map(str::to_owned),
+            user_agent: request.header("User-Agent").map(str::to_owned),
+        }
+    }
+}
+
+// 無い値は null にする。
+fn json_line(entry: &Entry) -> String {
+    let remote_addr = entry.remote_addr.map(|addr| addr.ip().to_string());
+    let string = |value: Option<&str>| value.map(quote).unwrap_or_else(|| "null".to_owned());
+    let head = entry.head.as_ref();
+    format!(
+        "{{\"time\":{},\"remote_addr\":{},\"method\":{},\"target\":{},\"version\":{},\
+         \"status\":{},\"bytes\":{},\"referer\":{},\"user_agent\":{},\"latency_ms\":{:.3},\
+         \"request_id\":{}}}\n",
+        quote(&rfc3339(entry.time)),
+        string(remote_addr.as_deref()),
+        string(head.map(|head| head.method.as_str())),
+        string(head.map(|head| &*head.target)),
+        string(head.map(|head| head.version.as_str())),
+        entry.status.code(),
+        entry.bytes,
+        string(head.and_then(|head| head.referer.as_deref())),
+        string(head.and_then(|head| head.user_agent.as_deref())),
+        entry.latency.as_secs_f64() * 1000.0,
+        string(entry.request_id.as_deref())
+    )
+}
+
+// client から来た値で行や引用符が崩れないよう、 Apache と同じく `"` と `\` と
+// 制御文字を escape する。
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,78 @@
+//! Pinning worker threads to CPU cores. Only Linux is supported; elsewhere
+//! pinning fails with a warning and workers run unpinned.
+
+use std::io;
+use std::thread;
+
+/// Which CPU cores worker threads are pinned to, set with
+/// `ThreadPoolBuilder::affinity`.
+///
+/// Cores are numbered as the OS numbers them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Affinity {
+    /// Pin worker `id` to core `id`, wrapping around after the number of
+    /// cores available to the process.
+    PerCore,
+    /// Pin worker `id` to `cores[id % cores.len()]`.
+    Cores(Vec<usize>),
+    /// Let every worker run on any core in the set.
+    Set(Vec<usize>),
+}
+
+impl Affinity {
+    // worker `id` を動かす core の一覧。
+    fn cores_for(&self, id: usize) -> Vec<usize> {
+        match *self {
+            Affinity::PerCore => {
+                let cores = thread::available_parallelism().map_or(1, |n| n.get());
+                vec![id % cores]
+            }
+            Affinity::Cores(ref cores) if cores.is_empty() => Vec::new(),
+            Affinity::Cores(ref cores) => vec![cores[id % cores.len()]],
+            Affinity::Set(ref cores) => cores.clone(),
+        }
+    }
+}
+
+// 今の thread を worker `id` の core に固定する。失敗しても worker は動かし続ける。
+pub(crate) fn pin_current(affinity: &Affinity, id: usize) {
+    let cores = affinity.cores_for(id);
+    if cores.is_empty() {
+        return;
+    }
+    match set_current(&cores) {
+        Ok(()) => debug!("Pinned worker {} to cores {:?}.", id, cores),
+        Err(err) => warn!("Failed to pin worker {} to cores {:?}: {}", id, cores, err),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_current(cores: &[usize]) -> io::Result<()> {
+    use std::mem;
+
+    // cpu_set_t は単なる bit の配列なので、 0 埋めが空の集合になる。
+    let mut set: ::libc::cpu_set_t = unsafe { mem::zeroed() };
+    for &core in cores {
+        if core >= ::libc::CPU_SETSIZE as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("core {} is out of range", core),
+            ));
+        }
+        unsafe { ::libc::CPU_SET(core, &mut set) };
+    }
+    // pid に 0 を渡すと呼び出した thread が対象になる。
+    let result = unsafe { ::libc::sched_setaffinity(0, mem::size_of_val(&set), &set) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_current(_cores: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "CPU affinity is only supported on Linux",
+    ))
+}
@@ -0,0 +1,137 @@
+//! HTTP authentication with the `Basic` and `Bearer` schemes.
+
+use std::fmt;
+
+use super::base64;
+use super::{Middleware, Next, Request, Response, Status};
+
+type TokenCheck = Box<dyn Fn(&str) -> Option<String> + Send + Sync + 'static>;
+
+/// A middleware that lets only authenticated requests through.
+///
+/// `Authorization: Basic` credentials are checked against the users added
+/// with `user`, and `Authorization: Bearer` tokens are given to the
+/// function set with `bearer`, which returns the user the token belongs to.
+/// The user is then available from `Request::user`. Other requests are
+/// answered with `401 Unauthorized` and a `WWW-Authenticate` challenge for
+/// each scheme in use.
+///
+/// Add it to a `Router` that is nested into the main one to protect only
+/// part of the site. Basic credentials travel in the clear, so serve them
+/// over HTTPS only.
+pub struct Auth {
+    realm: String,
+    users: Vec<(String, String)>,
+    bearer: Option<TokenCheck>,
+}
+
+impl Auth {
+    /// Create a layer that lets no one in, naming the protected area
+    /// `realm` in its challenges.
+    pub fn new<S: Into<String>>(realm: S) -> Auth {
+        Auth {
+            realm: realm.into(),
+            users: Vec::new(),
+            bearer: None,
+        }
+    }
+
+    /// Accept Basic credentials for `name` with `password`.
+    pub fn user<N: Into<String>, P: Into<String>>(mut self, name: N, password: P) -> Auth {
+        self.users.push((name.into(), password.into()));
+        self
+    }
+
+    /// Accept Bearer tokens for which `check` returns the user they belong
+    /// to.
+    pub fn bearer<F>(mut self, check: F) -> Auth
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        self.bearer = Some(Box::new(check));
+        self
+    }
+
+    // Authorization は "Scheme credentials" の形。 scheme は大文字小文字を区別しない。
+    fn authenticate(&self, authorization: &str) -> Option<String> {
+        let (scheme, credentials) = authorization.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let decoded = String::from_utf8(base64::decode(credentials)?).ok()?;
+            let (name, password) = decoded.split_once(':')?;
+            // 一致の早さで password を探られないよう、全部の user と比べる。
+            let mut found = None;
+            for (user, expected) in &self.users {
+                if constant_time_eq(user.as_bytes(), name.as_bytes())
+                    & constant_time_eq(expected.as_bytes(), password.as_bytes())
+                {
+                    found = Some(user.clone());
+                }
+            }
+            found
+        } else if scheme.eq_ignore_ascii_case("Bearer") {
+            self.bearer.as_ref().and_then(|check| check(credentials))
+        } else {
+            None
+        }
+    }
+
+    fn challenge(&self, rejected_bearer: bool) -> Response {
+        let realm = self.realm.replace(['\\', '"'], "");
+        let mut response = Response::new(Status::Unauthorized);
+        if !self.users.is_empty() || self.bearer.is_none() {
+            let basic = format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm);
+            response = response.header("WWW-Authenticate", basic);
+        }
+        if self.bearer.is_some() {
+            // 送られた token が通らなかったことは error で伝える (RFC 6750 3)。
+            let bearer = if rejected_bearer {
+                format!("Bearer realm=\"{}\", error=\"invalid_token\"", realm)
+            } else {
+                format!("Bearer realm=\"{}\"", realm)
+            };
+            response = response.header("WWW-Authenticate", bearer);
+        }
+        response
+    }
+}
+
+impl Middleware for Auth {
+    fn handle(&self, mut request: Request, next: Next<'_>) -> Response {
+        let authorization = request.header("Authorization");
+        match authorization.and_then(|value| self.authenticate(value)) {
+            Some(user) => {
+                request.set_user(user);
+                next.run(request)
+            }
+            None => {
+                let rejected_bearer = authorization.is_some_and(|value| {
+                    value
+                        .trim()
+                        .get(..7)
+                        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("Bearer "))
+                });
+                self.challenge(rejected_bearer)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let users: Vec<&str> = self.users.iter().map(|(name, _)| &**name).collect();
+        f.debug_struct("Auth")
+            .field("realm", &self.realm)
+            .field("users", &users)
+            .field("bearer", &self.bearer.is_some())
+            .finish()
+    }
+}
+
+// 長さ以外は中身によらない時間で比べる。
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,97 @@
+//! The standard base64 alphabet with padding (RFC 4648).
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode `data` as padded base64.
+pub(crate) fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decode padded base64, or return `None` if `text` is not valid.
+pub(crate) fn decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    for (index, chunk) in text.chunks(4).enumerate() {
+        let last = index == text.len() / 4 - 1;
+        // '=' は最後の組の末尾 2 文字までにしか来ない。
+        let padding = chunk.iter().rev().take_while(|&&b| b == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for &b in &chunk[..4 - padding] {
+            let value = ALPHABET.iter().position(|&a| a == b)?;
+            n = (n << 6) | value as u32;
+        }
+        n <<= 6 * padding as u32;
+        let bytes = n.to_be_bytes();
+        out.extend_from_slice(&bytes[1..4 - padding]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+
+    // RFC 4648 section 10 の例。
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn encodes_rfc_vectors() {
+        for (data, text) in VECTORS {
+            assert_eq!(encode(data.as_bytes()), text);
+        }
+    }
+
+    #[test]
+    fn decodes_rfc_vectors() {
+        for (data, text) in VECTORS {
+            assert_eq!(decode(text).as_deref(), Some(data.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn round_trips_every_byte() {
+        let data: Vec<u8> = (0..=255).collect();
+        for len in 0..data.len() {
+            assert_eq!(decode(&encode(&data[..len])).as_deref(), Some(&data[..len]));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_text() {
+        for text in [
+            "Zg", "Zg=", "Zm9", "Z===", "====", "Zg==Zm8=", "Zm9v!A==", "Zm9-", "Zm9_",
+        ] {
+            assert_eq!(decode(text), None, "{:?}", text);
+        }
+    }
+}
@@ -1,46 +1,244 @@
 extern crate hello;
+extern crate log;
 
-use std::io::prelude::*;
-use std::fs::File;
-use std::net::{TcpListener, TcpStream};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::Duration;
 
-use hello::ThreadPool;
+use hello::{
+    AccessLog, Context, HealthCheck, JsonLogger, LogFormat, RateLimit, RejectionPolicy, Request,
+    RequestId, Response, Router, Server, StaticFiles, Status, Templates, ThreadPool,
+};
+use log::{LevelFilter, Log, Metadata, Record};
 
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
-    let pool = ThreadPool::new(4);
+// pool のログを stderr に出すだけの logger。
+// レベルは HELLO_LOG 環境変数 (error, warn, info, debug, trace) で変えられる。
+// HELLO_LOG_FORMAT=json にすると、これの代わりに JsonLogger を使い、
+// access log も JSON にする。
+struct StderrLogger;
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
     }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            match RequestId::current() {
+                Some(id) => eprintln!("[{}] [{}] {}", record.level(), id, record.args()),
+                None => eprintln!("[{}] {}", record.level(), record.args()),
+            }
+        }
+    }
+
+    fn flush(&self) {}
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = [0; 512];
-    stream.read(&mut buffer).unwrap();
+static LOGGER: StderrLogger = StderrLogger;
 
-    let get = b"GET / HTTP/1.1\r\n";
-    let sleep = b"GET /sleep HTTP/1.1\r\n";
+// HELLO_CONFIG 環境変数 (既定は hello.conf) の file から読む設定。
+// 1 行に 1 つ `key = value` と書き、 # から行末までは comment。
+// file がなければすべて既定値になる。 SIGHUP を受け取ると読み直す。
+//
+//   log_level = debug      # 既定は HELLO_LOG
+//   static_root = public   # /static/ で配る directory。既定はなし
+//   rate_limit = 20        # client ごとの 1 秒あたりの request 数。既定は 100
+//   burst = 40             # 既定は rate_limit
+#[derive(Debug, Default)]
+struct Settings {
+    log_level: Option<LevelFilter>,
+    static_root: Option<PathBuf>,
+    rate_limit: Option<u32>,
+    burst: Option<u32>,
+}
+
+const DEFAULT_RATE_LIMIT: u32 = 100;
+
+impl Settings {
+    fn read(path: &Path) -> io::Result<Settings> {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Settings::default())
+            }
+            Err(err) => return Err(err),
+        };
+        let mut settings = Settings::default();
+        for (number, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: invalid setting on line {}", path.display(), number + 1),
+                )
+            };
+            let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+            let value = value.trim();
+            match key.trim() {
+                "log_level" => settings.log_level = Some(value.parse().map_err(|_| invalid())?),
+                "static_root" => settings.static_root = Some(PathBuf::from(value)),
+                "rate_limit" => settings.rate_limit = Some(positive(value).ok_or_else(invalid)?),
+                "burst" => settings.burst = Some(positive(value).ok_or_else(invalid)?),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(settings)
+    }
 
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else if buffer.starts_with(sleep) {
-        thread::sleep(Duration::from_secs(5));
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
+    // 動いている server に設定を反映する。接続はそのまま。
+    fn apply(&self, default_level: LevelFilter, statics: &Statics, rate_limit: &RateLimit) {
+        log::set_max_level(self.log_level.unwrap_or(default_level));
+        *statics.write().unwrap() = self.static_root.clone().map(StaticFiles::new);
+        let requests = self.rate_limit.unwrap_or(DEFAULT_RATE_LIMIT);
+        rate_limit.set_limit(requests, Duration::from_secs(1));
+        rate_limit.set_burst(self.burst.unwrap_or(requests));
+    }
+}
+
+fn positive(value: &str) -> Option<u32> {
+    value.parse().ok().filter(|&n| n > 0)
+}
+
+// static_root を読み直すと入れ替わる。
+type Statics = Arc<RwLock<Option<StaticFiles>>>;
+
+fn main() {
+    let level = env::var("HELLO_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    let json = env::var("HELLO_LOG_FORMAT").is_ok_and(|format| format == "json");
+    let access_log = if json {
+        JsonLogger::new(io::stderr()).level(level).init().unwrap();
+        AccessLog::new(io::stdout()).format(LogFormat::Json)
     } else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(level);
+        AccessLog::new(io::stdout())
+    };
+
+    let config = PathBuf::from(env::var("HELLO_CONFIG").unwrap_or_else(|_| "hello.conf".into()));
+    let statics: Statics = Arc::new(RwLock::new(None));
+    let rate_limit = RateLimit::new(DEFAULT_RATE_LIMIT, Duration::from_secs(1));
+    Settings::read(&config)
+        .unwrap()
+        .apply(level, &statics, &rate_limit);
+
+    // debug build では、変更した template が再起動せずに反映される。
+    Templates::load("templates").unwrap().install();
+
+    let pool = ThreadPool::builder()
+        .size(4)
+        .queue_capacity(16)
+        .rejection_policy(RejectionPolicy::CallerRuns)
+        .name_prefix("hello-worker")
+        .propagate_context(RequestId::current, RequestId::enter);
+    let builder = Server::builder()
+        .thread_pool(pool)
+        .http2(true)
+        .not_found(not_found)
+        .access_log(access_log)
+        .middleware(RequestId::new())
+        .middleware(rate_limit.clone())
+        // 止めるときは、 /healthz を 2 秒間 503 にしてから drain する。
+        .health_check(
+            HealthCheck::new("/healthz")
+                .max_queued(8)
+                .shutdown_delay(Duration::from_secs(2)),
+        )
+        .drain_timeout(Duration::from_secs(10));
+    // Ctrl-C で /sleep の途中の request を返してから止まる。
+    // `kill -HUP` で設定を読み直す。読めなければ前の設定のまま。
+    #[cfg(feature = "signals")]
+    let builder = {
+        let statics = Arc::clone(&statics);
+        builder
+            .shutdown_on_signals(true)
+            .on_reload(move || match Settings::read(&config) {
+                Ok(settings) => settings.apply(level, &statics, &rate_limit),
+                Err(err) => log::warn!("Failed to reload the settings: {}", err),
+            })
     };
+    #[allow(unused_mut)]
+    let mut server = builder.bind("127.0.0.1:8080").unwrap();
+    // HELLO_TLS_CERT と HELLO_TLS_KEY があれば、 8443 で HTTPS も受け付ける。
+    // HELLO_TLS_CLIENT_CA があれば、 client 証明書も (任意で) 受け取る。
+    #[cfg(feature = "tls")]
+    if let (Ok(cert), Ok(key)) = (env::var("HELLO_TLS_CERT"), env::var("HELLO_TLS_KEY")) {
+        let mut tls = hello::TlsConfig::from_files(cert, key).unwrap();
+        if let Ok(ca) = env::var("HELLO_TLS_CLIENT_CA") {
+            tls = tls.client_ca_file(ca).unwrap().optional_client_cert(true);
+        }
+        server.listen_tls("127.0.0.1:8443", tls).unwrap();
+    }
 
-    let mut file = File::open(filename).unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
+    let router = Router::new()
+        .get("/", |_| hello())
+        .get("/sleep", |_| {
+            thread::sleep(Duration::from_secs(5));
+            hello()
+        })
+        .get("/whoami", whoami)
+        .post("/echo", echo)
+        .post("/greet", greet)
+        .get("/static/*path", move |request| {
+            match *statics.read().unwrap() {
+                Some(ref files) => files.serve(request.param("path").unwrap_or(""), &request),
+                None => not_found(&request),
+            }
+        });
+
+    server.run(router).unwrap();
+}
+
+fn hello() -> Response {
+    let context = Context::new().set("title", "Hello").set("from", "Rust");
+    Response::render("hello", &context)
+}
 
-    let response = format!("{}{}", status_line, contents);
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+fn not_found(request: &Request) -> Response {
+    let context = Context::new()
+        .set("title", "Not Found")
+        .set("path", request.path());
+    let mut response = Response::render("404", &context);
+    response.set_status(Status::NotFound);
+    response
+}
+
+// 受け取った body をそのまま返す。
+fn echo(request: Request) -> Response {
+    let mut response = Response::new(Status::Ok).body(request.body());
+    if let Some(content_type) = request.header("Content-Type") {
+        response = response.header("Content-Type", content_type);
+    }
+    response
+}
+
+// TLS の client 証明書で名乗った相手を返す。
+fn whoami(request: Request) -> Response {
+    let name = request
+        .peer_identity()
+        .map_or("anonymous", |peer| peer.common_name().unwrap_or(peer.subject()));
+    Response::new(Status::Ok)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!("{}\n", name))
+}
+
+// hello.html の form から送られた名前に挨拶する。
+fn greet(request: Request) -> Response {
+    let form = match request.form() {
+        Some(form) => form,
+        None => return Response::new(Status::UnsupportedMediaType),
+    };
+    let name = form.get("name").filter(|name| !name.is_empty()).unwrap_or("stranger");
+    Response::new(Status::Ok)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(format!("Hello, {}!\n", name))
 }
@@ -0,0 +1,78 @@
+//! A dedicated pool for offloading blocking work from async runtimes.
+
+use std::sync::mpsc;
+use std::time::Duration;
+
+use super::future::{FutureJob, JobFuture};
+use super::{PoolCreationError, PoolMetrics, ThreadPool, ThreadPoolBuilder, TryExecuteError};
+
+// 使われていない追加の worker が終了するまでの時間。
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// Runs blocking closures off an async runtime and hands back their results
+/// as futures.
+///
+/// Submitting never blocks the calling thread, so it is safe to call from
+/// inside an async task. When the queue is full the closure is handed back
+/// instead.
+pub struct BlockingPool {
+    pool: ThreadPool,
+}
+
+impl BlockingPool {
+    /// Create a pool of at most `max_threads` threads whose queue holds at
+    /// most `queue_capacity` jobs.
+    ///
+    /// Threads are spawned on demand and exit after being idle for a while,
+    /// keeping one around.
+    pub fn new(
+        max_threads: usize,
+        queue_capacity: usize,
+    ) -> Result<BlockingPool, PoolCreationError> {
+        ThreadPoolBuilder::new()
+            .size(1)
+            .max_size(max_threads)
+            .queue_capacity(queue_capacity)
+            .keep_alive(KEEP_ALIVE, 1)
+            .name_prefix("blocking")
+            .build()
+            .map(BlockingPool::from_pool)
+    }
+
+    /// Use an existing pool, e.g. one configured with `ThreadPoolBuilder`.
+    pub fn from_pool(pool: ThreadPool) -> BlockingPool {
+        BlockingPool { pool }
+    }
+
+    /// Run `f` on the pool and return a future that resolves to its result.
+    pub fn spawn_blocking<F, T>(&self, f: F) -> Result<JobFuture<T>, TryExecuteError<F>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, future) = FutureJob::new(f);
+        self.pool
+            .handle
+            .try_send_job(job, FutureJob::run)
+            .map(|_| future)
+            .map_err(|err| match err {
+                mpsc::TrySendError::Full(job) => TryExecuteError::Full(job.f),
+                mpsc::TrySendError::Disconnected(job) => TryExecuteError::Disconnected(job.f),
+            })
+    }
+
+    /// Return a snapshot of the underlying pool's activity.
+    pub fn metrics(&self) -> PoolMetrics {
+        self.pool.metrics()
+    }
+
+    /// Return the underlying pool.
+    pub fn pool(&self) -> &ThreadPool {
+        &self.pool
+    }
+
+    /// Take back the underlying pool.
+    pub fn into_pool(self) -> ThreadPool {
+        self.pool
+    }
+}
@@ -0,0 +1,444 @@
+use std::any::Any;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::handle::{Core, PoolHandle};
+use super::job::DeadLetters;
+use super::metrics::WaitRecorder;
+use super::queue;
+#[cfg(feature = "affinity")]
+use super::Affinity;
+use super::{
+    DropPolicy, JobMeta, KeepAlive, Message, NestedPolicy, PanicPolicy, PoolCreationError,
+    RejectionPolicy, Scaling, ThreadPool, WorkerContext, WorkerListener, Workers, GLOBAL,
+    NEXT_POOL_ID,
+};
+
+pub(crate) type ThreadHook = Arc<dyn Fn(usize) + Send + Sync>;
+pub(crate) type JobStartHook = Arc<dyn Fn(&JobMeta) + Send + Sync>;
+pub(crate) type JobEndHook = Arc<dyn Fn(&JobMeta, Duration) + Send + Sync>;
+// job を送る thread で context を取り出し、 worker で入れる関数を返す。
+pub(crate) type CaptureHook = Arc<dyn Fn() -> Installer + Send + Sync>;
+// worker で context を入れる。返した値は job が終わった後に drop される。
+pub(crate) type Installer = Box<dyn FnOnce() -> Box<dyn Any> + Send>;
+
+// class を指定せずに送った job の class。
+const DEFAULT_CLASS: &str = "default";
+
+// ThreadPool::failed_jobs で覚えておく job の数の既定値。
+const DEFAULT_FAILED_JOB_CAPACITY: usize = 64;
+
+/// Configures and creates a `ThreadPool`.
+///
+/// Start with `ThreadPoolBuilder::new()` (or `ThreadPool::builder()`), chain
+/// the settings you need and finish with `build`.
+#[derive(Default)]
+pub struct ThreadPoolBuilder {
+    size: Option<usize>,
+    max_size: Option<usize>,
+    scale_threshold: Option<usize>,
+    initial_size: Option<usize>,
+    queue_capacity: Option<usize>,
+    rejection_policy: RejectionPolicy,
+    nested_policy: NestedPolicy,
+    panic_policy: PanicPolicy,
+    drop_policy: DropPolicy,
+    keep_alive: Option<KeepAlive>,
+    name_prefix: Option<String>,
+    stack_size: Option<usize>,
+    on_thread_start: Option<ThreadHook>,
+    on_thread_stop: Option<ThreadHook>,
+    on_job_start: Option<JobStartHook>,
+    on_job_end: Option<JobEndHook>,
+    listener: Option<Arc<dyn WorkerListener>>,
+    on_job_timeout: Option<ThreadHook>,
+    replace_timed_out: bool,
+    propagators: Vec<CaptureHook>,
+    failed_job_capacity: Option<usize>,
+    job_classes: Vec<(String, u32)>,
+    #[cfg(feature = "affinity")]
+    affinity: Option<Affinity>,
+    #[cfg(feature = "thread-priority")]
+    nice: Option<i32>,
+}
+
+impl ThreadPoolBuilder {
+    /// Create a builder with the default configuration.
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::default()
+    }
+
+    /// Set the number of worker threads. Defaults to the number of CPUs.
+    pub fn size(mut self, size: usize) -> ThreadPoolBuilder {
+        self.size = Some(size);
+        self
+    }
+
+    /// Make the pool elastic, growing up to `max_size` workers under load.
+    /// See `ThreadPool::elastic`.
+    pub fn max_size(mut self, max_size: usize) -> ThreadPoolBuilder {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Set how many jobs may be pending before an elastic pool spawns an
+    /// extra worker. Defaults to the pool size.
+    pub fn scale_threshold(mut self, pending: usize) -> ThreadPoolBuilder {
+        self.scale_threshold = Some(pending);
+        self
+    }
+
+    /// Start with only `initial` workers and spawn the rest, up to `size`,
+    /// as jobs are submitted while every worker is busy.
+    ///
+    /// Workers spawned this way stay until the pool is shut down, unless a
+    /// keep-alive lets them go. `initial` may be zero; values above `size`
+    /// are treated as `size`. By default all workers are spawned by `build`.
+    pub fn lazy(mut self, initial: usize) -> ThreadPoolBuilder {
+        self.initial_size = Some(initial);
+        self
+    }
+
+    /// Bound the job queue to `capacity` jobs. Unbounded by default.
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Set what `execute` does when the job queue is full.
+    pub fn rejection_policy(mut self, policy: RejectionPolicy) -> ThreadPoolBuilder {
+        self.rejection_policy = policy;
+        self
+    }
+
+    /// Set what submitting a job from one of the pool's own workers does.
+    /// See `NestedPolicy`.
+    pub fn nested_policy(mut self, policy: NestedPolicy) -> ThreadPoolBuilder {
+        self.nested_policy = policy;
+        self
+    }
+
+    /// Set what workers do when a job panics.
+    pub fn panic_policy(mut self, policy: PanicPolicy) -> ThreadPoolBuilder {
+        self.panic_policy = policy;
+        self
+    }
+
+    /// Set what dropping the pool waits for. See `DropPolicy`.
+    ///
+    /// This only applies to a plain drop; `shutdown` and `shutdown_now`
+    /// behave as documented regardless.
+    pub fn drop_policy(mut self, policy: DropPolicy) -> ThreadPoolBuilder {
+        self.drop_policy = policy;
+        self
+    }
+
+    /// Let idle workers exit after `timeout`, keeping at least `core_size`.
+    /// See `ThreadPool::set_keep_alive`.
+    pub fn keep_alive(mut self, timeout: Duration, core_size: usize) -> ThreadPoolBuilder {
+        self.keep_alive = Some(KeepAlive { timeout, core_size });
+        self
+    }
+
+    /// Name worker threads `"{prefix}-{id}"`.
+    pub fn name_prefix<S: Into<String>>(mut self, prefix: S) -> ThreadPoolBuilder {
+        self.name_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Set the stack size of worker threads in bytes.
+    pub fn stack_size(mut self, bytes: usize) -> ThreadPoolBuilder {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Run `f` on each worker thread when it starts, with the worker id.
+    pub fn on_thread_start<F>(mut self, f: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_thread_start = Some(Arc::new(f));
+        self
+    }
+
+    /// Run `f` on each worker thread right before it exits, with the worker
+    /// id. This also runs when the thread dies from an escalated panic.
+    pub fn on_thread_stop<F>(mut self, f: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_thread_stop = Some(Arc::new(f));
+        self
+    }
+
+    /// Run `f` on the worker thread right before each job starts.
+    pub fn on_job_start<F>(mut self, f: F) -> ThreadPoolBuilder
+    where
+        F: Fn(&JobMeta) + Send + Sync + 'static,
+    {
+        self.on_job_start = Some(Arc::new(f));
+        self
+    }
+
+    /// Run `f` on the worker thread after each job, with the time the job
+    /// took to run. This also runs when the job panics.
+    pub fn on_job_end<F>(mut self, f: F) -> ThreadPoolBuilder
+    where
+        F: Fn(&JobMeta, Duration) + Send + Sync + 'static,
+    {
+        self.on_job_end = Some(Arc::new(f));
+        self
+    }
+
+    /// Report worker lifecycle events to `listener`. See `WorkerListener`.
+    pub fn worker_listener<L>(mut self, listener: L) -> ThreadPoolBuilder
+    where
+        L: WorkerListener + 'static,
+    {
+        self.listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// Run `f` with the worker id when a job submitted with
+    /// `ThreadPool::execute_with_timeout` runs past its timeout.
+    ///
+    /// The hook runs on the pool's timer thread, so it should return quickly.
+    pub fn on_job_timeout<F>(mut self, f: F) -> ThreadPoolBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_job_timeout = Some(Arc::new(f));
+        self
+    }
+
+    /// Replace a worker's thread with a fresh one after it finishes a job
+    /// that ran past its timeout. Off by default.
+    pub fn replace_timed_out_workers(mut self, replace: bool) -> ThreadPoolBuilder {
+        self.replace_timed_out = replace;
+        self
+    }
+
+    /// Pin worker threads to CPU cores. See `Affinity` for the choices.
+    ///
+    /// Pinning is done by each worker thread as it starts. If it fails, for
+    /// example on a platform other than Linux, a warning is logged and the
+    /// worker runs unpinned.
+    #[cfg(feature = "affinity")]
+    pub fn affinity(mut self, affinity: Affinity) -> ThreadPoolBuilder {
+        self.affinity = Some(affinity);
+        self
+    }
+
+    /// Run worker threads with the given nice value, from -20 (highest
+    /// priority) to 19 (lowest).
+    ///
+    /// A positive value keeps a background pool from competing with other
+    /// threads such as the acceptor. Raising the priority above the
+    /// default usually needs extra privileges. If setting it fails, for
+    /// example on a platform other than Linux, a warning is logged and the
+    /// worker keeps the default priority.
+    #[cfg(feature = "thread-priority")]
+    pub fn thread_priority(mut self, nice: i32) -> ThreadPoolBuilder {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Add a job class for `ThreadPool::execute_in` with the given weight.
+    ///
+    /// While several classes have jobs queued, workers take jobs from each in
+    /// proportion to its weight. There is always a `"default"` class of
+    /// weight 1 for jobs submitted without a class; naming it here changes
+    /// its weight.
+    pub fn job_class<S: Into<String>>(mut self, name: S, weight: u32) -> ThreadPoolBuilder {
+        self.job_classes.push((name.into(), weight));
+        self
+    }
+
+    /// Keep the last `capacity` jobs that panicked for
+    /// `ThreadPool::failed_jobs`. Defaults to 64; zero keeps none.
+    pub fn failed_job_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.failed_job_capacity = Some(capacity);
+        self
+    }
+
+    /// Carry thread-local context, such as a request id, from the thread
+    /// that submits a job to the worker that runs it.
+    ///
+    /// `capture` runs on the submitting thread, and its result is passed to
+    /// `install` on the worker right before the job starts. Whatever
+    /// `install` returns is dropped after the job finishes, so a guard can
+    /// restore the worker's previous state. Call this more than once to
+    /// carry several kinds of context. Jobs run by the timer thread, such as
+    /// those from `execute_after`, carry no context.
+    pub fn propagate_context<C, G, Cap, Ins>(
+        mut self,
+        capture: Cap,
+        install: Ins,
+    ) -> ThreadPoolBuilder
+    where
+        Cap: Fn() -> C + Send + Sync + 'static,
+        Ins: Fn(C) -> G + Send + Sync + 'static,
+        C: Send + 'static,
+        G: 'static,
+    {
+        let install = Arc::new(install);
+        self.propagators.push(Arc::new(move || {
+            let context = capture();
+            let install = Arc::clone(&install);
+            Box::new(move || Box::new(install(context)) as Box<dyn Any>) as Installer
+        }));
+        self
+    }
+
+    /// Create the pool and spawn its workers.
+    ///
+    /// Workers spawned before a failure are shut down again.
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        let size = match self.size {
+            Some(size) => size,
+            None => thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        if size == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+        if self.queue_capacity == Some(0) {
+            return Err(PoolCreationError::ZeroCapacity);
+        }
+        let initial = self.initial_size.map_or(size, |initial| initial.min(size));
+        let lazy_size = if initial < size { size } else { 0 };
+        let scaling = match self.max_size {
+            Some(max) if max < size => return Err(PoolCreationError::MaxBelowMin),
+            Some(max) => Some(Scaling {
+                max,
+                threshold: self.scale_threshold.unwrap_or(size),
+                lazy_size,
+            }),
+            None if lazy_size > 0 => Some(Scaling {
+                max: size,
+                threshold: size,
+                lazy_size,
+            }),
+            None => None,
+        };
+
+        let mut classes = vec![DEFAULT_CLASS.to_string()];
+        let mut weights = vec![1];
+        for (name, weight) in self.job_classes {
+            if weight == 0 {
+                return Err(PoolCreationError::ZeroWeight);
+            }
+            match classes.iter().position(|class| *class == name) {
+                Some(index) => weights[index] = weight,
+                None => {
+                    classes.push(name);
+                    weights.push(weight);
+                }
+            }
+        }
+
+        let (sender, receiver) =
+            queue::channel(self.queue_capacity, &weights, |message: &Message| {
+                message.class
+            });
+        let (exit_sender, exits) = mpsc::channel();
+        let context = WorkerContext {
+            receiver,
+            exits: exit_sender,
+            panic_policy: Arc::new(Mutex::new(self.panic_policy)),
+            keep_alive: Arc::new(Mutex::new(self.keep_alive)),
+            live: Arc::new(AtomicUsize::new(0)),
+            pool_id: NEXT_POOL_ID.fetch_add(1, Ordering::SeqCst),
+            completed: Arc::new(AtomicUsize::new(0)),
+            queue_wait: Arc::new(WaitRecorder::new()),
+            failed_jobs: Arc::new(DeadLetters::new(
+                self.failed_job_capacity
+                    .unwrap_or(DEFAULT_FAILED_JOB_CAPACITY),
+            )),
+            name_prefix: self.name_prefix.map(Arc::new),
+            stack_size: self.stack_size,
+            on_thread_start: self.on_thread_start,
+            on_thread_stop: self.on_thread_stop,
+            on_job_start: self.on_job_start,
+            on_job_end: self.on_job_end,
+            listener: self.listener,
+            #[cfg(feature = "affinity")]
+            affinity: self.affinity.map(Arc::new),
+            #[cfg(feature = "thread-priority")]
+            nice: self.nice,
+        };
+
+        // 途中で spawn に失敗した場合は pool の Drop で既存の worker を止める。
+        let pool = ThreadPool {
+            handle: PoolHandle {
+                core: Arc::new(Core {
+                    workers: Mutex::new(Workers {
+                        list: Vec::with_capacity(initial),
+                        next_id: 0,
+                        exits,
+                        retired_panics: 0,
+                    }),
+                    sender,
+                    rejection_policy: Mutex::new(self.rejection_policy),
+                    nested_policy: self.nested_policy,
+                    context,
+                    scaling: Mutex::new(scaling),
+                    propagators: self.propagators,
+                    classes,
+                }),
+            },
+            scheduler: Mutex::new(None),
+            on_job_timeout: self.on_job_timeout,
+            replace_timed_out: self.replace_timed_out,
+            drop_policy: self.drop_policy,
+        };
+        {
+            let core = &pool.handle.core;
+            let mut workers = core.workers.lock().unwrap();
+            for _ in 0..initial {
+                workers
+                    .spawn(&core.context, false)
+                    .map_err(PoolCreationError::Spawn)?;
+            }
+        }
+
+        Ok(pool)
+    }
+
+    /// Create the pool and install it as the one `ThreadPool::global`
+    /// returns.
+    ///
+    /// Returns `PoolCreationError::GlobalExists` if the global pool has
+    /// already been created, whether by an earlier call or by
+    /// `ThreadPool::global` itself.
+    pub fn build_global(self) -> Result<&'static ThreadPool, PoolCreationError> {
+        if GLOBAL.get().is_some() {
+            return Err(PoolCreationError::GlobalExists);
+        }
+        // 他の thread と競争して負けた場合、作った pool はここで捨てる。
+        GLOBAL
+            .set(self.build()?)
+            .map_err(|_| PoolCreationError::GlobalExists)?;
+        Ok(GLOBAL.get().unwrap())
+    }
+}
+
+impl fmt::Debug for ThreadPoolBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThreadPoolBuilder")
+            .field("size", &self.size)
+            .field("max_size", &self.max_size)
+            .field("initial_size", &self.initial_size)
+            .field("queue_capacity", &self.queue_capacity)
+            .field("rejection_policy", &self.rejection_policy)
+            .field("nested_policy", &self.nested_policy)
+            .field("panic_policy", &self.panic_policy)
+            .field("drop_policy", &self.drop_policy)
+            .field("name_prefix", &self.name_prefix)
+            .field("stack_size", &self.stack_size)
+            .field("replace_timed_out", &self.replace_timed_out)
+            .finish()
+    }
+}
@@ -0,0 +1,348 @@
+//! Compressing response bodies for clients that accept it.
+
+use std::io::{self, Write};
+
+#[cfg(feature = "brotli")]
+use brotli::CompressorWriter;
+#[cfg(feature = "gzip")]
+use flate2::write::GzEncoder;
+
+use super::{Method, Middleware, Next, Request, Response, Status};
+
+const DEFAULT_MIN_SIZE: u64 = 1024;
+// その場で圧縮するので、 quality は速さを優先した中ほどにする。
+#[cfg(feature = "brotli")]
+const BROTLI_QUALITY: u32 = 5;
+#[cfg(feature = "brotli")]
+const BROTLI_WINDOW_BITS: u32 = 22;
+#[cfg(feature = "brotli")]
+const BROTLI_BUFFER: usize = 4096;
+
+/// A middleware that compresses response bodies with brotli or gzip.
+///
+/// A response is compressed when the request's `Accept-Encoding` allows
+/// one of the two, its `Content-Type` is text-like (`text/*`, JSON,
+/// JavaScript, XML, SVG or WebAssembly) and its body is at least
+/// `min_size` bytes. Brotli is preferred when the client accepts both
+/// equally. Streamed bodies, whose size is not known, are always
+/// compressed. Responses that already have a `Content-Encoding`, such as
+/// precompressed static files, partial content and bodiless responses are
+/// left alone.
+///
+/// Compressed responses get a `Content-Encoding` and a weak `ETag`, and
+/// every response that could have been compressed gets
+/// `Vary: Accept-Encoding` so caches keep the variants apart.
+///
+/// Gzip is done with flate2 and brotli with the brotli crate, behind the
+/// `gzip` and `brotli` features. Both are on by default, and an encoding
+/// whose feature is off is not offered.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: u64,
+    brotli: bool,
+}
+
+/// A content coding the server can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Compression {
+    /// Create a layer that compresses bodies of 1 KiB or more.
+    pub fn new() -> Compression {
+        Compression {
+            min_size: DEFAULT_MIN_SIZE,
+            brotli: true,
+        }
+    }
+
+    /// Set the smallest body in bytes worth compressing. Defaults to 1 KiB.
+    pub fn min_size(mut self, bytes: u64) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Set whether brotli is offered besides gzip. Defaults to `true`.
+    pub fn brotli(mut self, brotli: bool) -> Compression {
+        self.brotli = brotli;
+        self
+    }
+
+    fn choose(&self, request: &Request) -> Option<Encoding> {
+        let encodings: Vec<Encoding> = [Encoding::Brotli, Encoding::Gzip]
+            .into_iter()
+            .filter(|&e| e.is_supported() && (self.brotli || e != Encoding::Brotli))
+            .collect();
+        preferred_encoding(request, &encodings)
+    }
+
+    fn apply(&self, mut response: Response, encoding: Option<Encoding>, head: bool) -> Response {
+        let status = response.status();
+        if status == Status::PartialContent
+            || status == Status::NoContent
+            || status == Status::NotModified
+            || response.headers().contains("Content-Encoding")
+            || !response
+                .headers()
+                .get("Content-Type")
+                .is_some_and(is_compressible)
+        {
+            return response;
+        }
+        if response.body_len().is_some_and(|len| len < self.min_size) {
+            return response;
+        }
+
+        add_vary(&mut response, "Accept-Encoding");
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => return response,
+        };
+        let headers = response.headers_mut();
+        headers.insert("Content-Encoding", encoding.as_str());
+        // range は圧縮前の byte 列に対するものなので、圧縮したものには出せない。
+        headers.remove("Accept-Ranges");
+        // 中身の byte 列が変わるので、強い validator のままにはできない。
+        if let Some(etag) = headers.get("ETag") {
+            if !etag.starts_with("W/") {
+                let weak = format!("W/{}", etag);
+                headers.insert("ETag", weak);
+            }
+        }
+        // HEAD では送らない body を圧縮しても無駄になる。 header は GET と同じにする。
+        if head {
+            response.omit_encoded_body();
+        } else {
+            response.encode_body(encoding);
+        }
+        response
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        // 次に渡すと request は読めなくなるので、先に選んでおく。
+        let encoding = self.choose(&request);
+        let head = *request.method() == Method::Head;
+        self.apply(next.run(request), encoding, head)
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new()
+    }
+}
+
+impl Encoding {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match *self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    // この build で圧縮できるか。 precompressed な static file には要らない。
+    fn is_supported(&self) -> bool {
+        match *self {
+            Encoding::Brotli => cfg!(feature = "brotli"),
+            Encoding::Gzip => cfg!(feature = "gzip"),
+        }
+    }
+
+    pub(crate) fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut encoder = self.encoder(Vec::new());
+        // Vec への書き込みは失敗しない。
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // 圧縮できない encoding は Compression が選ばないので、そのまま書けばいい。
+    pub(crate) fn encoder<W: Write>(&self, inner: W) -> Encoder<W> {
+        match *self {
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => Encoder::Brotli(Box::new(CompressorWriter::new(
+                inner,
+                BROTLI_BUFFER,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW_BITS,
+            ))),
+            #[cfg(not(feature = "brotli"))]
+            Encoding::Brotli => Encoder::Identity(inner),
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(inner, flate2::Compression::default())),
+            #[cfg(not(feature = "gzip"))]
+            Encoding::Gzip => Encoder::Identity(inner),
+        }
+    }
+}
+
+// flush は、それまでに書いた分を client が展開できるところまで送る。
+pub(crate) enum Encoder<W: Write> {
+    #[cfg(feature = "brotli")]
+    Brotli(Box<CompressorWriter<W>>),
+    #[cfg(feature = "gzip")]
+    Gzip(GzEncoder<W>),
+    #[cfg(not(all(feature = "brotli", feature = "gzip")))]
+    Identity(W),
+}
+
+impl<W: Write> Encoder<W> {
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            // into_inner は最後に書く分の error を捨てるので、先に flush
+            // して確かめる。
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(mut encoder) => {
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.finish(),
+            #[cfg(not(all(feature = "brotli", feature = "gzip")))]
+            Encoder::Identity(inner) => Ok(inner),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(ref mut encoder) => encoder.write(buf),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(ref mut encoder) => encoder.write(buf),
+            #[cfg(not(all(feature = "brotli", feature = "gzip")))]
+            Encoder::Identity(ref mut inner) => inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(ref mut encoder) => encoder.flush(),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(ref mut encoder) => encoder.flush(),
+            #[cfg(not(all(feature = "brotli", feature = "gzip")))]
+            Encoder::Identity(ref mut inner) => inner.flush(),
+        }
+    }
+}
+
+// Accept-Encoding で q 値が一番高いものを選ぶ。同じなら `encodings` で先のもの。
+// header がなければ何でもいいことになっているが、圧縮を読めない client もいるので
+// 圧縮しない。
+pub(crate) fn preferred_encoding(request: &Request, encodings: &[Encoding]) -> Option<Encoding> {
+    let accept = request.accepts_encodings();
+    if accept.is_missing() {
+        return None;
+    }
+    let names: Vec<&str> = encodings.iter().map(Encoding::as_str).collect();
+    let best = accept.best(&names)?;
+    encodings.iter().copied().find(|e| e.as_str() == best)
+}
+
+pub(crate) fn add_vary(response: &mut Response, field: &str) {
+    let headers = response.headers_mut();
+    let varies = headers.get_all("Vary").any(|value| {
+        value
+            .split(',')
+            .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case(field))
+    });
+    if !varies {
+        headers.append("Vary", field);
+    }
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+        )
+}
+
+#[cfg(all(test, any(feature = "brotli", feature = "gzip")))]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::{Compression, Encoding};
+    use crate::{Request, Response, Router, Status};
+
+    // 圧縮したものが元に戻り、 flush を挟んで書いても 1 つの stream になる。
+    fn check_round_trips(encoding: Encoding, decompress: fn(&[u8]) -> Vec<u8>) {
+        let data = b"hello, hello, hello\n".repeat(1000);
+        let compressed = encoding.compress(&data);
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(decompress(&compressed), data);
+        assert_eq!(decompress(&encoding.compress(b"")), b"");
+
+        let mut encoder = encoding.encoder(Vec::new());
+        encoder.write_all(b"data: one\n\n").unwrap();
+        encoder.flush().unwrap();
+        encoder.write_all(b"data: two\n\n").unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(decompress(&compressed), b"data: one\n\ndata: two\n\n");
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn brotli_round_trips() {
+        check_round_trips(Encoding::Brotli, |data| {
+            let mut decoded = Vec::new();
+            brotli::Decompressor::new(data, 4096)
+                .read_to_end(&mut decoded)
+                .unwrap();
+            decoded
+        });
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_round_trips() {
+        check_round_trips(Encoding::Gzip, |data| {
+            let mut decoded = Vec::new();
+            flate2::read::GzDecoder::new(data)
+                .read_to_end(&mut decoded)
+                .unwrap();
+            decoded
+        });
+    }
+
+    #[test]
+    fn answers_head_with_the_headers_of_get() {
+        let router = Router::new().middleware(Compression::new()).get("/", |_| {
+            Response::new(Status::Ok)
+                .header("Content-Type", "text/plain")
+                .header("ETag", "\"a\"")
+                .body("hello\n".repeat(1000))
+        });
+        let respond = |method: &str| {
+            let head = format!("{} / HTTP/1.1\r\nAccept-Encoding: br, gzip\r\n\r\n", method);
+            router.handle(Request::read_from(&mut head.as_bytes()).unwrap().unwrap())
+        };
+        let (get, head) = (respond("GET"), respond("HEAD"));
+        assert!(get.headers().contains("Content-Encoding"));
+        assert_eq!(
+            head.headers().into_iter().collect::<Vec<_>>(),
+            get.headers().into_iter().collect::<Vec<_>>()
+        );
+        // 送らない body は圧縮しない。
+        assert!(head.is_body_omitted());
+        assert_eq!(head.body_len(), None);
+    }
+}
@@ -0,0 +1,270 @@
+//! Reading `Cookie` headers and building `Set-Cookie` ones (RFC 6265).
+
+use std::fmt;
+use std::slice;
+use std::time::Duration;
+
+/// The cookies sent with a request, returned by `Request::cookies`.
+#[derive(Debug, Clone, Default)]
+pub struct Cookies {
+    pairs: Vec<(String, String)>,
+}
+
+impl Cookies {
+    /// Parse the value of a `Cookie` header, such as `a=1; b=2`. Pairs
+    /// without `=` are skipped.
+    pub fn parse(header: &str) -> Cookies {
+        let mut cookies = Cookies::default();
+        cookies.extend(header);
+        cookies
+    }
+
+    pub(crate) fn extend(&mut self, header: &str) {
+        let pairs = header.split(';').filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let name = name.trim();
+            let value = value.trim();
+            // 値は "..." で囲まれて送られることもある。
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            (!name.is_empty()).then(|| (name.to_owned(), value.to_owned()))
+        });
+        self.pairs.extend(pairs);
+    }
+
+    /// Return the value of the cookie `name`. If the client sent several,
+    /// the first one is usually the one with the most specific path.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|pair| pair.0 == name)
+            .map(|pair| &*pair.1)
+    }
+
+    /// Return `true` if the cookie `name` was sent.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Return the number of cookies.
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    /// Return `true` if no cookies were sent.
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// Iterate over the cookies as `(name, value)` pairs.
+    pub fn iter(&self) -> CookiePairs<'_> {
+        CookiePairs(self.pairs.iter())
+    }
+}
+
+impl<'a> IntoIterator for &'a Cookies {
+    type Item = (&'a str, &'a str);
+    type IntoIter = CookiePairs<'a>;
+
+    fn into_iter(self) -> CookiePairs<'a> {
+        self.iter()
+    }
+}
+
+/// An iterator over cookies, returned by `Cookies::iter`.
+#[derive(Debug)]
+pub struct CookiePairs<'a>(slice::Iter<'a, (String, String)>);
+
+impl<'a> Iterator for CookiePairs<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        self.0.next().map(|pair| (&*pair.0, &*pair.1))
+    }
+}
+
+/// When browsers send a cookie with requests from other sites.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SameSite {
+    /// Only with requests from the same site.
+    Strict,
+    /// Also when following a link from another site.
+    Lax,
+    /// With every request. Browsers require `Secure` for this.
+    None,
+}
+
+impl SameSite {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// A cookie to set on the client, added to a response with
+/// `Response::cookie`.
+///
+/// Its `Display` is the value of the `Set-Cookie` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// Create a session cookie, which browsers drop when they close.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not a token, or `value` has characters cookies
+    /// cannot carry, such as spaces, `"`, `,`, `;` or `\`. Encode such
+    /// values first, e.g. as hex.
+    pub fn new<N: Into<String>, V: Into<String>>(name: N, value: V) -> Cookie {
+        let name = name.into();
+        let value = value.into();
+        assert!(is_token(&name), "invalid cookie name: {:?}", name);
+        assert!(
+            value.bytes().all(is_cookie_octet),
+            "invalid cookie value: {:?}",
+            value
+        );
+        Cookie {
+            name,
+            value,
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// Create a cookie that makes the client delete its cookie `name`. Its
+    /// path and domain have to match the ones the cookie was set with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not a token.
+    pub fn removal<N: Into<String>>(name: N) -> Cookie {
+        Cookie::new(name, "").max_age(Duration::ZERO)
+    }
+
+    /// Return the name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the value.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Send the cookie only for paths below `path`, e.g. `/` for the whole
+    /// site. Browsers default to the directory of the request path.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `path` has `;` or control characters.
+    pub fn path<S: Into<String>>(mut self, path: S) -> Cookie {
+        let path = path.into();
+        assert!(is_attribute_value(&path), "invalid cookie path: {:?}", path);
+        self.path = Some(path);
+        self
+    }
+
+    /// Send the cookie to `domain` and its subdomains too. Browsers
+    /// default to the host of the request only.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `domain` has `;` or control characters.
+    pub fn domain<S: Into<String>>(mut self, domain: S) -> Cookie {
+        let domain = domain.into();
+        assert!(
+            is_attribute_value(&domain),
+            "invalid cookie domain: {:?}",
+            domain
+        );
+        self.domain = Some(domain);
+        self
+    }
+
+    /// Keep the cookie for `max_age`, instead of until the browser closes.
+    pub fn max_age(mut self, max_age: Duration) -> Cookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Set whether the cookie is only sent over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    /// Set whether the cookie is hidden from scripts.
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Set when the cookie is sent with requests from other sites.
+    /// Browsers treat cookies without it as `Lax`.
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(ref path) = self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(ref domain) = self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+// RFC 7230 3.2.6 の token。
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+// RFC 6265 4.1.1 の cookie-octet。
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+fn is_attribute_value(value: &str) -> bool {
+    !value.is_empty() && !value.contains(|c: char| c == ';' || c.is_control())
+}
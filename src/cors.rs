@@ -0,0 +1,211 @@
+//! Cross-origin resource sharing.
+
+use std::time::Duration;
+
+use super::compression::add_vary;
+use super::{Method, Middleware, Next, Request, Response, Status};
+
+/// A middleware that lets browsers call the server from other origins.
+///
+/// Preflight requests, `OPTIONS` with an `Origin` and an
+/// `Access-Control-Request-Method`, are answered here with
+/// `204 No Content` and the allowed methods and headers, or with
+/// `403 Forbidden` if the origin, method or headers are not allowed.
+/// Other requests from an allowed origin get `Access-Control-Allow-Origin`
+/// and the other headers on their response; requests without an `Origin`
+/// are passed through untouched.
+///
+/// Nothing is allowed until origins are added with `allow_origin` or
+/// `allow_any_origin`. With credentials allowed, the origin is echoed back
+/// instead of `*`, as browsers require.
+#[derive(Debug, Clone)]
+pub struct Cors {
+    origins: Vec<String>,
+    any_origin: bool,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    any_header: bool,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    /// Create a layer that allows `GET`, `HEAD` and `POST` from no origins.
+    pub fn new() -> Cors {
+        Cors {
+            origins: Vec::new(),
+            any_origin: false,
+            methods: vec![Method::Get, Method::Head, Method::Post],
+            headers: Vec::new(),
+            any_header: false,
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Allow requests from `origin`, e.g. `https://example.com`.
+    pub fn allow_origin<S: Into<String>>(mut self, origin: S) -> Cors {
+        self.origins.push(origin.into());
+        self
+    }
+
+    /// Allow requests from every origin.
+    pub fn allow_any_origin(mut self) -> Cors {
+        self.any_origin = true;
+        self
+    }
+
+    /// Set the methods preflights allow. Defaults to `GET`, `HEAD` and
+    /// `POST`.
+    pub fn allow_methods<I: IntoIterator<Item = Method>>(mut self, methods: I) -> Cors {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Allow request headers besides the ones browsers always send, such
+    /// as `Content-Type: application/json` or `Authorization`. `*` allows
+    /// any header.
+    pub fn allow_headers<I, S>(mut self, headers: I) -> Cors
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        for header in headers {
+            let header = header.into();
+            if header == "*" {
+                self.any_header = true;
+            } else {
+                self.headers.push(header);
+            }
+        }
+        self
+    }
+
+    /// Let scripts read these response headers besides the basic ones.
+    pub fn expose_headers<I, S>(mut self, headers: I) -> Cors
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.expose_headers
+            .extend(headers.into_iter().map(Into::into));
+        self
+    }
+
+    /// Set whether requests may carry cookies and `Authorization`.
+    /// Defaults to `false`.
+    pub fn allow_credentials(mut self, credentials: bool) -> Cors {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Set how long browsers may cache a preflight result. Browsers use
+    /// their own short default when unset.
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.any_origin
+            || self
+                .origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+
+    fn allow_origin_header(&self, response: &mut Response, origin: &str) {
+        if self.any_origin && !self.credentials {
+            response
+                .headers_mut()
+                .insert("Access-Control-Allow-Origin", "*");
+            return;
+        }
+        response
+            .headers_mut()
+            .insert("Access-Control-Allow-Origin", origin);
+        if self.credentials {
+            response
+                .headers_mut()
+                .insert("Access-Control-Allow-Credentials", "true");
+        }
+    }
+
+    fn preflight(&self, request: &Request, origin: &str, method: &str) -> Response {
+        let mut response = Response::new(Status::NoContent);
+        if !self.any_origin || self.credentials {
+            add_vary(&mut response, "Origin");
+        }
+        add_vary(&mut response, "Access-Control-Request-Method");
+        add_vary(&mut response, "Access-Control-Request-Headers");
+        // 要求された header は "a, b" のような列で来る。
+        let requested: Vec<&str> = request
+            .headers()
+            .get_all("Access-Control-Request-Headers")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        let allowed = self.allows_origin(origin)
+            && self.methods.iter().any(|m| m.as_str() == method)
+            && requested.iter().all(|name| {
+                self.any_header || self.headers.iter().any(|h| h.eq_ignore_ascii_case(name))
+            });
+        if !allowed {
+            debug!("Rejecting a CORS preflight from {}", origin);
+            response.set_status(Status::Forbidden);
+            return response;
+        }
+
+        self.allow_origin_header(&mut response, origin);
+        let methods: Vec<&str> = self.methods.iter().map(Method::as_str).collect();
+        let headers = response.headers_mut();
+        headers.insert("Access-Control-Allow-Methods", methods.join(", "));
+        if !requested.is_empty() {
+            headers.insert("Access-Control-Allow-Headers", requested.join(", "));
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert("Access-Control-Max-Age", max_age.as_secs().to_string());
+        }
+        response
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Cors {
+        Cors::new()
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        let origin = match request.header("Origin") {
+            Some(origin) => origin.to_owned(),
+            None => return next.run(request),
+        };
+        if *request.method() == Method::Options {
+            if let Some(method) = request.header("Access-Control-Request-Method") {
+                return self.preflight(&request, &origin, method);
+            }
+        }
+
+        let mut response = next.run(request);
+        // origin ごとに違う response になるので、 cache が混ざらないようにする。
+        if !self.any_origin || self.credentials {
+            add_vary(&mut response, "Origin");
+        }
+        if !self.allows_origin(&origin) {
+            return response;
+        }
+        self.allow_origin_header(&mut response, &origin);
+        if !self.expose_headers.is_empty() {
+            let exposed = self.expose_headers.join(", ");
+            response
+                .headers_mut()
+                .insert("Access-Control-Expose-Headers", exposed);
+        }
+        response
+    }
+}
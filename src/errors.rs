@@ -0,0 +1,98 @@
+//! Custom responses for requests that end in an error.
+
+use std::error::Error;
+use std::fmt;
+
+use super::{Request, Response, Status};
+
+type PageHandler = Box<dyn Fn(&Request) -> Response + Send + Sync + 'static>;
+type ErrorHook = Box<dyn Fn(&HttpError, &Request) -> Response + Send + Sync + 'static>;
+
+/// Why a request ended in an error, passed to the hook set with
+/// `ServerBuilder::on_error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpError {
+    /// The handler answered with this error status and an empty body.
+    Status(Status),
+    /// The handler panicked, with the panic message.
+    Panic(String),
+}
+
+impl HttpError {
+    /// Return the status the error is answered with by default.
+    pub fn status(&self) -> Status {
+        match *self {
+            HttpError::Status(status) => status,
+            HttpError::Panic(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HttpError::Status(status) => write!(f, "{}", status),
+            HttpError::Panic(ref message) => write!(f, "handler panicked: {}", message),
+        }
+    }
+}
+
+impl Error for HttpError {}
+
+// ServerBuilder で登録された error 用の handler。
+#[derive(Default)]
+pub(crate) struct ErrorPages {
+    pub(crate) not_found: Option<PageHandler>,
+    pub(crate) internal_error: Option<PageHandler>,
+    pub(crate) on_error: Option<ErrorHook>,
+}
+
+impl ErrorPages {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.not_found.is_none() && self.internal_error.is_none() && self.on_error.is_none()
+    }
+
+    // 中身のない error の response を、登録された page に置き換える。
+    // 元の response の header (Allow など) は page になければ残す。
+    pub(crate) fn replace(&self, response: Response, request: Option<&Request>) -> Response {
+        let status = response.status();
+        if status.code() < 400 || response.body_len() != Some(0) {
+            return response;
+        }
+        let mut page = match self.render(&HttpError::Status(status), request) {
+            Some(page) => page,
+            None => return response,
+        };
+        for (name, value) in response.headers() {
+            if !page.headers().contains(name) {
+                page.headers_mut().append(name, value);
+            }
+        }
+        page
+    }
+
+    // status ごとの handler を先に、なければ on_error を使う。どちらもなければ None。
+    pub(crate) fn render(&self, error: &HttpError, request: Option<&Request>) -> Option<Response> {
+        let request = request?;
+        let page = match error.status() {
+            Status::NotFound => self.not_found.as_ref(),
+            Status::InternalServerError => self.internal_error.as_ref(),
+            _ => None,
+        };
+        match (page, &self.on_error) {
+            (Some(page), _) => Some(page(request)),
+            (None, Some(on_error)) => Some(on_error(error, request)),
+            (None, None) => None,
+        }
+    }
+}
+
+impl fmt::Debug for ErrorPages {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ErrorPages")
+            .field("not_found", &self.not_found.is_some())
+            .field("internal_error", &self.internal_error.is_some())
+            .field("on_error", &self.on_error.is_some())
+            .finish()
+    }
+}
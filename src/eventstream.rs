@@ -0,0 +1,167 @@
+//! Server-Sent Events (`text/event-stream`).
+
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use super::{Response, Status};
+
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// One event of an `EventStream`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    data: String,
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    /// Create an unnamed event carrying `data`, which may span lines.
+    pub fn new<S: Into<String>>(data: S) -> Event {
+        Event {
+            data: data.into(),
+            event: None,
+            id: None,
+            retry: None,
+        }
+    }
+
+    /// Set the event name, which picks the listener on the client. Line
+    /// breaks are removed.
+    pub fn event<S: Into<String>>(mut self, name: S) -> Event {
+        self.event = Some(single_line(name.into()));
+        self
+    }
+
+    /// Set the id the client sends back in `Last-Event-ID` when it
+    /// reconnects. Line breaks and NUL are removed.
+    pub fn id<S: Into<String>>(mut self, id: S) -> Event {
+        self.id = Some(single_line(id.into()).replace('\0', ""));
+        self
+    }
+
+    /// Set how long the client waits before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let mut frame = String::new();
+        if let Some(ref event) = self.event {
+            frame.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(ref id) = self.id {
+            frame.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(retry) = self.retry {
+            frame.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        // 改行は CRLF, LF, CR のどれでも行の区切りになる。
+        for line in self.data.split("\r\n").flat_map(|l| l.split(['\r', '\n'])) {
+            frame.push_str("data: ");
+            frame.push_str(line);
+            frame.push('\n');
+        }
+        frame.push('\n');
+        writer.write_all(frame.as_bytes())
+    }
+}
+
+/// A response that streams events pushed through an `EventSender`.
+///
+/// Create one with `EventStream::channel` and return it from the handler
+/// with `Response::from`; hand the sender to whatever produces the events,
+/// e.g. a job on the pool. Each event is flushed to the client as soon as
+/// it is sent. The stream ends when every sender has been dropped, and the
+/// senders start failing once the client has disconnected.
+///
+/// Comments are sent while no events come, so proxies keep the connection
+/// open and a disconnected client is noticed. The response ties up the
+/// connection's worker until it ends.
+pub struct EventStream {
+    events: Receiver<Event>,
+    keep_alive: Option<Duration>,
+}
+
+impl EventStream {
+    /// Create a stream and the sender that feeds it.
+    pub fn channel() -> (EventSender, EventStream) {
+        let (sender, events) = mpsc::channel();
+        let stream = EventStream {
+            events,
+            keep_alive: Some(DEFAULT_KEEP_ALIVE),
+        };
+        (EventSender { sender }, stream)
+    }
+
+    /// Set how long the stream may be idle before a comment is sent, or
+    /// `None` to send none. Defaults to 15 seconds.
+    pub fn keep_alive(mut self, interval: Option<Duration>) -> EventStream {
+        self.keep_alive = interval;
+        self
+    }
+}
+
+impl From<EventStream> for Response {
+    fn from(stream: EventStream) -> Response {
+        let EventStream { events, keep_alive } = stream;
+        Response::new(Status::Ok)
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .stream(move |writer| {
+                // 最初の event を待つ間も head は届けておく。
+                writer.flush()?;
+                loop {
+                    let event = match keep_alive {
+                        Some(interval) => events.recv_timeout(interval),
+                        None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    match event {
+                        Ok(event) => event.write_to(writer)?,
+                        // client が切れていれば、ここで書けずに終わる。
+                        Err(RecvTimeoutError::Timeout) => writer.write_all(b":\n\n")?,
+                        Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                    }
+                    writer.flush()?;
+                }
+            })
+    }
+}
+
+impl fmt::Debug for EventStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EventStream")
+            .field("keep_alive", &self.keep_alive)
+            .finish()
+    }
+}
+
+/// Sends events to an `EventStream`. Clone it to send from several places.
+#[derive(Debug, Clone)]
+pub struct EventSender {
+    sender: Sender<Event>,
+}
+
+impl EventSender {
+    /// Send `event` to the client.
+    ///
+    /// Fails with `BrokenPipe` once the stream has ended, usually because
+    /// the client disconnected, so the producer can stop.
+    pub fn send(&self, event: Event) -> io::Result<()> {
+        self.sender
+            .send(event)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "event stream has ended"))
+    }
+}
+
+fn single_line(value: String) -> String {
+    if value.contains(['\r', '\n']) {
+        value.replace(['\r', '\n'], "")
+    } else {
+        value
+    }
+}
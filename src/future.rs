@@ -0,0 +1,101 @@
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use super::{panic_message, JobError};
+
+/// The result of a job submitted with `ThreadPool::spawn`, as a future.
+///
+/// Resolves to the job's return value, or a `JobError` if the job panicked
+/// or was dropped before it could run.
+pub struct JobFuture<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+struct Slot<T> {
+    result: Option<Result<T, JobError>>,
+    waker: Option<Waker>,
+}
+
+impl<T> Future for JobFuture<T> {
+    type Output = Result<T, JobError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut slot = self.slot.lock().unwrap();
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// 結果を JobFuture に渡す job。 ResultJob と同じく、 send に失敗した時に
+// クロージャを取り出せるよう struct として持つ。
+pub(crate) struct FutureJob<F, T> {
+    pub f: F,
+    completer: Completer<T>,
+}
+
+impl<F, T> FutureJob<F, T> {
+    pub fn new(f: F) -> (FutureJob<F, T>, JobFuture<T>) {
+        let slot = Arc::new(Mutex::new(Slot {
+            result: None,
+            waker: None,
+        }));
+        let job = FutureJob {
+            f,
+            completer: Completer(Arc::clone(&slot)),
+        };
+        (job, JobFuture { slot })
+    }
+}
+
+impl<F, T> FutureJob<F, T>
+where
+    F: FnOnce() -> T,
+{
+    pub fn run(self) {
+        match panic::catch_unwind(AssertUnwindSafe(self.f)) {
+            Ok(value) => self.completer.complete(Ok(value)),
+            Err(payload) => {
+                let msg = panic_message(&*payload);
+                self.completer.complete(Err(JobError::Panicked(msg)));
+                // worker 側でも panic として扱えるように投げ直す。
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+// 結果を渡さずに drop された場合は Canceled を渡す。
+struct Completer<T>(Arc<Mutex<Slot<T>>>);
+
+impl<T> Completer<T> {
+    fn complete(&self, result: Result<T, JobError>) {
+        let waker = {
+            let mut slot = match self.0.lock() {
+                Ok(slot) => slot,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if slot.result.is_none() {
+                slot.result = Some(result);
+            }
+            slot.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        // complete 済みなら何もしない。
+        self.complete(Err(JobError::Canceled));
+    }
+}
@@ -0,0 +1,408 @@
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Mutex};
+
+use super::builder::{CaptureHook, Installer};
+#[cfg(feature = "futures")]
+use super::future::{FutureJob, JobFuture};
+use super::queue;
+use super::{
+    call, CancellableJob, CancellationToken, ExecuteError, JobHandle, Message, NestedPolicy,
+    RejectionPolicy, ResultJob, Scaling, TryExecuteError, WorkerContext, Workers, BATCH_SIZE,
+    DEFAULT_PRIORITY, POOL_ID,
+};
+
+/// A cheap, cloneable handle for submitting jobs to a `ThreadPool`.
+///
+/// Handles share the pool's queue and workers but do not own them; only the
+/// `ThreadPool` controls shutdown. Once the pool has been shut down or
+/// dropped, submitting through a handle fails with `Disconnected`.
+#[derive(Clone)]
+pub struct PoolHandle {
+    pub(crate) core: Arc<Core>,
+}
+
+// pool と handle が共有する、 job を送るのに必要な状態。
+pub(crate) struct Core {
+    pub workers: Mutex<Workers>,
+    pub sender: queue::Sender<Message>,
+    pub rejection_policy: Mutex<RejectionPolicy>,
+    pub nested_policy: NestedPolicy,
+    pub context: WorkerContext,
+    pub scaling: Mutex<Option<Scaling>>,
+    pub propagators: Vec<CaptureHook>,
+    // job class の名前。 queue での class の番号の順に並ぶ。
+    pub classes: Vec<String>,
+}
+
+impl PoolHandle {
+    /// Submit a job to the pool. See `ThreadPool::execute`.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.send_job(f, call, DEFAULT_PRIORITY)
+    }
+
+    /// Submit a batch of jobs. See `ThreadPool::execute_all`.
+    pub fn execute_all<I, F>(&self, jobs: I) -> Result<(), ExecuteError<Vec<F>>>
+    where
+        I: IntoIterator<Item = F>,
+        F: FnOnce() + Send + 'static,
+    {
+        let sender = &self.core.sender;
+        let mut jobs = jobs.into_iter();
+        if self.nested_policy().is_some() {
+            // 1 つずつ送り、 nested policy に従う。
+            while let Some(f) = jobs.next() {
+                if let Err(err) = self.send_job(f, call, DEFAULT_PRIORITY) {
+                    return Err(err.map(|f| {
+                        let mut failed = vec![f];
+                        failed.extend(jobs);
+                        failed
+                    }));
+                }
+            }
+            return Ok(());
+        }
+        loop {
+            let chunk: Vec<_> = jobs
+                .by_ref()
+                .take(BATCH_SIZE)
+                .map(|f| self.outgoing(f, call))
+                .collect();
+            if chunk.is_empty() {
+                return Ok(());
+            }
+            let sent = if self.rejection_policy() == RejectionPolicy::Block {
+                sender
+                    .send_all(chunk, DEFAULT_PRIORITY, Outgoing::into_message)
+                    .map(|_| Vec::new())
+            } else {
+                sender.try_send_all(chunk, DEFAULT_PRIORITY, Outgoing::into_message)
+            };
+            let rest = match sent {
+                Ok(rest) => rest,
+                Err(mpsc::SendError(chunk)) => {
+                    let mut failed: Vec<F> = chunk.into_iter().map(|job| job.job).collect();
+                    failed.extend(jobs);
+                    return Err(ExecuteError::Disconnected(failed));
+                }
+            };
+            self.scale_up();
+            // 入りきらなかった分は 1 つずつ送り、 rejection policy に従う。
+            let mut rest = rest.into_iter();
+            while let Some(job) = rest.next() {
+                if let Err(err) = self.submit(job, DEFAULT_PRIORITY) {
+                    return Err(err.map(|f| {
+                        let mut failed = vec![f];
+                        failed.extend(rest.map(|job| job.job));
+                        failed.extend(jobs);
+                        failed
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Submit a job with the given priority. See
+    /// `ThreadPool::execute_with_priority`.
+    pub fn execute_with_priority<F>(&self, priority: i32, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.send_job(f, call, priority)
+    }
+
+    /// Submit a job to a job class. See `ThreadPool::execute_in`.
+    pub fn execute_in<F>(&self, class: &str, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let class = match self.core.classes.iter().position(|name| name == class) {
+            Some(class) => class,
+            None => panic!("no job class named '{}'", class),
+        };
+        let mut job = self.outgoing(f, call);
+        job.class = class;
+        self.submit(job, DEFAULT_PRIORITY)
+    }
+
+    /// Submit a named job. See `ThreadPool::execute_named`.
+    pub fn execute_named<S, F>(&self, name: S, f: F) -> Result<(), ExecuteError<F>>
+    where
+        S: Into<Arc<str>>,
+        F: FnOnce() + Send + 'static,
+    {
+        let mut job = self.outgoing(f, call);
+        job.name = Some(name.into());
+        self.submit(job, DEFAULT_PRIORITY)
+    }
+
+    /// Submit a cancellable job. See `ThreadPool::execute_with_token`.
+    pub fn execute_with_token<F>(
+        &self,
+        token: &CancellationToken,
+        f: F,
+    ) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job = CancellableJob {
+            f,
+            token: token.clone(),
+        };
+        self.send_job(job, CancellableJob::run, DEFAULT_PRIORITY)
+            .map_err(|err| err.map(|job| job.f))
+    }
+
+    /// Submit a job without blocking. See `ThreadPool::try_execute`.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), TryExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_send_job(f, call).map_err(|err| match err {
+            mpsc::TrySendError::Full(f) => TryExecuteError::Full(f),
+            mpsc::TrySendError::Disconnected(f) => TryExecuteError::Disconnected(f),
+        })
+    }
+
+    /// Submit a job whose return value can be retrieved later. See
+    /// `ThreadPool::execute_with_result`.
+    pub fn execute_with_result<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError<F>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.send_job(ResultJob { f, sender }, ResultJob::run, DEFAULT_PRIORITY)
+            .map(|_| JobHandle { receiver })
+            .map_err(|err| err.map(|job| job.f))
+    }
+
+    /// Submit a job and return a future that resolves to its result. See
+    /// `ThreadPool::spawn`.
+    #[cfg(feature = "futures")]
+    pub fn spawn<F, T>(&self, f: F) -> Result<JobFuture<T>, ExecuteError<F>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (job, future) = FutureJob::new(f);
+        self.send_job(job, FutureJob::run, DEFAULT_PRIORITY)
+            .map(|_| future)
+            .map_err(|err| err.map(|job| job.f))
+    }
+
+    /// Return `true` if the pool has been shut down or dropped.
+    pub fn is_closed(&self) -> bool {
+        self.core.sender.is_closed()
+    }
+
+    // queue に溜まっている job の数。
+    pub(crate) fn queued(&self) -> usize {
+        self.core.sender.len()
+    }
+
+    // job を待っている worker の数。 queue に溜まっている分は引く。
+    pub(crate) fn idle_workers(&self) -> usize {
+        let size = self.core.workers.lock().unwrap().list.len();
+        size.saturating_sub(self.core.sender.active() + self.core.sender.len())
+    }
+
+    // 送る thread の context を取り込んだ Outgoing を作る。
+    fn outgoing<J>(&self, job: J, run: fn(J)) -> Outgoing<J> {
+        Outgoing {
+            job,
+            run,
+            name: None,
+            class: 0,
+            context: self
+                .core
+                .propagators
+                .iter()
+                .map(|capture| capture())
+                .collect(),
+        }
+    }
+
+    // この pool の worker から呼ばれていれば、従うべき nested policy を返す。
+    fn nested_policy(&self) -> Option<NestedPolicy> {
+        let policy = self.core.nested_policy;
+        if policy == NestedPolicy::Queue {
+            return None;
+        }
+        let pool = POOL_ID.with(|pool| pool.get());
+        if pool == Some(self.core.context.pool_id) {
+            Some(policy)
+        } else {
+            None
+        }
+    }
+
+    fn rejection_policy(&self) -> RejectionPolicy {
+        *self.core.rejection_policy.lock().unwrap()
+    }
+
+    // elastic な pool で待ち job が閾値を超えていれば worker を増やす。
+    // lazy_size に満たない pool では、暇な worker が居なければ増やす。
+    pub(crate) fn scale_up(&self) {
+        let scaling = match *self.core.scaling.lock().unwrap() {
+            Some(scaling) => scaling,
+            None => return,
+        };
+        let sender = &self.core.sender;
+        let pending = sender.len();
+        let lazy = self.core.context.live.load(Ordering::SeqCst) < scaling.lazy_size;
+        // pause 中に増やしても、新しい worker は job を取れずにすぐ終わる。
+        if pending == 0 || (!lazy && pending <= scaling.threshold) || sender.is_paused() {
+            return;
+        }
+        // shutdown や resize は lock を持ったまま worker の終了を待つので、
+        // job の中から呼ばれた時に待ち合わないよう try_lock にする。
+        // lock が取れない時は他の誰かが worker を増減している。
+        let mut workers = match self.core.workers.try_lock() {
+            Ok(workers) => workers,
+            Err(_) => return,
+        };
+        // pool が worker を待ち終えた後に増やさないようにする。 shutdown と
+        // Drop は queue を閉じてから workers の lock を手放す。
+        if self.core.sender.is_closed() {
+            return;
+        }
+        workers.reap_exited();
+        let count = workers.list.len();
+        if count < scaling.lazy_size {
+            // 送った job を受け取れる暇な worker が居れば、それに任せる。
+            let idle = count.saturating_sub(sender.active());
+            if pending <= idle {
+                return;
+            }
+            debug!("Every worker is busy; starting another.");
+            if let Err(err) = workers.spawn(&self.core.context, false) {
+                error!("Failed to add a worker: {}", err);
+            }
+        } else if count < scaling.max && pending > scaling.threshold {
+            debug!("Queue is backing up; adding a worker.");
+            if let Err(err) = workers.spawn(&self.core.context, true) {
+                error!("Failed to add a worker: {}", err);
+            }
+        }
+    }
+
+    pub(crate) fn send_job<J>(
+        &self,
+        job: J,
+        run: fn(J),
+        priority: i32,
+    ) -> Result<(), ExecuteError<J>>
+    where
+        J: Send + 'static,
+    {
+        self.submit(self.outgoing(job, run), priority)
+    }
+
+    fn submit<J>(&self, job: Outgoing<J>, priority: i32) -> Result<(), ExecuteError<J>>
+    where
+        J: Send + 'static,
+    {
+        match self.nested_policy() {
+            Some(NestedPolicy::Inline) => {
+                trace!("Running a nested job inline.");
+                (job.run)(job.job);
+                return Ok(());
+            }
+            Some(NestedPolicy::Reject) => return Err(ExecuteError::Nested(job.job)),
+            _ => {}
+        }
+        let result = self.enqueue(job, priority);
+        if result.is_ok() {
+            self.scale_up();
+        }
+        result
+    }
+
+    // rejection policy に従って queue に入れる。失敗したら job を返す。
+    fn enqueue<J>(&self, job: Outgoing<J>, priority: i32) -> Result<(), ExecuteError<J>>
+    where
+        J: Send + 'static,
+    {
+        let sender = &self.core.sender;
+        let into = Outgoing::into_message;
+        let disconnected = |mpsc::SendError(job): mpsc::SendError<Outgoing<J>>| {
+            ExecuteError::Disconnected(job.job)
+        };
+        let policy = self.rejection_policy();
+        match policy {
+            RejectionPolicy::Block => sender.send(job, priority, into).map_err(disconnected),
+            RejectionPolicy::DiscardOldest => {
+                // 追い出された job はここで drop される。
+                sender
+                    .send_evicting(job, priority, into)
+                    .map(|_| ())
+                    .map_err(disconnected)
+            }
+            RejectionPolicy::CallerRuns | RejectionPolicy::Error => {
+                match sender.try_send(job, priority, into) {
+                    Ok(()) => Ok(()),
+                    Err(mpsc::TrySendError::Full(job)) => {
+                        if policy == RejectionPolicy::CallerRuns {
+                            (job.run)(job.job);
+                            Ok(())
+                        } else {
+                            Err(ExecuteError::Rejected(job.job))
+                        }
+                    }
+                    Err(mpsc::TrySendError::Disconnected(job)) => {
+                        Err(ExecuteError::Disconnected(job.job))
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn try_send_job<J>(&self, job: J, run: fn(J)) -> Result<(), mpsc::TrySendError<J>>
+    where
+        J: Send + 'static,
+    {
+        let result = self.core.sender.try_send(
+            self.outgoing(job, run),
+            DEFAULT_PRIORITY,
+            Outgoing::into_message,
+        );
+        if result.is_ok() {
+            self.scale_up();
+        }
+        result.map_err(|err| match err {
+            mpsc::TrySendError::Full(job) => mpsc::TrySendError::Full(job.job),
+            mpsc::TrySendError::Disconnected(job) => mpsc::TrySendError::Disconnected(job.job),
+        })
+    }
+}
+
+// queue に入る前の job。 queue に受け付けられた時に Message になるので、
+// 送れなかった時は元の job をそのまま返せる。
+struct Outgoing<J> {
+    job: J,
+    run: fn(J),
+    name: Option<Arc<str>>,
+    class: usize,
+    context: Vec<Installer>,
+}
+
+impl<J: Send + 'static> Outgoing<J> {
+    fn into_message(self) -> Message {
+        let Outgoing {
+            job,
+            run,
+            name,
+            class,
+            context,
+        } = self;
+        Message {
+            name,
+            class,
+            context,
+            ..Message::new(Box::new(move || run(job)))
+        }
+    }
+}
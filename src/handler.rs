@@ -0,0 +1,21 @@
+//! The trait for code that answers requests.
+
+use super::{Request, Response};
+
+/// Answers requests, e.g. an application passed to `Server::run`.
+///
+/// Closures `Fn(Request) -> Response` are handlers, and so is `Router`.
+/// Implement it on your own type to keep state the responses depend on.
+pub trait Handler: Send + Sync + 'static {
+    /// Return the response to `request`.
+    fn handle(&self, request: Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: Request) -> Response {
+        self(request)
+    }
+}
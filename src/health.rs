@@ -0,0 +1,289 @@
+//! Health check endpoints for load balancers.
+
+use std::fmt;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::server::Config;
+use super::{CancellationToken, Method, PoolHandle, Request, Response, Status, Version};
+
+// triage で見る request の先頭の大きさ。これに収まらない head は health
+// check ではないとみなす。
+const PEEK_BYTES: usize = 4096;
+// 届いていない接続を見直す間隔。
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Health check endpoints served by the server itself, before the
+/// middlewares and the handler, given to `ServerBuilder::health_check`.
+///
+/// `GET` of the path reports readiness: `200 OK` while the server takes
+/// requests, and `503 Service Unavailable` once a shutdown has started,
+/// after the pool has stopped, or while more jobs are queued than
+/// `max_queued` allows. `GET` of the path with `/live` appended reports
+/// liveness, which is `200 OK` whenever the server answers at all. Both
+/// answer with a short plain text reason, and are not cached.
+///
+/// While every worker is busy, new connections are looked at by a thread
+/// of the server's own before they are queued: health checks are answered
+/// there and closed, and the rest go on to the pool. So the checks are
+/// answered even when the pool is saturated, which is when load balancers
+/// need them most. Health checks sent on a kept-alive connection are
+/// answered by its worker.
+pub struct HealthCheck {
+    path: String,
+    live_path: String,
+    max_queued: Option<usize>,
+    shutdown_delay: Duration,
+    // bind で server の状態につなぐ。
+    shutdown: Option<CancellationToken>,
+    pool: Option<PoolHandle>,
+}
+
+impl HealthCheck {
+    /// Create health checks at `path`, such as `/healthz`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `path` does not start with `/`.
+    pub fn new<S: Into<String>>(path: S) -> HealthCheck {
+        let path = path.into();
+        assert!(
+            path.starts_with('/'),
+            "a health check path must start with /"
+        );
+        let live_path = format!("{}/live", path.trim_end_matches('/'));
+        HealthCheck {
+            path,
+            live_path,
+            max_queued: None,
+            shutdown_delay: Duration::ZERO,
+            shutdown: None,
+            pool: None,
+        }
+    }
+
+    /// Report not ready while more than `jobs` are waiting in the pool's
+    /// queue. Unlimited by default.
+    pub fn max_queued(mut self, jobs: usize) -> HealthCheck {
+        self.max_queued = Some(jobs);
+        self
+    }
+
+    /// Keep serving for `delay` after a shutdown starts, reporting not
+    /// ready, before the connections are drained. This gives load
+    /// balancers time to notice and stop sending requests. Defaults to
+    /// zero.
+    pub fn shutdown_delay(mut self, delay: Duration) -> HealthCheck {
+        self.shutdown_delay = delay;
+        self
+    }
+
+    pub(crate) fn delay(&self) -> Duration {
+        self.shutdown_delay
+    }
+
+    pub(crate) fn attach(&mut self, shutdown: CancellationToken, pool: PoolHandle) {
+        self.shutdown = Some(shutdown);
+        self.pool = Some(pool);
+    }
+
+    // health check の request でなければ None。
+    pub(crate) fn respond(&self, request: &Request) -> Option<Response> {
+        if !matches!(*request.method(), Method::Get | Method::Head) {
+            return None;
+        }
+        self.answer(request.path())
+    }
+
+    // path が health check のものなら response を返す。
+    fn answer(&self, path: &str) -> Option<Response> {
+        let (status, reason) = if path == self.live_path {
+            (Status::Ok, "live".to_string())
+        } else if path == self.path {
+            match self.unready() {
+                None => (Status::Ok, "ready".to_string()),
+                Some(reason) => (Status::ServiceUnavailable, reason),
+            }
+        } else {
+            return None;
+        };
+        Some(
+            Response::new(status)
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .header("Cache-Control", "no-store")
+                .body(format!("{}\n", reason)),
+        )
+    }
+
+    // ready でなければ理由を返す。
+    fn unready(&self) -> Option<String> {
+        if self.shutdown.as_ref().is_some_and(|t| t.is_cancelled()) {
+            return Some("shutting down".to_string());
+        }
+        let pool = self.pool.as_ref()?;
+        if pool.is_closed() {
+            return Some("pool stopped".to_string());
+        }
+        let queued = pool.queued();
+        match self.max_queued {
+            Some(max) if queued > max => Some(format!("{} jobs queued", queued)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for HealthCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HealthCheck")
+            .field("path", &self.path)
+            .field("max_queued", &self.max_queued)
+            .field("shutdown_delay", &self.shutdown_delay)
+            .finish()
+    }
+}
+
+// worker が空いていないときに accept した接続を見て、 health check ならその場で
+// 返し、そうでなければ dispatch に渡す thread。
+pub(crate) struct Triage {
+    sender: Sender<TcpStream>,
+}
+
+impl Triage {
+    pub(crate) fn spawn<F>(config: Arc<Config>, dispatch: F) -> io::Result<Triage>
+    where
+        F: Fn(TcpStream) + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("hello-health".to_string())
+            .spawn(move || triage(&receiver, &config, dispatch))?;
+        Ok(Triage { sender })
+    }
+
+    // thread が止まっていたら接続を返す。
+    pub(crate) fn send(&self, stream: TcpStream) -> Result<(), TcpStream> {
+        self.sender.send(stream).map_err(|err| err.0)
+    }
+}
+
+fn triage<F: Fn(TcpStream)>(receiver: &Receiver<TcpStream>, config: &Config, dispatch: F) {
+    let check = match config.health_check {
+        Some(ref check) => check,
+        None => return,
+    };
+    let timeout = config.header_timeout.unwrap_or(Duration::from_secs(10));
+    let mut pending: Vec<(TcpStream, Instant)> = Vec::new();
+    let mut buf = [0; PEEK_BYTES];
+    loop {
+        // 待っている接続がなければ、次が来るまで眠る。
+        if pending.is_empty() {
+            match receiver.recv() {
+                Ok(stream) => pending.push((stream, Instant::now())),
+                Err(_) => return,
+            }
+        }
+        loop {
+            match receiver.try_recv() {
+                Ok(stream) => pending.push((stream, Instant::now())),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        let mut i = 0;
+        while i < pending.len() {
+            let (ref stream, accepted) = pending[i];
+            let peeked = stream
+                .set_nonblocking(true)
+                .and_then(|_| stream.peek(&mut buf));
+            let seen = match peeked {
+                Ok(0) => Seen::Closed,
+                Ok(n) => classify(&buf[..n], n == buf.len(), check),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if accepted.elapsed() >= timeout {
+                        Seen::Closed
+                    } else {
+                        Seen::Incomplete
+                    }
+                }
+                Err(_) => Seen::Closed,
+            };
+            if let Seen::Incomplete = seen {
+                i += 1;
+                continue;
+            }
+            let (stream, _) = pending.swap_remove(i);
+            match seen {
+                Seen::Health(head, response) => answer(stream, head, response, config),
+                Seen::Other => match stream.set_nonblocking(false) {
+                    Ok(()) => dispatch(stream),
+                    Err(err) => debug!("Failed to hand over a connection: {}", err),
+                },
+                Seen::Closed | Seen::Incomplete => {}
+            }
+        }
+        if !pending.is_empty() {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+enum Seen {
+    Incomplete,
+    Closed,
+    Other,
+    // head の byte 数と、返す response。
+    Health(usize, Response),
+}
+
+// 覗いた bytes から、接続をどう扱うか決める。
+fn classify(peeked: &[u8], full: bool, check: &HealthCheck) -> Seen {
+    let line_end = match peeked.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if full => return Seen::Other,
+        None => return Seen::Incomplete,
+    };
+    let mut parts = peeked[..line_end].split(|&b| b == b' ');
+    let method = parts.next().unwrap_or(b"");
+    let target = parts.next().unwrap_or(b"");
+    let path = target.split(|&b| b == b'?').next().unwrap_or(b"");
+    let is_head = method == b"HEAD";
+    let response = match std::str::from_utf8(path) {
+        Ok(path) if method == b"GET" || is_head => check.answer(path),
+        _ => None,
+    };
+    let mut response = match response {
+        Some(response) => response,
+        None => return Seen::Other,
+    };
+    // 返す前に head を読み切っておく。
+    match peeked.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => {
+            if is_head {
+                response.omit_body();
+            }
+            Seen::Health(end + 4, response)
+        }
+        None if full => Seen::Other,
+        None => Seen::Incomplete,
+    }
+}
+
+fn answer(mut stream: TcpStream, head: usize, response: Response, config: &Config) {
+    let mut discard = vec![0; head];
+    let written = stream
+        .set_nonblocking(false)
+        .and_then(|_| stream.set_write_timeout(config.write_timeout))
+        .and_then(|_| stream.read_exact(&mut discard))
+        .and_then(|_| {
+            response
+                .header("Connection", "close")
+                .write_as(&mut stream, Version::Http11)
+        });
+    if let Err(err) = written {
+        debug!("Failed to answer a health check: {}", err);
+    }
+}
@@ -0,0 +1,979 @@
+//! HPACK header compression for HTTP/2 (RFC 7541).
+//!
+//! The decoder keeps the dynamic table the client's encoder builds up. The
+//! encoder never adds to the peer's table: every field is sent as a literal
+//! without indexing, with names from the static table where possible.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::sync::OnceLock;
+
+// 各 entry の大きさに足される分 (RFC 7541 4.1)。
+const ENTRY_OVERHEAD: usize = 32;
+
+/// An error decoding a header block. The connection cannot continue after
+/// one, since the dynamic tables are out of step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HpackError;
+
+impl fmt::Display for HpackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("malformed header block")
+    }
+}
+
+impl Error for HpackError {}
+
+// 接続ごとに 1 つ。 client の encoder と同じ dynamic table を持つ。
+pub(crate) struct Decoder {
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    // SETTINGS_HEADER_TABLE_SIZE で client に伝えた上限。
+    limit: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new(limit: usize) -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: limit,
+            limit,
+        }
+    }
+
+    pub(crate) fn decode(&mut self, mut block: &[u8]) -> Result<Vec<(String, String)>, HpackError> {
+        let mut fields = Vec::new();
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                // Indexed Header Field
+                let index = decode_int(&mut block, 7)?;
+                fields.push(self.get(index)?.clone());
+            } else if first & 0x40 != 0 {
+                // Literal Header Field with Incremental Indexing
+                let field = self.literal(&mut block, 6)?;
+                self.add(field.clone());
+                fields.push(field);
+            } else if first & 0x20 != 0 {
+                // Dynamic Table Size Update。 field より前にしか来ない。
+                if !fields.is_empty() {
+                    return Err(HpackError);
+                }
+                let size = decode_int(&mut block, 5)?;
+                if size > self.limit {
+                    return Err(HpackError);
+                }
+                self.max_size = size;
+                self.evict(0);
+            } else {
+                // Literal Header Field without Indexing / Never Indexed
+                fields.push(self.literal(&mut block, 4)?);
+            }
+        }
+        Ok(fields)
+    }
+
+    fn literal(&self, block: &mut &[u8], prefix: u8) -> Result<(String, String), HpackError> {
+        let index = decode_int(block, prefix)?;
+        let name = match index {
+            0 => decode_string(block)?,
+            index => self.get(index)?.0.clone(),
+        };
+        let value = decode_string(block)?;
+        Ok((name, value))
+    }
+
+    fn get(&self, index: usize) -> Result<&(String, String), HpackError> {
+        match index {
+            0 => Err(HpackError),
+            index if index <= STATIC_ENTRIES.len() => Ok(&static_entries()[index - 1]),
+            index => self
+                .table
+                .get(index - STATIC_ENTRIES.len() - 1)
+                .ok_or(HpackError),
+        }
+    }
+
+    fn add(&mut self, field: (String, String)) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // table より大きい entry は、 table を空にするだけで入らない。
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    // `incoming` byte 分の空きができるまで古いものから消す。
+    fn evict(&mut self, incoming: usize) {
+        while self.size + incoming > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Decoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Decoder")
+            .field("entries", &self.table.len())
+            .field("size", &self.size)
+            .field("max_size", &self.max_size)
+            .finish()
+    }
+}
+
+// response の header block を作る。 name は小文字で渡す。
+pub(crate) fn encode<'a, I>(status: u16, fields: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    let mut block = Vec::new();
+    let status = status.to_string();
+    match STATIC_ENTRIES[7..14]
+        .iter()
+        .position(|&(_, value)| value == status)
+    {
+        Some(offset) => encode_int(&mut block, 0x80, 7, 8 + offset),
+        None => {
+            encode_int(&mut block, 0x00, 4, 8);
+            encode_string(&mut block, &status);
+        }
+    }
+    for (name, value) in fields {
+        match STATIC_ENTRIES.iter().position(|&(n, _)| n == name) {
+            Some(index) => encode_int(&mut block, 0x00, 4, index + 1),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name);
+            }
+        }
+        encode_string(&mut block, value);
+    }
+    block
+}
+
+// 先頭 byte の下位 `prefix` bit から始まる整数 (RFC 7541 5.1)。
+fn decode_int(block: &mut &[u8], prefix: u8) -> Result<usize, HpackError> {
+    let (&first, mut rest) = block.split_first().ok_or(HpackError)?;
+    let max = (1usize << prefix) - 1;
+    let mut value = usize::from(first) & max;
+    if value == max {
+        let mut shift = 0;
+        loop {
+            let (&byte, tail) = rest.split_first().ok_or(HpackError)?;
+            rest = tail;
+            // 大きすぎる値は壊れているものとして扱う。
+            if shift > 21 {
+                return Err(HpackError);
+            }
+            value += usize::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+    }
+    *block = rest;
+    Ok(value)
+}
+
+fn encode_int(block: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn decode_string(block: &mut &[u8]) -> Result<String, HpackError> {
+    let huffman = block.first().ok_or(HpackError)? & 0x80 != 0;
+    let length = decode_int(block, 7)?;
+    if block.len() < length {
+        return Err(HpackError);
+    }
+    let (bytes, rest) = block.split_at(length);
+    *block = rest;
+    let bytes = if huffman {
+        huffman_decode(bytes)?
+    } else {
+        bytes.to_vec()
+    };
+    String::from_utf8(bytes).map_err(|_| HpackError)
+}
+
+// Huffman は使わず、そのまま送る。
+fn encode_string(block: &mut Vec<u8>, value: &str) {
+    encode_int(block, 0x00, 7, value.len());
+    block.extend_from_slice(value.as_bytes());
+}
+
+// 葉には LEAF を足した記号を入れる。
+const LEAF: u16 = 0x8000;
+const EOS: u16 = 256;
+
+fn tree() -> &'static Vec<[u16; 2]> {
+    static TREE: OnceLock<Vec<[u16; 2]>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut nodes = vec![[0; 2]];
+        for (symbol, &(code, length)) in CODES.iter().enumerate() {
+            let mut node = 0;
+            for i in (0..length).rev() {
+                let bit = (code >> i) as usize & 1;
+                if i == 0 {
+                    nodes[node][bit] = LEAF + symbol as u16;
+                } else {
+                    if nodes[node][bit] == 0 {
+                        nodes.push([0; 2]);
+                        nodes[node][bit] = (nodes.len() - 1) as u16;
+                    }
+                    node = usize::from(nodes[node][bit]);
+                }
+            }
+        }
+        nodes
+    })
+}
+
+fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>, HpackError> {
+    let tree = tree();
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let mut node = 0;
+    // 最後の記号の後に読んだ bit の数と、それが全部 1 だったか。
+    let mut pending = 0;
+    let mut all_ones = true;
+    for &byte in bytes {
+        for i in (0..8).rev() {
+            let bit = usize::from(byte >> i & 1);
+            let next = tree[node][bit];
+            if next & LEAF != 0 {
+                let symbol = next - LEAF;
+                if symbol == EOS {
+                    return Err(HpackError);
+                }
+                decoded.push(symbol as u8);
+                node = 0;
+                pending = 0;
+                all_ones = true;
+            } else {
+                node = usize::from(next);
+                pending += 1;
+                all_ones &= bit == 1;
+            }
+        }
+    }
+    // 余りは EOS の頭の 7 bit 以下でなければならない (RFC 7541 5.2)。
+    if pending > 7 || !all_ones {
+        return Err(HpackError);
+    }
+    Ok(decoded)
+}
+
+// static table の (name, value) を String で持っておく。 dynamic table と
+// 同じ形で返せるようにするため。
+fn static_entries() -> &'static Vec<(String, String)> {
+    static ENTRIES: OnceLock<Vec<(String, String)>> = OnceLock::new();
+    ENTRIES.get_or_init(|| {
+        STATIC_ENTRIES
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    })
+}
+
+// RFC 7541 Appendix A。
+const STATIC_ENTRIES: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// RFC 7541 Appendix B。 記号ごとの (code, bit 数)。
+const CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, encode_int, Decoder, HpackError};
+
+    // RFC の hex dump を bytes にする。空白は読み飛ばす。
+    fn unhex(dump: &str) -> Vec<u8> {
+        let digits: Vec<u8> = dump.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn decode(decoder: &mut Decoder, dump: &str) -> Vec<(String, String)> {
+        decoder.decode(&unhex(dump)).unwrap()
+    }
+
+    fn pairs(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // 新しい entry から順に並べた dynamic table と、その大きさ。
+    fn assert_table(decoder: &Decoder, entries: &[(&str, &str)], size: usize) {
+        assert_eq!(
+            decoder.table.iter().cloned().collect::<Vec<_>>(),
+            pairs(entries)
+        );
+        assert_eq!(decoder.size, size);
+    }
+
+    // RFC 7541 C.2.1
+    #[test]
+    fn literal_with_indexing() {
+        let mut decoder = Decoder::new(4096);
+        let fields = decode(
+            &mut decoder,
+            "400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572",
+        );
+        assert_eq!(fields, pairs(&[("custom-key", "custom-header")]));
+        assert_table(&decoder, &[("custom-key", "custom-header")], 55);
+    }
+
+    // RFC 7541 C.2.2
+    #[test]
+    fn literal_without_indexing() {
+        let mut decoder = Decoder::new(4096);
+        let fields = decode(&mut decoder, "040c 2f73 616d 706c 652f 7061 7468");
+        assert_eq!(fields, pairs(&[(":path", "/sample/path")]));
+        assert_table(&decoder, &[], 0);
+    }
+
+    // RFC 7541 C.2.3
+    #[test]
+    fn literal_never_indexed() {
+        let mut decoder = Decoder::new(4096);
+        let fields = decode(&mut decoder, "1008 7061 7373 776f 7264 0673 6563 7265 74");
+        assert_eq!(fields, pairs(&[("password", "secret")]));
+        assert_table(&decoder, &[], 0);
+    }
+
+    // RFC 7541 C.2.4
+    #[test]
+    fn indexed() {
+        let mut decoder = Decoder::new(4096);
+        assert_eq!(decode(&mut decoder, "82"), pairs(&[(":method", "GET")]));
+        assert_table(&decoder, &[], 0);
+    }
+
+    // C.3 と C.4 は同じ request を、 Huffman なしとありで送る。
+    fn requests(blocks: [&str; 3]) {
+        let mut decoder = Decoder::new(4096);
+        let fields = decode(&mut decoder, blocks[0]);
+        assert_eq!(
+            fields,
+            pairs(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        assert_table(&decoder, &[(":authority", "www.example.com")], 57);
+
+        let fields = decode(&mut decoder, blocks[1]);
+        assert_eq!(
+            fields,
+            pairs(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+        assert_table(
+            &decoder,
+            &[
+                ("cache-control", "no-cache"),
+                (":authority", "www.example.com"),
+            ],
+            110,
+        );
+
+        let fields = decode(&mut decoder, blocks[2]);
+        assert_eq!(
+            fields,
+            pairs(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_table(
+            &decoder,
+            &[
+                ("custom-key", "custom-value"),
+                ("cache-control", "no-cache"),
+                (":authority", "www.example.com"),
+            ],
+            164,
+        );
+    }
+
+    // RFC 7541 C.3
+    #[test]
+    fn requests_without_huffman() {
+        requests([
+            "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+            "8286 84be 5808 6e6f 2d63 6163 6865",
+            "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+        ]);
+    }
+
+    // RFC 7541 C.4
+    #[test]
+    fn requests_with_huffman() {
+        requests([
+            "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+            "8286 84be 5886 a8eb 1064 9cbf",
+            "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+        ]);
+    }
+
+    const DATE_1: &str = "Mon, 21 Oct 2013 20:13:21 GMT";
+    const DATE_2: &str = "Mon, 21 Oct 2013 20:13:22 GMT";
+    const COOKIE: &str = "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1";
+
+    // C.5 と C.6 は table を 256 byte にして、 entry を追い出していく。
+    fn responses(blocks: [&str; 3]) {
+        let mut decoder = Decoder::new(256);
+        let fields = decode(&mut decoder, blocks[0]);
+        assert_eq!(
+            fields,
+            pairs(&[
+                (":status", "302"),
+                ("cache-control", "private"),
+                ("date", DATE_1),
+                ("location", "https://www.example.com"),
+            ])
+        );
+        assert_table(
+            &decoder,
+            &[
+                ("location", "https://www.example.com"),
+                ("date", DATE_1),
+                ("cache-control", "private"),
+                (":status", "302"),
+            ],
+            222,
+        );
+
+        // :status 302 が追い出される。
+        let fields = decode(&mut decoder, blocks[1]);
+        assert_eq!(
+            fields,
+            pairs(&[
+                (":status", "307"),
+                ("cache-control", "private"),
+                ("date", DATE_1),
+                ("location", "https://www.example.com"),
+            ])
+        );
+        assert_table(
+            &decoder,
+            &[
+                (":status", "307"),
+                ("location", "https://www.example.com"),
+                ("date", DATE_1),
+                ("cache-control", "private"),
+            ],
+            222,
+        );
+
+        // 1 つの block の中で、 field を足すたびに追い出される。
+        let fields = decode(&mut decoder, blocks[2]);
+        assert_eq!(
+            fields,
+            pairs(&[
+                (":status", "200"),
+                ("cache-control", "private"),
+                ("date", DATE_2),
+                ("location", "https://www.example.com"),
+                ("content-encoding", "gzip"),
+                ("set-cookie", COOKIE),
+            ])
+        );
+        assert_table(
+            &decoder,
+            &[
+                ("set-cookie", COOKIE),
+                ("content-encoding", "gzip"),
+                ("date", DATE_2),
+            ],
+            215,
+        );
+    }
+
+    // RFC 7541 C.5
+    #[test]
+    fn responses_without_huffman() {
+        responses([
+            "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230
+             3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65
+             7861 6d70 6c65 2e63 6f6d",
+            "4803 3330 37c1 c0bf",
+            "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 3220
+             474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a 584f 5157
+             454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 3630 303b 2076
+             6572 7369 6f6e 3d31",
+        ]);
+    }
+
+    // RFC 7541 C.6
+    #[test]
+    fn responses_with_huffman() {
+        responses([
+            "4882 6402 5885 aec3 771a 4b61 96d0 7abe 9410 54d4 44a8 2005 9504 0b81 66e0
+             82a6 2d1b ff6e 919d 29ad 1718 63c7 8f0b 97c8 e9ae 82ae 43d3",
+            "4883 640e ffc1 c0bf",
+            "88c1 6196 d07a be94 1054 d444 a820 0595 040b 8166 e084 a62d 1bff c05a 839b
+             d9ab 77ad 94e7 821d d7f2 e6c7 b335 dfdf cd5b 3960 d5af 2708 7f36 72c1 ab27
+             0fb5 291f 9587 3160 65c0 03ed 4ee5 b106 3d50 07",
+        ]);
+    }
+
+    // C.5.1 の後の table。大きさは 222。
+    fn filled() -> Decoder {
+        let mut decoder = Decoder::new(256);
+        decode(
+            &mut decoder,
+            "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 3230
+             3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 7777 2e65
+             7861 6d70 6c65 2e63 6f6d",
+        );
+        decoder
+    }
+
+    #[test]
+    fn size_update_evicts() {
+        // 100 byte にすると、新しい location だけが残る。
+        let mut decoder = filled();
+        assert_eq!(decode(&mut decoder, "3f45"), pairs(&[]));
+        assert_table(&decoder, &[("location", "https://www.example.com")], 63);
+        assert_eq!(decoder.max_size, 100);
+        // 残った entry は index 62 で引ける。
+        assert_eq!(
+            decode(&mut decoder, "be"),
+            pairs(&[("location", "https://www.example.com")])
+        );
+        assert_eq!(decoder.decode(&unhex("bf")), Err(HpackError));
+
+        // 0 にすると空になり、その後は何も入らない。
+        assert_eq!(
+            decode(&mut decoder, "20 4803 3330 32"),
+            pairs(&[(":status", "302")])
+        );
+        assert_table(&decoder, &[], 0);
+    }
+
+    #[test]
+    fn size_update_up_to_limit() {
+        // 0 で空にしてから上限に戻す。 block の頭なら続けて送れる。
+        let mut decoder = filled();
+        let fields = decode(&mut decoder, "20 3fe1 01 4803 3330 37");
+        assert_eq!(fields, pairs(&[(":status", "307")]));
+        assert_table(&decoder, &[(":status", "307")], 42);
+        assert_eq!(decoder.max_size, 256);
+
+        // SETTINGS で伝えた上限は超えられない。
+        assert_eq!(decoder.decode(&unhex("3fe2 01")), Err(HpackError));
+        // field の後には置けない。
+        assert_eq!(decoder.decode(&unhex("be 20")), Err(HpackError));
+    }
+
+    // name が custom-key の、 table に足す literal。
+    fn custom(value: &str) -> Vec<u8> {
+        let mut block = unhex("400a 6375 7374 6f6d 2d6b 6579");
+        encode_int(&mut block, 0x00, 7, value.len());
+        block.extend_from_slice(value.as_bytes());
+        block
+    }
+
+    #[test]
+    fn entry_larger_than_table() {
+        // 256 byte を 1 超える entry は、 table を空にするだけで入らない。
+        let mut decoder = filled();
+        let value = "x".repeat(256 - 32 - 10 + 1);
+        let fields = decoder.decode(&custom(&value)).unwrap();
+        assert_eq!(fields, pairs(&[("custom-key", &value)]));
+        assert_table(&decoder, &[], 0);
+
+        // ちょうどの大きさなら、ほかを全部追い出して入る。
+        let mut decoder = filled();
+        let value = &value[1..];
+        decoder.decode(&custom(value)).unwrap();
+        assert_table(&decoder, &[("custom-key", value)], 256);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut decoder = Decoder::new(4096);
+        for dump in [
+            // index 0 と、 table にない index。
+            "80",
+            "be",
+            // 途中で切れた整数と文字列。
+            "ff",
+            "400a 6375 7374",
+            // EOS を含む Huffman と、長すぎる padding と、 1 でない padding。
+            "0084 ffff ffff",
+            "0083 ffff ff",
+            "0081 00",
+        ] {
+            assert_eq!(decoder.decode(&unhex(dump)), Err(HpackError), "{}", dump);
+        }
+    }
+
+    #[test]
+    fn encode_decodes() {
+        let block = encode(200, [("content-type", "text/plain"), ("x-hello", "world")]);
+        let fields = Decoder::new(4096).decode(&block).unwrap();
+        assert_eq!(
+            fields,
+            pairs(&[
+                (":status", "200"),
+                ("content-type", "text/plain"),
+                ("x-hello", "world"),
+            ])
+        );
+        let block = encode(418, []);
+        let fields = Decoder::new(4096).decode(&block).unwrap();
+        assert_eq!(fields, pairs(&[(":status", "418")]));
+    }
+}
@@ -0,0 +1,944 @@
+//! HTTP/1.x request parsing.
+//!
+//! Requests are read from a buffered stream one line at a time, so a
+//! request is parsed as its bytes arrive rather than from a fixed-size
+//! buffer. Both CRLF and bare LF line endings are accepted.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+use std::net::SocketAddr;
+use std::slice;
+use std::sync::Arc;
+
+#[cfg(feature = "json")]
+use serde::de::DeserializeOwned;
+
+#[cfg(feature = "json")]
+use super::json::{self, JsonError};
+use super::multipart;
+use super::{Accept, Cookies, Multipart, PeerIdentity, Session};
+
+/// The method of a request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    Connect,
+    Trace,
+    /// Any other method, as sent by the client.
+    Other(String),
+}
+
+impl Method {
+    /// Return the method as it appears on the request line.
+    pub fn as_str(&self) -> &str {
+        match *self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Connect => "CONNECT",
+            Method::Trace => "TRACE",
+            Method::Other(ref method) => method,
+        }
+    }
+
+    // method は大文字小文字を区別する。
+    pub(crate) fn parse(method: &str) -> Option<Method> {
+        let method = match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            "CONNECT" => Method::Connect,
+            "TRACE" => Method::Trace,
+            _ if is_token(method) => Method::Other(method.to_string()),
+            _ => return None,
+        };
+        Some(method)
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The HTTP version of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Version {
+    Http10,
+    Http11,
+    Http2,
+}
+
+impl Version {
+    /// Return the version as it appears on the request line.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+            Version::Http2 => "HTTP/2",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The header fields of a request or response, in the order they were
+/// received or added.
+///
+/// Names are matched case-insensitively. A name may appear more than once.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// Create an empty set of headers.
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// Return the first value of the header `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| entry.0.eq_ignore_ascii_case(name))
+            .map(|entry| &*entry.1)
+    }
+
+    /// Return every value of the header `name`.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.0.eq_ignore_ascii_case(name))
+            .map(|entry| &*entry.1)
+    }
+
+    /// Return `true` if the header `name` is present.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Add a value for `name`, keeping any existing ones.
+    pub fn append<N: Into<String>, V: Into<String>>(&mut self, name: N, value: V) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Set `name` to `value`, replacing any existing values.
+    pub fn insert<N: Into<String>, V: Into<String>>(&mut self, name: N, value: V) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    /// Remove every value of `name`.
+    pub fn remove(&mut self, name: &str) {
+        self.entries
+            .retain(|entry| !entry.0.eq_ignore_ascii_case(name));
+    }
+
+    /// Return the number of header fields, counting repeated names.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Return `true` if there are no header fields.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterate over the header fields as `(name, value)` pairs.
+    pub fn iter(&self) -> HeaderIter<'_> {
+        HeaderIter(self.entries.iter())
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = (&'a str, &'a str);
+    type IntoIter = HeaderIter<'a>;
+
+    fn into_iter(self) -> HeaderIter<'a> {
+        self.iter()
+    }
+}
+
+/// An iterator over header fields, returned by `Headers::iter`.
+#[derive(Debug)]
+pub struct HeaderIter<'a>(slice::Iter<'a, (String, String)>);
+
+impl<'a> Iterator for HeaderIter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        self.0.next().map(|entry| (&*entry.0, &*entry.1))
+    }
+}
+
+/// The parameters of a query string or a form body, in the order they
+/// appear.
+///
+/// Names and values are percent-decoded, with `+` read as a space. A name
+/// may appear more than once, and a parameter without `=` has an empty
+/// value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    pairs: Vec<(String, String)>,
+}
+
+impl Query {
+    /// Parse a query string, without the leading `?`.
+    pub fn parse(query: &str) -> Query {
+        let pairs = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode_query(name), decode_query(value))
+            })
+            .collect();
+        Query { pairs }
+    }
+
+    /// Return the first value of the parameter `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|pair| pair.0 == name)
+            .map(|pair| &*pair.1)
+    }
+
+    /// Return every value of the parameter `name`.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.pairs
+            .iter()
+            .filter(move |pair| pair.0 == name)
+            .map(|pair| &*pair.1)
+    }
+
+    /// Return `true` if the parameter `name` is present.
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Return the number of parameters, counting repeated names.
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    /// Return `true` if there are no parameters.
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// Iterate over the parameters as `(name, value)` pairs.
+    pub fn iter(&self) -> QueryPairs<'_> {
+        QueryPairs(self.pairs.iter())
+    }
+}
+
+impl<'a> IntoIterator for &'a Query {
+    type Item = (&'a str, &'a str);
+    type IntoIter = QueryPairs<'a>;
+
+    fn into_iter(self) -> QueryPairs<'a> {
+        self.iter()
+    }
+}
+
+/// An iterator over query parameters, returned by `Request::query_pairs`.
+#[derive(Debug)]
+pub struct QueryPairs<'a>(slice::Iter<'a, (String, String)>);
+
+impl<'a> Iterator for QueryPairs<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        self.0.next().map(|pair| (&*pair.0, &*pair.1))
+    }
+}
+
+/// An HTTP request.
+#[derive(Debug, Clone)]
+pub struct Request {
+    method: Method,
+    target: String,
+    version: Version,
+    headers: Headers,
+    body: Vec<u8>,
+    query: Query,
+    params: Vec<(String, String)>,
+    remote_addr: Option<SocketAddr>,
+    peer: Option<Arc<PeerIdentity>>,
+    id: Option<String>,
+    user: Option<String>,
+    session: Option<Session>,
+}
+
+impl Request {
+    /// Read one request from `reader`.
+    ///
+    /// Returns `Ok(None)` if the stream ends before the first byte of a
+    /// request, e.g. when the client closes an idle connection. The body is
+    /// read according to `Content-Length`, or decoded if it is sent with
+    /// `Transfer-Encoding: chunked`; without either the body is empty.
+    ///
+    /// If the stream has a read timeout, running into it while waiting for
+    /// the first byte is returned as `ParseError::Io`, and doing so later as
+    /// `ParseError::Timeout`.
+    ///
+    /// The request line and headers may take up to 64 KiB and there may be
+    /// up to 100 headers; `ParseError::HeadersTooLarge` is returned beyond
+    /// that. Bodies over 10 MiB are refused with `ParseError::BodyTooLarge`.
+    ///
+    /// `Expect: 100-continue` is not answered here, and other expectations
+    /// are refused with `ParseError::UnsupportedExpectation`.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Option<Request>, ParseError> {
+        Request::read_limited(reader, &Limits::default(), |_, _| {})
+    }
+
+    // head を読み終えたら、 body を読む前に `on_head` を呼ぶ。 client が
+    // 100 Continue を待っていれば、その引数は true になる。
+    pub(crate) fn read_limited<R, F>(
+        reader: &mut R,
+        limits: &Limits,
+        on_head: F,
+    ) -> Result<Option<Request>, ParseError>
+    where
+        R: BufRead,
+        F: FnOnce(&mut R, bool),
+    {
+        if reader.fill_buf().map_err(ParseError::Io)?.is_empty() {
+            return Ok(None);
+        }
+        let mut budget = limits.max_header_bytes;
+        // request line の前の空行は読み飛ばす (RFC 7230 3.5)。
+        let line = loop {
+            let line = read_line(reader, &mut budget).map_err(|err| match err {
+                ParseError::HeadersTooLarge => ParseError::UriTooLong,
+                err => err,
+            })?;
+            match line {
+                None => return Ok(None),
+                Some(ref line) if line.is_empty() => continue,
+                Some(line) => break line,
+            }
+        };
+        let (method, target, version) = parse_request_line(&line)?;
+
+        let mut headers = Headers::new();
+        loop {
+            let line = read_line(reader, &mut budget)?.ok_or(ParseError::Incomplete)?;
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() == limits.max_headers {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            let (name, value) = parse_header(&line)?;
+            headers.append(name, value);
+        }
+
+        let framing = body_framing(&headers)?;
+        // 送られてくる前に断る。
+        if matches!(framing, Framing::Length(length) if length > limits.max_body_bytes) {
+            return Err(ParseError::BodyTooLarge);
+        }
+        // HTTP/1.0 の client は Expect を知らないはずなので無視する (RFC 7231 5.1.1)。
+        let expects_continue = match headers.get("Expect") {
+            Some(_) if version == Version::Http10 => false,
+            Some(expect) if expect.eq_ignore_ascii_case("100-continue") => {
+                !matches!(framing, Framing::None | Framing::Length(0))
+            }
+            Some(_) => return Err(ParseError::UnsupportedExpectation),
+            None => false,
+        };
+        on_head(reader, expects_continue);
+
+        let body = match framing {
+            Framing::Chunked => read_chunked(reader, limits)?,
+            Framing::Length(length) => read_body(reader, length)?,
+            Framing::None => Vec::new(),
+        };
+
+        Ok(Some(Request::new(method, target, version, headers, body)))
+    }
+
+    // HTTP/2 の stream からも作る。
+    pub(crate) fn new(
+        method: Method,
+        target: String,
+        version: Version,
+        headers: Headers,
+        body: Vec<u8>,
+    ) -> Request {
+        let query = match target.split_once('?') {
+            Some((_, query)) => Query::parse(query),
+            None => Query::default(),
+        };
+        Request {
+            method,
+            target,
+            version,
+            headers,
+            body,
+            query,
+            params: Vec::new(),
+            remote_addr: None,
+            peer: None,
+            id: None,
+            user: None,
+            session: None,
+        }
+    }
+
+    /// Return the request method.
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// Return the request target as sent, including any query string.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// Return the path of the request target, without the query string.
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(index) => &self.target[..index],
+            None => &self.target,
+        }
+    }
+
+    /// Return the parameters of the query string.
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    /// Iterate over the parameters of the query string as `(name, value)`
+    /// pairs.
+    pub fn query_pairs(&self) -> QueryPairs<'_> {
+        self.query.iter()
+    }
+
+    /// Return the address of the client, if the request came in through a
+    /// `Server`.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// Return the identity the client proved with a TLS certificate, if
+    /// the listener asks for one with `TlsConfig::client_ca` and the client
+    /// sent it. Always `None` without the `tls` feature.
+    pub fn peer_identity(&self) -> Option<&PeerIdentity> {
+        self.peer.as_deref()
+    }
+
+    /// Return the id given to the request by the `RequestId` middleware.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Return the cookies sent with the request in its `Cookie` headers.
+    pub fn cookies(&self) -> Cookies {
+        let mut cookies = Cookies::default();
+        for header in self.headers.get_all("Cookie") {
+            cookies.extend(header);
+        }
+        cookies
+    }
+
+    /// Return the user the request was authenticated as by `Auth`, if any.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Return the session given to the request by `Sessions`.
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    /// Return the HTTP version of the request.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Return the request headers.
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Return the first value of the header `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Return the media types accepted by the client, from `Accept`.
+    pub fn accepts(&self) -> Accept {
+        Accept::media(self.headers.get_all("Accept"))
+    }
+
+    /// Return the content codings accepted by the client, from
+    /// `Accept-Encoding`.
+    pub fn accepts_encodings(&self) -> Accept {
+        Accept::encodings(self.headers.get_all("Accept-Encoding"))
+    }
+
+    /// Return the languages accepted by the client, from
+    /// `Accept-Language`.
+    pub fn accepts_languages(&self) -> Accept {
+        Accept::languages(self.headers.get_all("Accept-Language"))
+    }
+
+    /// Return the request body.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Parse an `application/x-www-form-urlencoded` body, as sent by HTML
+    /// forms. Returns `None` if the request has another `Content-Type`.
+    pub fn form(&self) -> Option<Query> {
+        let content_type = self.headers.get("Content-Type")?;
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        if !mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return None;
+        }
+        // form の body は ASCII のはずなので、それ以外の byte は置き換えてよい。
+        Some(Query::parse(&String::from_utf8_lossy(&self.body)))
+    }
+
+    /// Read a `multipart/form-data` body, as sent by HTML forms with file
+    /// inputs. Returns `None` if the request has another `Content-Type` or
+    /// no boundary.
+    pub fn multipart(&self) -> Option<Multipart<&[u8]>> {
+        let boundary = multipart::boundary(self.headers.get("Content-Type")?)?;
+        Some(Multipart::new(&self.body, &boundary))
+    }
+
+    /// Deserialize the body as JSON into a `T`.
+    ///
+    /// The `Content-Type` is not checked, so any body that is valid JSON
+    /// for `T` is accepted.
+    #[cfg(feature = "json")]
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, JsonError> {
+        json::from_slice(&self.body)
+    }
+
+    /// Return the path segment captured as `name` by the matched route, e.g.
+    /// `id` for a route `/users/:id`, percent-decoded.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|param| param.0 == name)
+            .map(|param| &*param.1)
+    }
+
+    // body を除いた写し。 handler に渡した後で error page を作るのに使う。
+    pub(crate) fn without_body(&self) -> Request {
+        Request {
+            method: self.method.clone(),
+            target: self.target.clone(),
+            version: self.version,
+            headers: self.headers.clone(),
+            body: Vec::new(),
+            query: self.query.clone(),
+            params: self.params.clone(),
+            remote_addr: self.remote_addr,
+            peer: self.peer.clone(),
+            id: self.id.clone(),
+            user: self.user.clone(),
+            session: self.session.clone(),
+        }
+    }
+
+    // nest された router の route なら、外側の route の分に足す。
+    pub(crate) fn add_params(&mut self, params: Vec<(String, String)>) {
+        self.params.extend(params);
+    }
+
+    pub(crate) fn set_remote_addr(&mut self, addr: Option<SocketAddr>) {
+        self.remote_addr = addr;
+    }
+
+    pub(crate) fn set_peer_identity(&mut self, peer: Option<Arc<PeerIdentity>>) {
+        self.peer = peer;
+    }
+
+    pub(crate) fn set_id(&mut self, id: String) {
+        self.id = Some(id);
+    }
+
+    pub(crate) fn set_user(&mut self, user: String) {
+        self.user = Some(user);
+    }
+
+    pub(crate) fn set_session(&mut self, session: Session) {
+        self.session = Some(session);
+    }
+}
+
+// 読み込み中に確かめる request の大きさの上限。
+#[derive(Debug, Clone)]
+pub(crate) struct Limits {
+    // request line と header を合わせた byte 数。
+    pub(crate) max_header_bytes: usize,
+    pub(crate) max_headers: usize,
+    pub(crate) max_body_bytes: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_header_bytes: 64 * 1024,
+            max_headers: 100,
+            max_body_bytes: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// An error from reading a request.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The request line was not `METHOD TARGET VERSION`.
+    BadRequestLine,
+    /// The request was for an HTTP version other than 1.0 and 1.1.
+    UnsupportedVersion,
+    /// A header line was malformed.
+    BadHeader,
+    /// The `Content-Length` header was not a number, was sent more than
+    /// once with different values, or was sent with `Transfer-Encoding`.
+    BadContentLength,
+    /// The stream ended in the middle of the request line, the headers or a
+    /// chunked body.
+    Incomplete,
+    /// The stream ended before the number of body bytes given by
+    /// `Content-Length` arrived.
+    TruncatedBody { expected: u64, received: u64 },
+    /// A chunk of a chunked body was malformed.
+    BadChunk,
+    /// `Transfer-Encoding` named a coding other than a lone `chunked`.
+    UnsupportedTransferEncoding,
+    /// The request line alone was longer than the limit on header bytes.
+    UriTooLong,
+    /// The headers were longer than the limit on header bytes, or there
+    /// were more of them than allowed.
+    HeadersTooLarge,
+    /// The body was longer than the limit on body bytes.
+    BodyTooLarge,
+    /// `Expect` asked for something other than `100-continue`.
+    UnsupportedExpectation,
+    /// The stream timed out in the middle of the request.
+    Timeout,
+    /// Reading from the stream failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::BadRequestLine => f.write_str("malformed request line"),
+            ParseError::UnsupportedVersion => f.write_str("unsupported HTTP version"),
+            ParseError::BadHeader => f.write_str("malformed header"),
+            ParseError::BadContentLength => f.write_str("invalid Content-Length"),
+            ParseError::Incomplete => f.write_str("connection closed mid-request"),
+            ParseError::TruncatedBody { expected, received } => write!(
+                f,
+                "body truncated: expected {} bytes, received {}",
+                expected, received
+            ),
+            ParseError::BadChunk => f.write_str("malformed chunk in a chunked body"),
+            ParseError::UnsupportedTransferEncoding => f.write_str("unsupported Transfer-Encoding"),
+            ParseError::UriTooLong => f.write_str("request line too long"),
+            ParseError::HeadersTooLarge => f.write_str("headers too large"),
+            ParseError::BodyTooLarge => f.write_str("body too large"),
+            ParseError::UnsupportedExpectation => f.write_str("unsupported expectation"),
+            ParseError::Timeout => f.write_str("timed out reading the request"),
+            ParseError::Io(ref err) => write!(f, "failed to read the request: {}", err),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ParseError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> ParseError {
+        // read timeout は platform により WouldBlock か TimedOut になる。
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ParseError::Timeout,
+            _ => ParseError::Io(err),
+        }
+    }
+}
+
+// 1 行読んで、行末の CRLF か LF を除いて返す。 EOF なら None を返す。
+// `budget` を超えて読もうとしたら HeadersTooLarge にする。
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Option<String>, ParseError> {
+    if *budget == 0 {
+        return Err(ParseError::HeadersTooLarge);
+    }
+    let mut line = Vec::new();
+    let read = reader.take(*budget as u64).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    *budget -= read;
+    if line.pop() != Some(b'\n') {
+        return Err(if *budget == 0 {
+            ParseError::HeadersTooLarge
+        } else {
+            ParseError::Incomplete
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    // header の値には obs-text が来うるので、 UTF-8 でない部分は置き換える。
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, String, Version), ParseError> {
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(ParseError::BadRequestLine),
+    };
+    let method = Method::parse(method).ok_or(ParseError::BadRequestLine)?;
+    if target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(ParseError::BadRequestLine);
+    }
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        _ if is_version(version) => return Err(ParseError::UnsupportedVersion),
+        _ => return Err(ParseError::BadRequestLine),
+    };
+    Ok((method, target.to_string(), version))
+}
+
+pub(crate) fn parse_header(line: &str) -> Result<(&str, &str), ParseError> {
+    // 行頭の空白は obs-fold。 RFC 7230 3.2.4 に従い受け付けない。
+    let colon = line.find(':').ok_or(ParseError::BadHeader)?;
+    let name = &line[..colon];
+    if !is_token(name) {
+        return Err(ParseError::BadHeader);
+    }
+    let value = line[colon + 1..].trim_matches(|c| c == ' ' || c == '\t');
+    // 裸の CR なども、下流で行の区切りと見なされうるので受け付けない。
+    if value.chars().any(|c| c.is_ascii_control() && c != '\t') {
+        return Err(ParseError::BadHeader);
+    }
+    Ok((name, value))
+}
+
+enum Framing {
+    Chunked,
+    Length(u64),
+    None,
+}
+
+fn body_framing(headers: &Headers) -> Result<Framing, ParseError> {
+    let codings: Vec<&str> = headers
+        .get_all("Transfer-Encoding")
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .collect();
+    if codings.is_empty() {
+        return Ok(match content_length(headers)? {
+            Some(length) => Framing::Length(length),
+            None => Framing::None,
+        });
+    }
+    // 両方あると、どちらで区切るかで前段の proxy と食い違いうる (request smuggling)。
+    if headers.contains("Content-Length") {
+        return Err(ParseError::BadContentLength);
+    }
+    if codings.len() == 1 && codings[0].eq_ignore_ascii_case("chunked") {
+        Ok(Framing::Chunked)
+    } else {
+        Err(ParseError::UnsupportedTransferEncoding)
+    }
+}
+
+// Content-Length は "5, 5" のように同じ値が並ぶことがある (RFC 7230 3.3.2)。
+fn content_length(headers: &Headers) -> Result<Option<u64>, ParseError> {
+    let mut length = None;
+    for value in headers.get_all("Content-Length").flat_map(|v| v.split(',')) {
+        let value = value.trim();
+        // u64::from_str は "+5" も受け付けてしまう。
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::BadContentLength);
+        }
+        let value = value
+            .parse::<u64>()
+            .map_err(|_| ParseError::BadContentLength)?;
+        if length.is_some() && length != Some(value) {
+            return Err(ParseError::BadContentLength);
+        }
+        length = Some(value);
+    }
+    Ok(length)
+}
+
+fn read_body<R: BufRead>(reader: &mut R, length: u64) -> Result<Vec<u8>, ParseError> {
+    // 巨大な Content-Length を送られても、先に確保はしない。
+    let mut body = Vec::new();
+    let received = reader.take(length).read_to_end(&mut body)? as u64;
+    if received < length {
+        return Err(ParseError::TruncatedBody {
+            expected: length,
+            received,
+        });
+    }
+    Ok(body)
+}
+
+// chunk-size [; ext] CRLF data CRLF を size 0 まで繰り返し、最後に trailer が続く。
+fn read_chunked<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    loop {
+        // chunk の数は決まらないので、 chunk-size の行ごとに上限をかける。
+        let mut budget = limits.max_header_bytes;
+        let line = read_line(reader, &mut budget)?.ok_or(ParseError::Incomplete)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseError::BadChunk);
+        }
+        let size = u64::from_str_radix(size, 16).map_err(|_| ParseError::BadChunk)?;
+        if size == 0 {
+            break;
+        }
+        if size > limits.max_body_bytes - body.len() as u64 {
+            return Err(ParseError::BodyTooLarge);
+        }
+        let received = reader.take(size).read_to_end(&mut body)? as u64;
+        if received < size {
+            return Err(ParseError::Incomplete);
+        }
+        // data の後には CRLF だけが来るはず。
+        match read_line(reader, &mut 2) {
+            Ok(Some(ref line)) if line.is_empty() => {}
+            Ok(Some(_)) | Err(ParseError::HeadersTooLarge) => return Err(ParseError::BadChunk),
+            Ok(None) => return Err(ParseError::Incomplete),
+            Err(err) => return Err(err),
+        }
+    }
+    // trailer は使わないので読み捨てる。
+    let mut budget = limits.max_header_bytes;
+    loop {
+        match read_line(reader, &mut budget)? {
+            Some(ref line) if line.is_empty() => return Ok(body),
+            Some(_) => {}
+            None => return Err(ParseError::Incomplete),
+        }
+    }
+}
+
+fn decode_query(s: &str) -> String {
+    percent_decode(&s.replace('+', " "))
+}
+
+/// Escape everything in `s` except unreserved characters (RFC 3986 2.3),
+/// so that it can be used as one segment of a URL path.
+pub(crate) fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// Decode `%XX` escapes in `s`. Malformed escapes are kept as they are and
+/// bytes that are not UTF-8 are replaced.
+pub(crate) fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(high), Some(low)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+// `HTTP/x.y` の形をしているか。
+fn is_version(version: &str) -> bool {
+    let digits = match version.strip_prefix("HTTP/") {
+        Some(digits) => digits.as_bytes(),
+        None => return false,
+    };
+    digits.len() == 3
+        && digits[0].is_ascii_digit()
+        && digits[1] == b'.'
+        && digits[2].is_ascii_digit()
+}
+
+// RFC 7230 の token (method や header 名) として正しいか。
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_header, ParseError, Request};
+
+    #[test]
+    fn rejects_control_characters_in_headers() {
+        assert_eq!(parse_header("X-A: b\tc").unwrap(), ("X-A", "b\tc"));
+        for line in ["X-A: b\rc", "X-A: b\0c", "X-A: b\x7fc"] {
+            assert!(
+                matches!(parse_header(line), Err(ParseError::BadHeader)),
+                "{:?}",
+                line
+            );
+        }
+        // 裸の CR は行の区切りにならず、値に残る。
+        let head = "GET / HTTP/1.1\r\nX-A: b\rSet-Cookie: c\r\n\r\n";
+        assert!(matches!(
+            Request::read_from(&mut head.as_bytes()),
+            Err(ParseError::BadHeader)
+        ));
+    }
+}
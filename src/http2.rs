@@ -0,0 +1,1026 @@
+//! HTTP/2 connections (RFC 7540).
+//!
+//! Clients that pick `h2` with ALPN on a TLS listener are served, as are
+//! those that open a plain connection with the preface ("prior
+//! knowledge"); `Upgrade: h2c` is left to HTTP/1.1. The connection's
+//! worker reads frames, and each complete request is handled by a job of
+//! its own, which writes its response frame by frame between the others.
+//! A request that finds no idle worker is refused with `REFUSED_STREAM`,
+//! which tells the client it may send it again.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::accesslog::{Entry, Head};
+use super::hpack::{self, Decoder};
+use super::http::Limits;
+use super::server::{respond, Config};
+use super::stream::Stream;
+use super::{
+    Handler, Headers, Method, PeerIdentity, PoolHandle, Request, Response, Status, Version,
+};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// 受け取る frame の上限。 SETTINGS で広げないので既定値のまま。
+const MAX_FRAME_SIZE: usize = 16_384;
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+const HEADER_TABLE_SIZE: usize = 4096;
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+// header の大きさを数えるときに、 field ごとに足す分 (RFC 7540 6.5.2)。
+const FIELD_OVERHEAD: usize = 32;
+
+// frame の種類。
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+
+// 接続ごと閉じる error。
+enum ConnError {
+    // GOAWAY で code を伝える。
+    Protocol(u32, &'static str),
+    Io(io::Error),
+}
+
+impl From<io::Error> for ConnError {
+    fn from(err: io::Error) -> ConnError {
+        ConnError::Io(err)
+    }
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+// connection の reader と、 response を書く job とで共有する。
+struct Shared {
+    writer: Mutex<Stream>,
+    flow: Mutex<Flow>,
+    // window が広がったり stream が reset されたりしたら知らせる。
+    changed: Condvar,
+}
+
+// 送る側の flow control の状態。
+struct Flow {
+    conn: i64,
+    // response を書いている stream の window。 reset された stream は消す。
+    streams: HashMap<u32, i64>,
+    initial: i64,
+    max_frame: usize,
+    closed: bool,
+}
+
+impl Shared {
+    fn flow(&self) -> MutexGuard<'_, Flow> {
+        self.flow.lock().unwrap()
+    }
+
+    fn frame(&self, kind: u8, flags: u8, stream: u32, payload: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(9 + payload.len());
+        encode_frame(&mut buf, kind, flags, stream, payload);
+        self.writer.lock().unwrap().write_all(&buf)
+    }
+
+    fn reset(&self, stream: u32, code: u32) -> io::Result<()> {
+        self.flow().streams.remove(&stream);
+        self.changed.notify_all();
+        self.frame(RST_STREAM, 0, stream, &code.to_be_bytes())
+    }
+
+    // 書いている途中の job を止める。
+    fn close(&self) {
+        self.flow().closed = true;
+        self.changed.notify_all();
+    }
+
+    // 送った body の byte 数を返す。
+    fn send_response(
+        &self,
+        stream: u32,
+        response: Response,
+        timeout: Option<Duration>,
+    ) -> io::Result<u64> {
+        let result = self.write_response(stream, response, timeout);
+        self.flow().streams.remove(&stream);
+        result
+    }
+
+    fn write_response(
+        &self,
+        stream: u32,
+        response: Response,
+        timeout: Option<Duration>,
+    ) -> io::Result<u64> {
+        let status = response.status();
+        let mut fields = Vec::new();
+        for (name, value) in response.headers() {
+            let name = name.to_ascii_lowercase();
+            if !is_connection_specific(&name) && name != "content-length" {
+                fields.push((name, value.to_owned()));
+            }
+        }
+        let length = response.body_len().filter(|_| status.allows_body());
+        if let Some(length) = length {
+            fields.push(("content-length".to_owned(), length.to_string()));
+        }
+        let has_body = status.allows_body() && length != Some(0) && !response.is_body_omitted();
+        let block = hpack::encode(
+            status.code(),
+            fields.iter().map(|(name, value)| (&**name, &**value)),
+        );
+        self.write_headers(stream, &block, !has_body)?;
+        if !has_body {
+            return Ok(0);
+        }
+        let mut writer = DataWriter {
+            shared: self,
+            stream,
+            timeout,
+        };
+        let sent = response.write_body(&mut writer, false)?;
+        self.frame(DATA, END_STREAM, stream, &[])?;
+        Ok(sent)
+    }
+
+    // 大きな header block は CONTINUATION に分ける。間に他の frame が
+    // 入らないよう、まとめて書く。
+    fn write_headers(&self, stream: u32, block: &[u8], end_stream: bool) -> io::Result<()> {
+        let max_frame = self.flow().max_frame;
+        let mut buf = Vec::with_capacity(block.len() + 9);
+        let mut chunks = block.chunks(max_frame).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        // 空の block でも HEADERS は 1 つ送る。
+        if chunks.peek().is_none() {
+            encode_frame(&mut buf, kind, flags | END_HEADERS, stream, &[]);
+        }
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_none() {
+                flags |= END_HEADERS;
+            }
+            encode_frame(&mut buf, kind, flags, stream, chunk);
+            kind = CONTINUATION;
+            flags = 0;
+        }
+        self.writer.lock().unwrap().write_all(&buf)
+    }
+}
+
+// body を DATA frame にして送る。 window が空いていなければ待つ。
+struct DataWriter<'a> {
+    shared: &'a Shared,
+    stream: u32,
+    timeout: Option<Duration>,
+}
+
+impl<'a> Write for DataWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let mut flow = self.shared.flow();
+        let length = loop {
+            if flow.closed {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "connection closed",
+                ));
+            }
+            let window = match flow.streams.get(&self.stream) {
+                Some(&window) => window,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionReset,
+                        "stream reset by the client",
+                    ))
+                }
+            };
+            let length = window
+                .min(flow.conn)
+                .min(flow.max_frame as i64)
+                .min(buf.len() as i64);
+            if length > 0 {
+                break length;
+            }
+            flow = match deadline {
+                None => self.shared.changed.wait(flow).unwrap(),
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "flow control window stayed closed",
+                        ));
+                    }
+                    self.shared.changed.wait_timeout(flow, left).unwrap().0
+                }
+            };
+        };
+        flow.conn -= length;
+        if let Some(window) = flow.streams.get_mut(&self.stream) {
+            *window -= length;
+        }
+        drop(flow);
+        let length = length as usize;
+        self.shared.frame(DATA, 0, self.stream, &buf[..length])?;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// body を受け取っている途中の stream。
+struct Incoming {
+    fields: Vec<(String, String)>,
+    body: Vec<u8>,
+    started: Instant,
+    time: SystemTime,
+}
+
+impl Incoming {
+    fn new(started: Instant, time: SystemTime) -> Incoming {
+        Incoming {
+            fields: Vec::new(),
+            body: Vec::new(),
+            started,
+            time,
+        }
+    }
+}
+
+// 接続の worker で frame を読む側。
+struct Connection<'a, H> {
+    shared: Arc<Shared>,
+    decoder: Decoder,
+    incoming: HashMap<u32, Incoming>,
+    // client が最後に開いた stream。
+    last_stream: u32,
+    // END_HEADERS がまだ来ていない header block と、その HEADERS の flag。
+    continuation: Option<(u32, u8, Vec<u8>)>,
+    remote_addr: Option<SocketAddr>,
+    peer: Option<Arc<PeerIdentity>>,
+    handler: &'a Arc<H>,
+    config: &'a Arc<Config>,
+    pool: &'a PoolHandle,
+}
+
+pub(crate) fn serve<R, H>(
+    reader: &mut R,
+    writer: Stream,
+    remote_addr: Option<SocketAddr>,
+    peer: Option<Arc<PeerIdentity>>,
+    handler: &Arc<H>,
+    config: &Arc<Config>,
+    pool: &PoolHandle,
+) where
+    R: BufRead,
+    H: Handler,
+{
+    let shared = Arc::new(Shared {
+        writer: Mutex::new(writer),
+        flow: Mutex::new(Flow {
+            conn: DEFAULT_WINDOW,
+            streams: HashMap::new(),
+            initial: DEFAULT_WINDOW,
+            max_frame: MAX_FRAME_SIZE,
+            closed: false,
+        }),
+        changed: Condvar::new(),
+    });
+    let mut conn = Connection {
+        shared: Arc::clone(&shared),
+        decoder: Decoder::new(HEADER_TABLE_SIZE),
+        incoming: HashMap::new(),
+        last_stream: 0,
+        continuation: None,
+        remote_addr,
+        peer,
+        handler,
+        config,
+        pool,
+    };
+    let code = match conn.run(reader) {
+        Ok(()) => NO_ERROR,
+        Err(ConnError::Protocol(code, reason)) => {
+            debug!("Closing an HTTP/2 connection: {}", reason);
+            code
+        }
+        Err(ConnError::Io(err)) => {
+            debug!("Failed to read from an HTTP/2 connection: {}", err);
+            shared.close();
+            return;
+        }
+    };
+    let mut payload = conn.last_stream.to_be_bytes().to_vec();
+    payload.extend_from_slice(&code.to_be_bytes());
+    if let Err(err) = shared.frame(GOAWAY, 0, 0, &payload) {
+        debug!("Failed to write a response: {}", err);
+    }
+    // 正常に閉じるときは、書いている途中の response は最後まで送る。
+    if code != NO_ERROR {
+        shared.close();
+    }
+}
+
+impl<'a, H: Handler> Connection<'a, H> {
+    fn run<R: BufRead>(&mut self, reader: &mut R) -> Result<(), ConnError> {
+        let mut preface = [0; 24];
+        reader.read_exact(&mut preface)?;
+        if preface != PREFACE {
+            return Err(ConnError::Protocol(
+                PROTOCOL_ERROR,
+                "invalid connection preface",
+            ));
+        }
+        let mut settings = SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes().to_vec();
+        settings.extend_from_slice(&MAX_CONCURRENT_STREAMS.to_be_bytes());
+        self.shared.frame(SETTINGS, 0, 0, &settings)?;
+        loop {
+            match reader.fill_buf() {
+                Ok([]) => return Ok(()),
+                Ok(_) => {}
+                // 返事を待たせている stream がなければ、 idle として閉じる。
+                Err(ref err) if is_timeout(err) => {
+                    if self.incoming.is_empty() && self.shared.flow().streams.is_empty() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            }
+            let frame = read_frame(reader)?;
+            if !self.handle(frame)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // GOAWAY を受け取ったら false を返す。
+    fn handle(&mut self, frame: Frame) -> Result<bool, ConnError> {
+        if let Some((stream, _, _)) = self.continuation {
+            if frame.kind != CONTINUATION || frame.stream != stream {
+                return Err(protocol_error("expected a CONTINUATION frame"));
+            }
+        }
+        let valid = match frame.kind {
+            DATA | HEADERS | PRIORITY | RST_STREAM | CONTINUATION => frame.stream != 0,
+            SETTINGS | PING | GOAWAY => frame.stream == 0,
+            _ => true,
+        };
+        if !valid {
+            return Err(protocol_error("frame sent on the wrong stream"));
+        }
+        match frame.kind {
+            DATA => self.data(frame)?,
+            HEADERS => self.headers(frame)?,
+            RST_STREAM => {
+                if frame.payload.len() != 4 {
+                    return Err(frame_size_error());
+                }
+                self.incoming.remove(&frame.stream);
+                self.shared.flow().streams.remove(&frame.stream);
+                self.shared.changed.notify_all();
+            }
+            SETTINGS => self.settings(frame)?,
+            PUSH_PROMISE => return Err(protocol_error("clients cannot push")),
+            PING => {
+                if frame.payload.len() != 8 {
+                    return Err(frame_size_error());
+                }
+                if frame.flags & ACK == 0 {
+                    self.shared.frame(PING, ACK, 0, &frame.payload)?;
+                }
+            }
+            GOAWAY => return Ok(false),
+            WINDOW_UPDATE => self.window_update(frame)?,
+            CONTINUATION => self.continuation(frame)?,
+            // PRIORITY と知らない種類は読み捨てる。
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn data(&mut self, frame: Frame) -> Result<(), ConnError> {
+        let length = frame.payload.len() as u32;
+        // 使わなかった分も含めて、受け取った分だけ window を戻す。
+        if length > 0 {
+            self.shared
+                .frame(WINDOW_UPDATE, 0, 0, &length.to_be_bytes())?;
+        }
+        let data = strip_padding(&frame.payload, frame.flags)?;
+        let incoming = match self.incoming.get_mut(&frame.stream) {
+            Some(incoming) => incoming,
+            None if frame.stream > self.last_stream => {
+                return Err(protocol_error("DATA on an idle stream"));
+            }
+            // reset したか、もう受け取り終えた stream。
+            None => return Ok(()),
+        };
+        if (incoming.body.len() + data.len()) as u64 > self.config.limits.max_body_bytes {
+            let incoming = self.incoming.remove(&frame.stream).unwrap();
+            // 残りの body は要らない (RFC 7540 8.1)。
+            self.reject(frame.stream, &incoming, Status::PayloadTooLarge);
+            self.shared
+                .frame(RST_STREAM, 0, frame.stream, &NO_ERROR.to_be_bytes())?;
+            return Ok(());
+        }
+        incoming.body.extend_from_slice(data);
+        if frame.flags & END_STREAM != 0 {
+            let incoming = self.incoming.remove(&frame.stream).unwrap();
+            self.dispatch(frame.stream, incoming)?;
+        } else if length > 0 {
+            self.shared
+                .frame(WINDOW_UPDATE, 0, frame.stream, &length.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn headers(&mut self, frame: Frame) -> Result<(), ConnError> {
+        let mut block = strip_padding(&frame.payload, frame.flags)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            block = block.get(5..).ok_or_else(frame_size_error)?;
+        }
+        if frame.flags & END_HEADERS == 0 {
+            self.continuation = Some((frame.stream, frame.flags, block.to_vec()));
+            return Ok(());
+        }
+        self.header_block(frame.stream, frame.flags, block)
+    }
+
+    fn continuation(&mut self, frame: Frame) -> Result<(), ConnError> {
+        let (stream, flags, mut block) = self
+            .continuation
+            .take()
+            .ok_or_else(|| protocol_error("unexpected CONTINUATION frame"))?;
+        block.extend_from_slice(&frame.payload);
+        // 終わらない header block で memory を使い切らせない。
+        if block.len() > self.config.limits.max_header_bytes.max(MAX_FRAME_SIZE) * 2 {
+            return Err(protocol_error("header block too large"));
+        }
+        if frame.flags & END_HEADERS == 0 {
+            self.continuation = Some((stream, flags, block));
+            return Ok(());
+        }
+        self.header_block(stream, flags, &block)
+    }
+
+    fn header_block(&mut self, stream: u32, flags: u8, block: &[u8]) -> Result<(), ConnError> {
+        // 使わない stream の分も、 dynamic table を揃えるために decode する。
+        let fields = self
+            .decoder
+            .decode(block)
+            .map_err(|_| ConnError::Protocol(COMPRESSION_ERROR, "malformed header block"))?;
+        let end_stream = flags & END_STREAM != 0;
+        // body の後の trailer。中身は使わない。
+        if let Some(incoming) = self.incoming.remove(&stream) {
+            if !end_stream {
+                return Err(protocol_error("trailers without END_STREAM"));
+            }
+            return self.dispatch(stream, incoming);
+        }
+        if stream.is_multiple_of(2) || stream <= self.last_stream {
+            return Err(protocol_error("invalid stream id"));
+        }
+        self.last_stream = stream;
+        let open = self.incoming.len() + self.shared.flow().streams.len();
+        if open >= MAX_CONCURRENT_STREAMS as usize {
+            self.shared.reset(stream, REFUSED_STREAM)?;
+            return Ok(());
+        }
+        let mut incoming = Incoming::new(Instant::now(), SystemTime::now());
+        incoming.fields = fields;
+        if end_stream {
+            return self.dispatch(stream, incoming);
+        }
+        if let Some(status) = self.expectation(stream, &incoming.fields) {
+            self.reject(stream, &incoming, status);
+            self.shared
+                .frame(RST_STREAM, 0, stream, &NO_ERROR.to_be_bytes())?;
+            return Ok(());
+        }
+        self.incoming.insert(stream, incoming);
+        Ok(())
+    }
+
+    // 100 Continue を待つ client には、 body を受け取る前に答える。断るなら
+    // その status を返す。
+    fn expectation(&self, stream: u32, fields: &[(String, String)]) -> Option<Status> {
+        let field = |name: &str| fields.iter().find(|f| f.0 == name).map(|f| &*f.1);
+        let expect = field("expect")?;
+        if !expect.eq_ignore_ascii_case("100-continue") {
+            return Some(Status::ExpectationFailed);
+        }
+        let length = field("content-length").and_then(|length| length.parse::<u64>().ok());
+        if length.is_some_and(|length| length > self.config.limits.max_body_bytes) {
+            return Some(Status::PayloadTooLarge);
+        }
+        let block = hpack::encode(Status::Continue.code(), []);
+        if let Err(err) = self.shared.write_headers(stream, &block, false) {
+            debug!("Failed to send 100 Continue: {}", err);
+        }
+        None
+    }
+
+    fn settings(&mut self, frame: Frame) -> Result<(), ConnError> {
+        if frame.flags & ACK != 0 {
+            return if frame.payload.is_empty() {
+                Ok(())
+            } else {
+                Err(frame_size_error())
+            };
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(frame_size_error());
+        }
+        {
+            let mut flow = self.shared.flow();
+            for setting in frame.payload.chunks(6) {
+                let id = u16::from_be_bytes([setting[0], setting[1]]);
+                let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                match id {
+                    SETTINGS_INITIAL_WINDOW_SIZE => {
+                        let value = i64::from(value);
+                        if value > MAX_WINDOW {
+                            return Err(ConnError::Protocol(
+                                FLOW_CONTROL_ERROR,
+                                "window too large",
+                            ));
+                        }
+                        // 既に開いている stream の window も差の分だけ変わる。
+                        // 上限を超えたら接続の error (RFC 7540 6.9.2)。
+                        let delta = value - flow.initial;
+                        for window in flow.streams.values_mut() {
+                            *window += delta;
+                            if *window > MAX_WINDOW {
+                                return Err(ConnError::Protocol(
+                                    FLOW_CONTROL_ERROR,
+                                    "window too large",
+                                ));
+                            }
+                        }
+                        flow.initial = value;
+                    }
+                    SETTINGS_MAX_FRAME_SIZE => {
+                        if !(16_384..=16_777_215).contains(&value) {
+                            return Err(protocol_error("invalid SETTINGS_MAX_FRAME_SIZE"));
+                        }
+                        flow.max_frame = value as usize;
+                    }
+                    // header table は使わず、 push もしないので他は気にしない。
+                    _ => {}
+                }
+            }
+        }
+        self.shared.changed.notify_all();
+        self.shared.frame(SETTINGS, ACK, 0, &[])?;
+        Ok(())
+    }
+
+    fn window_update(&mut self, frame: Frame) -> Result<(), ConnError> {
+        let payload: [u8; 4] = frame
+            .payload
+            .as_slice()
+            .try_into()
+            .map_err(|_| frame_size_error())?;
+        let increment = i64::from(u32::from_be_bytes(payload) & 0x7fff_ffff);
+        if increment == 0 {
+            if frame.stream == 0 {
+                return Err(protocol_error("zero window increment"));
+            }
+            self.shared.reset(frame.stream, PROTOCOL_ERROR)?;
+            return Ok(());
+        }
+        let mut flow = self.shared.flow();
+        if frame.stream == 0 {
+            flow.conn += increment;
+            if flow.conn > MAX_WINDOW {
+                return Err(ConnError::Protocol(FLOW_CONTROL_ERROR, "window too large"));
+            }
+        } else if let Some(window) = flow.streams.get_mut(&frame.stream) {
+            *window += increment;
+            if *window > MAX_WINDOW {
+                drop(flow);
+                self.shared.reset(frame.stream, FLOW_CONTROL_ERROR)?;
+                return Ok(());
+            }
+        }
+        drop(flow);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    // 受け取り終えた request を job にする。この thread で handler を呼ぶと、
+    // response が window を待つ間 WINDOW_UPDATE を読めなくなるので、 idle な
+    // worker がなければ REFUSED_STREAM で断る。 client は送り直してよい。
+    fn dispatch(&mut self, stream: u32, incoming: Incoming) -> Result<(), ConnError> {
+        let (started, time) = (incoming.started, incoming.time);
+        let mut request = match build_request(incoming.fields, incoming.body, &self.config.limits) {
+            Ok(request) => request,
+            Err(Some(status)) => {
+                self.reject(stream, &Incoming::new(started, time), status);
+                return Ok(());
+            }
+            Err(None) => {
+                debug!("Rejecting a malformed HTTP/2 request.");
+                self.shared.reset(stream, PROTOCOL_ERROR)?;
+                return Ok(());
+            }
+        };
+        if self.pool.idle_workers() == 0 {
+            debug!("Refusing an HTTP/2 stream: no worker is idle.");
+            self.shared.reset(stream, REFUSED_STREAM)?;
+            return Ok(());
+        }
+        request.set_remote_addr(self.remote_addr);
+        request.set_peer_identity(self.peer.clone());
+        {
+            let mut flow = self.shared.flow();
+            let initial = flow.initial;
+            flow.streams.insert(stream, initial);
+        }
+        let shared = Arc::clone(&self.shared);
+        let handler = Arc::clone(self.handler);
+        let config = Arc::clone(self.config);
+        let remote_addr = self.remote_addr;
+        let job = move || {
+            let head = config.access_log.as_ref().map(|_| Head::new(&request));
+            let (response, _) = respond(request, &*handler, &config);
+            let reply = Reply {
+                shared: &shared,
+                stream,
+                started,
+                time,
+                remote_addr,
+                head,
+            };
+            reply.send(response, &config);
+        };
+        if self.pool.try_execute(job).is_err() {
+            debug!("Refusing an HTTP/2 stream: the job queue is full.");
+            self.shared.reset(stream, REFUSED_STREAM)?;
+        }
+        Ok(())
+    }
+
+    // handler を通さずに status だけを返す。
+    fn reject(&self, stream: u32, incoming: &Incoming, status: Status) {
+        {
+            let mut flow = self.shared.flow();
+            let initial = flow.initial;
+            flow.streams.insert(stream, initial);
+        }
+        let reply = Reply {
+            shared: &self.shared,
+            stream,
+            started: incoming.started,
+            time: incoming.time,
+            remote_addr: self.remote_addr,
+            head: None,
+        };
+        reply.send(Response::new(status), self.config);
+    }
+}
+
+// response を送って access log に書くのに要るもの。
+struct Reply<'a> {
+    shared: &'a Shared,
+    stream: u32,
+    started: Instant,
+    time: SystemTime,
+    remote_addr: Option<SocketAddr>,
+    head: Option<Head>,
+}
+
+impl<'a> Reply<'a> {
+    fn send(self, response: Response, config: &Config) {
+        let status = response.status();
+        let request_id = config
+            .access_log
+            .as_ref()
+            .and_then(|_| response.headers().get("X-Request-Id"))
+            .map(str::to_owned);
+        let written = self
+            .shared
+            .send_response(self.stream, response, config.write_timeout);
+        if let Some(ref log) = config.access_log {
+            log.log(&Entry {
+                time: self.time,
+                remote_addr: self.remote_addr,
+                head: self.head,
+                status,
+                bytes: *written.as_ref().unwrap_or(&0),
+                latency: self.started.elapsed(),
+                request_id,
+            });
+        }
+        if let Err(err) = written {
+            debug!("Failed to write a response: {}", err);
+        }
+    }
+}
+
+// 壊れた request は None、大きすぎる header は返す status にする。
+fn build_request(
+    fields: Vec<(String, String)>,
+    body: Vec<u8>,
+    limits: &Limits,
+) -> Result<Request, Option<Status>> {
+    let size: usize = fields
+        .iter()
+        .map(|(name, value)| name.len() + value.len() + FIELD_OVERHEAD)
+        .sum();
+    let count = fields
+        .iter()
+        .filter(|(name, _)| !name.starts_with(':'))
+        .count();
+    if size > limits.max_header_bytes || count > limits.max_headers {
+        return Err(Some(Status::RequestHeaderFieldsTooLarge));
+    }
+    let (mut method, mut path, mut scheme, mut authority) = (None, None, None, None);
+    let mut headers = Headers::new();
+    let mut cookies = Vec::new();
+    for (name, value) in fields {
+        // HTTP/1.1 と同じく、 CR や LF を含む値は送り返すと response splitting
+        // になりうるので断る (RFC 7540 10.3)。
+        if name
+            .chars()
+            .chain(value.chars())
+            .any(|c| c.is_ascii_control() && c != '\t')
+        {
+            return Err(None);
+        }
+        if let Some(pseudo) = name.strip_prefix(':') {
+            // pseudo header は普通の header より前にしか来ない。
+            if !headers.is_empty() || !cookies.is_empty() {
+                return Err(None);
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "path" => &mut path,
+                "scheme" => &mut scheme,
+                "authority" => &mut authority,
+                _ => return Err(None),
+            };
+            if slot.replace(value).is_some() {
+                return Err(None);
+            }
+            continue;
+        }
+        if name.bytes().any(|b| b.is_ascii_uppercase())
+            || is_connection_specific(&name)
+            || (name == "te" && value != "trailers")
+        {
+            return Err(None);
+        }
+        // 分けて送られた cookie は 1 つにまとめる (RFC 7540 8.1.2.5)。
+        if name == "cookie" {
+            cookies.push(value);
+        } else {
+            headers.append(name, value);
+        }
+    }
+    if !cookies.is_empty() {
+        headers.append("cookie", cookies.join("; "));
+    }
+    let method = method.as_deref().and_then(Method::parse).ok_or(None)?;
+    let target = if method == Method::Connect {
+        authority.clone().ok_or(None)?
+    } else {
+        scheme.ok_or(None)?;
+        path.filter(|path| !path.is_empty()).ok_or(None)?
+    };
+    // handler からは HTTP/1.1 と同じく Host で見えるようにする。
+    if let Some(authority) = authority {
+        if !headers.contains("host") {
+            headers.insert("host", authority);
+        }
+    }
+    Ok(Request::new(method, target, Version::Http2, headers, body))
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> Result<Frame, ConnError> {
+    let mut head = [0; 9];
+    reader.read_exact(&mut head)?;
+    let length = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(frame_size_error());
+    }
+    let mut payload = vec![0; length];
+    reader.read_exact(&mut payload)?;
+    Ok(Frame {
+        kind: head[3],
+        flags: head[4],
+        stream: u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff,
+        payload,
+    })
+}
+
+fn encode_frame(buf: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    buf.push(kind);
+    buf.push(flags);
+    buf.extend_from_slice(&stream.to_be_bytes());
+    buf.extend_from_slice(payload);
+}
+
+fn strip_padding(payload: &[u8], flags: u8) -> Result<&[u8], ConnError> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let (&padding, rest) = payload
+        .split_first()
+        .ok_or_else(|| protocol_error("missing pad length"))?;
+    let end = rest
+        .len()
+        .checked_sub(usize::from(padding))
+        .ok_or_else(|| protocol_error("padding longer than the frame"))?;
+    Ok(&rest[..end])
+}
+
+// HTTP/2 では使えない、 HTTP/1.1 の接続のための header (RFC 7540 8.1.2.2)。
+fn is_connection_specific(name: &str) -> bool {
+    matches!(
+        name,
+        "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade"
+    )
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn protocol_error(reason: &'static str) -> ConnError {
+    ConnError::Protocol(PROTOCOL_ERROR, reason)
+}
+
+fn frame_size_error() -> ConnError {
+    ConnError::Protocol(FRAME_SIZE_ERROR, "invalid frame size")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{
+        build_request, encode_frame, read_frame, Frame, DATA, DEFAULT_WINDOW, END_HEADERS,
+        END_STREAM, FLOW_CONTROL_ERROR, GOAWAY, HEADERS, MAX_WINDOW, PREFACE, REFUSED_STREAM,
+        RST_STREAM, SETTINGS, SETTINGS_INITIAL_WINDOW_SIZE, WINDOW_UPDATE,
+    };
+    use crate::http::Limits;
+    use crate::{Request, Response, Server, Status};
+
+    // 既定の window (65,535 byte) には収まらない。
+    const BODY: usize = 100_000;
+
+    // threads 個の worker で動く server に、 stream 1 で GET / を送る。
+    fn get(threads: usize) -> TcpStream {
+        let server = Server::builder()
+            .threads(threads)
+            .http2(true)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            server.run(|_: Request| Response::new(Status::Ok).body(vec![b'a'; BODY]))
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = PREFACE.to_vec();
+        encode_frame(&mut buf, SETTINGS, 0, 0, &[]);
+        // :method GET, :scheme http, :path / と :authority localhost。
+        let mut block = vec![0x82, 0x86, 0x84, 0x41, 9];
+        block.extend_from_slice(b"localhost");
+        encode_frame(&mut buf, HEADERS, END_HEADERS | END_STREAM, 1, &block);
+        stream.write_all(&buf).unwrap();
+        stream
+    }
+
+    // stream 1 の次の frame。接続の frame は読み飛ばす。
+    fn next_frame(stream: &mut TcpStream) -> Frame {
+        loop {
+            match read_frame(stream) {
+                Ok(frame) if frame.stream == 1 => return frame,
+                Ok(_) => {}
+                Err(_) => panic!("no frame for stream 1"),
+            }
+        }
+    }
+
+    #[test]
+    fn sends_bodies_larger_than_the_window() {
+        let mut stream = get(2);
+        assert_eq!(next_frame(&mut stream).kind, HEADERS);
+        let mut received = 0;
+        loop {
+            let frame = next_frame(&mut stream);
+            assert_eq!(frame.kind, DATA);
+            received += frame.payload.len();
+            if frame.flags & END_STREAM != 0 {
+                break;
+            }
+            // 受け取った分だけ window を戻す。
+            let increment = (frame.payload.len() as u32).to_be_bytes();
+            let mut buf = Vec::new();
+            encode_frame(&mut buf, WINDOW_UPDATE, 0, 0, &increment);
+            encode_frame(&mut buf, WINDOW_UPDATE, 0, 1, &increment);
+            stream.write_all(&buf).unwrap();
+        }
+        assert_eq!(received, BODY);
+    }
+
+    #[test]
+    fn rejects_settings_that_overflow_an_open_window() {
+        let mut stream = get(2);
+        assert_eq!(next_frame(&mut stream).kind, HEADERS);
+        // 接続の window を使い切るまで読むと、 stream 1 は window を待つ。
+        let mut received = 0;
+        while received < DEFAULT_WINDOW as usize {
+            received += next_frame(&mut stream).payload.len();
+        }
+        let mut buf = Vec::new();
+        encode_frame(
+            &mut buf,
+            WINDOW_UPDATE,
+            0,
+            1,
+            &(MAX_WINDOW as u32).to_be_bytes(),
+        );
+        let mut setting = SETTINGS_INITIAL_WINDOW_SIZE.to_be_bytes().to_vec();
+        setting.extend_from_slice(&(DEFAULT_WINDOW as u32 + 1).to_be_bytes());
+        encode_frame(&mut buf, SETTINGS, 0, 0, &setting);
+        stream.write_all(&buf).unwrap();
+        let goaway = loop {
+            match read_frame(&mut stream) {
+                Ok(frame) if frame.kind == GOAWAY => break frame,
+                Ok(_) => {}
+                Err(_) => panic!("no GOAWAY"),
+            }
+        };
+        assert_eq!(goaway.payload[4..], FLOW_CONTROL_ERROR.to_be_bytes());
+    }
+
+    // 唯一の worker が frame を読んでいるなら、 window を待って止まらずに断る。
+    #[test]
+    fn refuses_streams_without_an_idle_worker() {
+        let mut stream = get(1);
+        let frame = next_frame(&mut stream);
+        assert_eq!(frame.kind, RST_STREAM);
+        assert_eq!(frame.payload, REFUSED_STREAM.to_be_bytes());
+    }
+
+    #[test]
+    fn rejects_control_characters_in_fields() {
+        for (name, value) in [(":path", "/a\r\nb"), ("x-a", "b\rc"), ("x-a", "b\0c")] {
+            let fields = [(":method", "GET"), (":scheme", "https"), (":path", "/")]
+                .iter()
+                .filter(|field| field.0 != name)
+                .chain([(name, value)].iter())
+                .map(|&(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            let request = build_request(fields, Vec::new(), &Limits::default());
+            assert!(matches!(request, Err(None)), "{:?}", value);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Sending plain HTTP clients over to HTTPS.
+
+use super::{Handler, Request, Response, Status};
+
+const HTTPS_PORT: u16 = 443;
+
+/// A handler that answers every request with a `301 Moved Permanently` to
+/// the same host, path and query over `https://`.
+///
+/// Run it on port 80 as a server of its own, e.g.
+/// `Server::bind("0.0.0.0:80", 1)?` with `run(HttpsRedirect::new())` on its
+/// own thread, next to a main server bound with `ServerBuilder::bind_tls`
+/// or one behind a proxy or load balancer that ends TLS. The listeners of
+/// a single server share its handler, so `Server::listen` does not fit
+/// here. Requests without a usable `Host` are answered with
+/// `400 Bad Request`.
+#[derive(Debug, Clone)]
+pub struct HttpsRedirect {
+    port: u16,
+}
+
+impl HttpsRedirect {
+    /// Create a handler that redirects to the default HTTPS port.
+    pub fn new() -> HttpsRedirect {
+        HttpsRedirect { port: HTTPS_PORT }
+    }
+
+    /// Set the port HTTPS is served on. Defaults to 443, which is left out
+    /// of the redirect URLs.
+    pub fn port(mut self, port: u16) -> HttpsRedirect {
+        self.port = port;
+        self
+    }
+
+    fn location(&self, request: &Request) -> Option<String> {
+        let target = request.target();
+        // absolute-form (`GET http://host/path`) なら host もそこから取る。
+        let (host, path) = match target.strip_prefix("http://") {
+            Some(rest) => match rest.find('/') {
+                Some(index) => (&rest[..index], &rest[index..]),
+                None => (rest, "/"),
+            },
+            None => (request.header("Host")?, target),
+        };
+        let host = strip_port(host);
+        if !is_valid_host(host) {
+            return None;
+        }
+        // `OPTIONS *` などの path でない target は root に送る。
+        let path = if path.starts_with('/') { path } else { "/" };
+        Some(match self.port {
+            HTTPS_PORT => format!("https://{}{}", host, path),
+            port => format!("https://{}:{}{}", host, port, path),
+        })
+    }
+}
+
+impl Default for HttpsRedirect {
+    fn default() -> HttpsRedirect {
+        HttpsRedirect::new()
+    }
+}
+
+impl Handler for HttpsRedirect {
+    fn handle(&self, request: Request) -> Response {
+        match self.location(&request) {
+            Some(location) => Response::permanent_redirect(location),
+            None => Response::new(Status::BadRequest),
+        }
+    }
+}
+
+// `[::1]:80` の形の IPv6 も考える。
+fn strip_port(host: &str) -> &str {
+    let end = host.rfind(']').map_or(0, |index| index + 1);
+    match host[end..].rfind(':') {
+        Some(index) => &host[..end + index],
+        None => host,
+    }
+}
+
+fn is_valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._[]:".contains(&b))
+}
@@ -0,0 +1,129 @@
+//! Allowing and denying clients by IP address range.
+
+use std::net::IpAddr;
+
+use super::{Middleware, Next, Request, Response, Status};
+
+/// Allow and deny lists of IP address ranges in CIDR notation, such as
+/// `10.0.0.0/8` or `fd00::/8`. A bare address is a range of one.
+///
+/// An address is refused if a deny range contains it, or if there are
+/// allow ranges and none of them does. IPv4 addresses mapped into IPv6
+/// (`::ffff:10.0.0.1`) are checked as IPv4.
+///
+/// Give it to `ServerBuilder::ip_filter` to close connections from refused
+/// peers as soon as they are accepted, or add it as a middleware, e.g. to a
+/// nested `Router` for admin pages, to answer their requests with
+/// `403 Forbidden`.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpFilter {
+    /// Create a filter that allows every address.
+    pub fn new() -> IpFilter {
+        IpFilter::default()
+    }
+
+    /// Allow the addresses in `range`, refusing every address outside the
+    /// allow ranges.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is not a valid address or CIDR range.
+    pub fn allow(mut self, range: &str) -> IpFilter {
+        self.allow.push(Cidr::parse(range));
+        self
+    }
+
+    /// Refuse the addresses in `range`, even if an allow range has them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is not a valid address or CIDR range.
+    pub fn deny(mut self, range: &str) -> IpFilter {
+        self.deny.push(Cidr::parse(range));
+        self
+    }
+
+    /// Return `true` if `addr` may connect.
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        let addr = canonical(addr);
+        if self.deny.iter().any(|range| range.contains(addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(addr))
+    }
+}
+
+impl Middleware for IpFilter {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        // Server 以外から来た request は address が分からないので通さない。
+        match request.remote_addr() {
+            Some(addr) if self.allows(addr.ip()) => next.run(request),
+            _ => Response::new(Status::Forbidden),
+        }
+    }
+}
+
+impl Cidr {
+    fn parse(range: &str) -> Cidr {
+        let invalid = || -> ! { panic!("invalid IP address range: {}", range) };
+        let (addr, prefix) = match range.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (range, None),
+        };
+        let addr: IpAddr = addr.trim().parse().unwrap_or_else(|_| invalid());
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix.map(|p| p.trim().parse::<u8>()) {
+            None => max,
+            Some(Ok(prefix)) if prefix <= max => prefix,
+            Some(_) => invalid(),
+        };
+        // ::ffff:0:0/96 の中の range は IPv4 の range にする。
+        match canonical(addr) {
+            IpAddr::V4(v4) if addr.is_ipv6() => {
+                let prefix = prefix.checked_sub(96).unwrap_or_else(|| invalid());
+                Cidr {
+                    addr: IpAddr::V4(v4),
+                    prefix,
+                }
+            }
+            addr => Cidr { addr, prefix },
+        }
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(range), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(range) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(range) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// IPv6 に埋め込まれた IPv4 は IPv4 として比べる。
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        addr => addr,
+    }
+}
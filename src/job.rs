@@ -0,0 +1,100 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+/// Information about a job, passed to the `on_job_start` and `on_job_end`
+/// hooks.
+#[derive(Debug, Clone)]
+pub struct JobMeta {
+    worker_id: usize,
+    queue_wait: Duration,
+    name: Option<Arc<str>>,
+}
+
+impl JobMeta {
+    pub(crate) fn new(worker_id: usize, queue_wait: Duration, name: Option<Arc<str>>) -> JobMeta {
+        JobMeta {
+            worker_id,
+            queue_wait,
+            name,
+        }
+    }
+
+    /// The id of the worker running the job.
+    pub fn worker_id(&self) -> usize {
+        self.worker_id
+    }
+
+    /// How long the job waited in the queue before a worker took it.
+    pub fn queue_wait(&self) -> Duration {
+        self.queue_wait
+    }
+
+    /// The name given to `ThreadPool::execute_named`, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+/// A job that panicked, as kept by `ThreadPool::failed_jobs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedJob {
+    /// The id of the worker that ran the job.
+    pub worker_id: usize,
+    /// The name given to `ThreadPool::execute_named`, if any.
+    pub name: Option<String>,
+    /// The panic message.
+    pub message: String,
+    /// When the job panicked.
+    pub time: SystemTime,
+}
+
+// 最近 panic した job を capacity 件まで覚えておく。溢れたら古いものから捨てる。
+pub(crate) struct DeadLetters {
+    capacity: usize,
+    jobs: Mutex<VecDeque<FailedJob>>,
+}
+
+impl DeadLetters {
+    pub fn new(capacity: usize) -> DeadLetters {
+        DeadLetters {
+            capacity,
+            jobs: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, job: FailedJob) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut jobs = self.lock();
+        if jobs.len() == self.capacity {
+            jobs.pop_front();
+        }
+        jobs.push_back(job);
+    }
+
+    pub fn snapshot(&self) -> Vec<FailedJob> {
+        self.lock().iter().cloned().collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<FailedJob>> {
+        match self.jobs.lock() {
+            Ok(jobs) => jobs,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+// job を実行する間に入っておく span。 実行時間は終了後に記録する。
+// pool 内の log も tracing-log を使えばこの span の event として扱われる。
+#[cfg(feature = "tracing")]
+pub(crate) fn span(meta: &JobMeta) -> ::tracing::Span {
+    ::tracing::debug_span!(
+        "job",
+        worker_id = meta.worker_id,
+        name = meta.name(),
+        queue_wait = ?meta.queue_wait,
+        exec_time = ::tracing::field::Empty,
+    )
+}
@@ -0,0 +1,39 @@
+//! Reading and writing serde types as JSON (RFC 8259), with serde_json.
+
+use std::error::Error;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// An error from reading or writing JSON with `Request::json` or
+/// `Response::json`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonError {
+    message: String,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for JsonError {}
+
+impl From<serde_json::Error> for JsonError {
+    fn from(err: serde_json::Error) -> JsonError {
+        JsonError {
+            message: err.to_string(),
+        }
+    }
+}
+
+// compact な JSON にする。
+pub(crate) fn to_string<T: Serialize + ?Sized>(value: &T) -> Result<String, JsonError> {
+    Ok(serde_json::to_string(value)?)
+}
+
+pub(crate) fn from_slice<T: DeserializeOwned>(input: &[u8]) -> Result<T, JsonError> {
+    Ok(serde_json::from_slice(input)?)
+}
@@ -0,0 +1,117 @@
+//! Logging as one JSON object per line.
+
+use std::fmt;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use super::date::rfc3339;
+use super::RequestId;
+
+/// A `log` logger that writes each record as a JSON object on its own line,
+/// ready for log shippers such as Filebeat or Promtail.
+///
+/// The pool and the server report errors and diagnostics through `log`, so
+/// installing this logger makes them JSON. Pair it with
+/// `LogFormat::Json` for the access log:
+/// `{"time":"2018-03-04T05:06:07.089Z","level":"WARN","target":"hello","message":"..."}`.
+/// Records logged while a request is handled get its `request_id` too;
+/// see `RequestId`.
+pub struct JsonLogger {
+    writer: Mutex<Box<dyn Write + Send>>,
+    level: LevelFilter,
+}
+
+impl JsonLogger {
+    /// Create a logger that writes records of level `Info` and above to
+    /// `writer`.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> JsonLogger {
+        JsonLogger {
+            writer: Mutex::new(Box::new(writer)),
+            level: LevelFilter::Info,
+        }
+    }
+
+    /// Set the most verbose level written. Defaults to `Info`.
+    pub fn level(mut self, level: LevelFilter) -> JsonLogger {
+        self.level = level;
+        self
+    }
+
+    /// Install the logger for the whole process, setting the maximum log
+    /// level to match. Fails if a logger is already installed.
+    ///
+    /// Records are written up to the maximum level, so changing it later
+    /// with `log::set_max_level` makes the logger more or less verbose.
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let level = self.level;
+        log::set_logger(Box::leak(Box::new(self)))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = format!(
+            "{{\"time\":{},\"level\":{},\"target\":{},\"message\":{}",
+            quote(&rfc3339(SystemTime::now())),
+            quote(record.level().as_str()),
+            quote(record.target()),
+            quote(&record.args().to_string())
+        );
+        if let Some(id) = RequestId::current() {
+            line.push_str(&format!(",\"request_id\":{}", quote(&id)));
+        }
+        line.push_str("}\n");
+        // 他の thread が panic して poison されていても書ける。
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        // 書けなくても log で知らせると同じ所に戻ってくるので、諦める。
+        let _ = writer.write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = writer.flush();
+        }
+    }
+}
+
+impl fmt::Debug for JsonLogger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JsonLogger")
+            .field("level", &self.level)
+            .finish()
+    }
+}
+
+// JSON の文字列にする。
+pub(crate) fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
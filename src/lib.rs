@@ -1,14 +1,288 @@
+#[cfg(all(
+    any(feature = "affinity", feature = "thread-priority", feature = "signals"),
+    target_os = "linux"
+))]
+extern crate libc;
+#[cfg(feature = "brotli")]
+extern crate brotli;
+#[cfg(feature = "crossbeam")]
+extern crate crossbeam_channel;
+#[cfg(feature = "gzip")]
+extern crate flate2;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "json")]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "tls")]
+extern crate x509_parser;
+
+use std::any::Any;
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, OnceLock};
 use std::thread;
-use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+mod accept;
+mod accesslog;
+#[cfg(feature = "affinity")]
+mod affinity;
+mod auth;
+mod base64;
+#[cfg(feature = "futures")]
+mod blocking;
+mod builder;
+mod cancel;
+mod compression;
+mod cookie;
+mod cors;
+mod date;
+mod errors;
+mod eventstream;
+#[cfg(feature = "futures")]
+mod future;
+mod handle;
+mod handler;
+mod health;
+mod hpack;
+mod http;
+mod http2;
+mod httpsredirect;
+mod ipfilter;
+mod job;
+#[cfg(feature = "json")]
+mod json;
+mod jsonlog;
+mod limiter;
+mod listener;
+mod metrics;
+mod middleware;
+mod multipart;
+mod peer;
+#[cfg(feature = "thread-priority")]
+mod priority;
+mod queue;
+mod ratelimit;
+mod requestid;
+mod response;
+mod retry;
+mod router;
+mod scheduler;
+mod scope;
+mod server;
+mod session;
+mod sha1;
+mod sha256;
+mod shutdown;
+#[cfg(feature = "signals")]
+mod signal;
+mod staticfiles;
+mod stream;
+mod template;
+#[cfg(feature = "tls")]
+mod tls;
+mod websocket;
+
+pub use accept::{Accept, AcceptIter};
+pub use accesslog::{AccessLog, LogFormat};
+#[cfg(feature = "affinity")]
+pub use affinity::Affinity;
+pub use auth::Auth;
+#[cfg(feature = "futures")]
+pub use blocking::BlockingPool;
+pub use builder::ThreadPoolBuilder;
+use builder::{Installer, JobEndHook, JobStartHook, ThreadHook};
+pub use cancel::CancellationToken;
+pub use compression::Compression;
+pub use cookie::{Cookie, CookiePairs, Cookies, SameSite};
+pub use cors::Cors;
+pub use errors::HttpError;
+pub use eventstream::{Event, EventSender, EventStream};
+#[cfg(feature = "futures")]
+pub use future::JobFuture;
+use handle::Core;
+pub use handle::PoolHandle;
+pub use handler::Handler;
+pub use health::HealthCheck;
+pub use http::{HeaderIter, Headers, Method, ParseError, Query, QueryPairs, Request, Version};
+pub use httpsredirect::HttpsRedirect;
+pub use ipfilter::IpFilter;
+use job::DeadLetters;
+pub use job::{FailedJob, JobMeta};
+#[cfg(feature = "json")]
+pub use json::JsonError;
+pub use jsonlog::JsonLogger;
+pub use limiter::{Limiter, Permit};
+pub use listener::WorkerListener;
+use metrics::WaitRecorder;
+pub use metrics::{PoolMetrics, QueueWait, ShutdownReport, WorkerStats};
+pub use middleware::{Middleware, Next};
+pub use multipart::{Multipart, Part};
+pub use peer::{AltName, PeerIdentity};
+pub use ratelimit::RateLimit;
+pub use requestid::{RequestId, RequestIdGuard};
+pub use response::{Response, ResponseWriter, Status};
+use retry::RetryJob;
+pub use retry::{Backoff, RetryPolicy};
+pub use router::Router;
+pub use scheduler::ScheduleHandle;
+use scheduler::{Recurring, Scheduler, Task, Timers};
+pub use scope::Scope;
+pub use server::{Server, ServerBuilder, ServerError};
+pub use session::{MemoryStore, Session, SessionData, SessionStore, Sessions};
+pub use shutdown::ShutdownHandle;
+pub use staticfiles::{content_type, StaticFiles};
+pub use template::{Context, Templates};
+#[cfg(feature = "tls")]
+pub use tls::TlsConfig;
+pub use websocket::{WebSocket, WebSocketMessage};
+
+struct Message {
+    job: Job,
+    // queue に入れた時刻。
+    queued_at: Instant,
+    // execute_named で付けられた名前。
+    name: Option<Arc<str>>,
+    // 送った thread から持ってきた context。
+    context: Vec<Installer>,
+    // job class の番号。 0 は execute などで使う既定の class。
+    class: usize,
+}
+
+impl Message {
+    fn new(job: Job) -> Message {
+        Message {
+            job,
+            queued_at: Instant::now(),
+            name: None,
+            context: Vec::new(),
+            class: 0,
+        }
+    }
+}
+
+/// The priority of jobs submitted without an explicit one.
+pub const DEFAULT_PRIORITY: i32 = 0;
+
+// execute_all が一度に queue に入れる job の数。
+const BATCH_SIZE: usize = 64;
+
+// map が worker 1 つあたりに作る job の数。処理時間のばらつきを均すため 1 より多くする。
+const MAP_CHUNKS_PER_WORKER: usize = 4;
+
+// ThreadPool::global が返す pool。最初に使われた時に作る。
+static GLOBAL: OnceLock<ThreadPool> = OnceLock::new();
+
+// 次に作る pool の id。 worker がどの pool のものかを見分けるのに使う。
+static NEXT_POOL_ID: AtomicUsize = AtomicUsize::new(0);
 
-enum Message {
-    NewJob(Job),
-    Terminate,
+thread_local! {
+    // この thread で動いている worker の id。 worker thread 以外では None。
+    static WORKER_ID: Cell<Option<usize>> = const { Cell::new(None) };
+    // この thread で動いている worker の pool の id。
+    static POOL_ID: Cell<Option<usize>> = const { Cell::new(None) };
+    // 今の job が終わったら worker thread を作り直すか。
+    static REPLACE_WORKER: Cell<bool> = const { Cell::new(false) };
 }
 
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    handle: PoolHandle,
+    // 最初に execute_after が呼ばれた時に起動する。
+    scheduler: Mutex<Option<Scheduler>>,
+    on_job_timeout: Option<ThreadHook>,
+    replace_timed_out: bool,
+    drop_policy: DropPolicy,
+}
+
+// 暇な worker を終了させる設定。
+#[derive(Debug, Clone, Copy)]
+struct KeepAlive {
+    timeout: Duration,
+    core_size: usize,
+}
+
+// elastic な pool や、 worker を後から起動する pool の設定。
+#[derive(Debug, Clone, Copy)]
+struct Scaling {
+    max: usize,
+    threshold: usize,
+    // worker がこの数より少ない間は、全員が忙しければ threshold を待たずに
+    // 終了しない worker を追加する。
+    lazy_size: usize,
+}
+
+/// What `ThreadPool::execute` does when the job queue is at capacity.
+///
+/// The policy only matters for pools created with a queue capacity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RejectionPolicy {
+    /// Block the caller until a worker frees a slot. This is the default.
+    #[default]
+    Block,
+    /// Run the job on the calling thread instead of queueing it.
+    CallerRuns,
+    /// Drop the oldest queued job to make room for the new one.
+    DiscardOldest,
+    /// Return `ExecuteError::Rejected` to the caller.
+    Error,
+}
+
+/// What dropping a `ThreadPool` waits for.
+///
+/// Whichever is chosen, the queue is closed on drop, so workers that are
+/// left running finish the jobs already queued and then exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// Wait for every queued job to finish. This is the default.
+    #[default]
+    Wait,
+    /// Wait up to the given time, then detach the workers still running.
+    Drain(Duration),
+    /// Return right away, leaving the workers running in the background.
+    Detach,
+}
+
+/// What submitting a job does when it is called from one of the pool's own
+/// workers.
+///
+/// A job that queues another job and then waits for its result ties up a
+/// worker while it waits. Once every worker is waiting like this, the pool
+/// deadlocks. The policy applies to `execute` and the other methods that
+/// queue a job right away; `try_execute` and delayed jobs are not affected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NestedPolicy {
+    /// Queue the job like any other. This is the default.
+    #[default]
+    Queue,
+    /// Run the job right away on the submitting worker. A panic in the
+    /// job unwinds into the job that submitted it.
+    Inline,
+    /// Return `ExecuteError::Nested` to the caller.
+    Reject,
+}
+
+/// What a worker does when one of its jobs panics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Recover and keep running jobs. This is the default.
+    #[default]
+    Restart,
+    /// Recover up to the given number of panics per worker, then escalate.
+    RestartWithLimit(usize),
+    /// Shut the pool down and let the panic kill the worker thread. The
+    /// panic is re-raised in the owner's thread when the pool is dropped.
+    Escalate,
 }
 
 impl ThreadPool {
@@ -21,92 +295,1469 @@ impl ThreadPool {
     /// The `new` function will panic if the size is zero.
     pub fn new(size: usize) -> ThreadPool {
         assert!(size > 0);
+        ThreadPool::build(size).expect("failed to spawn worker thread")
+    }
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+    /// Create a new ThreadPool, reporting bad configuration as an error.
+    ///
+    /// Unlike `new`, this returns `PoolCreationError::ZeroSize` when the size
+    /// is zero and `PoolCreationError::Spawn` when the OS refuses to create
+    /// a worker thread. Workers spawned before the failure are shut down.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPoolBuilder::new().size(size).build()
+    }
+
+    /// Return the process-wide pool, creating it on first use.
+    ///
+    /// The pool has one worker per CPU and names its threads `global-{id}`.
+    /// To configure it differently, call `ThreadPoolBuilder::build_global`
+    /// at startup before anything uses it. The global pool is never shut
+    /// down.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool has to be created and a worker thread cannot be
+    /// spawned.
+    pub fn global() -> &'static ThreadPool {
+        GLOBAL.get_or_init(|| {
+            ThreadPoolBuilder::new()
+                .name_prefix("global")
+                .build()
+                .expect("failed to create the global thread pool")
+        })
+    }
+
+    /// Return a `ThreadPoolBuilder` for configuring a new pool.
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
+
+    /// Create a new ThreadPool whose job queue holds at most `capacity` jobs.
+    ///
+    /// Once the queue is full, `execute` blocks until a worker takes a job,
+    /// which gives natural backpressure to the caller.
+    pub fn with_queue_capacity(
+        size: usize,
+        capacity: usize,
+    ) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPoolBuilder::new()
+            .size(size)
+            .queue_capacity(capacity)
+            .build()
+    }
 
-        let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+    /// Create an elastic ThreadPool that keeps `min` workers and grows up to
+    /// `max` workers under load.
+    ///
+    /// Whenever more jobs are pending than the scale-up threshold (by
+    /// default `min`), `execute` spawns an extra worker. Extra workers exit
+    /// as soon as they find the queue empty, shrinking the pool back
+    /// towards `min`.
+    pub fn elastic(min: usize, max: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPoolBuilder::new().size(min).max_size(max).build()
+    }
+
+    /// Return the number of workers in the pool.
+    pub fn size(&self) -> usize {
+        let mut workers = self.core().workers.lock().unwrap();
+        workers.reap_exited();
+        workers.list.len()
+    }
+
+    /// Let workers that receive no job within `keep_alive` terminate
+    /// themselves, as long as at least `core_size` workers remain.
+    ///
+    /// By default workers live until the pool is shut down. The setting takes
+    /// effect the next time each worker waits for a job.
+    pub fn set_keep_alive(&mut self, keep_alive: Duration, core_size: usize) {
+        *self.core().context.keep_alive.lock().unwrap() = Some(KeepAlive {
+            timeout: keep_alive,
+            core_size,
+        });
+    }
+
+    /// Set how many jobs may be pending before an elastic pool spawns an
+    /// extra worker. Has no effect on pools not created by `elastic`.
+    pub fn set_scale_threshold(&mut self, pending: usize) {
+        if let Some(ref mut scaling) = *self.core().scaling.lock().unwrap() {
+            scaling.threshold = pending;
+        }
+    }
+
+    /// Grow or shrink the pool to `new_size` workers.
+    ///
+    /// New workers start taking jobs right away. When shrinking, this blocks
+    /// until the surplus workers have finished the job they are running and
+    /// exited; queued jobs are left for the remaining workers.
+    pub fn resize(&self, new_size: usize) -> Result<(), PoolCreationError> {
+        if new_size == 0 {
+            return Err(PoolCreationError::ZeroSize);
         }
 
-        ThreadPool { workers, sender }
+        let mut workers = self.core().workers.lock().unwrap();
+        workers.reap_exited();
+        let current = workers.list.len();
+        if new_size > current {
+            info!("Adding {} workers.", new_size - current);
+            for _ in current..new_size {
+                workers
+                    .spawn(&self.core().context, false)
+                    .map_err(PoolCreationError::Spawn)?;
+            }
+        } else if new_size < current {
+            info!("Removing {} workers.", current - new_size);
+            self.core().sender.retire(current - new_size);
+            while workers.list.len() > new_size {
+                match workers.exits.recv() {
+                    Ok(id) => workers.reap(id),
+                    Err(_) => break,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop workers from taking new jobs until `resume` is called.
+    ///
+    /// Jobs that are already running finish normally, and the queue keeps
+    /// accepting submissions. `join` does not return while queued jobs are
+    /// held back by a pause. Shutting the pool down lifts the pause so that
+    /// queued jobs can still run.
+    pub fn pause(&self) {
+        info!("Pausing the pool.");
+        self.core().sender.pause();
+    }
+
+    /// Let workers take jobs again after `pause`.
+    pub fn resume(&self) {
+        info!("Resuming the pool.");
+        self.core().sender.resume();
+    }
+
+    /// Return `true` if the pool is paused.
+    pub fn is_paused(&self) -> bool {
+        self.core().sender.is_paused()
+    }
+
+    /// Set how `execute` behaves when the job queue is full.
+    pub fn set_rejection_policy(&mut self, policy: RejectionPolicy) {
+        *self.core().rejection_policy.lock().unwrap() = policy;
+    }
+
+    /// Return the policy applied when the job queue is full.
+    pub fn rejection_policy(&self) -> RejectionPolicy {
+        *self.core().rejection_policy.lock().unwrap()
+    }
+
+    /// Set what workers do when a job panics.
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        *self.core().context.panic_policy.lock().unwrap() = policy;
+    }
+
+    /// Return the policy applied when a job panics.
+    pub fn panic_policy(&self) -> PanicPolicy {
+        *self.core().context.panic_policy.lock().unwrap()
+    }
+
+    /// Return how many jobs have panicked on each worker, as pairs of
+    /// worker id and panic count.
+    pub fn panic_counts(&self) -> Vec<(usize, usize)> {
+        let mut workers = self.core().workers.lock().unwrap();
+        workers.reap_exited();
+        workers
+            .list
+            .iter()
+            .map(|w| (w.id, w.stats.panics.load(Ordering::SeqCst)))
+            .collect()
+    }
+
+    /// Return statistics for each worker, in the order the workers were
+    /// spawned.
+    ///
+    /// Comparing the job counts and busy times shows how evenly the workers
+    /// share the load.
+    pub fn worker_stats(&self) -> Vec<WorkerStats> {
+        let mut workers = self.core().workers.lock().unwrap();
+        workers.reap_exited();
+        workers.list.iter().map(Worker::stats).collect()
+    }
+
+    /// Return the total number of jobs that have panicked in this pool,
+    /// including those on workers that have since been removed.
+    pub fn total_panics(&self) -> usize {
+        let mut workers = self.core().workers.lock().unwrap();
+        workers.reap_exited();
+        let live: usize = workers
+            .list
+            .iter()
+            .map(|w| w.stats.panics.load(Ordering::SeqCst))
+            .sum();
+        live + workers.retired_panics
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// Return the most recent jobs that panicked, oldest first.
+    ///
+    /// The number kept is set with `ThreadPoolBuilder::failed_job_capacity`.
+    pub fn failed_jobs(&self) -> Vec<FailedJob> {
+        self.core().context.failed_jobs.snapshot()
+    }
+
+    /// Return a snapshot of the pool's queue and workers.
+    pub fn metrics(&self) -> PoolMetrics {
+        let size = self.size();
+        let busy = self.core().sender.active();
+        PoolMetrics {
+            queued: self.core().sender.len(),
+            busy,
+            idle: size.saturating_sub(busy),
+            completed: self.core().context.completed.load(Ordering::SeqCst),
+            panics: self.total_panics(),
+            queue_wait: self.core().context.queue_wait.snapshot(),
+        }
+    }
+
+    /// Submit a job to the pool.
+    ///
+    /// If the pool was created with a queue capacity and the queue is full,
+    /// the pool's `RejectionPolicy` decides what happens; by default this
+    /// blocks until there is room. If every worker has already terminated
+    /// the job cannot run, and the closure is handed back inside the
+    /// `ExecuteError`.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.handle.execute(f)
+    }
+
+    /// Submit a batch of jobs, taking the queue's lock once per chunk
+    /// instead of once per job.
+    ///
+    /// With `RejectionPolicy::Block` this waits for room like `execute`. Under
+    /// the other policies, jobs that do not fit into the queue are submitted
+    /// one at a time so the policy applies to each of them. If a job cannot
+    /// be submitted, the error holds it together with every job after it.
+    pub fn execute_all<I, F>(&self, jobs: I) -> Result<(), ExecuteError<Vec<F>>>
     where
+        I: IntoIterator<Item = F>,
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        self.sender.send(Message::NewJob(job)).unwrap();
+        self.handle.execute_all(jobs)
+    }
+
+    /// Submit a job with the given priority.
+    ///
+    /// Jobs with a higher priority are taken by workers before those with a
+    /// lower one, so latency-sensitive work can jump ahead of bulk work. Jobs
+    /// of equal priority run in the order they were submitted. `execute`
+    /// uses `DEFAULT_PRIORITY`.
+    ///
+    /// Workers take jobs from the queue in small batches, so a job can still
+    /// wait behind the rest of a batch a worker has already taken.
+    pub fn execute_with_priority<F>(&self, priority: i32, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.handle.execute_with_priority(priority, f)
+    }
+
+    /// Submit a job to the job class `class`, set up with
+    /// `ThreadPoolBuilder::job_class`.
+    ///
+    /// Workers take jobs from the classes in turn, in proportion to their
+    /// weights, so a flood of jobs in one class cannot starve another.
+    /// Jobs from `execute` and the other methods go to the `"default"`
+    /// class.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pool has no class named `class`.
+    pub fn execute_in<F>(&self, class: &str, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.handle.execute_in(class, f)
+    }
+
+    /// Submit a job with a name that shows up in the pool's log lines,
+    /// panic reports, `WorkerStats::current_job`, the job hooks' `JobMeta`
+    /// and, with the `tracing` feature, the job's span.
+    pub fn execute_named<S, F>(&self, name: S, f: F) -> Result<(), ExecuteError<F>>
+    where
+        S: Into<Arc<str>>,
+        F: FnOnce() + Send + 'static,
+    {
+        self.handle.execute_named(name, f)
+    }
+
+    /// Run `f` with a `Scope` whose jobs may borrow non-`'static` data.
+    ///
+    /// Returns once `f` and every job submitted through the scope have
+    /// finished. If any of those jobs panicked, this panics as well after
+    /// the rest are done. Calling this from one of the pool's own jobs can
+    /// deadlock when no other worker is free to run the scoped jobs.
+    pub fn scope<'pool, 'scope, F, R>(&'pool self, f: F) -> R
+    where
+        F: FnOnce(&Scope<'pool, 'scope>) -> R,
+    {
+        Scope::run(self, f)
+    }
+
+    /// Apply `f` to every item on the pool and collect the results in the
+    /// order of `items`.
+    ///
+    /// Items are split into a few chunks per worker so that each job does a
+    /// reasonable amount of work. Like `scope`, this blocks until every item
+    /// is done and panics if `f` panicked for any of them.
+    pub fn map<I, F, R>(&self, items: I, f: F) -> Vec<R>
+    where
+        I: IntoIterator,
+        I::Item: Send,
+        F: Fn(I::Item) -> R + Sync,
+        R: Send,
+    {
+        let mut items: Vec<_> = items.into_iter().map(Some).collect();
+        let mut results: Vec<Option<R>> = items.iter().map(|_| None).collect();
+        if items.is_empty() {
+            return Vec::new();
+        }
+        let chunks = self.size().max(1) * MAP_CHUNKS_PER_WORKER;
+        let chunk_size = items.len().div_ceil(chunks);
+        let f = &f;
+        self.scope(|scope| {
+            for (items, results) in items
+                .chunks_mut(chunk_size)
+                .zip(results.chunks_mut(chunk_size))
+            {
+                scope.execute(move || {
+                    for (item, result) in items.iter_mut().zip(results) {
+                        *result = item.take().map(f);
+                    }
+                });
+            }
+        });
+        results
+            .into_iter()
+            .map(|result| result.expect("every item is mapped"))
+            .collect()
+    }
+
+    /// Submit a job that is skipped if `token` is cancelled before it starts.
+    ///
+    /// The job stays in the queue until a worker reaches it, at which point
+    /// it is dropped without running. To stop a job that has already
+    /// started, have it poll `CancellationToken::is_cancelled`.
+    pub fn execute_with_token<F>(
+        &self,
+        token: &CancellationToken,
+        f: F,
+    ) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.handle.execute_with_token(token, f)
+    }
+
+    /// Submit a job with a time budget.
+    ///
+    /// If the job is still running after `timeout`, a warning is logged and
+    /// the pool's `on_job_timeout` hook is called with the worker id. The job
+    /// itself is not interrupted. If the pool was built with
+    /// `replace_timed_out_workers`, the worker's thread is replaced with a
+    /// fresh one once the job finishes. Jobs run on the caller's thread
+    /// through `RejectionPolicy::CallerRuns` are not watched.
+    pub fn execute_with_timeout<F>(&self, timeout: Duration, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let timers = match self.scheduler() {
+            Some(scheduler) => scheduler.as_ref().unwrap().timers(),
+            None => return Err(ExecuteError::Disconnected(f)),
+        };
+        let job = TimedJob {
+            f,
+            timeout,
+            timers,
+            on_timeout: self.on_job_timeout.clone(),
+            replace: self.replace_timed_out,
+        };
+        self.handle
+            .send_job(job, TimedJob::run, DEFAULT_PRIORITY)
+            .map_err(|err| err.map(|job| job.f))
+    }
+
+    /// Submit a job that is run again, after a backoff, when it returns an
+    /// error or panics.
+    ///
+    /// The job runs at most `policy.max_attempts` times. Retries are queued
+    /// by the pool's timer thread like jobs from `execute_after`, so `join`
+    /// does not wait for a retry that is not due yet. Failed attempts are
+    /// logged; a panic on the last attempt is passed on to the worker and
+    /// handled by the panic policy.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `policy.max_attempts` is zero.
+    pub fn execute_with_retry<F, E>(&self, policy: RetryPolicy, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnMut() -> Result<(), E> + Send + 'static,
+        E: fmt::Display,
+    {
+        assert!(policy.max_attempts > 0, "max_attempts must be non-zero");
+        let timers = match self.scheduler() {
+            Some(scheduler) => scheduler.as_ref().unwrap().timers(),
+            None => return Err(ExecuteError::Disconnected(f)),
+        };
+        let job = RetryJob {
+            f,
+            policy,
+            attempt: 1,
+            timers,
+        };
+        self.handle
+            .send_job(job, RetryJob::run, DEFAULT_PRIORITY)
+            .map_err(|err| err.map(|job| job.f))
+    }
+
+    /// Submit a job to run once `delay` has passed.
+    ///
+    /// The pool starts a timer thread the first time this is called. When the
+    /// delay expires the job is put on the queue like any other job at
+    /// `DEFAULT_PRIORITY`, waiting for room if the queue is full; the
+    /// rejection policy is not consulted. Jobs that are not due yet when the
+    /// pool shuts down are dropped without running, and `join` does not wait
+    /// for them.
+    pub fn execute_after<F>(&self, delay: Duration, f: F) -> Result<(), ExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let deadline = Instant::now() + delay;
+        match self.scheduler() {
+            Some(scheduler) => {
+                let scheduler = scheduler.as_ref().unwrap();
+                scheduler.schedule(deadline, Task::Once(Box::new(f)));
+                Ok(())
+            }
+            None => Err(ExecuteError::Disconnected(f)),
+        }
+    }
+
+    /// Submit a job to run every `interval`, starting one `interval` from now.
+    ///
+    /// Each run is queued like a job from `execute_after`. If the previous
+    /// run has not finished when the next one is due, that run is skipped,
+    /// so runs never overlap. The schedule lasts until it is cancelled
+    /// through the returned `ScheduleHandle` or the pool shuts down.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn execute_every<F>(
+        &self,
+        interval: Duration,
+        f: F,
+    ) -> Result<ScheduleHandle, ExecuteError<F>>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        assert!(
+            interval > Duration::from_secs(0),
+            "interval must be non-zero"
+        );
+        let deadline = Instant::now() + interval;
+        match self.scheduler() {
+            Some(scheduler) => {
+                let scheduler = scheduler.as_ref().unwrap();
+                let (recurring, handle) = Recurring::new(interval, f);
+                scheduler.schedule(deadline, Task::Every(recurring));
+                Ok(handle)
+            }
+            None => Err(ExecuteError::Disconnected(f)),
+        }
+    }
+
+    /// Submit a job without blocking.
+    ///
+    /// Unlike `execute`, this fails immediately with `TryExecuteError::Full`
+    /// when the job queue is at capacity, handing the closure back. The
+    /// rejection policy is not consulted.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), TryExecuteError<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.handle.try_execute(f)
+    }
+
+    /// Submit a job whose return value can be retrieved later.
+    ///
+    /// The returned `JobHandle` can be waited on or polled for the result.
+    pub fn execute_with_result<F, T>(&self, f: F) -> Result<JobHandle<T>, ExecuteError<F>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.handle.execute_with_result(f)
+    }
+
+    /// Submit a job and return a future that resolves to its result.
+    ///
+    /// This lets async code offload blocking work to the pool and await it
+    /// without blocking the executor. The job runs whether or not the future
+    /// is polled.
+    #[cfg(feature = "futures")]
+    pub fn spawn<F, T>(&self, f: F) -> Result<JobFuture<T>, ExecuteError<F>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.handle.spawn(f)
+    }
+
+    /// Return a cloneable handle for submitting jobs from other threads.
+    ///
+    /// The handle does not keep the pool alive: once the pool is shut down or
+    /// dropped, submitting through it fails.
+    pub fn handle(&self) -> PoolHandle {
+        self.handle.clone()
+    }
+
+    /// Block until every submitted job has finished.
+    ///
+    /// Returns once the queue is empty and all workers are idle. Unlike
+    /// `shutdown`, the pool stays usable afterwards.
+    pub fn join(&self) {
+        self.core().sender.wait_idle();
+    }
+
+    /// Stop accepting jobs and wait up to `timeout` for the workers to finish.
+    ///
+    /// Jobs already queued are still run. Workers that are not done by the
+    /// deadline are detached and left running in the background. The
+    /// report tells how much work was left to them.
+    pub fn shutdown(&mut self, timeout: Duration) -> ShutdownReport {
+        info!("Shutting down with a timeout of {:?}.", timeout);
+        let started = Instant::now();
+        let queued = self.core().sender.len();
+        self.core().sender.close();
+        self.stop_scheduler();
+        let mut report = self.join_workers(Some(started + timeout));
+        report.queued = queued;
+        report.elapsed = started.elapsed();
+        report
+    }
+
+    /// Stop accepting jobs and drop every job still in the queue.
+    ///
+    /// Only the jobs that workers are currently running are waited for.
+    pub fn shutdown_now(&mut self) -> ShutdownReport {
+        info!("Shutting down now, discarding queued jobs.");
+        let started = Instant::now();
+        self.core().sender.close();
+        self.stop_scheduler();
+        let discarded = self.core().sender.drain();
+        info!("Discarded {} queued jobs.", discarded.len());
+        let mut report = ShutdownReport {
+            discarded: discarded.len(),
+            ..self.join_workers(None)
+        };
+        drop(discarded);
+        report.queued = report.discarded;
+        report.elapsed = started.elapsed();
+        report
+    }
+
+    // Worker の終了を待ち、 deadline までに終わらなかった worker は切り離す。
+    // 残った job と worker の数を報告する。
+    fn join_workers(&mut self, deadline: Option<Instant>) -> ShutdownReport {
+        let mut workers = self.core().workers.lock().unwrap();
+        while !workers.list.is_empty() {
+            let id = match deadline {
+                None => workers.exits.recv().ok(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    workers.exits.recv_timeout(deadline - now).ok()
+                }
+            };
+            match id {
+                Some(id) => workers.reap(id),
+                None => break,
+            }
+        }
+
+        let sender = &self.handle.core.sender;
+        let report = ShutdownReport {
+            abandoned: sender.len() + sender.active(),
+            detached_workers: workers.list.len(),
+            ..ShutdownReport::default()
+        };
+        for worker in workers.list.drain(..) {
+            warn!(
+                "Detaching worker {} that did not finish in time.",
+                worker.id
+            );
+        }
+        if report.abandoned > 0 {
+            warn!("Leaving {} unfinished jobs behind.", report.abandoned);
+        }
+        report
+    }
+
+    fn core(&self) -> &Core {
+        &self.handle.core
+    }
+
+    // timer thread を (必要なら起動して) 返す。 pool が閉じていれば None を返す。
+    // scheduler を止めるのは queue を閉じた後なので、 Some なら動いている。
+    fn scheduler(&self) -> Option<MutexGuard<'_, Option<Scheduler>>> {
+        if self.core().sender.is_closed() {
+            return None;
+        }
+        let mut scheduler = self.scheduler.lock().unwrap();
+        if scheduler.is_none() {
+            let name = self
+                .core()
+                .context
+                .name_prefix
+                .as_ref()
+                .map(|prefix| format!("{}-scheduler", prefix));
+            match Scheduler::start(self.handle(), name) {
+                Ok(started) => *scheduler = Some(started),
+                Err(err) => {
+                    error!("Failed to start the scheduler thread: {}", err);
+                    return None;
+                }
+            }
+        }
+        Some(scheduler)
+    }
+
+    fn stop_scheduler(&mut self) {
+        let scheduler = match self.scheduler.get_mut() {
+            Ok(scheduler) => scheduler,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(ref mut scheduler) = *scheduler {
+            scheduler.stop();
+        }
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        println!("Sending terminate message to all workers.");
-        for _ in &self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+        // 予約された job が来なくなってから worker を止める。
+        self.stop_scheduler();
+
+        let workers = match self.core().workers.lock() {
+            Ok(workers) => workers,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if workers.list.is_empty() {
+            // shutdown 済み。
+            return;
+        }
+
+        // queue を閉じると、全ての worker は残っている job を片付けてから終了する。
+        // 既に終了した worker がいても他の worker の終了には影響しない。
+        debug!("Closing the queue for all workers.");
+        self.core().sender.close();
+
+        let mut escalated = None;
+        match self.drop_policy {
+            DropPolicy::Wait => {
+                debug!("Shutting down all workers.");
+                for worker in &workers.list {
+                    debug!("Shutting down worker {}", worker.id);
+                    if let Err(payload) = worker.join() {
+                        escalated = escalated.or(Some(payload));
+                    }
+                }
+            }
+            DropPolicy::Drain(timeout) => {
+                debug!("Waiting up to {:?} for all workers.", timeout);
+                let deadline = Instant::now() + timeout;
+                let mut workers = workers;
+                while !workers.list.is_empty() {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    let id = match workers.exits.recv_timeout(deadline - now) {
+                        Ok(id) => id,
+                        Err(_) => break,
+                    };
+                    if let Some(index) = workers.list.iter().position(|w| w.id == id) {
+                        if let Err(payload) = workers.list.remove(index).join() {
+                            escalated = escalated.or(Some(payload));
+                        }
+                    }
+                }
+                for worker in workers.list.drain(..) {
+                    warn!(
+                        "Detaching worker {} that did not finish in time.",
+                        worker.id
+                    );
+                }
+            }
+            DropPolicy::Detach => {
+                debug!("Detaching {} workers.", workers.list.len());
+            }
+        }
+
+        // escalate された panic は pool の持ち主に伝える。
+        if let Some(payload) = escalated {
+            if !thread::panicking() {
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+/// An error returned by `ThreadPool::build`.
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// The requested pool size was zero.
+    ZeroSize,
+    /// The requested queue capacity was zero.
+    ZeroCapacity,
+    /// The maximum size of an elastic pool was smaller than its minimum.
+    MaxBelowMin,
+    /// A job class was given a weight of zero.
+    ZeroWeight,
+    /// `build_global` was called after the global pool had been created.
+    GlobalExists,
+    /// A worker thread could not be spawned.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolCreationError::ZeroSize => write!(f, "thread pool size must be greater than zero"),
+            PoolCreationError::ZeroCapacity => {
+                write!(f, "job queue capacity must be greater than zero")
+            }
+            PoolCreationError::MaxBelowMin => {
+                write!(f, "maximum pool size must not be smaller than the minimum")
+            }
+            PoolCreationError::ZeroWeight => {
+                write!(f, "job class weight must be greater than zero")
+            }
+            PoolCreationError::GlobalExists => {
+                write!(f, "the global thread pool has already been created")
+            }
+            PoolCreationError::Spawn(ref err) => {
+                write!(f, "failed to spawn worker thread: {}", err)
+            }
         }
+    }
+}
 
-        println!("Shutting down all workers.");
-        for worker in &mut self.workers {
-            println!("Shutting down woerker {}", worker.id);
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PoolCreationError::ZeroSize
+            | PoolCreationError::ZeroCapacity
+            | PoolCreationError::MaxBelowMin
+            | PoolCreationError::ZeroWeight
+            | PoolCreationError::GlobalExists => None,
+            PoolCreationError::Spawn(ref err) => Some(err),
+        }
+    }
+}
+
+/// An error returned by `ThreadPool::execute`. Either way the rejected
+/// closure is returned to the caller.
+pub enum ExecuteError<F> {
+    /// The job queue was full and the pool uses `RejectionPolicy::Error`.
+    Rejected(F),
+    /// The pool has been shut down or every worker has terminated.
+    Disconnected(F),
+    /// The job was submitted from one of the pool's own workers and the
+    /// pool uses `NestedPolicy::Reject`.
+    Nested(F),
+}
+
+impl<F> ExecuteError<F> {
+    /// Take back the closure that could not be executed.
+    pub fn into_inner(self) -> F {
+        match self {
+            ExecuteError::Rejected(f) | ExecuteError::Disconnected(f) | ExecuteError::Nested(f) => {
+                f
             }
         }
     }
+
+    fn map<G, M: FnOnce(F) -> G>(self, m: M) -> ExecuteError<G> {
+        match self {
+            ExecuteError::Rejected(f) => ExecuteError::Rejected(m(f)),
+            ExecuteError::Disconnected(f) => ExecuteError::Disconnected(m(f)),
+            ExecuteError::Nested(f) => ExecuteError::Nested(m(f)),
+        }
+    }
+}
+
+impl<F> fmt::Debug for ExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecuteError::Rejected(_) => f.write_str("Rejected(..)"),
+            ExecuteError::Disconnected(_) => f.write_str("Disconnected(..)"),
+            ExecuteError::Nested(_) => f.write_str("Nested(..)"),
+        }
+    }
+}
+
+impl<F> fmt::Display for ExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecuteError::Rejected(_) => f.write_str("job queue is full"),
+            ExecuteError::Disconnected(_) => f.write_str("thread pool is shut down"),
+            ExecuteError::Nested(_) => f.write_str("job submitted from a worker of the same pool"),
+        }
+    }
+}
+
+impl<F> Error for ExecuteError<F> {}
+
+/// An error returned by `ThreadPool::try_execute`. Either way the rejected
+/// closure is returned to the caller.
+pub enum TryExecuteError<F> {
+    /// The job queue is at capacity.
+    Full(F),
+    /// The pool has been shut down or every worker has terminated.
+    Disconnected(F),
+}
+
+impl<F> TryExecuteError<F> {
+    /// Take back the closure that could not be executed.
+    pub fn into_inner(self) -> F {
+        match self {
+            TryExecuteError::Full(f) | TryExecuteError::Disconnected(f) => f,
+        }
+    }
+}
+
+impl<F> fmt::Debug for TryExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryExecuteError::Full(_) => f.write_str("Full(..)"),
+            TryExecuteError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<F> fmt::Display for TryExecuteError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryExecuteError::Full(_) => f.write_str("job queue is full"),
+            TryExecuteError::Disconnected(_) => f.write_str("thread pool is shut down"),
+        }
+    }
+}
+
+impl<F> Error for TryExecuteError<F> {}
+
+/// A handle to the result of a job submitted by `ThreadPool::execute_with_result`.
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<Result<T, JobError>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Block until the job finishes and return its result.
+    pub fn wait(self) -> Result<T, JobError> {
+        self.receiver.recv().unwrap_or(Err(JobError::Canceled))
+    }
+
+    /// Wait for the result for at most `timeout`.
+    ///
+    /// Returns `None` if the job is still running when the timeout elapses.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<Result<T, JobError>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => Some(result),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => Some(Err(JobError::Canceled)),
+        }
+    }
+
+    /// Check whether the job has finished without blocking.
+    ///
+    /// Returns `None` while the job is still pending. Once the result has
+    /// been taken, later calls return `Some(Err(JobError::Canceled))`.
+    pub fn try_wait(&self) -> Option<Result<T, JobError>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(JobError::Canceled)),
+        }
+    }
+}
+
+/// An error returned when a job finished without producing a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobError {
+    /// The job was dropped before it could run.
+    Canceled,
+    /// The job panicked with the given message.
+    Panicked(String),
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JobError::Canceled => f.write_str("job finished without producing a result"),
+            JobError::Panicked(ref msg) => write!(f, "job panicked: {}", msg),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+// pool が管理する worker の一覧。
+struct Workers {
+    list: Vec<Worker>,
+    next_id: usize,
+    exits: mpsc::Receiver<usize>,
+    // 既に取り除かれた worker の panic 数。
+    retired_panics: usize,
+}
+
+impl Workers {
+    fn spawn(&mut self, context: &WorkerContext, transient: bool) -> io::Result<()> {
+        let worker = Worker::new(self.next_id, context.clone(), transient)?;
+        self.next_id += 1;
+        self.list.push(worker);
+        Ok(())
+    }
+
+    // 既に終了を通知してきた worker を全て回収する。
+    fn reap_exited(&mut self) {
+        while let Ok(id) = self.exits.try_recv() {
+            self.reap(id);
+        }
+    }
+
+    // 終了した worker を join して一覧から取り除く。
+    fn reap(&mut self, id: usize) {
+        if let Some(index) = self.list.iter().position(|w| w.id == id) {
+            let worker = self.list.remove(index);
+            self.retired_panics += worker.stats.panics.load(Ordering::SeqCst);
+            // job の panic で終了した worker もここで回収する。
+            let _ = worker.join();
+        }
+    }
+}
+
+// pool と各 worker が共有する状態。
+#[derive(Clone)]
+struct WorkerContext {
+    receiver: queue::Receiver<Message>,
+    exits: mpsc::Sender<usize>,
+    panic_policy: Arc<Mutex<PanicPolicy>>,
+    keep_alive: Arc<Mutex<Option<KeepAlive>>>,
+    // 動いている worker thread の数。
+    live: Arc<AtomicUsize>,
+    pool_id: usize,
+    // panic せずに終わった job の数。
+    completed: Arc<AtomicUsize>,
+    queue_wait: Arc<WaitRecorder>,
+    failed_jobs: Arc<DeadLetters>,
+    name_prefix: Option<Arc<String>>,
+    stack_size: Option<usize>,
+    on_thread_start: Option<ThreadHook>,
+    on_thread_stop: Option<ThreadHook>,
+    on_job_start: Option<JobStartHook>,
+    on_job_end: Option<JobEndHook>,
+    listener: Option<Arc<dyn WorkerListener>>,
+    #[cfg(feature = "affinity")]
+    affinity: Option<Arc<Affinity>>,
+    #[cfg(feature = "thread-priority")]
+    nice: Option<i32>,
 }
 
 struct Worker {
     id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+    // thread を作り直すと、新しい thread の handle に入れ替わる。
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+    stats: Arc<WorkerCounters>,
+}
+
+// worker ごとの統計。 thread を作り直しても引き継ぐ。
+#[derive(Default)]
+struct WorkerCounters {
+    panics: AtomicUsize,
+    activity: Mutex<Activity>,
+}
+
+#[derive(Default)]
+struct Activity {
+    jobs: usize,
+    busy_time: Duration,
+    last_job: Option<SystemTime>,
+    // 実行中の job の名前。
+    current_job: Option<Arc<str>>,
+}
+
+impl WorkerCounters {
+    fn activity(&self) -> MutexGuard<'_, Activity> {
+        match self.activity.lock() {
+            Ok(activity) => activity,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn start_job(&self, name: Option<&Arc<str>>) {
+        self.activity().current_job = name.cloned();
+    }
+
+    // `elapsed` かかった job の終了を記録する。
+    fn record_job(&self, elapsed: Duration) {
+        let mut activity = self.activity();
+        activity.jobs += 1;
+        activity.busy_time += elapsed;
+        activity.last_job = SystemTime::now().checked_sub(elapsed);
+        activity.current_job = None;
+    }
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
-            match message {
-                Message::NewJob(job) => {
-                    println!("Worker {} got a job; executing.", id);
-                    job.call_box();
-                    println!("Worker {} done.", id);
+    fn stats(&self) -> WorkerStats {
+        let activity = self.stats.activity.lock().unwrap();
+        WorkerStats {
+            id: self.id,
+            jobs: activity.jobs,
+            busy_time: activity.busy_time,
+            last_job: activity.last_job,
+            current_job: activity.current_job.as_ref().map(|name| name.to_string()),
+            panics: self.stats.panics.load(Ordering::SeqCst),
+        }
+    }
+
+    // worker thread の終了を待つ。作り直されていれば新しい thread も待つ。
+    fn join(&self) -> thread::Result<()> {
+        let mut result = Ok(());
+        loop {
+            let thread = self.thread.lock().unwrap().take();
+            match thread {
+                Some(thread) => {
+                    if let Err(payload) = thread.join() {
+                        result = result.and(Err(payload));
+                    }
+                }
+                None => return result,
+            }
+        }
+    }
+}
+
+impl Worker {
+    // transient な worker は elastic な pool が負荷に応じて追加したもので、
+    // queue が空になると自ら終了する。
+    fn new(id: usize, context: WorkerContext, transient: bool) -> io::Result<Worker> {
+        let stats = Arc::new(WorkerCounters::default());
+        let thread = Arc::new(Mutex::new(None));
+        Worker::spawn_thread(id, context, transient, &thread, &stats, None)?;
+        Ok(Worker { id, thread, stats })
+    }
+
+    // worker thread を起動して `slot` に handle を入れる。 thread を作り直す
+    // 場合は、新しい thread が `predecessor` (古い thread) の終了を待つ。
+    fn spawn_thread(
+        id: usize,
+        context: WorkerContext,
+        transient: bool,
+        slot: &Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+        stats: &Arc<WorkerCounters>,
+        predecessor: Option<thread::JoinHandle<()>>,
+    ) -> io::Result<()> {
+        // handle を入れ終わるまで、新しい thread が slot に触れないよう lock しておく。
+        let mut handle = slot.lock().unwrap();
+        context.live.fetch_add(1, Ordering::SeqCst);
+        let live = LiveCount(Arc::clone(&context.live));
+        let mut builder = thread::Builder::new();
+        if let Some(ref prefix) = context.name_prefix {
+            builder = builder.name(format!("{}-{}", prefix, id));
+        }
+        if let Some(size) = context.stack_size {
+            builder = builder.stack_size(size);
+        }
+        let slot = Arc::clone(slot);
+        let stats = Arc::clone(stats);
+        let thread = builder.spawn(move || {
+            let mut notice = ExitNotice {
+                id,
+                sender: Some(context.exits.clone()),
+            };
+            if let Some(predecessor) = predecessor {
+                let _ = predecessor.join();
+            }
+            WORKER_ID.with(|worker| worker.set(Some(id)));
+            POOL_ID.with(|pool| pool.set(Some(context.pool_id)));
+            #[cfg(feature = "affinity")]
+            {
+                if let Some(ref affinity) = context.affinity {
+                    affinity::pin_current(affinity, id);
                 }
-                Message::Terminate => {
-                    println!("Worker {} was told to terminate.", id);
-                    break;
+            }
+            #[cfg(feature = "thread-priority")]
+            {
+                if let Some(nice) = context.nice {
+                    priority::set_current(nice, id);
                 }
             }
-        });
+            if let Some(ref on_start) = context.on_thread_start {
+                on_start(id);
+            }
+            if let Some(ref listener) = context.listener {
+                listener.on_start(id, SystemTime::now());
+            }
+            let stop = context
+                .on_thread_stop
+                .as_ref()
+                .map(|hook| StopHook(id, hook));
+            let terminate = context
+                .listener
+                .as_ref()
+                .map(|listener| TerminateHook(id, &**listener));
+            if !Worker::run(id, &context, &stats, transient, live) {
+                return;
+            }
+            drop(terminate);
+            drop(stop);
+
+            let own = slot.lock().unwrap().take();
+            match Worker::spawn_thread(id, context.clone(), transient, &slot, &stats, own) {
+                // 同じ worker として動き続けるので、終了は通知しない。
+                Ok(()) => notice.sender = None,
+                Err(err) => error!("Failed to replace worker {}: {}", id, err),
+            }
+        })?;
+        *handle = Some(thread);
+        Ok(())
+    }
+
+    // job の後に thread を作り直すよう求められた場合は true を返す。
+    fn run(
+        id: usize,
+        context: &WorkerContext,
+        stats: &WorkerCounters,
+        transient: bool,
+        live: LiveCount,
+    ) -> bool {
+        let receiver = &context.receiver;
+        // on_idle を既に呼んだか。
+        let mut idle = false;
+        loop {
+            let keep_alive = *context.keep_alive.lock().unwrap();
+            // 待つ前に、 queue が空なら listener に知らせる。
+            if let Some(ref listener) = context.listener {
+                if !transient && !idle && receiver.len() == 0 {
+                    idle = true;
+                    listener.on_idle(id, SystemTime::now());
+                }
+            }
+            let message = match keep_alive {
+                _ if transient => receiver.try_recv(),
+                None => receiver.recv(),
+                Some(keep_alive) => match receiver.recv_timeout(keep_alive.timeout) {
+                    Ok(message) => Some(message),
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        if live.release_above(keep_alive.core_size) {
+                            // 既に数を減らしたので guard は drop させない。
+                            mem::forget(live);
+                            debug!(
+                                "Worker {} was idle for {:?}; exiting.",
+                                id, keep_alive.timeout
+                            );
+                            return false;
+                        }
+                        continue;
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => None,
+                },
+            };
+            let message = match message {
+                Some(message) => message,
+                None => {
+                    debug!("Worker {} was told to terminate.", id);
+                    return false;
+                }
+            };
+            idle = false;
+            // job が panic しても task_done されるよう guard で包む。
+            let _done = TaskDone(receiver);
+            let Message {
+                job,
+                queued_at,
+                name,
+                context: captured,
+                ..
+            } = message;
+            // span より後に drop されるよう先に入れる。
+            let _captured: Vec<_> = captured.into_iter().map(|install| install()).collect();
+            let meta = JobMeta::new(id, queued_at.elapsed(), name.clone());
+            context.queue_wait.record(meta.queue_wait());
+            let label = JobLabel(meta.name());
+            #[cfg(feature = "tracing")]
+            let span = job::span(&meta);
+            #[cfg(feature = "tracing")]
+            let _entered = span.enter();
+            trace!("Worker {} got {}; executing.", id, label);
+            stats.start_job(name.as_ref());
+            if let Some(ref on_start) = context.on_job_start {
+                on_start(&meta);
+            }
+            let started = Instant::now();
+            let result = panic::catch_unwind(AssertUnwindSafe(job));
+            let elapsed = started.elapsed();
+            #[cfg(feature = "tracing")]
+            span.record("exec_time", tracing::field::debug(elapsed));
+            stats.record_job(elapsed);
+            if let Some(ref on_end) = context.on_job_end {
+                on_end(&meta, elapsed);
+            }
+            // job が panic しても、 policy が許す限り worker は死なずに次の job を待つ。
+            match result {
+                Ok(()) => {
+                    context.completed.fetch_add(1, Ordering::SeqCst);
+                    trace!("Worker {} done with {}.", id, label);
+                }
+                Err(payload) => {
+                    let message = panic_message(&*payload);
+                    error!("Worker {} panicked in {}: {}", id, label, message);
+                    if let Some(ref listener) = context.listener {
+                        listener.on_panic(id, SystemTime::now(), &message);
+                    }
+                    context.failed_jobs.push(FailedJob {
+                        worker_id: id,
+                        name: meta.name().map(String::from),
+                        message,
+                        time: SystemTime::now(),
+                    });
+                    let count = stats.panics.fetch_add(1, Ordering::SeqCst) + 1;
+                    let escalate = match *context.panic_policy.lock().unwrap() {
+                        PanicPolicy::Restart => false,
+                        PanicPolicy::RestartWithLimit(limit) => count > limit,
+                        PanicPolicy::Escalate => true,
+                    };
+                    if escalate {
+                        error!("Worker {} escalating the panic; closing the pool.", id);
+                        receiver.close();
+                        panic::resume_unwind(payload);
+                    }
+                }
+            }
+            if REPLACE_WORKER.with(|replace| replace.replace(false)) {
+                debug!("Worker {} is being replaced after a timed-out job.", id);
+                return true;
+            }
+        }
+    }
+}
+
+// log に出す時の job の呼び方。
+struct JobLabel<'a>(Option<&'a str>);
+
+impl<'a> fmt::Display for JobLabel<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(name) => write!(f, "job '{}'", name),
+            None => f.write_str("a job"),
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+struct TaskDone<'a>(&'a queue::Receiver<Message>);
+
+impl<'a> Drop for TaskDone<'a> {
+    fn drop(&mut self) {
+        self.0.task_done();
+    }
+}
+
+// thread の終了時 (panic による終了も含む) に on_thread_stop を呼ぶ。
+struct StopHook<'a>(usize, &'a ThreadHook);
 
-        Worker {
-            id,
-            thread: Some(thread),
+impl<'a> Drop for StopHook<'a> {
+    fn drop(&mut self) {
+        (self.1)(self.0);
+    }
+}
+
+struct TerminateHook<'a>(usize, &'a dyn WorkerListener);
+
+impl<'a> Drop for TerminateHook<'a> {
+    fn drop(&mut self) {
+        self.1.on_terminate(self.0, SystemTime::now());
+    }
+}
+
+// 動いている worker の数を数える guard。 thread の終了時に減らす。
+struct LiveCount(Arc<AtomicUsize>);
+
+impl LiveCount {
+    // worker の数が `min` を上回っている場合に限り、自分の分を減らす。
+    // 複数の worker が同時に暇になっても `min` を下回らないよう CAS で行う。
+    fn release_above(&self, min: usize) -> bool {
+        let mut current = self.0.load(Ordering::SeqCst);
+        while current > min {
+            match self
+                .0
+                .compare_exchange(current, current - 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+        false
+    }
+}
+
+impl Drop for LiveCount {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// worker thread の終了を pool に知らせる。 job が panic した場合も
+// unwind の途中で drop されるので通知される。
+struct ExitNotice {
+    id: usize,
+    // None なら通知しない。
+    sender: Option<mpsc::Sender<usize>>,
+}
+
+impl Drop for ExitNotice {
+    fn drop(&mut self) {
+        if let Some(ref sender) = self.sender {
+            let _ = sender.send(self.id);
+        }
+    }
+}
+
+// worker が実行する job。
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// 普通のクロージャの job を実行する。
+fn call<F: FnOnce()>(f: F) {
+    f()
+}
+
+// 結果を JobHandle に送り返す job。 send に失敗した時にクロージャを
+// 取り出せるよう、クロージャで包まずに struct として持つ。
+struct ResultJob<F, T> {
+    f: F,
+    sender: mpsc::Sender<Result<T, JobError>>,
+}
+
+impl<F, T> ResultJob<F, T>
+where
+    F: FnOnce() -> T,
+{
+    fn run(self) {
+        // JobHandle が既に捨てられていても気にしない。
+        match panic::catch_unwind(AssertUnwindSafe(self.f)) {
+            Ok(value) => {
+                let _ = self.sender.send(Ok(value));
+            }
+            Err(payload) => {
+                let msg = panic_message(&*payload);
+                let _ = self.sender.send(Err(JobError::Panicked(msg)));
+                // worker 側でも panic として扱えるように投げ直す。
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+struct CancellableJob<F> {
+    f: F,
+    token: CancellationToken,
+}
+
+impl<F: FnOnce()> CancellableJob<F> {
+    fn run(self) {
+        if self.token.is_cancelled() {
+            trace!("Skipping a cancelled job.");
+            return;
         }
+        (self.f)();
     }
 }
 
-// FnBox を使わずに Job を Box<FnOnce() + Send + 'static>
-// として定義すると、 Box 内のクロージャをコールする際に
-// コンパイルエラーになってしまう (`(*job)()`とは書けない)。
-// これは、各クロージャは FnOnce trait を実装するそれぞれ別の型であり、
-// Box 内のクロージャを move しようとしてもコンパイル時にそいつのサイズが
-// 静的には決まらないため (たぶん)。
-// そこで FnOnce trait を実装する全ての型に FnBox という trait を
-// 実装し、`Box`内にいる場合のみ呼び出せる`call_box`を定義する。
-// Generics により、`call_box`は実際にそれを使用するクロージャごとに実装されるため、
-// コンパイル時に静的にサイズが決まる、という感じか (たぶん)。
-// 面倒だし、将来的にはこういう tricky な処理は不要にしたい、との事。
-type Job = Box<FnBox + Send + 'static>;
+// 時間制限付きの job。 timer thread に時間切れの確認を予約してから実行する。
+struct TimedJob<F> {
+    f: F,
+    timeout: Duration,
+    timers: Timers,
+    on_timeout: Option<ThreadHook>,
+    replace: bool,
+}
 
-trait FnBox {
-    fn call_box(self: Box<Self>);
+impl<F: FnOnce()> TimedJob<F> {
+    fn run(self) {
+        let id = match WORKER_ID.with(|id| id.get()) {
+            Some(id) => id,
+            None => return (self.f)(),
+        };
+        let watch = Arc::new(Watch {
+            done: AtomicBool::new(false),
+            timed_out: AtomicBool::new(false),
+        });
+        {
+            let watch = Arc::clone(&watch);
+            let timeout = self.timeout;
+            let on_timeout = self.on_timeout;
+            let check = move || {
+                if watch.done.load(Ordering::SeqCst) {
+                    return;
+                }
+                watch.timed_out.store(true, Ordering::SeqCst);
+                warn!("Worker {} job exceeded its timeout of {:?}.", id, timeout);
+                if let Some(on_timeout) = on_timeout {
+                    on_timeout(id);
+                }
+            };
+            let deadline = Instant::now() + self.timeout;
+            self.timers
+                .schedule(deadline, Task::Inline(Box::new(check)));
+        }
+        let _finished = Finished {
+            watch,
+            replace: self.replace,
+        };
+        (self.f)();
+    }
 }
 
-impl<F: FnOnce()> FnBox for F {
-    fn call_box(self: Box<F>) {
-        (*self)();
+struct Watch {
+    done: AtomicBool,
+    timed_out: AtomicBool,
+}
+
+// job の終了 (panic も含む) を watchdog に知らせ、時間切れだった場合は
+// worker に作り直しを求める。
+struct Finished {
+    watch: Arc<Watch>,
+    replace: bool,
+}
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.watch.done.store(true, Ordering::SeqCst);
+        if self.replace && self.watch.timed_out.load(Ordering::SeqCst) {
+            REPLACE_WORKER.with(|replace| replace.set(true));
+        }
     }
 }
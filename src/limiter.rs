@@ -0,0 +1,114 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A counting semaphore for capping concurrent access to a resource.
+///
+/// Unlike the pool size, which bounds how many jobs run at once, a limiter
+/// bounds how many of them are inside a particular section, such as a file
+/// conversion, at the same time. Each `acquire` returns a `Permit` that is
+/// given back when dropped. Clones share the same permits.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    available: Mutex<usize>,
+    released: Condvar,
+    permits: usize,
+}
+
+/// A permit from `Limiter::acquire`. It is returned when dropped.
+#[derive(Debug)]
+pub struct Permit {
+    shared: Arc<Shared>,
+}
+
+impl Limiter {
+    /// Create a limiter that lets `permits` holders in at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `permits` is zero.
+    pub fn new(permits: usize) -> Limiter {
+        assert!(permits > 0, "a limiter needs at least one permit");
+        Limiter {
+            shared: Arc::new(Shared {
+                available: Mutex::new(permits),
+                released: Condvar::new(),
+                permits,
+            }),
+        }
+    }
+
+    /// Take a permit, blocking until one is free.
+    pub fn acquire(&self) -> Permit {
+        let mut available = self.lock();
+        while *available == 0 {
+            available = self.shared.released.wait(available).unwrap();
+        }
+        self.take(available)
+    }
+
+    /// Take a permit if one is free right now.
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let available = self.lock();
+        if *available == 0 {
+            return None;
+        }
+        Some(self.take(available))
+    }
+
+    /// Take a permit, waiting at most `timeout` for one to become free.
+    pub fn acquire_timeout(&self, timeout: Duration) -> Option<Permit> {
+        let deadline = Instant::now() + timeout;
+        let mut available = self.lock();
+        while *available == 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            available = self
+                .shared
+                .released
+                .wait_timeout(available, deadline - now)
+                .unwrap()
+                .0;
+        }
+        Some(self.take(available))
+    }
+
+    /// Return the number of permits that are free right now.
+    pub fn available(&self) -> usize {
+        *self.lock()
+    }
+
+    /// Return the number of permits the limiter was created with.
+    pub fn permits(&self) -> usize {
+        self.shared.permits
+    }
+
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.shared.available.lock().unwrap()
+    }
+
+    fn take(&self, mut available: MutexGuard<'_, usize>) -> Permit {
+        *available -= 1;
+        Permit {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        // permit を持ったまま panic した場合も返す。
+        let mut available = match self.shared.available.lock() {
+            Ok(available) => available,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *available += 1;
+        self.shared.released.notify_one();
+    }
+}
@@ -0,0 +1,31 @@
+use std::time::SystemTime;
+
+/// Receives lifecycle events of a pool's workers, e.g. for health reporting.
+///
+/// Register one with `ThreadPoolBuilder::worker_listener`. Every method is
+/// called on the worker thread the event is about, with the worker id and
+/// the time of the event, and does nothing by default. Keep them short, as
+/// the worker cannot take jobs while they run.
+pub trait WorkerListener: Send + Sync {
+    /// A worker thread has started and is about to wait for jobs.
+    fn on_start(&self, worker_id: usize, time: SystemTime) {
+        let _ = (worker_id, time);
+    }
+
+    /// A worker has found the queue empty and is waiting for a job. This is
+    /// called once each time the worker runs out of work, not repeatedly
+    /// while it waits.
+    fn on_idle(&self, worker_id: usize, time: SystemTime) {
+        let _ = (worker_id, time);
+    }
+
+    /// A job panicked on the worker. `message` is the panic message.
+    fn on_panic(&self, worker_id: usize, time: SystemTime, message: &str) {
+        let _ = (worker_id, time, message);
+    }
+
+    /// A worker thread is exiting, including when a panic is escalated.
+    fn on_terminate(&self, worker_id: usize, time: SystemTime) {
+        let _ = (worker_id, time);
+    }
+}
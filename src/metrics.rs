@@ -0,0 +1,119 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// A snapshot of a pool's load, returned by `ThreadPool::metrics`.
+///
+/// The counts are read one after another while jobs keep running, so they
+/// may be slightly out of step with each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PoolMetrics {
+    /// Jobs waiting in the queue.
+    pub queued: usize,
+    /// Workers currently running a job.
+    pub busy: usize,
+    /// Workers waiting for a job.
+    pub idle: usize,
+    /// Jobs that have finished without panicking.
+    pub completed: usize,
+    /// Jobs that have panicked.
+    pub panics: usize,
+    /// How long jobs waited in the queue before a worker took them.
+    pub queue_wait: QueueWait,
+}
+
+/// What was left undone when a pool shut down, returned by
+/// `ThreadPool::shutdown` and `ThreadPool::shutdown_now`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShutdownReport {
+    /// Jobs waiting in the queue when the shutdown began.
+    pub queued: usize,
+    /// Jobs dropped from the queue without running.
+    pub discarded: usize,
+    /// Jobs, queued or running, that had not finished when the shutdown
+    /// returned. They are left to the detached workers.
+    pub abandoned: usize,
+    /// Workers that did not exit in time and were detached.
+    pub detached_workers: usize,
+    /// How long the shutdown took.
+    pub elapsed: Duration,
+}
+
+impl ShutdownReport {
+    /// Return `true` if every job was run to completion.
+    pub fn is_complete(&self) -> bool {
+        self.discarded == 0 && self.abandoned == 0
+    }
+}
+
+/// Queue wait times of the jobs taken by workers so far, as part of
+/// `PoolMetrics`. All durations are zero until the first job is taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueueWait {
+    /// Jobs measured.
+    pub count: u64,
+    /// The shortest wait.
+    pub min: Duration,
+    /// The average wait.
+    pub mean: Duration,
+    /// The longest wait.
+    pub max: Duration,
+}
+
+// 全ての worker から lock なしで記録できるよう、 nanosecond 単位の atomic で持つ。
+pub(crate) struct WaitRecorder {
+    count: AtomicU64,
+    total: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl WaitRecorder {
+    pub fn new() -> WaitRecorder {
+        WaitRecorder {
+            count: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, wait: Duration) {
+        let nanos = u64::try_from(wait.as_nanos()).unwrap_or(u64::MAX);
+        self.total.fetch_add(nanos, Ordering::Relaxed);
+        self.min.fetch_min(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // 記録中の値と同時に読むので、 count と他の値は少しずれうる。
+    pub fn snapshot(&self) -> QueueWait {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return QueueWait::default();
+        }
+        QueueWait {
+            count,
+            min: Duration::from_nanos(self.min.load(Ordering::Relaxed)),
+            mean: Duration::from_nanos(self.total.load(Ordering::Relaxed) / count),
+            max: Duration::from_nanos(self.max.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Statistics for one worker, returned by `ThreadPool::worker_stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// The worker id, as passed to the thread hooks.
+    pub id: usize,
+    /// Jobs this worker has run, including those that panicked.
+    pub jobs: usize,
+    /// Total time this worker has spent running jobs.
+    pub busy_time: Duration,
+    /// When this worker last started a job.
+    pub last_job: Option<SystemTime>,
+    /// The name of the job this worker is running, if it was submitted with
+    /// `ThreadPool::execute_named`.
+    pub current_job: Option<String>,
+    /// Jobs that have panicked on this worker.
+    pub panics: usize,
+}
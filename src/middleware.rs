@@ -0,0 +1,64 @@
+//! Wrapping handlers with shared behaviour.
+
+use std::fmt;
+
+use super::{Request, Response};
+
+/// Code that runs around a handler, such as logging, authentication or
+/// compression.
+///
+/// `handle` gets each request with the rest of the chain as `next`. It can
+/// answer the request itself, or pass it on with `Next::run` and change the
+/// response that comes back. Layer middlewares with
+/// `ServerBuilder::middleware` or `Router::middleware`; the first one added
+/// sees the request first and the response last.
+///
+/// Closures `Fn(Request, Next) -> Response` are middlewares too.
+pub trait Middleware: Send + Sync + 'static {
+    /// Handle `request`, calling `next` to pass it on.
+    fn handle(&self, request: Request, next: Next<'_>) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Request, Next<'_>) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of a middleware chain, ending in the handler.
+pub struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    endpoint: &'a dyn Fn(Request) -> Response,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        middlewares: &'a [Box<dyn Middleware>],
+        endpoint: &'a dyn Fn(Request) -> Response,
+    ) -> Next<'a> {
+        Next {
+            middlewares,
+            endpoint,
+        }
+    }
+
+    /// Pass `request` to the next middleware, or to the handler after the
+    /// last one, and return its response.
+    pub fn run(self, request: Request) -> Response {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => middleware.handle(request, Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Next<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Next")
+            .field("middlewares", &self.middlewares.len())
+            .finish()
+    }
+}
@@ -0,0 +1,381 @@
+//! Reading `multipart/form-data` bodies (RFC 7578), as sent by HTML forms
+//! with file inputs.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use super::http::{parse_header, percent_decode};
+use super::Headers;
+
+const CHUNK: usize = 8 * 1024;
+// 1 つの part の header の上限。
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+const MAX_HEADERS: usize = 32;
+
+/// A reader of the parts of a `multipart/form-data` body, returned by
+/// `Request::multipart`.
+///
+/// Parts are read one at a time with `next_part`, and their contents are
+/// streamed from the body, so a file can be copied to disk without holding
+/// it in memory.
+pub struct Multipart<R> {
+    reader: R,
+    // part の区切り。 "\r\n--" と boundary。
+    delimiter: Vec<u8>,
+    // reader から読んだが、まだ使っていない byte。
+    buf: Vec<u8>,
+    state: State,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // part の中身か、最初の区切りの前の preamble を読んでいる。
+    Data,
+    // buf が区切りから始まる。
+    Delimiter,
+    Done,
+}
+
+impl<R: Read> Multipart<R> {
+    /// Read the parts of a body from `reader`, separated by `boundary`
+    /// from the `Content-Type` header.
+    pub fn new(reader: R, boundary: &str) -> Multipart<R> {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        Multipart {
+            reader,
+            delimiter,
+            // 最初の区切りは body の先頭にあり、前に改行がない。
+            buf: b"\r\n".to_vec(),
+            state: State::Data,
+        }
+    }
+
+    /// Return the next part, or `None` after the last one. The rest of the
+    /// previous part is skipped.
+    ///
+    /// Returns an error with `io::ErrorKind::InvalidData` if the body is
+    /// malformed, and `io::ErrorKind::UnexpectedEof` if it ends before the
+    /// closing delimiter.
+    pub fn next_part(&mut self) -> io::Result<Option<Part<'_, R>>> {
+        let mut skip = [0; CHUNK];
+        while self.read_data(&mut skip)? > 0 {}
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        self.fill_to(self.delimiter.len() + 2)?;
+        self.buf.drain(..self.delimiter.len());
+        if self.buf.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        // 区切りの後には空白があってもよい (RFC 2046 5.1.1)。
+        loop {
+            self.fill_to(2)?;
+            match self.buf[0] {
+                b' ' | b'\t' => {
+                    self.buf.remove(0);
+                }
+                b'\r' if self.buf[1] == b'\n' => {
+                    self.buf.drain(..2);
+                    break;
+                }
+                _ => return Err(invalid("malformed multipart delimiter")),
+            }
+        }
+
+        let headers = self.read_headers()?;
+        let disposition = headers
+            .get("Content-Disposition")
+            .ok_or_else(|| invalid("multipart part without Content-Disposition"))?;
+        let (kind, params) = disposition.split_once(';').unwrap_or((disposition, ""));
+        if !kind.trim().eq_ignore_ascii_case("form-data") {
+            return Err(invalid("multipart part is not form-data"));
+        }
+        let params = parse_params(params);
+        let name = param(&params, "name")
+            .ok_or_else(|| invalid("multipart part without a name"))?
+            .to_owned();
+        // filename* が送られていればそちらを使う (RFC 6266 4.3)。
+        let filename = param(&params, "filename*")
+            .and_then(decode_ext_value)
+            .or_else(|| param(&params, "filename").map(str::to_owned));
+        let content_type = headers.get("Content-Type").map(str::to_owned);
+
+        self.state = State::Data;
+        Ok(Some(Part {
+            name,
+            filename,
+            content_type,
+            headers,
+            multipart: self,
+        }))
+    }
+
+    fn read_headers(&mut self) -> io::Result<Headers> {
+        let mut headers = Headers::new();
+        let mut budget = MAX_HEADER_BYTES;
+        loop {
+            let end = loop {
+                if let Some(end) = find(&self.buf, b"\r\n") {
+                    break end;
+                }
+                if self.buf.len() > budget {
+                    return Err(invalid("multipart headers are too large"));
+                }
+                self.fill()?;
+            };
+            if end + 2 > budget {
+                return Err(invalid("multipart headers are too large"));
+            }
+            budget -= end + 2;
+            let line: Vec<u8> = self.buf.drain(..end + 2).take(end).collect();
+            if line.is_empty() {
+                return Ok(headers);
+            }
+            if headers.len() == MAX_HEADERS {
+                return Err(invalid("too many multipart headers"));
+            }
+            let line =
+                String::from_utf8(line).map_err(|_| invalid("malformed multipart header"))?;
+            let (name, value) =
+                parse_header(&line).map_err(|_| invalid("malformed multipart header"))?;
+            headers.append(name, value);
+        }
+    }
+
+    // 今の part の中身を読む。区切りに着いたら 0 を返す。
+    fn read_data(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.state != State::Data || out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            // 区切りの途中かもしれない末尾は、続きを読むまで残す。
+            let available = match find(&self.buf, &self.delimiter) {
+                Some(0) => {
+                    self.state = State::Delimiter;
+                    return Ok(0);
+                }
+                Some(index) => index,
+                None => self.buf.len().saturating_sub(self.delimiter.len() - 1),
+            };
+            if available > 0 {
+                let n = available.min(out.len());
+                out[..n].copy_from_slice(&self.buf[..n]);
+                self.buf.drain(..n);
+                return Ok(n);
+            }
+            self.fill()?;
+        }
+    }
+
+    // reader から読み足す。終わっていたら UnexpectedEof。
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0; CHUNK];
+        let n = loop {
+            match self.reader.read(&mut chunk) {
+                Ok(n) => break n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        };
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "multipart body ended before the closing delimiter",
+            ));
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    fn fill_to(&mut self, len: usize) -> io::Result<()> {
+        while self.buf.len() < len {
+            self.fill()?;
+        }
+        Ok(())
+    }
+}
+
+impl<R> fmt::Debug for Multipart<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Multipart")
+            .field("boundary", &String::from_utf8_lossy(&self.delimiter[4..]))
+            .field("done", &(self.state == State::Done))
+            .finish()
+    }
+}
+
+/// One part of a `multipart/form-data` body, returned by
+/// `Multipart::next_part`: a form field or an uploaded file.
+///
+/// Its contents are read with `Read`, or copied out with `copy_to`, `save`
+/// or `read_limited`.
+pub struct Part<'a, R> {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    headers: Headers,
+    multipart: &'a mut Multipart<R>,
+}
+
+impl<'a, R: Read> Part<'a, R> {
+    /// Return the name of the form field.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the name of the uploaded file, if the part is one. It is
+    /// whatever the client sent, so do not use it as a path as it is.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// Return the `Content-Type` of the part. Fields usually have none,
+    /// which means `text/plain`.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// Return the headers of the part.
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Copy the rest of the contents to `sink`, returning the number of
+    /// bytes copied.
+    pub fn copy_to<W: Write + ?Sized>(&mut self, sink: &mut W) -> io::Result<u64> {
+        io::copy(self, sink)
+    }
+
+    /// Write the rest of the contents to a new file at `path`, e.g. in
+    /// `std::env::temp_dir()`, returning the number of bytes written.
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> io::Result<u64> {
+        let mut file = File::create(path)?;
+        let written = self.copy_to(&mut file)?;
+        file.flush()?;
+        Ok(written)
+    }
+
+    /// Read the rest of the contents into memory, unless they are longer
+    /// than `limit` bytes, in which case an error with
+    /// `io::ErrorKind::InvalidData` is returned.
+    pub fn read_limited(&mut self, limit: usize) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        let mut chunk = [0; CHUNK];
+        loop {
+            let n = self.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(contents);
+            }
+            if contents.len() + n > limit {
+                return Err(invalid("multipart part is too large"));
+            }
+            contents.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Read the rest of the contents as text, like `read_limited`. Returns
+    /// an error with `io::ErrorKind::InvalidData` if they are not UTF-8.
+    pub fn text(&mut self, limit: usize) -> io::Result<String> {
+        String::from_utf8(self.read_limited(limit)?)
+            .map_err(|_| invalid("multipart part is not UTF-8"))
+    }
+}
+
+impl<'a, R: Read> Read for Part<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.multipart.read_data(buf)
+    }
+}
+
+impl<'a, R> fmt::Debug for Part<'a, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Part")
+            .field("name", &self.name)
+            .field("filename", &self.filename)
+            .field("content_type", &self.content_type)
+            .finish()
+    }
+}
+
+/// Return the boundary of a `multipart/form-data` `Content-Type`, or `None`
+/// for other types.
+pub(crate) fn boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    let params = parse_params(params);
+    let boundary = param(&params, "boundary")?;
+    // boundary は 1 から 70 文字 (RFC 2046 5.1.1)。
+    (1..=70)
+        .contains(&boundary.len())
+        .then(|| boundary.to_owned())
+}
+
+// `; name=value; name="quoted"` の形の引数。名前は小文字にする。
+fn parse_params(params: &str) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    let mut rest = params;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ';' || c.is_ascii_whitespace());
+        let Some(eq) = rest.find('=') else {
+            return parsed;
+        };
+        let name = rest[..eq].trim().to_ascii_lowercase();
+        rest = rest[eq + 1..].trim_start();
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                    c => value.push(c),
+                }
+            }
+            rest = &quoted[end..];
+            value
+        } else {
+            let end = rest.find(';').unwrap_or(rest.len());
+            let value = rest[..end].trim().to_owned();
+            rest = &rest[end..];
+            value
+        };
+        parsed.push((name, value));
+    }
+}
+
+fn param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|param| param.0 == name)
+        .map(|param| &*param.1)
+}
+
+// RFC 8187 の `UTF-8''%E2%82%AC.txt` の形。
+fn decode_ext_value(value: &str) -> Option<String> {
+    let (charset, rest) = value.split_once('\'')?;
+    let (_language, encoded) = rest.split_once('\'')?;
+    charset
+        .eq_ignore_ascii_case("UTF-8")
+        .then(|| percent_decode(encoded))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
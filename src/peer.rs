@@ -0,0 +1,84 @@
+//! The identity a TLS client proves with its certificate.
+
+use std::net::IpAddr;
+
+#[cfg(feature = "tls")]
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// The subject and the subject alternative names of the certificate a
+/// client sent over TLS, verified against the CAs given to
+/// `TlsConfig::client_ca`. Returned by `Request::peer_identity`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    subject: String,
+    common_name: Option<String>,
+    alt_names: Vec<AltName>,
+}
+
+/// A subject alternative name of a client certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AltName {
+    /// A DNS name, such as `client.example.com`.
+    Dns(String),
+    /// An email address.
+    Email(String),
+    /// A URI, such as a SPIFFE id.
+    Uri(String),
+    /// An IP address.
+    Ip(IpAddr),
+}
+
+impl PeerIdentity {
+    // 検証済みの証明書 (DER) から読む。読めなければ None。
+    #[cfg(feature = "tls")]
+    pub(crate) fn from_der(der: &[u8]) -> Option<PeerIdentity> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let mut alt_names = Vec::new();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                alt_names.push(match *name {
+                    GeneralName::DNSName(name) => AltName::Dns(name.to_string()),
+                    GeneralName::RFC822Name(email) => AltName::Email(email.to_string()),
+                    GeneralName::URI(uri) => AltName::Uri(uri.to_string()),
+                    GeneralName::IPAddress(ip) => match *ip {
+                        [a, b, c, d] => AltName::Ip(IpAddr::from([a, b, c, d])),
+                        _ => match <[u8; 16]>::try_from(ip) {
+                            Ok(ip) => AltName::Ip(IpAddr::from(ip)),
+                            Err(_) => continue,
+                        },
+                    },
+                    // 他の形の名前は使われることが少ないので見ない。
+                    _ => continue,
+                });
+            }
+        }
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_owned);
+        Some(PeerIdentity {
+            subject: cert.subject().to_string(),
+            common_name,
+            alt_names,
+        })
+    }
+
+    /// Return the distinguished name of the subject, such as
+    /// `CN=alice, O=Example`.
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Return the common name of the subject, if it has one.
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    /// Return the subject alternative names, in the order of the
+    /// certificate.
+    pub fn alt_names(&self) -> &[AltName] {
+        &self.alt_names
+    }
+}
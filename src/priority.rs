@@ -0,0 +1,35 @@
+//! Setting the scheduling priority of worker threads. Only Linux is
+//! supported, where each thread has its own nice value; elsewhere setting it
+//! fails with a warning.
+
+use std::io;
+
+// 今の thread の nice 値を変える。失敗しても worker は動かし続ける。
+pub(crate) fn set_current(nice: i32, id: usize) {
+    match set_nice(nice) {
+        Ok(()) => debug!("Set the nice value of worker {} to {}.", id, nice),
+        Err(err) => warn!(
+            "Failed to set the nice value of worker {} to {}: {}",
+            id, nice, err
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_nice(nice: i32) -> io::Result<()> {
+    // Linux では PRIO_PROCESS に thread id を渡すとその thread だけが変わる。
+    let tid = unsafe { ::libc::syscall(::libc::SYS_gettid) };
+    let result = unsafe { ::libc::setpriority(::libc::PRIO_PROCESS, tid as ::libc::id_t, nice) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_nice(_nice: i32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "thread priorities are only supported on Linux",
+    ))
+}
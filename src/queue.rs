@@ -0,0 +1,906 @@
+//! A multi-producer, multi-consumer job queue with an optional capacity.
+//!
+//! `std::sync::mpsc` only allows a single receiver, so the pool used to share
+//! it behind a `Mutex`. This queue is built on a `Mutex` and `Condvar` pair
+//! instead, which also lets us bound it and inspect its state.
+//!
+//! Items carry a priority. Higher priorities are received first, and items
+//! of equal priority are received in the order they were sent.
+//!
+//! Items can also be split into classes, each with a weight. Classes are
+//! served in weighted round robin, so a class with weight 4 gets four items
+//! taken for every one of a class with weight 1 while both have items
+//! queued. Priorities only order items within a class.
+//!
+//! To keep receivers from serializing on the shared lock, each receiver has
+//! its own local deque. When it runs dry, a receiver takes a batch of items
+//! from the shared queue at once, and a receiver that finds the shared
+//! queue empty steals half of another receiver's batch. Most items are then
+//! received without touching the shared lock. The price is that priorities
+//! are only honoured per batch: an item sent while a receiver still holds a
+//! batch waits until that batch is done, even if its priority is higher.
+//!
+//! With the `crossbeam` feature, the local deques are crossbeam-channel
+//! channels instead of `Mutex`-guarded `VecDeque`s, so a receiver and the
+//! ones stealing from it never wait on each other for a lock. The shared
+//! queue stays as it is, as a channel cannot keep its priorities and
+//! classes, nor the eviction used by `RejectionPolicy::DiscardOldest`.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::mpsc::{RecvTimeoutError, SendError, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::vec;
+
+// 一度に共有 queue から取ってくる item の上限。
+const MAX_BATCH: usize = 16;
+
+struct Entry<T> {
+    priority: i32,
+    seq: u64,
+    class: usize,
+    item: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Entry<T>) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Entry<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    // BinaryHeap は最大のものから取り出すので、 priority が高く
+    // seq が小さい (先に入った) ものほど大きいとみなす。
+    fn cmp(&self, other: &Entry<T>) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+// 共有 queue。 class ごとに heap を持ち、 weight に応じて順番に取り出す。
+struct Injector<T> {
+    classes: Vec<Class<T>>,
+    next_seq: u64,
+    len: usize,
+}
+
+struct Class<T> {
+    heap: BinaryHeap<Entry<T>>,
+    weight: i64,
+    // smooth weighted round robin の現在値。
+    current: i64,
+}
+
+impl<T> Injector<T> {
+    fn new(weights: &[u32]) -> Injector<T> {
+        let classes = weights
+            .iter()
+            .map(|&weight| Class {
+                heap: BinaryHeap::new(),
+                weight: i64::from(weight),
+                current: 0,
+            })
+            .collect();
+        Injector {
+            classes,
+            next_seq: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, item: T, priority: i32, class: usize) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.restore(Entry {
+            priority,
+            seq,
+            class,
+            item,
+        });
+    }
+
+    // 一度取り出した entry を、元の順番のまま戻す。
+    fn restore(&mut self, entry: Entry<T>) {
+        self.classes[entry.class].heap.push(entry);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Entry<T>> {
+        // item のある class の current に weight を足し、一番大きい class から
+        // 取ってその class の current から合計を引く。 weight の比で順番に選ばれる。
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for index in 0..self.classes.len() {
+            let class = &mut self.classes[index];
+            if class.heap.is_empty() {
+                continue;
+            }
+            class.current += class.weight;
+            total += class.weight;
+            let current = class.current;
+            if best.is_none_or(|best| current > self.classes[best].current) {
+                best = Some(index);
+            }
+        }
+        let class = &mut self.classes[best?];
+        class.current -= total;
+        self.len -= 1;
+        class.heap.pop()
+    }
+
+    // priority や class に関係なく、 `seq` の item を取り除く。
+    fn remove(&mut self, seq: u64) -> Option<T> {
+        for class in &mut self.classes {
+            let mut entries = mem::take(&mut class.heap).into_vec();
+            let index = entries.iter().position(|entry| entry.seq == seq);
+            let entry = index.map(|index| entries.swap_remove(index));
+            class.heap = BinaryHeap::from(entries);
+            if let Some(entry) = entry {
+                self.len -= 1;
+                return Some(entry.item);
+            }
+        }
+        None
+    }
+
+    fn oldest(&self) -> Option<u64> {
+        self.classes
+            .iter()
+            .flat_map(|class| class.heap.iter())
+            .map(|entry| entry.seq)
+            .min()
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn drain(&mut self) -> Vec<T> {
+        self.len = 0;
+        self.classes
+            .iter_mut()
+            .flat_map(|class| class.heap.drain())
+            .map(|entry| entry.item)
+            .collect()
+    }
+}
+
+type Local<T> = Arc<Deque<T>>;
+
+// receiver ごとの deque。 priority の高い順に並んでいて、持ち主は前から、
+// 盗む側は後ろから取る。 item を足すのは共有 lock を持っている間だけ。
+#[cfg(not(feature = "crossbeam"))]
+struct Deque<T>(Mutex<VecDeque<Entry<T>>>);
+
+#[cfg(not(feature = "crossbeam"))]
+impl<T> Deque<T> {
+    fn new() -> Deque<T> {
+        Deque(Mutex::new(VecDeque::new()))
+    }
+
+    fn pop(&self) -> Option<Entry<T>> {
+        lock(&self.0).pop_front()
+    }
+
+    fn extend(&self, batch: VecDeque<Entry<T>>) {
+        lock(&self.0).extend(batch);
+    }
+
+    // 後ろ半分を盗む。
+    fn steal(&self) -> VecDeque<Entry<T>> {
+        let mut deque = lock(&self.0);
+        let at = deque.len() / 2;
+        deque.split_off(at)
+    }
+
+    fn oldest(&self) -> Option<u64> {
+        lock(&self.0).iter().map(|entry| entry.seq).min()
+    }
+
+    fn remove(&self, seq: u64) -> Option<T> {
+        let mut deque = lock(&self.0);
+        let position = deque.iter().position(|entry| entry.seq == seq)?;
+        deque.remove(position).map(|entry| entry.item)
+    }
+
+    fn drain(&self) -> Vec<Entry<T>> {
+        lock(&self.0).drain(..).collect()
+    }
+}
+
+// crossbeam の channel は複数の receiver から取り出せるので、盗む側も
+// 持ち主と同じく前から取る。
+#[cfg(feature = "crossbeam")]
+struct Deque<T> {
+    sender: crossbeam_channel::Sender<Entry<T>>,
+    receiver: crossbeam_channel::Receiver<Entry<T>>,
+}
+
+#[cfg(feature = "crossbeam")]
+impl<T> Deque<T> {
+    fn new() -> Deque<T> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        Deque { sender, receiver }
+    }
+
+    fn pop(&self) -> Option<Entry<T>> {
+        self.receiver.try_recv().ok()
+    }
+
+    fn extend(&self, batch: VecDeque<Entry<T>>) {
+        for entry in batch {
+            // receiver も持っているので、送れないことはない。
+            let _ = self.sender.send(entry);
+        }
+    }
+
+    // 半分を盗む。
+    fn steal(&self) -> VecDeque<Entry<T>> {
+        let count = self.receiver.len() - self.receiver.len() / 2;
+        self.receiver.try_iter().take(count).collect()
+    }
+
+    // channel の中は見られないので、取り出してから同じ順に戻す。持ち主が
+    // その間に空だと見ても、共有 lock を取ってから取り直す。
+    fn oldest(&self) -> Option<u64> {
+        let entries = self.drain();
+        let oldest = entries.iter().map(|entry| entry.seq).min();
+        self.extend(entries.into());
+        oldest
+    }
+
+    fn remove(&self, seq: u64) -> Option<T> {
+        let mut entries = self.drain();
+        let position = entries.iter().position(|entry| entry.seq == seq);
+        let removed = position.map(|position| entries.remove(position).item);
+        self.extend(entries.into());
+        removed
+    }
+
+    fn drain(&self) -> Vec<Entry<T>> {
+        self.receiver.try_iter().collect()
+    }
+}
+
+// worker が panic で死んだ場合も queue は使い続けるので、 poison は無視する。
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// 共有 lock で守られる状態。 local の deque に item が増えるのは
+// この lock を持っている間だけなので、 lock を持っていれば
+// 「どこにも item が無い」ことを確かめられる。
+struct State<T> {
+    injector: Injector<T>,
+    locals: Vec<Local<T>>,
+    capacity: Option<usize>,
+    senders: usize,
+    receivers: usize,
+    // 新しい item を待って寝ている receiver の数。
+    sleepers: usize,
+}
+
+impl<T> State<T> {
+    // local の deque も含め、一番古い item を取り除く。
+    fn remove_oldest(&mut self) -> Option<T> {
+        let mut oldest = self.injector.oldest().map(|seq| (seq, None));
+        for (index, local) in self.locals.iter().enumerate() {
+            if let Some(seq) = local.oldest() {
+                if oldest.is_none_or(|(oldest, _)| seq < oldest) {
+                    oldest = Some((seq, Some(index)));
+                }
+            }
+        }
+        match oldest? {
+            (seq, None) => self.injector.remove(seq),
+            (seq, Some(index)) => self.locals[index].remove(seq),
+        }
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    // item の class を返す。
+    classify: fn(&T) -> usize,
+    // local の deque の分も含めた、 queue 内の item の数。
+    queued: AtomicUsize,
+    // recv されたが、まだ task_done されていない item の数。
+    active: AtomicUsize,
+    closed: AtomicBool,
+    // true の間、 receiver は item を取り出さない。閉じられると無視する。
+    paused: AtomicBool,
+    // 終了を求められている receiver の数。
+    retiring: AtomicUsize,
+    // not_full と idle を待っている thread の数。共有 lock を取らずに
+    // 数を変えた側が、起こす必要があるかを判断するのに使う。
+    send_waiters: AtomicUsize,
+    idle_waiters: AtomicUsize,
+    not_empty: Condvar,
+    not_full: Condvar,
+    idle: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn is_closed(&self) -> bool {
+        self.closed.load(AtomicOrdering::SeqCst)
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(AtomicOrdering::SeqCst) && !self.is_closed()
+    }
+
+    fn is_disconnected(&self, state: &State<T>) -> bool {
+        self.is_closed() || state.receivers == 0
+    }
+
+    fn is_full(&self, state: &State<T>) -> bool {
+        match state.capacity {
+            Some(cap) => self.queued.load(AtomicOrdering::SeqCst) >= cap,
+            None => false,
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.queued.load(AtomicOrdering::SeqCst) == 0
+            && self.active.load(AtomicOrdering::SeqCst) == 0
+    }
+
+    fn close(&self) {
+        let _state = lock(&self.state);
+        self.closed.store(true, AtomicOrdering::SeqCst);
+        // 寝ている receiver を起こし、 pause 中でも残りを片付けさせる。
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    fn try_retire(&self) -> bool {
+        let mut current = self.retiring.load(AtomicOrdering::SeqCst);
+        while current > 0 {
+            match self.retiring.compare_exchange(
+                current,
+                current - 1,
+                AtomicOrdering::SeqCst,
+                AtomicOrdering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+        false
+    }
+
+    // item が queue から取り出されたことを記録する。
+    fn taken(&self, holding_lock: bool) {
+        // 途中で queued と active が両方 0 に見えないよう、 active を先に増やす。
+        self.active.fetch_add(1, AtomicOrdering::SeqCst);
+        self.queued.fetch_sub(1, AtomicOrdering::SeqCst);
+        if self.send_waiters.load(AtomicOrdering::SeqCst) > 0 {
+            // 待つ側が確かめてから寝るまでの間に起こしてしまわないよう、
+            // lock を取ってから起こす。
+            let _state = if holding_lock {
+                None
+            } else {
+                Some(lock(&self.state))
+            };
+            self.not_full.notify_one();
+        }
+    }
+}
+
+/// Create a queue. Sending blocks while the queue holds `capacity` items.
+/// `None` means the queue is unbounded.
+///
+/// There is one class per entry of `weights`, and `classify` returns the
+/// index of an item's class.
+pub fn channel<T>(
+    capacity: Option<usize>,
+    weights: &[u32],
+    classify: fn(&T) -> usize,
+) -> (Sender<T>, Receiver<T>) {
+    let local: Local<T> = Arc::new(Deque::new());
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            injector: Injector::new(weights),
+            locals: vec![Arc::clone(&local)],
+            capacity,
+            senders: 1,
+            receivers: 1,
+            sleepers: 0,
+        }),
+        classify,
+        queued: AtomicUsize::new(0),
+        active: AtomicUsize::new(0),
+        closed: AtomicBool::new(false),
+        paused: AtomicBool::new(false),
+        retiring: AtomicUsize::new(0),
+        send_waiters: AtomicUsize::new(0),
+        idle_waiters: AtomicUsize::new(0),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        idle: Condvar::new(),
+    });
+    let sender = Sender {
+        shared: Arc::clone(&shared),
+    };
+    (sender, Receiver { shared, local })
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+// 送る側は item を `into` で queue の値に変える。受け付けられると決まってから
+// 変えるので、失敗した時には元の item をそのまま返せる。
+impl<T> Sender<T> {
+    /// Push an item, blocking while the queue is full.
+    ///
+    /// Fails when the queue is closed or every receiver has been dropped.
+    pub fn send<U, I>(&self, item: U, priority: i32, into: I) -> Result<(), SendError<U>>
+    where
+        I: FnOnce(U) -> T,
+    {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if shared.is_disconnected(&state) {
+                return Err(SendError(item));
+            }
+            if !shared.is_full(&state) {
+                break;
+            }
+            // 数を増やしてからもう一度確かめ、それでも一杯なら寝る。
+            shared.send_waiters.fetch_add(1, AtomicOrdering::SeqCst);
+            if shared.is_full(&state) {
+                state = shared.not_full.wait(state).unwrap();
+            }
+            shared.send_waiters.fetch_sub(1, AtomicOrdering::SeqCst);
+        }
+        self.push(&mut state, into(item), priority);
+        Ok(())
+    }
+
+    /// Push an item without blocking. If the queue is full the oldest item is
+    /// evicted to make room and handed back to the caller.
+    pub fn send_evicting<U, I>(
+        &self,
+        item: U,
+        priority: i32,
+        into: I,
+    ) -> Result<Option<T>, SendError<U>>
+    where
+        I: FnOnce(U) -> T,
+    {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if shared.is_disconnected(&state) {
+            return Err(SendError(item));
+        }
+        let evicted = if shared.is_full(&state) {
+            state.remove_oldest()
+        } else {
+            None
+        };
+        if evicted.is_some() {
+            shared.queued.fetch_sub(1, AtomicOrdering::SeqCst);
+        }
+        self.push(&mut state, into(item), priority);
+        Ok(evicted)
+    }
+
+    /// Push an item without blocking, failing if the queue is full.
+    pub fn try_send<U, I>(&self, item: U, priority: i32, into: I) -> Result<(), TrySendError<U>>
+    where
+        I: FnOnce(U) -> T,
+    {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if shared.is_disconnected(&state) {
+            return Err(TrySendError::Disconnected(item));
+        }
+        if shared.is_full(&state) {
+            return Err(TrySendError::Full(item));
+        }
+        self.push(&mut state, into(item), priority);
+        Ok(())
+    }
+
+    /// Push every item, blocking while the queue is full.
+    ///
+    /// Items are pushed in bulk as room frees up, so the lock is taken once
+    /// per wake-up rather than once per item. On failure the items that were
+    /// not pushed are handed back.
+    pub fn send_all<U, I>(
+        &self,
+        items: Vec<U>,
+        priority: i32,
+        mut into: I,
+    ) -> Result<(), SendError<Vec<U>>>
+    where
+        I: FnMut(U) -> T,
+    {
+        let shared = &*self.shared;
+        let mut items = items.into_iter();
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if shared.is_disconnected(&state) {
+                return Err(SendError(items.collect()));
+            }
+            self.push_all(&mut state, &mut items, priority, &mut into);
+            if items.len() == 0 {
+                return Ok(());
+            }
+            shared.send_waiters.fetch_add(1, AtomicOrdering::SeqCst);
+            if shared.is_full(&state) {
+                state = shared.not_full.wait(state).unwrap();
+            }
+            shared.send_waiters.fetch_sub(1, AtomicOrdering::SeqCst);
+        }
+    }
+
+    /// Push as many of `items` as fit without blocking, taking the lock once.
+    ///
+    /// Returns the items that did not fit, in their original order.
+    pub fn try_send_all<U, I>(
+        &self,
+        items: Vec<U>,
+        priority: i32,
+        mut into: I,
+    ) -> Result<Vec<U>, SendError<Vec<U>>>
+    where
+        I: FnMut(U) -> T,
+    {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        if shared.is_disconnected(&state) {
+            return Err(SendError(items));
+        }
+        let mut items = items.into_iter();
+        self.push_all(&mut state, &mut items, priority, &mut into);
+        Ok(items.collect())
+    }
+
+    // 空いている分だけ items から push する。
+    fn push_all<U, I>(
+        &self,
+        state: &mut State<T>,
+        items: &mut vec::IntoIter<U>,
+        priority: i32,
+        into: &mut I,
+    ) where
+        I: FnMut(U) -> T,
+    {
+        let shared = &*self.shared;
+        let room = match state.capacity {
+            Some(cap) => cap.saturating_sub(shared.queued.load(AtomicOrdering::SeqCst)),
+            None => items.len(),
+        };
+        let mut pushed = 0;
+        for item in items.take(room) {
+            let item = into(item);
+            let class = (shared.classify)(&item);
+            state.injector.push(item, priority, class);
+            pushed += 1;
+        }
+        shared.queued.fetch_add(pushed, AtomicOrdering::SeqCst);
+        for _ in 0..pushed.min(state.sleepers) {
+            shared.not_empty.notify_one();
+        }
+    }
+
+    fn push(&self, state: &mut State<T>, item: T, priority: i32) {
+        let class = (self.shared.classify)(&item);
+        state.injector.push(item, priority, class);
+        self.shared.queued.fetch_add(1, AtomicOrdering::SeqCst);
+        if state.sleepers > 0 {
+            self.shared.not_empty.notify_one();
+        }
+    }
+
+    /// Close the queue. Further sends fail, and receivers return `None` once
+    /// the items already queued have been taken.
+    pub fn close(&self) {
+        self.shared.close();
+    }
+
+    /// Return `true` if the queue has been closed or every receiver has been
+    /// dropped.
+    pub fn is_closed(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        self.shared.is_disconnected(&state)
+    }
+
+    /// Return the number of queued items.
+    pub fn len(&self) -> usize {
+        self.shared.queued.load(AtomicOrdering::SeqCst)
+    }
+
+    /// Return the number of received items not yet marked done.
+    pub fn active(&self) -> usize {
+        self.shared.active.load(AtomicOrdering::SeqCst)
+    }
+
+    /// Stop receivers from taking items until `resume` is called. Items can
+    /// still be sent. Closing the queue lifts the pause so that receivers
+    /// can drain it.
+    pub fn pause(&self) {
+        let _state = self.shared.state.lock().unwrap();
+        self.shared.paused.store(true, AtomicOrdering::SeqCst);
+    }
+
+    /// Let receivers take items again after `pause`.
+    pub fn resume(&self) {
+        let _state = self.shared.state.lock().unwrap();
+        self.shared.paused.store(false, AtomicOrdering::SeqCst);
+        self.shared.not_empty.notify_all();
+    }
+
+    /// Return `true` if the queue is paused.
+    pub fn is_paused(&self) -> bool {
+        self.shared.is_paused()
+    }
+
+    /// Ask `count` receivers to stop. The next `count` calls to `recv` return
+    /// `None` instead of waiting for an item, even if items are queued.
+    pub fn retire(&self, count: usize) {
+        let _state = self.shared.state.lock().unwrap();
+        self.shared
+            .retiring
+            .fetch_add(count, AtomicOrdering::SeqCst);
+        self.shared.not_empty.notify_all();
+    }
+
+    /// Remove every queued item and hand them back to the caller.
+    pub fn drain(&self) -> Vec<T> {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        let mut items = state.injector.drain();
+        for local in &state.locals {
+            items.extend(local.drain().into_iter().map(|entry| entry.item));
+        }
+        shared.queued.fetch_sub(items.len(), AtomicOrdering::SeqCst);
+        shared.not_full.notify_all();
+        if shared.is_idle() {
+            shared.idle.notify_all();
+        }
+        items
+    }
+
+    /// Block until the queue is empty and every received item has been
+    /// marked done with `Receiver::task_done`.
+    pub fn wait_idle(&self) {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        while !shared.is_idle() {
+            shared.idle_waiters.fetch_add(1, AtomicOrdering::SeqCst);
+            if !shared.is_idle() {
+                state = shared.idle.wait(state).unwrap();
+            }
+            shared.idle_waiters.fetch_sub(1, AtomicOrdering::SeqCst);
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.state.lock().unwrap().senders += 1;
+        Sender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = lock(&self.shared.state);
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    local: Local<T>,
+}
+
+impl<T> Receiver<T> {
+    /// Return the number of items in the queue, as `Sender::len` does.
+    pub fn len(&self) -> usize {
+        self.shared.queued.load(AtomicOrdering::SeqCst)
+    }
+
+    /// Pop an item, blocking while the queue is empty.
+    ///
+    /// Each received item must be followed by a call to `task_done` once it
+    /// has been processed. Returns `None` once the queue is empty and it has
+    /// been closed or every sender has been dropped, or when this receiver
+    /// is asked to retire.
+    pub fn recv(&self) -> Option<T> {
+        self.recv_until(None).ok()
+    }
+
+    /// Like `recv`, but gives up with `RecvTimeoutError::Timeout` if no item
+    /// arrives within `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let shared = &*self.shared;
+        if shared.try_retire() {
+            return Err(RecvTimeoutError::Disconnected);
+        }
+        if !shared.is_paused() {
+            if let Some(item) = self.pop_local() {
+                return Ok(item);
+            }
+        }
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            // retire 、 resume と新しい item は lock を持って知らされるので、
+            // lock を持ったまま確かめてから寝れば取りこぼさない。
+            if shared.try_retire() {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            if !shared.is_paused() {
+                // pause 中に自分の deque に残っていた分から取る。
+                if let Some(item) = self.pop_local() {
+                    return Ok(item);
+                }
+                if let Some(item) = self.refill(&mut state) {
+                    return Ok(item);
+                }
+                if shared.is_closed() || state.senders == 0 {
+                    return Err(RecvTimeoutError::Disconnected);
+                }
+            }
+            let timeout = match deadline {
+                None => None,
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    Some(deadline - now)
+                }
+            };
+            state.sleepers += 1;
+            state = match timeout {
+                None => shared.not_empty.wait(state).unwrap(),
+                Some(timeout) => shared.not_empty.wait_timeout(state, timeout).unwrap().0,
+            };
+            state.sleepers -= 1;
+        }
+    }
+
+    /// Pop an item if one is queued, without blocking.
+    ///
+    /// Like `recv`, a received item must be marked with `task_done`.
+    pub fn try_recv(&self) -> Option<T> {
+        if self.shared.try_retire() || self.shared.is_paused() {
+            return None;
+        }
+        if let Some(item) = self.pop_local() {
+            return Some(item);
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        // lock を待つ間に、自分の deque に戻された分があるかもしれない。
+        if let Some(item) = self.pop_local() {
+            return Some(item);
+        }
+        self.refill(&mut state)
+    }
+
+    // 自分の deque から取り出す。共有 lock は取らない。
+    fn pop_local(&self) -> Option<T> {
+        let entry = self.local.pop()?;
+        self.shared.taken(false);
+        Some(entry.item)
+    }
+
+    // 自分の deque が空の時に、共有 queue からまとめて取ってくるか、
+    // 他の receiver から盗んでくる。取ってきたうちの最初の item を返す。
+    fn refill(&self, state: &mut State<T>) -> Option<T> {
+        let mut batch = VecDeque::new();
+        if !state.injector.is_empty() {
+            // 他の receiver の分も残るよう、 receiver 数で割った分だけ取る。
+            let share = state.injector.len() / state.receivers.max(1);
+            while batch.len() < share.clamp(1, MAX_BATCH) {
+                match state.injector.pop() {
+                    Some(entry) => batch.push_back(entry),
+                    None => break,
+                }
+            }
+        } else {
+            for local in &state.locals {
+                if Arc::ptr_eq(local, &self.local) {
+                    continue;
+                }
+                batch = local.steal();
+                if !batch.is_empty() {
+                    break;
+                }
+            }
+        }
+
+        let first = batch.pop_front()?;
+        if !batch.is_empty() {
+            self.local.extend(batch);
+            // 残りを他の寝ている receiver が盗めるように起こす。
+            if state.sleepers > 0 {
+                self.shared.not_empty.notify_one();
+            }
+        }
+        self.shared.taken(true);
+        Some(first.item)
+    }
+
+    /// Close the queue from the receiving side. See `Sender::close`.
+    pub fn close(&self) {
+        self.shared.close();
+    }
+
+    /// Mark an item returned by `recv` as processed.
+    pub fn task_done(&self) {
+        let shared = &*self.shared;
+        shared.active.fetch_sub(1, AtomicOrdering::SeqCst);
+        if shared.idle_waiters.load(AtomicOrdering::SeqCst) > 0 && shared.is_idle() {
+            let _state = lock(&shared.state);
+            shared.idle.notify_all();
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Receiver<T> {
+        let local: Local<T> = Arc::new(Deque::new());
+        let mut state = self.shared.state.lock().unwrap();
+        state.receivers += 1;
+        state.locals.push(Arc::clone(&local));
+        Receiver {
+            shared: Arc::clone(&self.shared),
+            local,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = lock(&self.shared.state);
+        // 取ってきたまま残っている item は共有 queue に戻す。
+        let leftover = self.local.drain();
+        if !leftover.is_empty() && state.sleepers > 0 {
+            self.shared.not_empty.notify_all();
+        }
+        for entry in leftover {
+            state.injector.restore(entry);
+        }
+        state
+            .locals
+            .retain(|local| !Arc::ptr_eq(local, &self.local));
+        state.receivers -= 1;
+        if self.shared.is_disconnected(&state) {
+            self.shared.not_full.notify_all();
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Limiting how often each client may send requests.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::{Middleware, Next, Request, Response, Status};
+
+// これより多くの client を覚えたら、満タンになった bucket を捨てる。
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// A middleware that limits each client to a rate of requests with a token
+/// bucket.
+///
+/// Every client gets a bucket of `burst` tokens that refills at the given
+/// rate. Each request takes a token, and requests that find the bucket
+/// empty are answered with `429 Too Many Requests` and a `Retry-After` of
+/// the seconds until the next token. Clients are told apart by IP address,
+/// or by a header set with `key_header`.
+///
+/// The buckets are shared by every worker, and clones share them too, so
+/// one limit can cover several routers. Add it to a nested `Router` to
+/// limit only some routes. Clones also share the rate and burst, which
+/// `set_limit` and `set_burst` change while the server runs.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    key_header: Option<String>,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    // 1 秒あたりに戻る token の数。
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<String, Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimit {
+    /// Create a limit of `requests` per `period` for each client, which may
+    /// all come at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `requests` or `period` is zero.
+    pub fn new(requests: u32, period: Duration) -> RateLimit {
+        let limit = RateLimit {
+            key_header: None,
+            state: Arc::new(Mutex::new(State {
+                rate: 0.0,
+                burst: 0.0,
+                buckets: HashMap::new(),
+            })),
+        };
+        limit.set_limit(requests, period);
+        limit
+    }
+
+    /// Set how many requests a client may send at once after being idle.
+    /// Defaults to the `requests` of `new`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `burst` is zero.
+    pub fn burst(self, burst: u32) -> RateLimit {
+        self.set_burst(burst);
+        self
+    }
+
+    /// Tell clients apart by the first value of header `name`, such as an
+    /// API key or the `X-Forwarded-For` of a trusted proxy, instead of by
+    /// IP address. Requests without it fall back to their address.
+    pub fn key_header<S: Into<String>>(mut self, name: S) -> RateLimit {
+        self.key_header = Some(name.into());
+        self
+    }
+
+    /// Change the limit to `requests` per `period`, and the burst to
+    /// `requests`, as in `new`. Clients keep the tokens they have, up to
+    /// the new burst.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `requests` or `period` is zero.
+    pub fn set_limit(&self, requests: u32, period: Duration) {
+        assert!(
+            requests > 0 && !period.is_zero(),
+            "a rate limit needs a positive rate"
+        );
+        let mut state = self.lock();
+        state.rate = f64::from(requests) / period.as_secs_f64();
+        state.burst = f64::from(requests);
+    }
+
+    /// Change the burst, as in `burst`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `burst` is zero.
+    pub fn set_burst(&self, burst: u32) {
+        assert!(burst > 0, "a rate limit needs a burst of at least one");
+        self.lock().burst = f64::from(burst);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn key(&self, request: &Request) -> String {
+        let header = self
+            .key_header
+            .as_ref()
+            .and_then(|name| request.header(name))
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+            .filter(|key| !key.is_empty());
+        match header {
+            Some(key) => key.to_owned(),
+            None => request
+                .remote_addr()
+                .map_or_else(String::new, |addr| addr.ip().to_string()),
+        }
+    }
+
+    // token を取れたら None、取れなければ次の token までの時間を返す。
+    fn take(&self, key: String) -> Option<Duration> {
+        let now = Instant::now();
+        let mut state = self.lock();
+        let State {
+            rate,
+            burst,
+            ref mut buckets,
+        } = *state;
+        if buckets.len() >= PRUNE_THRESHOLD && !buckets.contains_key(&key) {
+            buckets.retain(|_, bucket| bucket.refilled(now, rate, burst) < burst);
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(now, rate, burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate).min(burst)
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, request: Request, next: Next<'_>) -> Response {
+        let key = self.key(&request);
+        match self.take(key) {
+            None => next.run(request),
+            Some(wait) => {
+                debug!("Rate limiting a request for {}", request.path());
+                // Retry-After は秒単位なので切り上げる。
+                let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                Response::new(Status::TooManyRequests)
+                    .header("Retry-After", seconds.max(1).to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Ids that tie together the log lines of a request.
+
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use super::{Middleware, Next, Request, Response};
+
+const HEADER: &str = "X-Request-Id";
+const MAX_INCOMING_LEN: usize = 128;
+
+thread_local! {
+    // この thread で処理中の request の id。
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// A middleware that gives every request a unique id.
+///
+/// The id is available from `Request::id` and sent back in an
+/// `X-Request-Id` response header. While the request is handled it is also
+/// the thread's current id, returned by `RequestId::current`: `JsonLogger`
+/// adds it to each record, and the access log to each line. Add it to the
+/// server's pool with `ThreadPoolBuilder::propagate_context`, passing
+/// `RequestId::current` and `RequestId::enter`, to carry it into jobs the
+/// handler submits and into the pool's logs for them.
+///
+/// Ids are 24 hex digits: a random prefix chosen when the process starts,
+/// so ids from different instances do not collide, and a counter.
+#[derive(Debug, Clone, Default)]
+pub struct RequestId {
+    trust_incoming: bool,
+}
+
+impl RequestId {
+    /// Create a middleware that generates a new id for every request.
+    pub fn new() -> RequestId {
+        RequestId::default()
+    }
+
+    /// Set whether an `X-Request-Id` sent by the client is used instead of
+    /// a new id, e.g. behind a proxy that assigns ids. Only ids of up to
+    /// 128 letters, digits, `-`, `_`, `.` and `:` are used. Defaults to
+    /// `false`.
+    pub fn trust_incoming(mut self, trust: bool) -> RequestId {
+        self.trust_incoming = trust;
+        self
+    }
+
+    /// Return the id of the request being handled on this thread, if any.
+    pub fn current() -> Option<String> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Make `id` the current id of this thread until the returned guard is
+    /// dropped, when the previous one is restored.
+    pub fn enter(id: Option<String>) -> RequestIdGuard {
+        let previous = CURRENT.with(|current| current.replace(id));
+        RequestIdGuard { previous }
+    }
+}
+
+impl Middleware for RequestId {
+    fn handle(&self, mut request: Request, next: Next<'_>) -> Response {
+        let id = match request.header(HEADER) {
+            Some(id) if self.trust_incoming && is_valid(id) => id.to_owned(),
+            _ => generate(),
+        };
+        request.set_id(id.clone());
+        let _current = RequestId::enter(Some(id.clone()));
+        #[cfg(feature = "tracing")]
+        let span = ::tracing::info_span!("request", id = %id);
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        let mut response = next.run(request);
+        response.headers_mut().insert(HEADER, id);
+        response
+    }
+}
+
+/// Restores the previous request id of the thread when dropped, returned
+/// by `RequestId::enter`.
+#[derive(Debug)]
+pub struct RequestIdGuard {
+    previous: Option<String>,
+}
+
+impl Drop for RequestIdGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+fn generate() -> String {
+    static PREFIX: OnceLock<u64> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    // RandomState は process ごとに違う key を持つので、乱数の代わりに使う。
+    let prefix = *PREFIX.get_or_init(|| RandomState::new().build_hasher().finish());
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}{:08x}", prefix, count as u32)
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_INCOMING_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
@@ -0,0 +1,591 @@
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::mem;
+
+#[cfg(feature = "json")]
+use serde::Serialize;
+
+use super::compression::Encoding;
+#[cfg(feature = "json")]
+use super::json;
+use super::server::Conn;
+use super::template;
+use super::{Context, Cookie, Headers, Version};
+
+/// The status code of a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Status {
+    Continue,
+    SwitchingProtocols,
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    RequestTimeout,
+    LengthRequired,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UriTooLong,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    ExpectationFailed,
+    UpgradeRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+    HttpVersionNotSupported,
+}
+
+impl Status {
+    /// Return the numeric status code.
+    pub fn code(&self) -> u16 {
+        match *self {
+            Status::Continue => 100,
+            Status::SwitchingProtocols => 101,
+            Status::Ok => 200,
+            Status::Created => 201,
+            Status::Accepted => 202,
+            Status::NoContent => 204,
+            Status::PartialContent => 206,
+            Status::MovedPermanently => 301,
+            Status::Found => 302,
+            Status::SeeOther => 303,
+            Status::NotModified => 304,
+            Status::TemporaryRedirect => 307,
+            Status::PermanentRedirect => 308,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::NotAcceptable => 406,
+            Status::RequestTimeout => 408,
+            Status::LengthRequired => 411,
+            Status::PreconditionFailed => 412,
+            Status::PayloadTooLarge => 413,
+            Status::UriTooLong => 414,
+            Status::UnsupportedMediaType => 415,
+            Status::RangeNotSatisfiable => 416,
+            Status::ExpectationFailed => 417,
+            Status::UpgradeRequired => 426,
+            Status::TooManyRequests => 429,
+            Status::RequestHeaderFieldsTooLarge => 431,
+            Status::InternalServerError => 500,
+            Status::NotImplemented => 501,
+            Status::ServiceUnavailable => 503,
+            Status::HttpVersionNotSupported => 505,
+        }
+    }
+
+    /// Return the reason phrase sent after the code.
+    pub fn reason(&self) -> &'static str {
+        match *self {
+            Status::Continue => "Continue",
+            Status::SwitchingProtocols => "Switching Protocols",
+            Status::Ok => "OK",
+            Status::Created => "Created",
+            Status::Accepted => "Accepted",
+            Status::NoContent => "No Content",
+            Status::PartialContent => "Partial Content",
+            Status::MovedPermanently => "Moved Permanently",
+            Status::Found => "Found",
+            Status::SeeOther => "See Other",
+            Status::NotModified => "Not Modified",
+            Status::TemporaryRedirect => "Temporary Redirect",
+            Status::PermanentRedirect => "Permanent Redirect",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::NotAcceptable => "Not Acceptable",
+            Status::RequestTimeout => "Request Timeout",
+            Status::LengthRequired => "Length Required",
+            Status::PreconditionFailed => "Precondition Failed",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::UriTooLong => "URI Too Long",
+            Status::UnsupportedMediaType => "Unsupported Media Type",
+            Status::RangeNotSatisfiable => "Range Not Satisfiable",
+            Status::ExpectationFailed => "Expectation Failed",
+            Status::UpgradeRequired => "Upgrade Required",
+            Status::TooManyRequests => "Too Many Requests",
+            Status::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::NotImplemented => "Not Implemented",
+            Status::ServiceUnavailable => "Service Unavailable",
+            Status::HttpVersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+
+    // 1xx, 204, 304 の response は body を持たない (RFC 7230 3.3.2)。
+    pub(crate) fn allows_body(&self) -> bool {
+        let code = self.code();
+        code >= 200 && code != 204 && code != 304
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+type Stream = Box<dyn FnOnce(&mut ResponseWriter<'_>) -> io::Result<()> + Send + 'static>;
+// 101 を送った後の接続を引き取るもの。
+pub(crate) type Upgrade = Box<dyn FnOnce(BufReader<Conn>) + Send + 'static>;
+
+// 大きすぎる chunk を作らないよう、これだけ溜まったら書き出す。
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// An HTTP response.
+///
+/// Built with `Response::new` and the chained setters, then written with
+/// `write_to`, which adds the status line, `Content-Length` and framing.
+/// A body whose length is not known upfront can be streamed with `stream`
+/// instead.
+pub struct Response {
+    status: Status,
+    headers: Headers,
+    body: Body,
+}
+
+enum Body {
+    Bytes(Vec<u8>),
+    Reader(Box<dyn Read + Send + 'static>, u64),
+    Stream(Stream),
+    Upgrade(Upgrade),
+    // HEAD への response。 GET なら送った body の長さだけを持つ。
+    Omitted(Option<u64>),
+}
+
+impl Response {
+    /// Create an empty response with `status`.
+    pub fn new(status: Status) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    /// Create a `302 Found` response sending the client to `location`.
+    pub fn redirect<L: Into<String>>(location: L) -> Response {
+        Response::redirect_with(Status::Found, location)
+    }
+
+    /// Create a `301 Moved Permanently` response sending the client to
+    /// `location`, which clients may remember for later requests.
+    pub fn permanent_redirect<L: Into<String>>(location: L) -> Response {
+        Response::redirect_with(Status::MovedPermanently, location)
+    }
+
+    /// Create a redirect to `location` with `status`. Use `SeeOther` to
+    /// answer a form `POST` with a page to `GET`, and `TemporaryRedirect`
+    /// or `PermanentRedirect` to have the client repeat the request with
+    /// the same method and body, which 301 and 302 do not guarantee.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `status` is not 301, 302, 303, 307 or 308.
+    pub fn redirect_with<L: Into<String>>(status: Status, location: L) -> Response {
+        assert!(
+            matches!(status.code(), 301..=303 | 307 | 308),
+            "not a redirect status: {}",
+            status
+        );
+        Response::new(status).header("Location", location)
+    }
+
+    /// Add a header, keeping any existing values of `name`.
+    ///
+    /// `Content-Length` and `Transfer-Encoding` are always derived from the
+    /// body and cannot be set here.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` or `value` contains a CR or LF, which would end the
+    /// header early and let the rest be read as another header or response.
+    pub fn header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Response {
+        let (name, value) = (name.into(), value.into());
+        assert!(
+            !name.contains(['\r', '\n']) && !value.contains(['\r', '\n']),
+            "CR or LF in header {:?}: {:?}",
+            name,
+            value
+        );
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Add a `Set-Cookie` header for `cookie`.
+    pub fn cookie(self, cookie: Cookie) -> Response {
+        self.header("Set-Cookie", cookie.to_string())
+    }
+
+    /// Set the body.
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Response {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Create a `200 OK` response with `value` serialized as JSON, or a
+    /// `500 Internal Server Error` one if it cannot be serialized, e.g.
+    /// because a map has keys that are not strings or numbers.
+    #[cfg(feature = "json")]
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> Response {
+        match json::to_string(value) {
+            Ok(body) => Response::new(Status::Ok)
+                .header("Content-Type", "application/json")
+                .body(body),
+            Err(err) => {
+                error!("Failed to serialize a JSON response: {}", err);
+                Response::new(Status::InternalServerError)
+            }
+        }
+    }
+
+    /// Create a `200 OK` HTML response from the installed template `name`
+    /// rendered with `context`, or a `500 Internal Server Error` one if
+    /// there is no such template. See `Templates::install`.
+    pub fn render(name: &str, context: &Context) -> Response {
+        match template::render_installed(name, context) {
+            Some(body) => Response::new(Status::Ok)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(body),
+            None => {
+                error!("No template named {}", name);
+                Response::new(Status::InternalServerError)
+            }
+        }
+    }
+
+    /// Send the body by copying `length` bytes from `reader`, e.g. a file,
+    /// without loading it into memory first.
+    ///
+    /// The response is cut off if `reader` ends early.
+    pub fn body_reader<R: Read + Send + 'static>(mut self, reader: R, length: u64) -> Response {
+        self.body = Body::Reader(Box::new(reader), length);
+        self
+    }
+
+    /// Stream the body from `f` instead of sending a fixed one.
+    ///
+    /// `f` is called once the head has been sent and writes the body to the
+    /// `ResponseWriter` it is given, which sends it with chunked encoding.
+    /// Flushing the writer sends what has been written so far.
+    pub fn stream<F>(mut self, f: F) -> Response
+    where
+        F: FnOnce(&mut ResponseWriter<'_>) -> io::Result<()> + Send + 'static,
+    {
+        self.body = Body::Stream(Box::new(f));
+        self
+    }
+
+    // 送った後の接続を f に渡す。 status は 101 にしておくこと。
+    pub(crate) fn upgrade<F>(mut self, f: F) -> Response
+    where
+        F: FnOnce(BufReader<Conn>) + Send + 'static,
+    {
+        self.body = Body::Upgrade(Box::new(f));
+        self
+    }
+
+    // upgrade を取り出し、 body を空にする。
+    pub(crate) fn take_upgrade(&mut self) -> Option<Upgrade> {
+        match mem::replace(&mut self.body, Body::Bytes(Vec::new())) {
+            Body::Upgrade(upgrade) => Some(upgrade),
+            body => {
+                self.body = body;
+                None
+            }
+        }
+    }
+
+    /// Return the status.
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    /// Change the status.
+    pub fn set_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    /// Return the headers.
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Return the headers for modification.
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    /// Return the body. A body from `body_reader` or `stream` is empty here.
+    pub fn body_bytes(&self) -> &[u8] {
+        match self.body {
+            Body::Bytes(ref body) => body,
+            Body::Reader(..) | Body::Stream(_) | Body::Upgrade(_) | Body::Omitted(_) => &[],
+        }
+    }
+
+    /// Replace the body.
+    pub fn set_body<B: Into<Vec<u8>>>(&mut self, body: B) {
+        self.body = Body::Bytes(body.into());
+    }
+
+    /// Return `true` if the body is streamed with `stream`, so its length is
+    /// not known upfront.
+    pub fn is_streaming(&self) -> bool {
+        matches!(self.body, Body::Stream(_))
+    }
+
+    // stream の長さは送り終えるまで分からない。
+    pub(crate) fn body_len(&self) -> Option<u64> {
+        match self.body {
+            Body::Bytes(ref body) => Some(body.len() as u64),
+            Body::Reader(_, length) => Some(length),
+            Body::Stream(_) => None,
+            Body::Upgrade(_) => Some(0),
+            Body::Omitted(length) => length,
+        }
+    }
+
+    // HEAD への response にする。 header は GET と同じものを送る。
+    pub(crate) fn omit_body(&mut self) {
+        if !matches!(self.body, Body::Upgrade(_)) {
+            self.body = Body::Omitted(self.body_len());
+        }
+    }
+
+    // 圧縮した HEAD への response にする。圧縮後の長さは圧縮してみないと
+    // わからないので、 Content-Length は送らない。
+    pub(crate) fn omit_encoded_body(&mut self) {
+        if !matches!(self.body, Body::Upgrade(_)) {
+            self.body = Body::Omitted(None);
+        }
+    }
+
+    pub(crate) fn is_body_omitted(&self) -> bool {
+        matches!(self.body, Body::Omitted(_))
+    }
+
+    // body を圧縮したものにする。 reader は圧縮しながら送るので stream になる。
+    pub(crate) fn encode_body(&mut self, encoding: Encoding) {
+        self.body = match mem::replace(&mut self.body, Body::Bytes(Vec::new())) {
+            Body::Bytes(body) => Body::Bytes(encoding.compress(&body)),
+            Body::Reader(reader, length) => Body::Stream(Box::new(move |writer| {
+                let mut encoder = encoding.encoder(writer);
+                let copied = io::copy(&mut reader.take(length), &mut encoder)?;
+                if copied < length {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "body reader ended early",
+                    ));
+                }
+                encoder.finish()?;
+                Ok(())
+            })),
+            Body::Stream(stream) => Body::Stream(Box::new(move |writer| {
+                let mut encoder = encoding.encoder(writer);
+                // handler の flush が encoder まで届くよう、 chunk にせずに渡す。
+                let mut body = ResponseWriter::new(&mut encoder, false);
+                stream(&mut body)?;
+                body.finish()?;
+                encoder.finish()?;
+                Ok(())
+            })),
+            body @ (Body::Upgrade(_) | Body::Omitted(_)) => body,
+        };
+    }
+
+    /// Write the response to `writer` as HTTP/1.1 and flush it.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        self.write_as(writer, Version::Http11).map(|_| ())
+    }
+
+    // HTTP/1.0 の client は chunked を読めないので、 stream はそのまま流して
+    // 接続を閉じることで終わりを伝える。送った body の byte 数 (chunk の枠は
+    // 含めない) を返す。
+    pub(crate) fn write_as<W: Write>(self, writer: &mut W, version: Version) -> io::Result<u64> {
+        let chunked = version == Version::Http11;
+        // 小さい write を何度もしないよう、 head はまとめて組み立てる。
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in &self.headers {
+            if name.eq_ignore_ascii_case("Content-Length")
+                || name.eq_ignore_ascii_case("Transfer-Encoding")
+            {
+                continue;
+            }
+            head.push_str(name);
+            head.push_str(": ");
+            head.push_str(value);
+            head.push_str("\r\n");
+        }
+        let allows_body = self.status.allows_body();
+        if allows_body {
+            match self.body {
+                Body::Bytes(ref body) => {
+                    head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+                }
+                Body::Reader(_, length) => {
+                    head.push_str(&format!("Content-Length: {}\r\n", length));
+                }
+                Body::Stream(_) if chunked => head.push_str("Transfer-Encoding: chunked\r\n"),
+                Body::Omitted(Some(length)) => {
+                    head.push_str(&format!("Content-Length: {}\r\n", length));
+                }
+                Body::Stream(_) | Body::Upgrade(_) | Body::Omitted(None) => {}
+            }
+        }
+        head.push_str("\r\n");
+
+        writer.write_all(head.as_bytes())?;
+        let sent = if allows_body {
+            self.write_body(writer, chunked)?
+        } else {
+            0
+        };
+        writer.flush()?;
+        Ok(sent)
+    }
+
+    // body だけを書き、その byte 数を返す。 chunked なら stream を chunk に区切る。
+    pub(crate) fn write_body(self, writer: &mut dyn Write, chunked: bool) -> io::Result<u64> {
+        match self.body {
+            Body::Bytes(body) => {
+                writer.write_all(&body)?;
+                Ok(body.len() as u64)
+            }
+            Body::Reader(reader, length) => {
+                let copied = io::copy(&mut reader.take(length), writer)?;
+                if copied < length {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "body reader ended early",
+                    ));
+                }
+                Ok(copied)
+            }
+            Body::Stream(stream) => {
+                let mut body = ResponseWriter::new(writer, chunked);
+                stream(&mut body)?;
+                body.finish()
+            }
+            Body::Upgrade(_) | Body::Omitted(_) => Ok(0),
+        }
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = f.debug_struct("Response");
+        s.field("status", &self.status)
+            .field("headers", &self.headers);
+        match self.body {
+            Body::Bytes(ref body) => s.field("body", &format_args!("{} bytes", body.len())),
+            Body::Reader(_, length) => s.field("body", &format_args!("{} bytes", length)),
+            Body::Stream(_) => s.field("body", &format_args!("stream")),
+            Body::Upgrade(_) => s.field("body", &format_args!("upgrade")),
+            Body::Omitted(_) => s.field("body", &format_args!("omitted")),
+        };
+        s.finish()
+    }
+}
+
+/// Writes a streamed response body, given to the function passed to
+/// `Response::stream`.
+///
+/// Output is buffered and sent as one chunk when the buffer fills up or the
+/// writer is flushed.
+pub struct ResponseWriter<'a> {
+    inner: &'a mut dyn Write,
+    buffer: Vec<u8>,
+    chunked: bool,
+    written: u64,
+}
+
+impl<'a> ResponseWriter<'a> {
+    fn new(inner: &'a mut dyn Write, chunked: bool) -> ResponseWriter<'a> {
+        ResponseWriter {
+            inner,
+            buffer: Vec::new(),
+            chunked,
+            written: 0,
+        }
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.written += self.buffer.len() as u64;
+        if self.chunked {
+            write!(self.inner, "{:x}\r\n", self.buffer.len())?;
+            self.buffer.extend_from_slice(b"\r\n");
+        }
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    // 送った body の byte 数を返す。
+    fn finish(mut self) -> io::Result<u64> {
+        self.write_chunk()?;
+        if self.chunked {
+            self.inner.write_all(b"0\r\n\r\n")?;
+        }
+        Ok(self.written)
+    }
+}
+
+impl<'a> Write for ResponseWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.flush()
+    }
+}
+
+impl<'a> fmt::Debug for ResponseWriter<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResponseWriter")
+            .field("buffered", &self.buffer.len())
+            .field("chunked", &self.chunked)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Response;
+    use crate::Status;
+
+    #[test]
+    #[should_panic(expected = "CR or LF")]
+    fn refuses_line_breaks_in_headers() {
+        let _ = Response::new(Status::Ok).header("Location", "/\r\nSet-Cookie: a=b");
+    }
+}
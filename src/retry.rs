@@ -0,0 +1,86 @@
+use std::cmp;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+
+use super::panic_message;
+use super::scheduler::{Task, Timers};
+
+/// How often and how soon a job submitted with
+/// `ThreadPool::execute_with_retry` is run again after it fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The number of times the job is run at most, including the first run.
+    pub max_attempts: u32,
+    /// How long to wait before each retry.
+    pub backoff: Backoff,
+}
+
+/// The wait between attempts of a retried job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// Wait the same time before every retry.
+    Fixed(Duration),
+    /// Wait `initial` before the first retry and double the wait for each
+    /// retry after that, up to `max`.
+    Exponential { initial: Duration, max: Duration },
+}
+
+impl RetryPolicy {
+    // `attempt` 回目の実行が失敗した後に待つ時間。 attempt は 1 から数える。
+    fn delay(&self, attempt: u32) -> Duration {
+        match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => {
+                let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+                cmp::min(initial.checked_mul(factor).unwrap_or(max), max)
+            }
+        }
+    }
+}
+
+// 失敗したら timer thread 経由で自分自身を queue に入れ直す job。
+pub(crate) struct RetryJob<F> {
+    pub f: F,
+    pub policy: RetryPolicy,
+    pub attempt: u32,
+    pub timers: Timers,
+}
+
+impl<F, E> RetryJob<F>
+where
+    F: FnMut() -> Result<(), E> + Send + 'static,
+    E: fmt::Display,
+{
+    pub fn run(self) {
+        let mut job = self;
+        let result = panic::catch_unwind(AssertUnwindSafe(&mut job.f));
+        let reason = match result {
+            Ok(Ok(())) => return,
+            Ok(Err(ref err)) => err.to_string(),
+            Err(ref payload) => panic_message(&**payload),
+        };
+        if job.attempt >= job.policy.max_attempts {
+            error!(
+                "Giving up on a job after {} attempts: {}",
+                job.attempt, reason
+            );
+            // 最後の panic は worker に渡し、 panic policy に従わせる。
+            if let Err(payload) = result {
+                panic::resume_unwind(payload);
+            }
+            return;
+        }
+        let delay = job.policy.delay(job.attempt);
+        warn!(
+            "Job attempt {} of {} failed, retrying in {:?}: {}",
+            job.attempt, job.policy.max_attempts, delay, reason
+        );
+        job.attempt += 1;
+        let timers = job.timers.clone();
+        timers.schedule(
+            Instant::now() + delay,
+            Task::Once(Box::new(move || job.run())),
+        );
+    }
+}
@@ -0,0 +1,454 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use super::http::{percent_decode, percent_encode};
+use super::{Handler, Method, Middleware, Next, Request, Response, StaticFiles, Status};
+
+type BoxedHandler = Box<dyn Fn(Request) -> Response + Send + Sync + 'static>;
+
+/// Dispatches requests to handlers by method and path.
+///
+/// Routes are tried in the order they were added and the first match wins.
+/// Requests that match no route go to the not-found handler, which answers
+/// `404 Not Found` unless replaced with `not_found`.
+///
+/// A route path is matched segment by segment. A segment `:name` matches
+/// any single non-empty segment and a final `*name` matches the rest of the
+/// path, slashes included; handlers read the captured values with
+/// `Request::param`. For example `/users/:id` matches `/users/42` and
+/// `/static/*path` matches `/static/css/site.css`. Captured values are
+/// percent-decoded; a `*name` tail does not match a path with an encoded
+/// slash (`%2F`) in it, as it could not be told apart from a separator.
+///
+/// `HEAD` requests go to the `GET` route of a path unless it has a `HEAD`
+/// one, and the server sends the response without its body. A request for
+/// a path that only has routes for other methods is answered with
+/// `405 Method Not Allowed`, or `204 No Content` for `OPTIONS`, and an
+/// `Allow` header listing them.
+///
+/// Middlewares added with `middleware` run for every request before it is
+/// routed, including ones that end up at the not-found handler. To run one
+/// only for some routes, put them in their own router and `nest` it.
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: BoxedHandler,
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+struct Route {
+    // nest した router は method を問わない。
+    method: Option<Method>,
+    path: String,
+    segments: Vec<Segment>,
+    target: Target,
+}
+
+enum Target {
+    Handler(BoxedHandler),
+    Router(Router),
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+    Tail(String),
+}
+
+impl Route {
+    fn new(method: Option<Method>, path: String, target: Target) -> Route {
+        let segments: Vec<_> = path
+            .strip_prefix('/')
+            .unwrap_or(&path)
+            .split('/')
+            .map(Segment::parse)
+            .collect();
+        if let Some(index) = segments.iter().position(|s| matches!(*s, Segment::Tail(_))) {
+            assert!(
+                index == segments.len() - 1,
+                "a wildcard must be the last segment of a route: {}",
+                path
+            );
+        }
+        Route {
+            method,
+            path,
+            segments,
+            target,
+        }
+    }
+
+    // 一致したら、取り出した parameter を返す。
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let parts: Vec<&str> = path.strip_prefix('/')?.split('/').collect();
+        let mut params = Vec::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            if let Segment::Tail(ref name) = *segment {
+                let rest = &parts[i.min(parts.len())..];
+                // nest の wildcard は入れ子の router がもう一度照合するので、
+                // decode せずに渡す。
+                if name.is_empty() {
+                    params.push((String::new(), rest.join("/")));
+                    return Some(params);
+                }
+                // decode すると %2F と区切りの / を区別できなくなる。
+                let decoded: Vec<String> = rest.iter().map(|part| percent_decode(part)).collect();
+                if decoded.iter().any(|part| part.contains('/')) {
+                    return None;
+                }
+                params.push((name.clone(), decoded.join("/")));
+                return Some(params);
+            }
+            let part = *parts.get(i)?;
+            match *segment {
+                Segment::Literal(ref literal) if literal == part => {}
+                Segment::Param(ref name) if !part.is_empty() => {
+                    params.push((name.clone(), percent_decode(part)));
+                }
+                _ => return None,
+            }
+        }
+        if parts.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+impl Segment {
+    fn parse(segment: &str) -> Segment {
+        if let Some(name) = segment.strip_prefix(':') {
+            Segment::Param(name.to_string())
+        } else if let Some(name) = segment.strip_prefix('*') {
+            Segment::Tail(name.to_string())
+        } else {
+            Segment::Literal(segment.to_string())
+        }
+    }
+}
+
+impl Router {
+    /// Create a router with no routes.
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_| Response::new(Status::NotFound)),
+            middlewares: Vec::new(),
+        }
+    }
+
+    /// Route `method` requests for `path` to `handler`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a `*` wildcard is not the last segment of `path`.
+    pub fn route<P, H>(mut self, method: Method, path: P, handler: H) -> Router
+    where
+        P: Into<String>,
+        H: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        let target = Target::Handler(Box::new(handler));
+        self.routes
+            .push(Route::new(Some(method), path.into(), target));
+        self
+    }
+
+    /// Route `GET` requests for `path` to `handler`.
+    pub fn get<P, H>(self, path: P, handler: H) -> Router
+    where
+        P: Into<String>,
+        H: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Get, path, handler)
+    }
+
+    /// Route `POST` requests for `path` to `handler`.
+    pub fn post<P, H>(self, path: P, handler: H) -> Router
+    where
+        P: Into<String>,
+        H: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Post, path, handler)
+    }
+
+    /// Route `PUT` requests for `path` to `handler`.
+    pub fn put<P, H>(self, path: P, handler: H) -> Router
+    where
+        P: Into<String>,
+        H: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Put, path, handler)
+    }
+
+    /// Route `DELETE` requests for `path` to `handler`.
+    pub fn delete<P, H>(self, path: P, handler: H) -> Router
+    where
+        P: Into<String>,
+        H: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Delete, path, handler)
+    }
+
+    /// Serve the files under `dir` for `GET` requests below `prefix`, e.g.
+    /// `./public/app.js` for `/assets/app.js` with a prefix of `/assets`.
+    pub fn serve_dir<P, D>(self, prefix: P, dir: D) -> Router
+    where
+        P: AsRef<str>,
+        D: Into<PathBuf>,
+    {
+        self.serve_static(prefix, StaticFiles::new(dir))
+    }
+
+    /// Serve `files` for `GET` requests below `prefix`.
+    pub fn serve_static<P: AsRef<str>>(self, prefix: P, files: StaticFiles) -> Router {
+        let path = format!("{}/*path", prefix.as_ref().trim_end_matches('/'));
+        self.get(path, move |request| {
+            files.serve(request.param("path").unwrap_or(""), &request)
+        })
+    }
+
+    /// Route requests for `prefix` and the paths below it to `router`,
+    /// whatever their method. The routes of `router` are relative to
+    /// `prefix`: nested at `/admin`, its `/users` route matches
+    /// `/admin/users` and its `/` route `/admin` itself.
+    ///
+    /// Middlewares of `router` run only for the requests routed to it, and
+    /// the ones it has no route for go to its not-found handler.
+    pub fn nest<P: AsRef<str>>(mut self, prefix: P, router: Router) -> Router {
+        let path = format!("{}/*", prefix.as_ref().trim_end_matches('/'));
+        self.routes
+            .push(Route::new(None, path, Target::Router(router)));
+        self
+    }
+
+    /// Answer requests for `from`, whatever their method, with a
+    /// `308 Permanent Redirect` to `to`, keeping the query string.
+    ///
+    /// Parameters captured by `from` can be used in `to`, as in
+    /// `redirect("/posts/:id", "/articles/:id")`. Leading slashes and
+    /// backslashes of a captured value are dropped, so that it cannot turn
+    /// `to` into a redirect to another host.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a `*` wildcard is not the last segment of `from`.
+    pub fn redirect<F: Into<String>, T: Into<String>>(mut self, from: F, to: T) -> Router {
+        let to = to.into();
+        let target = Target::Handler(Box::new(move |request: Request| {
+            // :name と *name を一致した値に置き換える。 `/go//evil.com` の
+            // wildcard は `/evil.com` なので、先頭の / を落とさないと
+            // `//evil.com` という別の host への redirect になる。
+            let mut location: String = to
+                .split('/')
+                .map(|segment| {
+                    let name = segment
+                        .strip_prefix(':')
+                        .or_else(|| segment.strip_prefix('*'));
+                    match name.and_then(|name| request.param(name)) {
+                        // 値は decode されているので、 segment ごとに encode し直す。
+                        Some(value) => value
+                            .trim_start_matches(['/', '\\'])
+                            .split('/')
+                            .map(percent_encode)
+                            .collect::<Vec<_>>()
+                            .join("/"),
+                        None => segment.to_string(),
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            if let Some((_, query)) = request.target().split_once('?') {
+                location.push(if location.contains('?') { '&' } else { '?' });
+                location.push_str(query);
+            }
+            Response::redirect_with(Status::PermanentRedirect, location)
+        }));
+        self.routes.push(Route::new(None, from.into(), target));
+        self
+    }
+
+    /// Handle requests that match no route with `handler`.
+    pub fn not_found<H>(mut self, handler: H) -> Router
+    where
+        H: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// Run `middleware` around every route of this router. Middlewares run
+    /// in the order they are added. Route parameters are not set yet when
+    /// they see the request.
+    pub fn middleware<M: Middleware>(mut self, middleware: M) -> Router {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// Dispatch `request` to the handler of the first matching route,
+    /// through the middlewares.
+    pub fn handle(&self, request: Request) -> Response {
+        let path = request.path().to_owned();
+        self.handle_at(request, &path)
+    }
+
+    // nest された router では、 path は prefix を除いたもの。
+    fn handle_at(&self, request: Request, path: &str) -> Response {
+        Next::new(&self.middlewares, &|request| self.dispatch(request, path)).run(request)
+    }
+
+    fn dispatch(&self, mut request: Request, path: &str) -> Response {
+        // HEAD の route がなければ GET の route で答える。
+        let head_as_get = *request.method() == Method::Head
+            && !self
+                .routes
+                .iter()
+                .any(|route| route.method == Some(Method::Head) && route.matches(path).is_some());
+        // path は合うが method の違う route の method。
+        let mut allowed: Vec<&Method> = Vec::new();
+        for route in &self.routes {
+            let mut params = match route.matches(path) {
+                Some(params) => params,
+                None => continue,
+            };
+            if let Some(ref method) = route.method {
+                let accepts = method == request.method() || (head_as_get && *method == Method::Get);
+                if !accepts {
+                    if !allowed.contains(&method) {
+                        allowed.push(method);
+                    }
+                    continue;
+                }
+            }
+            match route.target {
+                Target::Handler(ref handler) => {
+                    request.add_params(params);
+                    return handler(request);
+                }
+                Target::Router(ref router) => {
+                    // 最後の wildcard が prefix より後ろの path。
+                    let (_, rest) = params.pop().unwrap();
+                    request.add_params(params);
+                    return router.handle_at(request, &format!("/{}", rest));
+                }
+            }
+        }
+        if allowed.is_empty() {
+            return (self.not_found)(request);
+        }
+        let status = if *request.method() == Method::Options {
+            Status::NoContent
+        } else {
+            Status::MethodNotAllowed
+        };
+        Response::new(status).header("Allow", allow_header(&allowed))
+    }
+}
+
+// GET があれば HEAD も、いつでも OPTIONS も受け付けると伝える。
+fn allow_header(methods: &[&Method]) -> String {
+    let mut names: Vec<&str> = methods.iter().map(|method| method.as_str()).collect();
+    if names.contains(&"GET") && !names.contains(&"HEAD") {
+        names.push("HEAD");
+    }
+    if !names.contains(&"OPTIONS") {
+        names.push("OPTIONS");
+    }
+    names.join(", ")
+}
+
+impl Handler for Router {
+    fn handle(&self, request: Request) -> Response {
+        Router::handle(self, request)
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let routes: Vec<_> = self
+            .routes
+            .iter()
+            .map(|route| match route.method {
+                Some(ref method) => format!("{} {}", method, route.path),
+                None => format!("* {}", route.path),
+            })
+            .collect();
+        f.debug_struct("Router")
+            .field("routes", &routes)
+            .field("middlewares", &self.middlewares.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Router;
+    use crate::{Request, Response, Status};
+
+    fn get(router: &Router, target: &str) -> Response {
+        let head = format!("GET {} HTTP/1.1\r\n\r\n", target);
+        let request = Request::read_from(&mut head.as_bytes()).unwrap().unwrap();
+        router.handle(request)
+    }
+
+    fn echo(name: &'static str) -> impl Fn(Request) -> Response + Send + Sync {
+        move |request: Request| Response::new(Status::Ok).body(request.param(name).unwrap())
+    }
+
+    #[test]
+    fn decodes_params() {
+        let router = Router::new()
+            .get("/users/:name", echo("name"))
+            .get("/files/*path", echo("path"))
+            .nest("/api", Router::new().get("/:name", echo("name")));
+        for (target, param) in [
+            ("/users/a%20b", "a b"),
+            ("/users/a%2Fb", "a/b"),
+            ("/files/a%20b/c%C3%A9", "a b/c\u{e9}"),
+            ("/files/%zz", "%zz"),
+            // 入れ子の router でも 1 回だけ decode する。
+            ("/api/a%2541", "a%41"),
+        ] {
+            let response = get(&router, target);
+            assert_eq!(response.body_bytes(), param.as_bytes(), "{}", target);
+        }
+    }
+
+    #[test]
+    fn tails_do_not_match_encoded_slashes() {
+        let router = Router::new()
+            .get("/files/*path", echo("path"))
+            .redirect("/go/*path", "/*path");
+        for target in ["/files/a%2Fb", "/files/a/%2f", "/go/%2F%2Fevil.com"] {
+            let response = get(&router, target);
+            assert_eq!(response.status(), Status::NotFound, "{}", target);
+        }
+    }
+
+    #[test]
+    fn redirects_stay_on_the_same_host() {
+        let router = Router::new().redirect("/go/*path", "/*path");
+        for (target, location) in [
+            ("/go/docs/intro", "/docs/intro"),
+            ("/go//evil.com", "/evil.com"),
+            ("/go///evil.com/x", "/evil.com/x"),
+            ("/go/\\evil.com", "/evil.com"),
+            ("/go//evil.com?a=1", "/evil.com?a=1"),
+            ("/go/%5Cevil.com", "/evil.com"),
+            ("/go/a%20b/%0D%0A", "/a%20b/%0D%0A"),
+        ] {
+            let response = get(&router, target);
+            assert_eq!(
+                response.headers().get("Location"),
+                Some(location),
+                "{}",
+                target
+            );
+        }
+    }
+}
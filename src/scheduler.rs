@@ -0,0 +1,294 @@
+//! A timer thread that feeds delayed jobs into the pool's job queue.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{panic_message, Job, Message, PoolHandle, DEFAULT_PRIORITY};
+
+/// Controls a job scheduled with `ThreadPool::execute_every`.
+///
+/// Dropping the handle does not cancel the schedule; call `cancel` for that.
+#[derive(Debug, Clone)]
+pub struct ScheduleHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ScheduleHandle {
+    /// Stop scheduling further runs. A run that is already queued or running
+    /// is not affected.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, AtomicOrdering::SeqCst);
+    }
+
+    /// Return `true` if `cancel` has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(AtomicOrdering::SeqCst)
+    }
+}
+
+pub(crate) enum Task {
+    Once(Job),
+    Every(Recurring),
+    // queue を通さず timer thread 上で直接実行する。短い処理に限る。
+    Inline(Box<dyn FnOnce() + Send>),
+}
+
+pub(crate) struct Recurring {
+    interval: Duration,
+    f: Arc<dyn Fn() + Send + Sync>,
+    cancelled: Arc<AtomicBool>,
+    // 前回の実行が終わっていなければ次の実行は飛ばす。
+    running: Arc<AtomicBool>,
+}
+
+impl Recurring {
+    pub fn new<F>(interval: Duration, f: F) -> (Recurring, ScheduleHandle)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let recurring = Recurring {
+            interval,
+            f: Arc::new(f),
+            cancelled: Arc::clone(&cancelled),
+            running: Arc::new(AtomicBool::new(false)),
+        };
+        (recurring, ScheduleHandle { cancelled })
+    }
+
+    // 1 回分の実行を job にする。前回の実行中なら None を返す。
+    fn job(&self) -> Option<Job> {
+        if self.running.swap(true, AtomicOrdering::SeqCst) {
+            return None;
+        }
+        let f = Arc::clone(&self.f);
+        let running = Running(Arc::clone(&self.running));
+        Some(Box::new(move || {
+            let _running = running;
+            f();
+        }))
+    }
+}
+
+// job が panic したり実行されずに捨てられたりしても running を戻す。
+struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, AtomicOrdering::SeqCst);
+    }
+}
+
+struct Timer {
+    deadline: Instant,
+    seq: u64,
+    task: Task,
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Timer) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Timer) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    // BinaryHeap は最大のものから取り出すので、 deadline が早いものほど大きいとみなす。
+    fn cmp(&self, other: &Timer) -> Ordering {
+        other
+            .deadline
+            .cmp(&self.deadline)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+struct State {
+    timers: BinaryHeap<Timer>,
+    next_seq: u64,
+    stopped: bool,
+}
+
+impl State {
+    fn push(&mut self, deadline: Instant, task: Task) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.timers.push(Timer {
+            deadline,
+            seq,
+            task,
+        });
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+pub(crate) struct Scheduler {
+    timers: Timers,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// A handle for adding timers from outside the pool, e.g. from jobs.
+#[derive(Clone)]
+pub(crate) struct Timers(Arc<Shared>);
+
+impl Timers {
+    /// Schedule `task` to be queued at `deadline`. Recurring tasks are
+    /// scheduled again after each run until they are cancelled.
+    pub fn schedule(&self, deadline: Instant, task: Task) {
+        let mut state = (self.0).state.lock().unwrap();
+        if state.stopped {
+            // pool は閉じる前に scheduler を止めないので、 pool からはここには来ない。
+            debug!("Dropping a job scheduled after the scheduler stopped.");
+            return;
+        }
+        state.push(deadline, task);
+        (self.0).changed.notify_one();
+    }
+}
+
+impl Scheduler {
+    /// Spawn the timer thread. Due jobs are sent to the pool behind `pool`.
+    pub fn start(pool: PoolHandle, name: Option<String>) -> io::Result<Scheduler> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                timers: BinaryHeap::new(),
+                next_seq: 0,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let mut builder = thread::Builder::new();
+        if let Some(name) = name {
+            builder = builder.name(name);
+        }
+        let thread = {
+            let shared = Arc::clone(&shared);
+            builder.spawn(move || Scheduler::run(&shared, &pool))?
+        };
+
+        Ok(Scheduler {
+            timers: Timers(shared),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn schedule(&self, deadline: Instant, task: Task) {
+        self.timers.schedule(deadline, task);
+    }
+
+    pub fn timers(&self) -> Timers {
+        self.timers.clone()
+    }
+
+    /// Stop the timer thread. Jobs that are not due yet are dropped.
+    pub fn stop(&mut self) {
+        // job の drop で任意のコードが走りうるので、 lock の外で捨てる。
+        let timers = {
+            let shared = &self.timers.0;
+            let mut state = shared.state.lock().unwrap();
+            state.stopped = true;
+            shared.changed.notify_one();
+            mem::take(&mut state.timers)
+        };
+        if !timers.is_empty() {
+            debug!(
+                "Dropping {} scheduled jobs that were not due yet.",
+                timers.len()
+            );
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    fn run(shared: &Shared, pool: &PoolHandle) {
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if state.stopped {
+                return;
+            }
+            let now = Instant::now();
+            let next = state.timers.peek().map(|timer| timer.deadline);
+            match next {
+                Some(deadline) if deadline <= now => {
+                    let timer = state.timers.pop().unwrap();
+                    let job = match timer.task {
+                        Task::Once(job) => Some(job),
+                        Task::Inline(f) => {
+                            drop(state);
+                            // 利用者のコードが panic しても timer thread は止めない。
+                            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                                error!("A timer callback panicked: {}", panic_message(&*payload));
+                            }
+                            state = shared.state.lock().unwrap();
+                            None
+                        }
+                        Task::Every(recurring) => {
+                            if recurring.cancelled.load(AtomicOrdering::SeqCst) {
+                                continue;
+                            }
+                            let job = recurring.job();
+                            if job.is_none() {
+                                debug!("Skipping a recurring job whose last run is not done.");
+                            }
+                            // 遅れが出ても溜まった分をまとめて実行しないように、
+                            // 次の実行は今から interval 後以降にする。
+                            let mut next = deadline + recurring.interval;
+                            if next <= now {
+                                next = now + recurring.interval;
+                            }
+                            state.push(next, Task::Every(recurring));
+                            job
+                        }
+                    };
+                    if let Some(job) = job {
+                        drop(state);
+                        // pool が既に閉じていれば job はここで捨てられる。
+                        let sent = pool.core.sender.send(job, DEFAULT_PRIORITY, Message::new);
+                        if sent.is_err() {
+                            debug!("Dropping a scheduled job because the pool is shut down.");
+                        } else {
+                            // worker を後から起動する pool では、ここで起動させる。
+                            pool.scale_up();
+                        }
+                        state = shared.state.lock().unwrap();
+                    }
+                }
+                Some(deadline) => {
+                    state = shared
+                        .changed
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0;
+                }
+                None => {
+                    state = shared.changed.wait(state).unwrap();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,123 @@
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::{panic_message, ThreadPool, DEFAULT_PRIORITY};
+
+/// A scope for jobs that borrow data from the caller's stack.
+///
+/// Created by `ThreadPool::scope`, which does not return until every job
+/// submitted through the scope has finished.
+pub struct Scope<'pool, 'scope> {
+    pool: &'pool ThreadPool,
+    state: Arc<State>,
+    // 'scope について invariant にして、短い lifetime への変換を防ぐ。
+    _marker: PhantomData<&'scope mut &'scope ()>,
+}
+
+#[derive(Default)]
+struct State {
+    pending: Mutex<usize>,
+    done: Condvar,
+    // 最初に panic した job の panic message。
+    panicked: Mutex<Option<String>>,
+}
+
+impl<'pool, 'scope> Scope<'pool, 'scope> {
+    pub(crate) fn run<F, R>(pool: &'pool ThreadPool, f: F) -> R
+    where
+        F: FnOnce(&Scope<'pool, 'scope>) -> R,
+    {
+        let scope = Scope {
+            pool,
+            state: Arc::new(State::default()),
+            _marker: PhantomData,
+        };
+        // f が panic しても、借用されたデータが生きているうちに job を待つ。
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.wait();
+        let result = match result {
+            Ok(result) => result,
+            Err(payload) => panic::resume_unwind(payload),
+        };
+        if let Some(msg) = scope.state.panicked.lock().unwrap().take() {
+            panic!("a scoped job panicked: {}", msg);
+        }
+        result
+    }
+
+    /// Submit a job that may borrow anything that outlives the scope.
+    ///
+    /// The pool's rejection policy applies as for `ThreadPool::execute`. If
+    /// the job cannot be queued, e.g. because the pool has shut down, it
+    /// runs on the calling thread instead.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'scope,
+    {
+        let f: Box<dyn FnOnce() + Send + 'scope> = Box::new(f);
+        // scope は job が全て終わる (または捨てられる) まで戻らないので、
+        // 'scope より長く生きているように見せても job が借用先より長生きすることはない。
+        let f: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(f) };
+        *self.state.pending.lock().unwrap() += 1;
+        let job = ScopedJob {
+            f,
+            pending: Pending(Arc::clone(&self.state)),
+        };
+        if let Err(err) = self
+            .pool
+            .handle
+            .send_job(job, ScopedJob::run, DEFAULT_PRIORITY)
+        {
+            err.into_inner().run();
+        }
+    }
+
+    fn wait(&self) {
+        let mut pending = self.state.pending.lock().unwrap();
+        while *pending > 0 {
+            pending = self.state.done.wait(pending).unwrap();
+        }
+    }
+}
+
+// 借用を含むクロージャ。 `f` が `pending` より先に drop されるよう、この順で並べる。
+struct ScopedJob {
+    f: Box<dyn FnOnce() + Send>,
+    pending: Pending,
+}
+
+impl ScopedJob {
+    fn run(self) {
+        let ScopedJob { f, pending } = self;
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        if let Err(ref payload) = result {
+            let mut panicked = pending.0.panicked.lock().unwrap();
+            if panicked.is_none() {
+                *panicked = Some(panic_message(&**payload));
+            }
+        }
+        drop(pending);
+        // worker 側でも panic として扱えるように投げ直す。
+        if let Err(payload) = result {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+// job が実行されても捨てられても、終了を scope に知らせる。
+struct Pending(Arc<State>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let mut pending = match self.0.pending.lock() {
+            Ok(pending) => pending,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *pending -= 1;
+        if *pending == 0 {
+            self.0.done.notify_all();
+        }
+    }
+}
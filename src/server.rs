@@ -0,0 +1,1142 @@
+//! A TCP server that hands each request to a handler on a thread pool.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "signals")]
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::accesslog::{Entry, Head};
+use super::errors::ErrorPages;
+use super::health::Triage;
+use super::http::Limits;
+use super::http2;
+use super::shutdown::{Connection, Connections};
+#[cfg(feature = "signals")]
+use super::signal;
+use super::stream::Stream;
+#[cfg(feature = "tls")]
+use super::tls::TlsListener;
+#[cfg(feature = "tls")]
+use super::TlsConfig;
+use super::{
+    panic_message, AccessLog, CancellationToken, Compression, Handler, HealthCheck, HttpError,
+    IpFilter, Method, Middleware, Next, ParseError, PoolCreationError, PoolHandle, Request,
+    Response, ShutdownHandle, Status, ThreadPool, ThreadPoolBuilder, TryExecuteError, Version,
+};
+
+/// Configures and creates a `Server`.
+///
+/// The server runs handlers on its own `ThreadPool`, configured here.
+#[derive(Debug, Default)]
+pub struct ServerBuilder {
+    pool: ThreadPoolBuilder,
+    config: Config,
+}
+
+// 接続を扱う job の間で共有する設定。
+pub(crate) struct Config {
+    keep_alive: bool,
+    http2: bool,
+    read_timeout: Option<Duration>,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) header_timeout: Option<Duration>,
+    pub(crate) limits: Limits,
+    middlewares: Vec<Box<dyn Middleware>>,
+    errors: ErrorPages,
+    pub(crate) access_log: Option<AccessLog>,
+    ip_filter: Option<IpFilter>,
+    pub(crate) health_check: Option<HealthCheck>,
+    drain_timeout: Duration,
+    #[cfg(feature = "signals")]
+    shutdown_on_signals: bool,
+    #[cfg(feature = "signals")]
+    on_reload: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            keep_alive: true,
+            http2: false,
+            read_timeout: Some(DEFAULT_TIMEOUT),
+            write_timeout: Some(DEFAULT_TIMEOUT),
+            header_timeout: Some(DEFAULT_HEADER_TIMEOUT),
+            limits: Limits::default(),
+            middlewares: Vec::new(),
+            errors: ErrorPages::default(),
+            access_log: None,
+            ip_filter: None,
+            health_check: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            #[cfg(feature = "signals")]
+            shutdown_on_signals: false,
+            #[cfg(feature = "signals")]
+            on_reload: None,
+        }
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = f.debug_struct("Config");
+        s.field("keep_alive", &self.keep_alive)
+            .field("http2", &self.http2)
+            .field("read_timeout", &self.read_timeout)
+            .field("write_timeout", &self.write_timeout)
+            .field("header_timeout", &self.header_timeout)
+            .field("limits", &self.limits)
+            .field("middlewares", &self.middlewares.len())
+            .field("errors", &self.errors)
+            .field("access_log", &self.access_log)
+            .field("ip_filter", &self.ip_filter)
+            .field("health_check", &self.health_check)
+            .field("drain_timeout", &self.drain_timeout);
+        #[cfg(feature = "signals")]
+        s.field("shutdown_on_signals", &self.shutdown_on_signals)
+            .field("on_reload", &self.on_reload.is_some());
+        s.finish()
+    }
+}
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl ServerBuilder {
+    /// Create a builder with the default configuration.
+    pub fn new() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// Set the number of threads handling connections. Defaults to the
+    /// number of CPUs.
+    pub fn threads(mut self, threads: usize) -> ServerBuilder {
+        self.pool = self.pool.size(threads);
+        self
+    }
+
+    /// Configure the thread pool in full. This replaces any settings made
+    /// with `threads` before it.
+    ///
+    /// Connections never run on the accepting thread, whatever the
+    /// rejection policy: a connection accepted while the pool's queue is
+    /// full is answered with `503 Service Unavailable` and closed. The
+    /// policy still applies to other jobs, such as those handlers submit.
+    pub fn thread_pool(mut self, pool: ThreadPoolBuilder) -> ServerBuilder {
+        self.pool = pool;
+        self
+    }
+
+    /// Set whether connections are kept open for further requests after a
+    /// response. Defaults to `true`.
+    ///
+    /// A kept-alive connection stays on its worker until the client closes
+    /// it or sends no new request within the header timeout, so a pool of
+    /// `n` threads serves at most `n` clients at once.
+    pub fn keep_alive(mut self, keep_alive: bool) -> ServerBuilder {
+        self.config.keep_alive = keep_alive;
+        self
+    }
+
+    /// Set whether HTTP/2 is served. Defaults to `false`.
+    ///
+    /// TLS listeners offer `h2` next to `http/1.1` with ALPN, which is how
+    /// browsers pick HTTP/2. On plain listeners, HTTP/2 is served to
+    /// clients that open the connection with the HTTP/2 preface, as with
+    /// curl's `--http2-prior-knowledge`, such as internal clients and
+    /// proxies that speak HTTP/2 to their backends.
+    ///
+    /// Each stream of an HTTP/2 connection is handled as a job of its own,
+    /// so requests sent together are answered in parallel; when no worker
+    /// is idle, a stream is refused with `REFUSED_STREAM` for the client to
+    /// retry. Set it before `bind_tls` for the listener to offer `h2`.
+    pub fn http2(mut self, http2: bool) -> ServerBuilder {
+        self.config.http2 = http2;
+        self
+    }
+
+    /// Set how long a read from a connection may block, or `None` to wait
+    /// forever. Defaults to 30 seconds.
+    ///
+    /// A connection that stalls partway through a request for this long is
+    /// answered with `408 Request Timeout` and closed. Waiting for a new
+    /// request is bounded by the header timeout as well.
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> ServerBuilder {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    /// Set how long a write to a connection may block, or `None` to wait
+    /// forever. Defaults to 30 seconds. A connection whose writes time out
+    /// is closed.
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> ServerBuilder {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    /// Set how long a client may take to send the request line and headers,
+    /// or `None` for no limit. Defaults to 10 seconds.
+    ///
+    /// The time is counted from when the server starts waiting for the
+    /// request: when the connection is accepted, and after each response
+    /// on a kept-alive connection. A connection that sends nothing in time
+    /// is closed, which keeps idle clients from holding workers. One that
+    /// trickles a request in a byte at a time, each read finishing within
+    /// the read timeout, is answered with `408 Request Timeout`.
+    pub fn header_timeout(mut self, timeout: Option<Duration>) -> ServerBuilder {
+        self.config.header_timeout = timeout;
+        self
+    }
+
+    /// Set the maximum size in bytes of the request line and headers
+    /// together. Defaults to 64 KiB.
+    ///
+    /// Larger requests are answered with `431 Request Header Fields Too
+    /// Large`, or `414 URI Too Long` if the request line alone is too long.
+    pub fn max_header_bytes(mut self, bytes: usize) -> ServerBuilder {
+        self.config.limits.max_header_bytes = bytes;
+        self
+    }
+
+    /// Set the maximum number of headers in a request. Defaults to 100.
+    /// Requests with more are answered with `431 Request Header Fields Too
+    /// Large`.
+    pub fn max_headers(mut self, count: usize) -> ServerBuilder {
+        self.config.limits.max_headers = count;
+        self
+    }
+
+    /// Set the maximum size in bytes of a request body. Defaults to 10 MiB.
+    ///
+    /// A request whose `Content-Length` is larger is answered with `413
+    /// Payload Too Large` before its body is read; a chunked body is cut
+    /// off with the same response once it grows past the limit.
+    pub fn max_body_bytes(mut self, bytes: u64) -> ServerBuilder {
+        self.config.limits.max_body_bytes = bytes;
+        self
+    }
+
+    /// Run `middleware` around the handler for every request. Middlewares
+    /// run in the order they are added, so the first one added sees the
+    /// request first.
+    pub fn middleware<M: Middleware>(mut self, middleware: M) -> ServerBuilder {
+        self.config.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// Compress the bodies of responses as configured by `compression`.
+    /// Responses are sent uncompressed by default.
+    ///
+    /// This is the same as adding `compression` with `middleware`.
+    pub fn compression(self, compression: Compression) -> ServerBuilder {
+        self.middleware(compression)
+    }
+
+    /// Answer requests the handler answers with a bodiless `404 Not Found`
+    /// with the response from `handler` instead, e.g. a branded page.
+    ///
+    /// Headers of the original response are kept unless `handler` sets
+    /// them. The request passed to `handler` has an empty body.
+    pub fn not_found<F>(mut self, handler: F) -> ServerBuilder
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.config.errors.not_found = Some(Box::new(handler));
+        self
+    }
+
+    /// Answer requests whose handler panics, or answers with a bodiless
+    /// `500 Internal Server Error`, with the response from `handler`.
+    ///
+    /// A panicking handler is answered with `500 Internal Server Error`
+    /// even without this, and its connection is closed; the worker lives
+    /// on.
+    pub fn internal_error<F>(mut self, handler: F) -> ServerBuilder
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.config.errors.internal_error = Some(Box::new(handler));
+        self
+    }
+
+    /// Answer every error without its own handler with the response from
+    /// `hook`: responses with a `4xx` or `5xx` status and no body, and
+    /// panics. `not_found` and `internal_error` take precedence.
+    ///
+    /// Requests too malformed to reach the handler are still answered
+    /// with a bare status.
+    pub fn on_error<F>(mut self, hook: F) -> ServerBuilder
+    where
+        F: Fn(&HttpError, &Request) -> Response + Send + Sync + 'static,
+    {
+        self.config.errors.on_error = Some(Box::new(hook));
+        self
+    }
+
+    /// Write a line to `log` for every request answered. Requests are not
+    /// logged by default.
+    pub fn access_log(mut self, log: AccessLog) -> ServerBuilder {
+        self.config.access_log = Some(log);
+        self
+    }
+
+    /// Close connections from peers `filter` refuses right after accepting
+    /// them, before anything is read. Every peer is served by default.
+    pub fn ip_filter(mut self, filter: IpFilter) -> ServerBuilder {
+        self.config.ip_filter = Some(filter);
+        self
+    }
+
+    /// Serve `check` ahead of the middlewares and the handler, so that
+    /// load balancers can tell whether the server takes requests.
+    pub fn health_check(mut self, check: HealthCheck) -> ServerBuilder {
+        self.config.health_check = Some(check);
+        self
+    }
+
+    /// Set how long a shutdown waits for requests in progress to finish
+    /// before `Server::run` returns anyway. Defaults to 30 seconds.
+    pub fn drain_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.config.drain_timeout = timeout;
+        self
+    }
+
+    /// Set whether `SIGINT` and `SIGTERM` shut the server down gracefully,
+    /// as `ShutdownHandle::shutdown` does. A second signal during the
+    /// drain exits the process at once. Defaults to `false`. Only
+    /// supported on Linux; elsewhere the signals keep their usual effect.
+    #[cfg(feature = "signals")]
+    pub fn shutdown_on_signals(mut self, enabled: bool) -> ServerBuilder {
+        self.config.shutdown_on_signals = enabled;
+        self
+    }
+
+    /// Call `f` each time the process receives `SIGHUP` while the server
+    /// runs, to re-read configuration. It runs on a thread of its own, so
+    /// connections are served as usual in the meantime. Only supported on
+    /// Linux.
+    ///
+    /// Settings that can change at runtime include the log level, with
+    /// `log::set_max_level`, and the limits of a `RateLimit`, with
+    /// `RateLimit::set_limit`.
+    /// TLS certificates read with `TlsConfig::from_files` are read again
+    /// on `SIGHUP` without it.
+    #[cfg(feature = "signals")]
+    pub fn on_reload<F>(mut self, f: F) -> ServerBuilder
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.config.on_reload = Some(Arc::new(f));
+        self
+    }
+
+    /// Create the thread pool and start listening on `addr`.
+    pub fn bind<A: ToSocketAddrs>(self, addr: A) -> Result<Server, ServerError> {
+        let listener = Listener::plain(TcpListener::bind(addr)?);
+        self.build(listener)
+    }
+
+    /// Create the thread pool and start listening on `addr` for HTTPS,
+    /// with the certificate and key in `tls`.
+    ///
+    /// Use `Server::listen` to take plain HTTP as well.
+    #[cfg(feature = "tls")]
+    pub fn bind_tls<A: ToSocketAddrs>(
+        self,
+        addr: A,
+        tls: TlsConfig,
+    ) -> Result<Server, ServerError> {
+        let listener = Listener::tls(TcpListener::bind(addr)?, tls, &self.config)?;
+        self.build(listener)
+    }
+
+    fn build(mut self, listener: Listener) -> Result<Server, ServerError> {
+        let pool = self.pool.build()?;
+        let shutdown = CancellationToken::new();
+        if let Some(ref mut check) = self.config.health_check {
+            check.attach(shutdown.clone(), pool.handle());
+        }
+        Ok(Server {
+            listeners: vec![listener],
+            pool,
+            config: Arc::new(self.config),
+            shutdown,
+            connections: Arc::new(Connections::default()),
+        })
+    }
+}
+
+// accept する socket。 TLS の listener は rustls の設定を持つ。
+struct Listener {
+    tcp: TcpListener,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<TlsListener>>,
+}
+
+impl Listener {
+    fn plain(tcp: TcpListener) -> Listener {
+        Listener {
+            tcp,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls(tcp: TcpListener, tls: TlsConfig, config: &Config) -> io::Result<Listener> {
+        Ok(Listener {
+            tcp,
+            tls: Some(Arc::new(TlsListener::new(tls, config.http2)?)),
+        })
+    }
+
+    fn is_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        false
+    }
+
+    // accept した接続を、 TLS の listener なら TLS の接続にする。
+    fn wrap(&self, tcp: TcpStream) -> io::Result<Stream> {
+        #[cfg(feature = "tls")]
+        if let Some(ref tls) = self.tls {
+            return tls.accept(tcp).map(Stream::Tls);
+        }
+        Ok(Stream::Plain(tcp))
+    }
+}
+
+/// A TCP server that runs a handler for every request it receives.
+pub struct Server {
+    listeners: Vec<Listener>,
+    pool: ThreadPool,
+    config: Arc<Config>,
+    shutdown: CancellationToken,
+    connections: Arc<Connections>,
+}
+
+impl Server {
+    /// Listen on `addr` with a pool of `pool_size` threads.
+    pub fn bind<A: ToSocketAddrs>(addr: A, pool_size: usize) -> Result<Server, ServerError> {
+        ServerBuilder::new().threads(pool_size).bind(addr)
+    }
+
+    /// Listen on `addr` for HTTPS with the default configuration, given
+    /// the certificate chain and private key in PEM. See `TlsConfig::new`.
+    #[cfg(feature = "tls")]
+    pub fn bind_tls<A: ToSocketAddrs>(
+        addr: A,
+        cert_pem: &[u8],
+        key_pem: &[u8],
+    ) -> Result<Server, ServerError> {
+        ServerBuilder::new().bind_tls(addr, TlsConfig::new(cert_pem, key_pem)?)
+    }
+
+    /// Return a `ServerBuilder` for configuring a new server.
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// Also listen on `addr` for plain HTTP, and return the address bound.
+    /// Every listener serves the same handler on the same pool.
+    pub fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<SocketAddr> {
+        self.add(Listener::plain(TcpListener::bind(addr)?))
+    }
+
+    /// Also listen on `addr` for HTTPS with the certificate and key in
+    /// `tls`, and return the address bound.
+    #[cfg(feature = "tls")]
+    pub fn listen_tls<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        tls: TlsConfig,
+    ) -> io::Result<SocketAddr> {
+        let listener = Listener::tls(TcpListener::bind(addr)?, tls, &self.config)?;
+        self.add(listener)
+    }
+
+    fn add(&mut self, listener: Listener) -> io::Result<SocketAddr> {
+        let addr = listener.tcp.local_addr()?;
+        self.listeners.push(listener);
+        Ok(addr)
+    }
+
+    /// Return the address the server is listening on, the first one if it
+    /// listens on several.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].tcp.local_addr()
+    }
+
+    /// Return every address the server is listening on, in the order they
+    /// were bound.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(|l| l.tcp.local_addr()).collect()
+    }
+
+    /// Return the pool the server runs handlers on, e.g. to schedule
+    /// maintenance jobs next to them.
+    pub fn pool(&self) -> &ThreadPool {
+        &self.pool
+    }
+
+    /// Return a handle for stopping the server from another thread, e.g.
+    /// a handler.
+    pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+        Ok(ShutdownHandle::new(
+            self.shutdown.clone(),
+            self.local_addrs()?,
+        ))
+    }
+
+    /// Accept connections and run `handler` on the pool for the requests
+    /// read from each one, sending back the responses it returns.
+    ///
+    /// `handler` can be a `Router`, a closure taking a `Request`, or any
+    /// other `Handler`. A closure may need its argument annotated, as in
+    /// `|request: Request| ...`.
+    ///
+    /// HTTP/1.1 connections are kept open for further requests unless the
+    /// client or the handler sends `Connection: close`; HTTP/1.0 ones only
+    /// if the client asks with `Connection: keep-alive`.
+    ///
+    /// A malformed request is answered with `400 Bad Request` without
+    /// calling the handler. Failing to accept a connection is logged and
+    /// does not stop the server.
+    ///
+    /// The first listener is served on the calling thread, and any others
+    /// added with `listen` on threads of their own.
+    ///
+    /// Returns after a shutdown, started with `shutdown_handle` or a
+    /// signal, or once the pool stops taking jobs, which happens when a
+    /// worker escalates a panic. Either way the server stops accepting
+    /// connections and closes the idle ones. Requests in progress get up
+    /// to the drain timeout to be answered, after which their connections
+    /// are closed and the pool is shut down.
+    pub fn run<H: Handler>(mut self, handler: H) -> Result<(), ServerError> {
+        for listener in &self.listeners {
+            let scheme = if listener.is_tls() { "https" } else { "http" };
+            info!("Listening on {}://{}.", scheme, listener.tcp.local_addr()?);
+        }
+        #[cfg(feature = "signals")]
+        if let Err(err) = self.watch_signals() {
+            warn!("Failed to watch signals: {}", err);
+        }
+        let dispatcher = Arc::new(Dispatcher {
+            handler: Arc::new(handler),
+            config: Arc::clone(&self.config),
+            connections: Arc::clone(&self.connections),
+            pool: self.pool.handle(),
+        });
+        let triage = match self.config.health_check {
+            Some(_) => {
+                let dispatcher = Arc::clone(&dispatcher);
+                let dispatch = move |stream| {
+                    dispatcher.dispatch(Stream::Plain(stream));
+                };
+                Some(Triage::spawn(Arc::clone(&self.config), dispatch)?)
+            }
+            None => None,
+        };
+        let acceptor = Acceptor {
+            dispatcher: &dispatcher,
+            triage: triage.as_ref(),
+            config: &self.config,
+            stopping: Stopping {
+                handle: self.shutdown_handle()?,
+                delay: self
+                    .config
+                    .health_check
+                    .as_ref()
+                    .map_or(Duration::ZERO, HealthCheck::delay),
+                since: Mutex::new(None),
+                aborted: AtomicBool::new(false),
+            },
+        };
+        let (first, rest) = self.listeners.split_first().expect("no listener");
+        thread::scope(|scope| {
+            for listener in rest {
+                let acceptor = &acceptor;
+                let spawned = thread::Builder::new()
+                    .name("hello-accept".to_string())
+                    .spawn_scoped(scope, move || acceptor.run(listener));
+                if let Err(err) = spawned {
+                    // 起動した thread が返るまで scope は終わらない。
+                    acceptor.stopping.abort();
+                    return Err(err);
+                }
+            }
+            acceptor.run(first);
+            Ok(())
+        })?;
+        drop(triage);
+        self.drain();
+        Ok(())
+    }
+
+    fn drain(&mut self) {
+        let idle = self.connections.drain();
+        info!(
+            "Stopped accepting connections. Closed {} idle ones; waiting up to {:?} for the rest.",
+            idle, self.config.drain_timeout
+        );
+        let report = self.pool.shutdown(self.config.drain_timeout);
+        if report.is_complete() {
+            info!("Drained all connections in {:?}.", report.elapsed);
+        } else {
+            warn!(
+                "Gave up on {} connections still open after {:?}.",
+                report.abandoned, report.elapsed
+            );
+        }
+    }
+
+    // SIGINT と SIGTERM は 1 回目で drain を始め、 2 回目ですぐに終了する。
+    #[cfg(feature = "signals")]
+    fn watch_signals(&self) -> io::Result<()> {
+        if let Some(ref on_reload) = self.config.on_reload {
+            let on_reload = Arc::clone(on_reload);
+            signal::on_signal(signal::SIGHUP, move || {
+                info!("Received SIGHUP. Reloading.");
+                on_reload();
+            })?;
+        }
+        // file から読んだ証明書も読み直す。
+        #[cfg(feature = "tls")]
+        for listener in &self.listeners {
+            let tls = match listener.tls {
+                Some(ref tls) if tls.is_reloadable() => Arc::clone(tls),
+                _ => continue,
+            };
+            let addr = listener.tcp.local_addr()?;
+            signal::on_signal(signal::SIGHUP, move || match tls.reload() {
+                Ok(()) => info!("Reloaded the TLS certificate for {}.", addr),
+                Err(err) => warn!("Failed to reload the TLS certificate for {}: {}", addr, err),
+            })?;
+        }
+        if !self.config.shutdown_on_signals {
+            return Ok(());
+        }
+        for signal in [signal::SIGINT, signal::SIGTERM] {
+            let handle = self.shutdown_handle()?;
+            signal::on_signal(signal, move || {
+                if handle.is_shut_down() {
+                    warn!("Received another signal. Exiting now.");
+                    process::exit(1);
+                }
+                info!("Received a signal. Shutting down.");
+                handle.shutdown();
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// listener ごとの accept の loop。 listener が複数なら thread の間で共有する。
+struct Acceptor<'a, H> {
+    dispatcher: &'a Dispatcher<H>,
+    triage: Option<&'a Triage>,
+    config: &'a Config,
+    stopping: Stopping,
+}
+
+impl<H: Handler> Acceptor<'_, H> {
+    fn run(&self, listener: &Listener) {
+        for stream in listener.tcp.incoming() {
+            if self.stopping.is_done() {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Failed to accept a connection: {}", err);
+                    continue;
+                }
+            };
+            if let Some(ref filter) = self.config.ip_filter {
+                // 相手が分からなければ、許されているとは言えない。
+                match stream.peer_addr() {
+                    Ok(addr) if filter.allows(addr.ip()) => {}
+                    peer => {
+                        debug!("Refusing a connection from {:?}", peer.ok());
+                        continue;
+                    }
+                }
+            }
+            // worker が空いていなければ、 health check に先に答える。 TLS の
+            // 接続は handshake をしないと中が見えないので、 worker に任せる。
+            let stream = match self.triage {
+                Some(triage) if !listener.is_tls() && self.dispatcher.pool.idle_workers() == 0 => {
+                    match triage.send(stream) {
+                        Ok(()) => continue,
+                        Err(stream) => stream,
+                    }
+                }
+                _ => stream,
+            };
+            let stream = match listener.wrap(stream) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Failed to start a TLS session: {}", err);
+                    continue;
+                }
+            };
+            if !self.dispatcher.dispatch(stream) {
+                error!("All workers have stopped. Shutting down.");
+                self.stopping.abort();
+                break;
+            }
+        }
+    }
+}
+
+// shutdown が始まってから accept をやめるまで。 health check が ready でないと
+// 伝える間は、まだ受け付ける。
+struct Stopping {
+    handle: ShutdownHandle,
+    delay: Duration,
+    since: Mutex<Option<Instant>>,
+    // pool が止まったら、待たずにやめる。
+    aborted: AtomicBool,
+}
+
+impl Stopping {
+    // accept をやめるなら true。
+    fn is_done(&self) -> bool {
+        if self.aborted.load(Ordering::SeqCst) {
+            return true;
+        }
+        if !self.handle.is_shut_down() {
+            return false;
+        }
+        let mut since = self.since.lock().unwrap_or_else(|p| p.into_inner());
+        let since = *since.get_or_insert_with(|| {
+            self.wake_after();
+            Instant::now()
+        });
+        since.elapsed() >= self.delay
+    }
+
+    // 他の listener の accept も止める。
+    fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+        if self.handle.is_shut_down() {
+            self.handle.wake();
+        } else {
+            self.handle.shutdown();
+        }
+    }
+
+    fn wake_after(&self) {
+        if self.delay.is_zero() {
+            return;
+        }
+        info!("Reporting not ready for {:?} before draining.", self.delay);
+        let handle = self.handle.clone();
+        let delay = self.delay;
+        thread::spawn(move || {
+            thread::sleep(delay);
+            handle.wake();
+        });
+    }
+}
+
+// accept した接続を pool の job にする。
+struct Dispatcher<H> {
+    handler: Arc<H>,
+    config: Arc<Config>,
+    connections: Arc<Connections>,
+    pool: PoolHandle,
+}
+
+impl<H: Handler> Dispatcher<H> {
+    // pool が止まっていたら false。
+    fn dispatch(&self, stream: Stream) -> bool {
+        let connection = match self.connections.register(stream.tcp()) {
+            Some(connection) => connection,
+            None => return true,
+        };
+        // queue が一杯のときに 503 を返すための写し。 TLS の接続には
+        // handshake の前なので返せない。
+        let rejected = match stream {
+            Stream::Plain(ref tcp) => Some(tcp.try_clone()),
+            #[cfg(feature = "tls")]
+            Stream::Tls(_) => None,
+        };
+        let handler = Arc::clone(&self.handler);
+        let config = Arc::clone(&self.config);
+        let pool = self.pool.clone();
+        // 接続は accept する thread では決して扱わないので、 rejection
+        // policy を通さずに渡す。
+        match self
+            .pool
+            .try_execute(move || serve(stream, connection, &handler, &config, &pool))
+        {
+            Ok(()) => true,
+            Err(TryExecuteError::Full(job)) => {
+                drop(job);
+                if let Some(Ok(stream)) = rejected {
+                    reject(stream, self.config.write_timeout);
+                }
+                true
+            }
+            Err(TryExecuteError::Disconnected(_)) => false,
+        }
+    }
+}
+
+// pool に空きがない接続に、 request を読まずに 503 を返して閉じる。
+fn reject(mut stream: TcpStream, write_timeout: Option<Duration>) {
+    debug!("Rejecting a connection: the job queue is full.");
+    let response = Response::new(Status::ServiceUnavailable)
+        .header("Retry-After", "1")
+        .header("Connection", "close");
+    let written = stream
+        .set_write_timeout(write_timeout.or(Some(DEFAULT_TIMEOUT)))
+        .and_then(|_| response.write_as(&mut stream, Version::Http11));
+    if let Err(err) = written {
+        debug!("Failed to write a response: {}", err);
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+fn serve<H: Handler>(
+    stream: Stream,
+    connection: Connection,
+    handler: &Arc<H>,
+    config: &Arc<Config>,
+    pool: &PoolHandle,
+) {
+    let timeouts = stream
+        .set_read_timeout(config.read_timeout)
+        .and_then(|_| stream.set_write_timeout(config.write_timeout));
+    if let Err(err) = timeouts {
+        warn!("Failed to set socket timeouts: {}", err);
+        return;
+    }
+    let remote_addr = stream.peer_addr().ok();
+    // 同じ接続の次の request の分まで読んでいることがあるので、
+    // reader は接続の間使い回す。
+    let mut reader = BufReader::new(Conn {
+        stream,
+        read_timeout: config.read_timeout,
+        deadline: None,
+        applied: config.read_timeout,
+    });
+    let mut first = true;
+    // TLS の client 証明書は handshake を終えた後で読める。
+    let mut peer = None;
+    loop {
+        // drain が始まったら、次の request は待たない。
+        if !connection.set_idle(true) {
+            return;
+        }
+        // 何も送らない client が worker を持ち続けないよう、 header の期限は
+        // 待ち始めたときから数える。
+        reader.get_mut().deadline = config.header_timeout.map(|t| Instant::now() + t);
+        // HTTP/2 の preface は "PRI * HTTP/2.0" で始まる。
+        let preface = match reader.fill_buf() {
+            Ok([]) => return,
+            Ok(buf) => buf.starts_with(b"PRI "),
+            Err(err) => {
+                debug!("Failed to read a request: {}", err);
+                return;
+            }
+        };
+        // TLS の接続なら、 HTTP/2 は handshake の ALPN で選ばれている。
+        // HTTP/2 の接続は frame を待つ間ずっと idle とする。
+        if first && config.http2 && (preface || reader.get_ref().stream.is_h2()) {
+            reader.get_mut().deadline = None;
+            let stream = &reader.get_ref().stream;
+            let peer = stream.peer_identity();
+            match stream.try_clone() {
+                Ok(writer) => http2::serve(
+                    &mut reader,
+                    writer,
+                    remote_addr,
+                    peer,
+                    handler,
+                    config,
+                    pool,
+                ),
+                Err(err) => warn!("Failed to clone a connection: {}", err),
+            }
+            return;
+        }
+        connection.set_idle(false);
+        if first {
+            peer = reader.get_ref().stream.peer_identity();
+        }
+        first = false;
+        let started = Instant::now();
+        let time = SystemTime::now();
+        let mut head = None;
+        let result =
+            Request::read_limited(&mut reader, &config.limits, |reader, expects_continue| {
+                reader.get_mut().deadline = None;
+                // curl などは body を送る前にこれを待つ。
+                if expects_continue {
+                    let conn = reader.get_mut();
+                    if let Err(err) = conn
+                        .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                        .and_then(|_| conn.flush())
+                    {
+                        debug!("Failed to send 100 Continue: {}", err);
+                    }
+                }
+            });
+        let (mut response, version, mut keep_alive) = match result {
+            Ok(Some(mut request)) => {
+                request.set_remote_addr(remote_addr);
+                request.set_peer_identity(peer.clone());
+                if config.access_log.is_some() {
+                    head = Some(Head::new(&request));
+                }
+                let version = request.version();
+                let keep_alive = config.keep_alive && wants_keep_alive(&request);
+                let (response, panicked) = respond(request, &**handler, config);
+                (response, version, keep_alive && !panicked)
+            }
+            // 何も送らずに閉じられた。
+            Ok(None) => return,
+            // 次の request を待つ間の timeout もここに来る。
+            Err(ParseError::Io(err)) => {
+                debug!("Failed to read a request: {}", err);
+                return;
+            }
+            Err(err) => {
+                debug!("Rejecting a malformed request: {}", err);
+                let status = match err {
+                    ParseError::UnsupportedVersion => Status::HttpVersionNotSupported,
+                    ParseError::UnsupportedTransferEncoding => Status::NotImplemented,
+                    ParseError::Timeout => Status::RequestTimeout,
+                    ParseError::UriTooLong => Status::UriTooLong,
+                    ParseError::HeadersTooLarge => Status::RequestHeaderFieldsTooLarge,
+                    ParseError::BodyTooLarge => Status::PayloadTooLarge,
+                    ParseError::UnsupportedExpectation => Status::ExpectationFailed,
+                    _ => Status::BadRequest,
+                };
+                // どこまでが壊れた request なのか分からないので、続けられない。
+                (Response::new(status), Version::Http11, false)
+            }
+        };
+
+        // upgrade する接続は response の Connection: Upgrade をそのまま送る。
+        let upgrade = response
+            .take_upgrade()
+            .filter(|_| response.status() == Status::SwitchingProtocols);
+        if upgrade.is_none() {
+            if connection.is_draining()
+                || has_token(response.headers().get_all("Connection"), "close")
+            {
+                keep_alive = false;
+            }
+            // HTTP/1.0 では stream の終わりを接続を閉じて伝えるしかない。
+            if version == Version::Http10 && response.is_streaming() {
+                keep_alive = false;
+            }
+            let headers = response.headers_mut();
+            if !keep_alive {
+                headers.insert("Connection", "close");
+            } else if version == Version::Http10 {
+                headers.insert("Connection", "keep-alive");
+            }
+        }
+
+        let status = response.status();
+        let request_id = config
+            .access_log
+            .as_ref()
+            .and_then(|_| response.headers().get("X-Request-Id"))
+            .map(str::to_owned);
+        let written = response.write_as(reader.get_mut(), version);
+        if let Some(ref log) = config.access_log {
+            log.log(&Entry {
+                time,
+                remote_addr,
+                head,
+                status,
+                bytes: *written.as_ref().unwrap_or(&0),
+                latency: started.elapsed(),
+                request_id,
+            });
+        }
+        if let Err(err) = written {
+            debug!("Failed to write a response: {}", err);
+            return;
+        }
+        if let Some(upgrade) = upgrade {
+            // ここからは別の protocol なので、接続が終わるまで返さない。
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| upgrade(reader))) {
+                error!(
+                    "Failed to handle an upgraded connection: {}",
+                    panic_message(&*payload)
+                );
+            }
+            return;
+        }
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+// middleware と handler を通して response を作る。 panic したら 500 にして、
+// true を返す。
+pub(crate) fn respond<H: Handler>(
+    request: Request,
+    handler: &H,
+    config: &Config,
+) -> (Response, bool) {
+    let errors = &config.errors;
+    let is_head = *request.method() == Method::Head;
+    // handler に渡した後の error page 用に、登録があるときだけ写しておく。
+    let head = (!errors.is_empty()).then(|| request.without_body());
+    let head = head.as_ref();
+    let panicked = Cell::new(false);
+    let recover = |payload: Box<dyn Any + Send>| {
+        panicked.set(true);
+        let error = HttpError::Panic(panic_message(&*payload));
+        error!("Failed to handle a request: {}", error);
+        errors
+            .render(&error, head)
+            .unwrap_or_else(|| Response::new(error.status()))
+    };
+    // handler の panic は middleware の中で 500 にして、 middleware にも見せる。
+    let endpoint = |request: Request| match panic::catch_unwind(AssertUnwindSafe(|| {
+        handler.handle(request)
+    })) {
+        Ok(response) => errors.replace(response, head),
+        Err(payload) => recover(payload),
+    };
+    let health = config
+        .health_check
+        .as_ref()
+        .and_then(|check| check.respond(&request));
+    let mut response = match health {
+        Some(response) => response,
+        None => panic::catch_unwind(AssertUnwindSafe(|| {
+            Next::new(&config.middlewares, &endpoint).run(request)
+        }))
+        .unwrap_or_else(&recover),
+    };
+    // HEAD には GET と同じ header を、 body なしで返す。
+    if is_head {
+        response.omit_body();
+    }
+    (response, panicked.get())
+}
+
+// header を読み終えるまでの期限をかけられる接続。
+pub(crate) struct Conn {
+    stream: Stream,
+    read_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    // socket に今設定してある read timeout。
+    applied: Option<Duration>,
+}
+
+impl Conn {
+    // upgrade した接続で read timeout を変える。
+    pub(crate) fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "header timeout elapsed",
+                    ));
+                }
+                Some(self.read_timeout.map_or(left, |t| t.min(left)))
+            }
+            None => self.read_timeout,
+        };
+        if timeout != self.applied {
+            self.stream.set_read_timeout(timeout)?;
+            self.applied = timeout;
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn wants_keep_alive(request: &Request) -> bool {
+    let connection = request.headers().get_all("Connection");
+    match request.version() {
+        Version::Http11 | Version::Http2 => !has_token(connection, "close"),
+        Version::Http10 => has_token(connection, "keep-alive"),
+    }
+}
+
+// Connection は "keep-alive, Upgrade" のような token の列。
+pub(crate) fn has_token<'a, I: Iterator<Item = &'a str>>(mut values: I, token: &str) -> bool {
+    values.any(|value| {
+        value
+            .split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+/// An error returned by `ServerBuilder::bind` and `Server::run`.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The thread pool could not be created.
+    Pool(PoolCreationError),
+    /// A socket operation failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerError::Pool(ref err) => write!(f, "failed to create the thread pool: {}", err),
+            ServerError::Io(ref err) => write!(f, "socket error: {}", err),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ServerError::Pool(ref err) => Some(err),
+            ServerError::Io(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<PoolCreationError> for ServerError {
+    fn from(err: PoolCreationError) -> ServerError {
+        ServerError::Pool(err)
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> ServerError {
+        ServerError::Io(err)
+    }
+}
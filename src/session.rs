@@ -0,0 +1,420 @@
+//! Sessions kept across requests with a signed cookie.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use super::auth::constant_time_eq;
+use super::sha256;
+use super::{
+    Cookie, Middleware, Next, Request, Response, SameSite, ScheduleHandle, Status, ThreadPool,
+};
+
+const DEFAULT_COOKIE_NAME: &str = "session";
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const MIN_SECRET_LEN: usize = 32;
+
+/// The values of a session.
+pub type SessionData = HashMap<String, String>;
+
+/// Where `Sessions` keeps session data between requests.
+///
+/// Implement it to keep sessions in a database or cache shared by several
+/// servers; `MemoryStore` keeps them in the process.
+pub trait SessionStore: Send + Sync + 'static {
+    /// Return the data of session `id`, unless it is unknown or expired.
+    fn load(&self, id: &str) -> Option<SessionData>;
+
+    /// Store `data` as session `id` until `expires`.
+    fn save(&self, id: &str, data: SessionData, expires: SystemTime);
+
+    /// Forget session `id`.
+    fn remove(&self, id: &str);
+
+    /// Forget every expired session. `Sessions::schedule_eviction` calls
+    /// this periodically; stores that expire entries themselves can leave
+    /// it empty.
+    fn evict_expired(&self);
+}
+
+/// A `SessionStore` that keeps sessions in memory, so they are lost when
+/// the process exits.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (SessionData, SystemTime)>>,
+}
+
+impl MemoryStore {
+    /// Create an empty store.
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    /// Return the number of sessions stored, including expired ones not
+    /// evicted yet.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Return `true` if no sessions are stored.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, (SessionData, SystemTime)>> {
+        match self.sessions.lock() {
+            Ok(sessions) => sessions,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let sessions = self.lock();
+        let (data, expires) = sessions.get(id)?;
+        (*expires > SystemTime::now()).then(|| data.clone())
+    }
+
+    fn save(&self, id: &str, data: SessionData, expires: SystemTime) {
+        self.lock().insert(id.to_owned(), (data, expires));
+    }
+
+    fn remove(&self, id: &str) {
+        self.lock().remove(id);
+    }
+
+    fn evict_expired(&self) {
+        let now = SystemTime::now();
+        self.lock().retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+/// A middleware that gives every request a `Session`, available from
+/// `Request::session`.
+///
+/// The session id travels in an `HttpOnly`, `SameSite=Lax` cookie signed
+/// with HMAC-SHA256, so clients cannot make up ids; the data stays in the
+/// store. A session is created the first time a value is set, and lasts
+/// for the time to live after the last request that used it. Run
+/// `schedule_eviction` to clear expired sessions out of the store.
+///
+/// Session ids are read from the OS random source. If it cannot be read,
+/// requests without a session are answered with `500 Internal Server
+/// Error` rather than given an id that could be guessed.
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    secret: Vec<u8>,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+}
+
+impl Sessions {
+    /// Create a layer that keeps sessions in `store` and signs their
+    /// cookies with `secret`. Keep the secret out of the source, and the
+    /// same across restarts and servers sharing the store.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `secret` is shorter than 32 bytes.
+    pub fn new<S: SessionStore>(store: S, secret: &[u8]) -> Sessions {
+        assert!(
+            secret.len() >= MIN_SECRET_LEN,
+            "a session secret needs at least {} bytes",
+            MIN_SECRET_LEN
+        );
+        Sessions {
+            store: Arc::new(store),
+            secret: secret.to_vec(),
+            cookie_name: DEFAULT_COOKIE_NAME.to_owned(),
+            ttl: DEFAULT_TTL,
+            secure: false,
+        }
+    }
+
+    /// Set the name of the cookie. Defaults to `session`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not a valid cookie name.
+    pub fn cookie_name<S: Into<String>>(mut self, name: S) -> Sessions {
+        let name = name.into();
+        // 名前の検査は Cookie に任せる。
+        Cookie::new(name.clone(), "");
+        self.cookie_name = name;
+        self
+    }
+
+    /// Set how long a session lasts after its last request. Defaults to a
+    /// day.
+    pub fn ttl(mut self, ttl: Duration) -> Sessions {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Set whether the cookie is only sent over HTTPS. Defaults to `false`;
+    /// turn it on when the site is served over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Sessions {
+        self.secure = secure;
+        self
+    }
+
+    /// Evict expired sessions from the store every `interval` on `pool`,
+    /// e.g. `Server::pool`. Returns `None` if the pool has shut down.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn schedule_eviction(
+        &self,
+        pool: &ThreadPool,
+        interval: Duration,
+    ) -> Option<ScheduleHandle> {
+        let store = Arc::clone(&self.store);
+        pool.execute_every(interval, move || store.evict_expired())
+            .ok()
+    }
+
+    fn sign(&self, id: &str) -> String {
+        hex(&sha256::hmac(&self.secret, id.as_bytes()))
+    }
+
+    // 署名の合う cookie の id。
+    fn verified_id(&self, request: &Request) -> Option<String> {
+        let cookies = request.cookies();
+        let (id, signature) = cookies.get(&self.cookie_name)?.split_once('.')?;
+        let expected = self.sign(id);
+        constant_time_eq(expected.as_bytes(), signature.as_bytes()).then(|| id.to_owned())
+    }
+
+    fn cookie(&self, id: &str) -> Cookie {
+        Cookie::new(
+            self.cookie_name.clone(),
+            format!("{}.{}", id, self.sign(id)),
+        )
+        .path("/")
+        .max_age(self.ttl)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(self.secure)
+    }
+}
+
+impl Middleware for Sessions {
+    fn handle(&self, mut request: Request, next: Next<'_>) -> Response {
+        let loaded = self
+            .verified_id(&request)
+            .and_then(|id| self.store.load(&id).map(|data| (id, data)));
+        let state = match loaded {
+            Some((id, data)) => State::new(id, data, true),
+            None => match generate_id() {
+                Ok(id) => State::new(id, SessionData::new(), false),
+                Err(err) => {
+                    error!("Failed to create a session id: {}", err);
+                    return Response::new(Status::InternalServerError);
+                }
+            },
+        };
+        let session = Session(Arc::new(Mutex::new(state)));
+        request.set_session(session.clone());
+
+        let mut response = next.run(request);
+        let state = session.lock();
+        if let Some(ref old) = state.renewed_from {
+            self.store.remove(old);
+        }
+        if state.destroyed {
+            if state.stored || state.renewed_from.is_some() {
+                self.store.remove(&state.id);
+                let removal = Cookie::removal(self.cookie_name.clone()).path("/");
+                response = response.cookie(removal);
+            }
+            return response;
+        }
+        // 使われた session は期限を延ばす。空のままの新しい session は作らない。
+        if state.stored || state.changed {
+            let expires = SystemTime::now() + self.ttl;
+            self.store.save(&state.id, state.data.clone(), expires);
+            response = response.cookie(self.cookie(&state.id));
+        }
+        response
+    }
+}
+
+impl fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sessions")
+            .field("cookie_name", &self.cookie_name)
+            .field("ttl", &self.ttl)
+            .field("secure", &self.secure)
+            .finish()
+    }
+}
+
+/// The session of a request, returned by `Request::session`.
+///
+/// Changes are saved to the store once the response passes back through
+/// `Sessions`.
+#[derive(Debug, Clone)]
+pub struct Session(Arc<Mutex<State>>);
+
+#[derive(Debug)]
+struct State {
+    id: String,
+    data: SessionData,
+    // store に入っている session か。
+    stored: bool,
+    changed: bool,
+    destroyed: bool,
+    renewed_from: Option<String>,
+}
+
+impl State {
+    fn new(id: String, data: SessionData, stored: bool) -> State {
+        State {
+            id,
+            data,
+            stored,
+            changed: false,
+            destroyed: false,
+            renewed_from: None,
+        }
+    }
+}
+
+impl Session {
+    /// Return the value stored under `key`.
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.lock().data.get(key).cloned()
+    }
+
+    /// Store `value` under `key`, starting the session if it is new.
+    pub fn set<K: Into<String>, V: Into<String>>(&self, key: K, value: V) {
+        let mut state = self.lock();
+        state.data.insert(key.into(), value.into());
+        state.changed = true;
+    }
+
+    /// Remove the value under `key` and return it.
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let mut state = self.lock();
+        let value = state.data.remove(key);
+        state.changed |= value.is_some();
+        value
+    }
+
+    /// Return the id of the session.
+    pub fn id(&self) -> String {
+        self.lock().id.clone()
+    }
+
+    /// Give the session a new id, keeping its values. Do this when a user
+    /// logs in, so an id planted before cannot be used to take over the
+    /// session.
+    ///
+    /// Returns an error, keeping the old id, if the OS random source
+    /// cannot be read.
+    pub fn renew(&self) -> io::Result<()> {
+        let id = generate_id()?;
+        let mut state = self.lock();
+        let old = std::mem::replace(&mut state.id, id);
+        if state.stored && state.renewed_from.is_none() {
+            state.renewed_from = Some(old);
+        }
+        state.stored = false;
+        state.changed = true;
+        Ok(())
+    }
+
+    /// End the session, deleting its values and its cookie, e.g. when the
+    /// user logs out.
+    pub fn destroy(&self) {
+        let mut state = self.lock();
+        state.data.clear();
+        state.destroyed = true;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.0.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+// OS の乱数から作る 128 bit の id。推測できる id は使わない。
+fn generate_id() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(hex(&bytes))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_id, MemoryStore, Sessions};
+    use crate::Request;
+
+    const ID: &str = "0123456789abcdef0123456789abcdef";
+    // Python の hmac.new(b"\x07" * 32, ID, "sha256") で確かめた値。
+    const SIGNATURE: &str = "d2f7b4e7089a4717a9bc7f4b169944c8adaba6c48bbcf9673b34d819693eb5a1";
+
+    #[test]
+    fn signs_ids_with_hmac_sha256() {
+        let sessions = Sessions::new(MemoryStore::new(), &[7; 32]);
+        assert_eq!(sessions.sign(ID), SIGNATURE);
+    }
+
+    #[test]
+    fn accepts_only_signed_ids() {
+        let sessions = Sessions::new(MemoryStore::new(), &[7; 32]);
+        let head = format!(
+            "GET / HTTP/1.1\r\nCookie: session={}.{}\r\n\r\n",
+            ID, SIGNATURE
+        );
+        let signed = Request::read_from(&mut head.as_bytes()).unwrap().unwrap();
+        assert_eq!(sessions.verified_id(&signed).as_deref(), Some(ID));
+
+        let mut forged = SIGNATURE.to_string();
+        forged.replace_range(..1, "e");
+        for cookie in [
+            format!("session={}.{}", ID, forged),
+            format!("session={}.{}", ID, &SIGNATURE[..63]),
+            format!("session={}0.{}", ID, SIGNATURE),
+            format!("session={}", ID),
+            format!("other={}.{}", ID, SIGNATURE),
+        ] {
+            let head = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", cookie);
+            let request = Request::read_from(&mut head.as_bytes()).unwrap().unwrap();
+            assert_eq!(sessions.verified_id(&request), None, "{}", cookie);
+        }
+    }
+
+    #[test]
+    fn rejects_a_signature_under_another_secret() {
+        let other = Sessions::new(MemoryStore::new(), &[8; 32]);
+        let head = format!(
+            "GET / HTTP/1.1\r\nCookie: session={}.{}\r\n\r\n",
+            ID, SIGNATURE
+        );
+        let signed = Request::read_from(&mut head.as_bytes()).unwrap().unwrap();
+        assert_eq!(other.verified_id(&signed), None);
+    }
+
+    #[test]
+    fn generates_distinct_ids() {
+        let a = generate_id().unwrap();
+        let b = generate_id().unwrap();
+        assert_eq!(a.len(), 32);
+        assert!(a.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+}
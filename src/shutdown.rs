@@ -0,0 +1,157 @@
+//! Stopping a running `Server` and draining its connections.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::CancellationToken;
+
+/// A handle for stopping a running `Server`, returned by
+/// `Server::shutdown_handle`. Clones stop the same server.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    token: CancellationToken,
+    addrs: Vec<SocketAddr>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(token: CancellationToken, addrs: Vec<SocketAddr>) -> ShutdownHandle {
+        ShutdownHandle { token, addrs }
+    }
+
+    /// Make the server stop accepting connections and drain the open ones,
+    /// after which `Server::run` returns. Calling it again does nothing.
+    pub fn shutdown(&self) {
+        if self.is_shut_down() {
+            return;
+        }
+        self.token.cancel();
+        self.wake();
+    }
+
+    /// Return `true` once `shutdown` has been called.
+    pub fn is_shut_down(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    // accept で待っている server を、 listener ごとに起こす。
+    pub(crate) fn wake(&self) {
+        for &addr in &self.addrs {
+            let mut addr = addr;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            if let Err(err) = TcpStream::connect(addr) {
+                debug!("Failed to wake the server for a shutdown: {}", err);
+            }
+        }
+    }
+}
+
+// 開いている接続。 drain のときに、次の request を待っているだけの接続を閉じる。
+#[derive(Default)]
+pub(crate) struct Connections {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    draining: bool,
+    next_id: u64,
+    // 接続と、次の request を待っているところか。
+    open: HashMap<u64, (TcpStream, bool)>,
+}
+
+impl Connections {
+    // drain 中なら None。 Connection が drop されると外れる。
+    pub(crate) fn register(self: &Arc<Self>, stream: &TcpStream) -> Option<Connection> {
+        let stream = match stream.try_clone() {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Failed to clone a connection: {}", err);
+                return None;
+            }
+        };
+        let mut state = self.lock();
+        if state.draining {
+            return None;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.open.insert(id, (stream, false));
+        Some(Connection {
+            connections: Arc::clone(self),
+            id,
+        })
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.lock().draining
+    }
+
+    // 新しい接続を断り、待っているだけの接続の読み込みを止める。
+    // request の途中の接続は、 response を返した後に閉じる。
+    pub(crate) fn drain(&self) -> usize {
+        let mut state = self.lock();
+        state.draining = true;
+        let mut closed = 0;
+        for (stream, idle) in state.open.values() {
+            if *idle {
+                let _ = stream.shutdown(Shutdown::Read);
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl fmt::Debug for Connections {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("Connections")
+            .field("draining", &state.draining)
+            .field("open", &state.open.len())
+            .finish()
+    }
+}
+
+// Connections に登録した接続。
+pub(crate) struct Connection {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Connection {
+    // 次の request を待ち始めるところか、読み始めたところかを伝える。
+    // drain 中に待ち始めようとしたら false。
+    pub(crate) fn set_idle(&self, idle: bool) -> bool {
+        let mut state = self.connections.lock();
+        if idle && state.draining {
+            return false;
+        }
+        if let Some(entry) = state.open.get_mut(&self.id) {
+            entry.1 = idle;
+        }
+        true
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.connections.is_draining()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.connections.lock().open.remove(&self.id);
+    }
+}
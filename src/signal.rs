@@ -0,0 +1,112 @@
+//! Running callbacks when the process receives Unix signals. Only Linux is
+//! supported; elsewhere watching a signal fails.
+
+use std::io;
+
+type Callback = Box<dyn Fn() + Send + Sync + 'static>;
+
+// `signal` を受け取るたびに、 signal handler の外の thread で `f` を呼ぶ。
+pub(crate) fn on_signal<F>(signal: i32, f: F) -> io::Result<()>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    imp::on_signal(signal, Box::new(f))
+}
+
+// どの Unix でも同じ番号。
+pub(crate) const SIGHUP: i32 = 1;
+pub(crate) const SIGINT: i32 = 2;
+pub(crate) const SIGTERM: i32 = 15;
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+    use std::ptr;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::{Mutex, OnceLock};
+    use std::thread;
+
+    use super::Callback;
+
+    // handler が signal の番号を書き込む pipe。
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+    static CALLBACKS: OnceLock<Mutex<Vec<(i32, Callback)>>> = OnceLock::new();
+
+    // signal handler の中では pipe に書くことしかしない。
+    extern "C" fn handle(signal: ::libc::c_int) {
+        let fd = PIPE.load(Ordering::Relaxed);
+        if fd >= 0 {
+            let byte = signal as u8;
+            unsafe { ::libc::write(fd, &byte as *const u8 as *const ::libc::c_void, 1) };
+        }
+    }
+
+    pub(super) fn on_signal(signal: i32, f: Callback) -> io::Result<()> {
+        let callbacks = match CALLBACKS.get() {
+            Some(callbacks) => callbacks,
+            None => start()?,
+        };
+        callbacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push((signal, f));
+
+        let mut action: ::libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = handle as extern "C" fn(::libc::c_int) as ::libc::sighandler_t;
+        // accept などは signal で止めずに続けさせる。
+        action.sa_flags = ::libc::SA_RESTART;
+        unsafe { ::libc::sigemptyset(&mut action.sa_mask) };
+        if unsafe { ::libc::sigaction(signal, &action, ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // pipe と、それを読んで callback を呼ぶ thread を用意する。
+    fn start() -> io::Result<&'static Mutex<Vec<(i32, Callback)>>> {
+        static STARTED: Mutex<()> = Mutex::new(());
+        let _started = STARTED
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(callbacks) = CALLBACKS.get() {
+            return Ok(callbacks);
+        }
+        let mut fds = [0; 2];
+        if unsafe { ::libc::pipe2(fds.as_mut_ptr(), ::libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+        let callbacks = CALLBACKS.get_or_init(|| Mutex::new(Vec::new()));
+        thread::Builder::new()
+            .name("hello-signals".to_string())
+            .spawn(move || {
+                let mut byte = [0; 1];
+                while let Ok(1) = reader.read(&mut byte) {
+                    let signal = i32::from(byte[0]);
+                    let callbacks = callbacks.lock().unwrap_or_else(|p| p.into_inner());
+                    for (_, f) in callbacks.iter().filter(|(s, _)| *s == signal) {
+                        f();
+                    }
+                }
+            })?;
+        PIPE.store(fds[1], Ordering::Relaxed);
+        Ok(callbacks)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+
+    use super::Callback;
+
+    pub(super) fn on_signal(_signal: i32, _f: Callback) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "signal handling is only supported on Linux",
+        ))
+    }
+}
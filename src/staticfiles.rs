@@ -0,0 +1,527 @@
+//! Serving files from a directory.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::compression::{add_vary, preferred_encoding, Encoding};
+use super::date::{http_date, parse_http_date, DateTime};
+use super::http::{percent_decode, percent_encode};
+use super::{Request, Response, Status};
+
+/// Serves the files under a directory.
+///
+/// Mount one on a router with `Router::serve_static`, or use
+/// `Router::serve_dir` for the defaults. Files are streamed from disk with a
+/// `Content-Type` guessed from the extension. A `Range` request for a
+/// single byte range is answered with `206 Partial Content`. Responses
+/// carry an `ETag` and `Last-Modified`, and requests with a matching
+/// `If-None-Match` or `If-Modified-Since` get `304 Not Modified`.
+///
+/// With `precompressed`, a `.br` or `.gz` file next to the requested one is
+/// sent instead when the client accepts that encoding.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    listing: bool,
+    index_files: Vec<String>,
+    fallback: Option<PathBuf>,
+    follow_symlinks: bool,
+    precompressed: bool,
+}
+
+impl StaticFiles {
+    /// Serve the files under `root`.
+    pub fn new<P: Into<PathBuf>>(root: P) -> StaticFiles {
+        StaticFiles {
+            root: root.into(),
+            listing: false,
+            index_files: vec!["index.html".to_string()],
+            fallback: None,
+            follow_symlinks: false,
+            precompressed: false,
+        }
+    }
+
+    /// Set whether symlinks under the root are followed. Defaults to
+    /// `false`, which answers `404 Not Found` for any path through one.
+    ///
+    /// Even when followed, a symlink that leads outside the root is
+    /// refused, as is any path with a `..` segment.
+    pub fn follow_symlinks(mut self, follow: bool) -> StaticFiles {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    /// Set the files served for a request for a directory, tried in order.
+    /// Defaults to `index.html`; pass an empty list to serve none.
+    pub fn index_files<I, S>(mut self, names: I) -> StaticFiles
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.index_files = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Serve `file`, a path relative to the root, for requests that match
+    /// no file, instead of answering `404 Not Found`.
+    ///
+    /// This is for single-page apps that route on the client, where e.g.
+    /// `/users/42` should load `index.html`.
+    pub fn fallback<P: Into<PathBuf>>(mut self, file: P) -> StaticFiles {
+        self.fallback = Some(file.into());
+        self
+    }
+
+    /// Set whether a request for a directory without an index file is
+    /// answered with an HTML page listing its entries. Defaults to `false`,
+    /// which answers `404 Not Found`.
+    pub fn listing(mut self, listing: bool) -> StaticFiles {
+        self.listing = listing;
+        self
+    }
+
+    /// Set whether files compressed ahead of time are served, e.g.
+    /// `app.js.br` or `app.js.gz` for `app.js`, to clients that accept
+    /// brotli or gzip. Defaults to `false`.
+    ///
+    /// The compressed file is sent with the `Content-Type` of the original
+    /// and a `Content-Encoding`, and responses for files that have one get
+    /// `Vary: Accept-Encoding`. Brotli is preferred when the client accepts
+    /// both equally.
+    pub fn precompressed(mut self, precompressed: bool) -> StaticFiles {
+        self.precompressed = precompressed;
+        self
+    }
+
+    /// Return the directory files are served from.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Respond to `request` with the file at `path`, a decoded path relative
+    /// to the root such as the one `Request::param` gives for `*path`.
+    ///
+    /// For a directory the first index file found in it is served, then the
+    /// listing if enabled. A directory requested without a trailing slash is
+    /// redirected to the path with one, so that relative links on the page
+    /// resolve inside it. Paths that match nothing get the fallback file if
+    /// one is set.
+    pub fn serve(&self, path: &str, request: &Request) -> Response {
+        let file = match self.resolve(path) {
+            Some(file) => file,
+            None => return Response::new(Status::NotFound),
+        };
+        match self.is_allowed(&file) {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!("Refusing to serve {} outside the root.", file.display());
+                return Response::new(Status::NotFound);
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return self.not_found(request)
+            }
+            Err(err) => return error_response(&file, &err),
+        }
+        let metadata = match fs::metadata(&file) {
+            Ok(metadata) => metadata,
+            Err(err) => return error_response(&file, &err),
+        };
+        if !metadata.is_dir() {
+            return self.serve_file(&file, request);
+        }
+
+        let index = self
+            .index_files
+            .iter()
+            .map(|name| file.join(name))
+            .find(|index| index.is_file() && self.is_allowed(index).unwrap_or(false));
+        if index.is_none() && !self.listing {
+            return self.not_found(request);
+        }
+        if !request.path().ends_with('/') {
+            return redirect_to_dir(request);
+        }
+        if let Some(index) = index {
+            return self.serve_file(&index, request);
+        }
+        match listing(
+            &file,
+            request.path(),
+            file == self.root,
+            self.follow_symlinks,
+        ) {
+            Ok(page) => Response::new(Status::Ok)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(page),
+            Err(err) => error_response(&file, &err),
+        }
+    }
+
+    fn not_found(&self, request: &Request) -> Response {
+        let fallback = match self.fallback {
+            Some(ref fallback) => self.root.join(fallback),
+            None => return Response::new(Status::NotFound),
+        };
+        match self.is_allowed(&fallback) {
+            Ok(true) => self.serve_file(&fallback, request),
+            Ok(false) => Response::new(Status::NotFound),
+            Err(err) => error_response(&fallback, &err),
+        }
+    }
+
+    fn serve_file(&self, path: &Path, request: &Request) -> Response {
+        let content_type = content_type(path);
+        if !self.precompressed {
+            return serve_file(path, content_type, request);
+        }
+        // 隣にある圧縮済みの file のうち、 client が受け取れるものを選ぶ。
+        let siblings: Vec<(Encoding, PathBuf)> = [(Encoding::Brotli, "br"), (Encoding::Gzip, "gz")]
+            .into_iter()
+            .map(|(encoding, extension)| {
+                let mut name = OsString::from(path.as_os_str());
+                name.push(".");
+                name.push(extension);
+                (encoding, PathBuf::from(name))
+            })
+            .filter(|(_, sibling)| sibling.is_file() && self.is_allowed(sibling).unwrap_or(false))
+            .collect();
+        if siblings.is_empty() {
+            return serve_file(path, content_type, request);
+        }
+        let encodings: Vec<Encoding> = siblings.iter().map(|&(encoding, _)| encoding).collect();
+        let mut response = match preferred_encoding(request, &encodings) {
+            Some(encoding) => {
+                let sibling = &siblings.iter().find(|s| s.0 == encoding).unwrap().1;
+                let mut response = serve_file(sibling, content_type, request);
+                if matches!(response.status(), Status::Ok | Status::PartialContent) {
+                    response
+                        .headers_mut()
+                        .insert("Content-Encoding", encoding.as_str());
+                }
+                response
+            }
+            None => serve_file(path, content_type, request),
+        };
+        add_vary(&mut response, "Accept-Encoding");
+        response
+    }
+
+    // 実際の置き場所が root の下にあり、 symlink を辿っていいかを確かめる。
+    fn is_allowed(&self, path: &Path) -> io::Result<bool> {
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) => return Ok(false),
+        };
+        // root 自体は symlink でもいい。
+        let root = fs::canonicalize(&self.root)?;
+        let canonical = fs::canonicalize(path)?;
+        if !canonical.starts_with(&root) {
+            return Ok(false);
+        }
+        // root より下に symlink がなければ、 canonicalize しても path は変わらない。
+        Ok(self.follow_symlinks || canonical == root.join(relative))
+    }
+
+    // decode された path を root 以下の path にする。 ".." など root の外を
+    // 指すものは None になる。
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut resolved = self.root.clone();
+        for segment in path.split('/') {
+            if segment.is_empty() || segment == "." {
+                continue;
+            }
+            // ".." のほか、 Windows の "C:" や "a\\b" も 1 つの名前にならない。
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) if !segment.contains(['\\', '\0']) => {
+                    resolved.push(segment)
+                }
+                _ => return None,
+            }
+        }
+        Some(resolved)
+    }
+}
+
+fn serve_file(path: &Path, content_type: &str, request: &Request) -> Response {
+    match open(path, content_type, request) {
+        Ok(response) => response,
+        Err(err) => error_response(path, &err),
+    }
+}
+
+fn open(path: &Path, content_type: &str, request: &Request) -> io::Result<Response> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let length = metadata.len();
+    // HTTP-date は秒までなので、比べる前に切り捨てておく。
+    let modified = metadata.modified().ok().and_then(|time| {
+        let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        Some((time, UNIX_EPOCH + Duration::from_secs(secs)))
+    });
+    let etag = match modified {
+        Some((time, _)) => {
+            let nanos = time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            format!("\"{:x}-{:x}\"", length, nanos)
+        }
+        None => format!("\"{:x}\"", length),
+    };
+    let modified = modified.map(|(_, secs)| secs);
+
+    let mut response = Response::new(Status::NotModified).header("ETag", &*etag);
+    if let Some(modified) = modified {
+        response = response.header("Last-Modified", http_date(modified));
+    }
+    if is_not_modified(request, &etag, modified) {
+        return Ok(response);
+    }
+    response.set_status(Status::Ok);
+    let response = response
+        .header("Content-Type", content_type)
+        .header("Accept-Ranges", "bytes");
+
+    let range = match request.header("Range") {
+        Some(range) if if_range_matches(request, &etag, modified) => parse_range(range, length),
+        _ => None,
+    };
+    match range {
+        None => Ok(response.body_reader(file, length)),
+        Some(Ok((start, end))) => {
+            file.seek(SeekFrom::Start(start))?;
+            let mut response = response
+                .header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", start, end, length),
+                )
+                .body_reader(file, end - start + 1);
+            response.set_status(Status::PartialContent);
+            Ok(response)
+        }
+        Some(Err(())) => Ok(Response::new(Status::RangeNotSatisfiable)
+            .header("Content-Range", format!("bytes */{}", length))),
+    }
+}
+
+// If-None-Match があれば If-Modified-Since は見ない (RFC 7232 6)。
+fn is_not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    let mut tags = request
+        .headers()
+        .get_all("If-None-Match")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .peekable();
+    if tags.peek().is_some() {
+        // 弱い比較なので W/ は外して比べる。
+        let etag = etag.trim_start_matches("W/");
+        return tags.any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    let since = request
+        .header("If-Modified-Since")
+        .and_then(parse_http_date);
+    match (since, modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+// If-Range が今の版を指していれば、 Range に従う。 ETag は強い比較をする。
+fn if_range_matches(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    let value = match request.header("If-Range") {
+        Some(value) => value.trim(),
+        None => return true,
+    };
+    if value.starts_with('"') || value.starts_with("W/") {
+        return value == etag;
+    }
+    match (parse_http_date(value), modified) {
+        (Some(date), Some(modified)) => date == modified,
+        _ => false,
+    }
+}
+
+// `bytes=0-99`, `bytes=100-`, `bytes=-100` の 1 つだけを扱い、両端を含む範囲を返す。
+// 読めないものや複数の範囲は None にして、全体を返す (RFC 7233 3.1)。
+fn parse_range(range: &str, length: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let parse = |n: &str| -> Option<u64> {
+        if n.is_empty() || !n.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        n.parse().ok()
+    };
+    let range = if start.is_empty() {
+        // 末尾から `end` byte。
+        let suffix = parse(end)?;
+        if suffix == 0 || length == 0 {
+            return Some(Err(()));
+        }
+        (length.saturating_sub(suffix), length - 1)
+    } else {
+        let start = parse(start)?;
+        let end = if end.is_empty() {
+            u64::MAX
+        } else {
+            parse(end)?
+        };
+        if end < start {
+            return None;
+        }
+        if start >= length {
+            return Some(Err(()));
+        }
+        (start, end.min(length - 1))
+    };
+    Some(Ok(range))
+}
+
+fn redirect_to_dir(request: &Request) -> Response {
+    let location = match request.target().split_once('?') {
+        Some((path, query)) => format!("{}/?{}", path, query),
+        None => format!("{}/", request.target()),
+    };
+    Response::new(Status::MovedPermanently).header("Location", location)
+}
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+// `url_path` は表示用。 link は相対にする。
+fn listing(dir: &Path, url_path: &str, is_root: bool, symlinks: bool) -> io::Result<String> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // symlink を辿らないなら、開けないので載せない。
+        if !symlinks && entry.file_type().map_or(true, |t| t.is_symlink()) {
+            continue;
+        }
+        // 読めないものは一覧から外すだけにする。
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        entries.push(Entry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+    // ディレクトリを先に並べる。
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let title = escape_html(&percent_decode(url_path));
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Index of {0}</title>\n</head>\n<body>\n<h1>Index of {0}</h1>\n\
+         <table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n",
+        title
+    );
+    if !is_root {
+        page.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in &entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        let modified = entry
+            .modified
+            .map(|time| DateTime::from_system_time(time).to_string())
+            .unwrap_or_default();
+        page.push_str(&format!(
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            percent_encode(&entry.name),
+            slash,
+            escape_html(&entry.name),
+            slash,
+            size,
+            modified
+        ));
+    }
+    page.push_str("</table>\n</body>\n</html>\n");
+    Ok(page)
+}
+
+pub(crate) fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn error_response(path: &Path, err: &io::Error) -> Response {
+    match err.kind() {
+        io::ErrorKind::NotFound => Response::new(Status::NotFound),
+        io::ErrorKind::PermissionDenied => Response::new(Status::Forbidden),
+        _ => {
+            warn!("Failed to open {}: {}", path.display(), err);
+            Response::new(Status::InternalServerError)
+        }
+    }
+}
+
+/// Guess the `Content-Type` of a file from its extension, falling back to
+/// `application/octet-stream`.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => extension.to_ascii_lowercase(),
+        None => return "application/octet-stream",
+    };
+    match &*extension {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
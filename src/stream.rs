@@ -0,0 +1,95 @@
+//! The connections a server reads requests from, plain or over TLS.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "tls")]
+use super::tls::TlsStream;
+use super::PeerIdentity;
+
+// accept した接続。 TLS の接続は handshake を最初の read で済ませる。
+pub(crate) enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(TlsStream),
+}
+
+impl Stream {
+    // 下の TCP の接続。
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match *self {
+            Stream::Plain(ref stream) => stream,
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref stream) => stream.tcp(),
+        }
+    }
+
+    // 同じ接続に読み書きする、もう 1 つの Stream を作る。
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match *self {
+            Stream::Plain(ref stream) => stream.try_clone().map(Stream::Plain),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref stream) => Ok(Stream::Tls(stream.clone())),
+        }
+    }
+
+    // TLS の handshake で HTTP/2 が選ばれたら true。
+    pub(crate) fn is_h2(&self) -> bool {
+        match *self {
+            Stream::Plain(_) => false,
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref stream) => stream.is_h2(),
+        }
+    }
+
+    // TLS の client 証明書で確かめた相手。
+    pub(crate) fn peer_identity(&self) -> Option<Arc<PeerIdentity>> {
+        match *self {
+            Stream::Plain(_) => None,
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref stream) => stream.peer_identity().map(Arc::new),
+        }
+    }
+
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_write_timeout(timeout)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! HTML templates with `{{ name }}` placeholders, rendered by
+//! `Response::render`.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use super::staticfiles::escape_html;
+
+const EXTENSION: &str = "html";
+
+// Response::render が使う template。 Templates::install で入れる。
+static INSTALLED: RwLock<Option<Templates>> = RwLock::new(None);
+
+/// The values of the placeholders in a template.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Context {
+    values: HashMap<String, String>,
+}
+
+impl Context {
+    /// Create a context without values.
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    /// Set the value of the placeholder `name`.
+    pub fn set<N: Into<String>, V: Display>(mut self, name: N, value: V) -> Context {
+        self.values.insert(name.into(), value.to_string());
+        self
+    }
+
+    /// Return the value of the placeholder `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|value| &**value)
+    }
+}
+
+/// The templates in a directory, each named after its file without the
+/// `.html` extension.
+///
+/// `{{ name }}` in a template is replaced with the value of `name` in the
+/// `Context`, escaped for HTML, and `{{{ name }}}` with the value as it is.
+/// Placeholders without a value are left empty.
+///
+/// In debug builds, templates are read again when their files change, and
+/// new files in the directory are picked up, so edits show up without a
+/// restart.
+#[derive(Debug)]
+pub struct Templates {
+    dir: PathBuf,
+    templates: HashMap<String, Template>,
+}
+
+impl Templates {
+    /// Load every `.html` file in `dir`.
+    ///
+    /// Returns an error with `io::ErrorKind::InvalidData` naming the file
+    /// if a template has an unclosed or empty placeholder.
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Templates> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut templates = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == EXTENSION) && path.is_file() {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    templates.insert(name.to_owned(), Template::read(&path)?);
+                }
+            }
+        }
+        Ok(Templates { dir, templates })
+    }
+
+    /// Return the names of the templates.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.templates.keys().map(|name| &**name)
+    }
+
+    /// Render the template `name` with `context`, or return `None` if there
+    /// is no such template.
+    pub fn render(&self, name: &str, context: &Context) -> Option<String> {
+        self.templates
+            .get(name)
+            .map(|template| template.render(context))
+    }
+
+    /// Make these the templates used by `Response::render`, replacing any
+    /// installed before. They are shared by the whole process.
+    pub fn install(self) {
+        *INSTALLED
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(self);
+    }
+
+    // 変わった file を読み直す。読めなければ前の template を使い続ける。
+    fn reload(&mut self, name: &str) {
+        let path = self.dir.join(format!("{}.{}", name, EXTENSION));
+        let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+        let current = self.templates.get(name).and_then(|t| t.modified);
+        if modified.is_none() || modified == current {
+            return;
+        }
+        match Template::read(&path) {
+            Ok(template) => {
+                debug!("Reloaded template {}", path.display());
+                self.templates.insert(name.to_owned(), template);
+            }
+            Err(err) => warn!("Failed to reload a template: {}", err),
+        }
+    }
+}
+
+// 描画する。 template がなければ None。
+pub(crate) fn render_installed(name: &str, context: &Context) -> Option<String> {
+    if cfg!(debug_assertions) {
+        let mut installed = INSTALLED
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let templates = installed.as_mut()?;
+        templates.reload(name);
+        templates.render(name, context)
+    } else {
+        let installed = INSTALLED
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        installed.as_ref()?.render(name, context)
+    }
+}
+
+#[derive(Debug)]
+struct Template {
+    segments: Vec<Segment>,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Text(String),
+    Value { name: String, escape: bool },
+}
+
+impl Template {
+    fn read(path: &Path) -> io::Result<Template> {
+        let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        let source = fs::read_to_string(path)?;
+        let segments = parse(&source).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        })?;
+        Ok(Template { segments, modified })
+    }
+
+    fn render(&self, context: &Context) -> String {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match *segment {
+                Segment::Text(ref text) => out.push_str(text),
+                Segment::Value { ref name, escape } => {
+                    let value = context.get(name).unwrap_or("");
+                    if escape {
+                        out.push_str(&escape_html(value));
+                    } else {
+                        out.push_str(value);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug)]
+struct ParseError {
+    line: usize,
+    message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on line {}", self.message, self.line)
+    }
+}
+
+fn parse(source: &str) -> Result<Vec<Segment>, ParseError> {
+    let mut segments = Vec::new();
+    let mut rest = source;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            segments.push(Segment::Text(rest[..start].to_owned()));
+        }
+        let line = source[..source.len() - rest.len() + start]
+            .matches('\n')
+            .count()
+            + 1;
+        let error = |message| ParseError { line, message };
+        // {{{ }}} は escape しない。
+        let (open, close, escape) = if rest[start..].starts_with("{{{") {
+            (3, "}}}", false)
+        } else {
+            (2, "}}", true)
+        };
+        let inner = &rest[start + open..];
+        let end = inner.find(close).ok_or(error("unclosed placeholder"))?;
+        let name = inner[..end].trim();
+        if name.is_empty() {
+            return Err(error("empty placeholder"));
+        }
+        segments.push(Segment::Value {
+            name: name.to_owned(),
+            escape,
+        });
+        rest = &inner[end + close.len()..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest.to_owned()));
+    }
+    Ok(segments)
+}
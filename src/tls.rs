@@ -0,0 +1,336 @@
+//! TLS listeners with rustls.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+
+use super::PeerIdentity;
+
+/// The certificate chain and private key of a TLS listener, given to
+/// `ServerBuilder::bind_tls` or `Server::listen_tls`.
+///
+/// Connections are served with TLS 1.2 and 1.3. The handshake runs on the
+/// worker that serves the connection, so a slow client does not hold up
+/// the others.
+pub struct TlsConfig {
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    // client 証明書を検証する CA。 None なら証明書を求めない。
+    client_roots: Option<Arc<RootCertStore>>,
+    client_optional: bool,
+    // SIGHUP で読み直す file。
+    files: Option<(PathBuf, PathBuf)>,
+    client_ca_file: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Read a certificate chain, leaf first, and its private key from PEM.
+    /// The key may be in PKCS #8, PKCS #1 or SEC1 form.
+    ///
+    /// Returns an error with `io::ErrorKind::InvalidData` if either
+    /// cannot be parsed, or if the key does not match the certificate.
+    pub fn new(cert_pem: &[u8], key_pem: &[u8]) -> io::Result<TlsConfig> {
+        let certs = CertificateDer::pem_slice_iter(cert_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| invalid(format!("invalid certificate: {}", err)))?;
+        if certs.is_empty() {
+            return Err(invalid("no certificate found".to_string()));
+        }
+        let key = PrivateKeyDer::from_pem_slice(key_pem)
+            .map_err(|err| invalid(format!("invalid private key: {}", err)))?;
+        let config = TlsConfig {
+            certs,
+            key,
+            client_roots: None,
+            client_optional: false,
+            files: None,
+            client_ca_file: None,
+        };
+        // 鍵が証明書と合うかを先に確かめる。
+        config.server_config(false)?;
+        Ok(config)
+    }
+
+    /// Read a certificate chain and its private key from PEM files, as
+    /// `new` does.
+    ///
+    /// With the `signals` feature, the server reads the files again when
+    /// the process receives `SIGHUP`, so a renewed certificate is used
+    /// without a restart. Connections accepted after that get the new one,
+    /// while open ones keep theirs. If the files cannot be read, or do not
+    /// match, the old certificate stays. Only supported on Linux.
+    pub fn from_files<P: AsRef<Path>, Q: AsRef<Path>>(cert: P, key: Q) -> io::Result<TlsConfig> {
+        let (cert, key) = (cert.as_ref(), key.as_ref());
+        let mut config = TlsConfig::new(&read(cert)?, &read(key)?)?;
+        config.files = Some((cert.to_path_buf(), key.to_path_buf()));
+        Ok(config)
+    }
+
+    /// Ask clients for a certificate, and verify it against the CAs in
+    /// `ca_pem`, one or more PEM certificates. A client that sends none, or
+    /// one that does not verify, fails the handshake, unless
+    /// `optional_client_cert` lets the former in. The verified identity is
+    /// given by `Request::peer_identity`.
+    ///
+    /// Returns an error with `io::ErrorKind::InvalidData` if the bundle
+    /// cannot be parsed or has no certificate.
+    pub fn client_ca(mut self, ca_pem: &[u8]) -> io::Result<TlsConfig> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(ca_pem) {
+            let cert = cert.map_err(|err| invalid(format!("invalid CA certificate: {}", err)))?;
+            roots
+                .add(cert)
+                .map_err(|err| invalid(format!("invalid CA certificate: {}", err)))?;
+        }
+        if roots.is_empty() {
+            return Err(invalid("no CA certificate found".to_string()));
+        }
+        self.client_roots = Some(Arc::new(roots));
+        Ok(self)
+    }
+
+    /// Read the CAs for `client_ca` from a PEM file. It is read again on
+    /// `SIGHUP` along with the files given to `from_files`.
+    pub fn client_ca_file<P: AsRef<Path>>(self, path: P) -> io::Result<TlsConfig> {
+        let path = path.as_ref();
+        let mut config = self.client_ca(&read(path)?)?;
+        config.client_ca_file = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    /// Let clients that send no certificate connect as well, with no peer
+    /// identity. A certificate that is sent must still verify. Defaults to
+    /// `false`; it has no effect without `client_ca`.
+    pub fn optional_client_cert(mut self, optional: bool) -> TlsConfig {
+        self.client_optional = optional;
+        self
+    }
+
+    // from_files で読んだ file を読み直す。 PEM から作ったなら None。
+    #[cfg(feature = "signals")]
+    fn reread(&self) -> Option<io::Result<TlsConfig>> {
+        let (ref cert, ref key) = *self.files.as_ref()?;
+        let reread = TlsConfig::from_files(cert, key).and_then(|config| {
+            let mut config = match self.client_ca_file {
+                Some(ref path) => config.client_ca_file(path)?,
+                None => TlsConfig {
+                    client_roots: self.client_roots.clone(),
+                    ..config
+                },
+            };
+            config.client_optional = self.client_optional;
+            Ok(config)
+        });
+        Some(reread)
+    }
+
+    // http2 なら ALPN で h2 も選べるようにする。
+    pub(crate) fn server_config(&self, http2: bool) -> io::Result<Arc<ServerConfig>> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| invalid(err.to_string()))?;
+        let builder = match self.client_roots {
+            Some(ref roots) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::clone(roots), provider);
+                let verifier = if self.client_optional {
+                    verifier.allow_unauthenticated()
+                } else {
+                    verifier
+                };
+                let verifier = verifier
+                    .build()
+                    .map_err(|err| invalid(format!("invalid CA certificates: {}", err)))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(self.certs.clone(), self.key.clone_key())
+            .map_err(|err| invalid(format!("invalid certificate or key: {}", err)))?;
+        if http2 {
+            config.alpn_protocols.push(b"h2".to_vec());
+        }
+        config.alpn_protocols.push(b"http/1.1".to_vec());
+        Ok(Arc::new(config))
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("certs", &self.certs.len())
+            .field("client_roots", &self.client_roots.as_ref().map(|r| r.len()))
+            .field("client_optional", &self.client_optional)
+            .field("files", &self.files)
+            .field("client_ca_file", &self.client_ca_file)
+            .finish()
+    }
+}
+
+// TLS の listener の設定。証明書を読み直したら、次に accept する接続から
+// 新しい設定を使う。
+pub(crate) struct TlsListener {
+    #[cfg(feature = "signals")]
+    source: TlsConfig,
+    #[cfg(feature = "signals")]
+    http2: bool,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsListener {
+    pub(crate) fn new(source: TlsConfig, http2: bool) -> io::Result<TlsListener> {
+        let current = RwLock::new(source.server_config(http2)?);
+        Ok(TlsListener {
+            #[cfg(feature = "signals")]
+            source,
+            #[cfg(feature = "signals")]
+            http2,
+            current,
+        })
+    }
+
+    pub(crate) fn accept(&self, tcp: TcpStream) -> io::Result<TlsStream> {
+        let config = Arc::clone(&self.current.read().unwrap_or_else(|p| p.into_inner()));
+        TlsStream::new(tcp, config)
+    }
+
+    // file から読んだ証明書なら true。
+    #[cfg(feature = "signals")]
+    pub(crate) fn is_reloadable(&self) -> bool {
+        self.source.files.is_some()
+    }
+
+    // file を読み直して設定を差し替える。読めなければ前の設定のまま。
+    #[cfg(feature = "signals")]
+    pub(crate) fn reload(&self) -> io::Result<()> {
+        let source = match self.source.reread() {
+            Some(source) => source?,
+            None => return Ok(()),
+        };
+        let config = source.server_config(self.http2)?;
+        *self.current.write().unwrap_or_else(|p| p.into_inner()) = config;
+        Ok(())
+    }
+}
+
+fn read(path: &Path) -> io::Result<Vec<u8>> {
+    fs::read(path).map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// TLS の接続。 HTTP/2 では読む thread と書く job とが同じ接続を使うので、
+// clone は session を共有する。 socket を待つ間は session を lock しない。
+#[derive(Clone)]
+pub(crate) struct TlsStream {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    tcp: TcpStream,
+    session: Mutex<ServerConnection>,
+}
+
+impl TlsStream {
+    pub(crate) fn new(tcp: TcpStream, config: Arc<ServerConfig>) -> io::Result<TlsStream> {
+        let session = ServerConnection::new(config).map_err(|err| invalid(err.to_string()))?;
+        Ok(TlsStream {
+            inner: Arc::new(Inner {
+                tcp,
+                session: Mutex::new(session),
+            }),
+        })
+    }
+
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        &self.inner.tcp
+    }
+
+    // ALPN で h2 が選ばれたら true。
+    pub(crate) fn is_h2(&self) -> bool {
+        self.lock().alpn_protocol() == Some(b"h2")
+    }
+
+    // handshake で検証した client 証明書の持ち主。
+    pub(crate) fn peer_identity(&self) -> Option<PeerIdentity> {
+        let session = self.lock();
+        let cert = session.peer_certificates()?.first()?;
+        PeerIdentity::from_der(cert)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ServerConnection> {
+        self.inner
+            .session
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.lock().reader().read(buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            // 復号できる分がなければ、 socket に record が届くのを待つ。
+            // 0 byte なら、接続が閉じられたことを次の read_tls が session に伝える。
+            self.inner.tcp.peek(&mut [0])?;
+            let mut session = self.lock();
+            session.read_tls(&mut &self.inner.tcp)?;
+            let processed = session.process_new_packets();
+            // handshake の返事や alert を送る。
+            let written = write_tls(&mut session, &self.inner.tcp);
+            if let Err(err) = processed {
+                return Err(invalid(err.to_string()));
+            }
+            written?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.lock();
+        let n = session.writer().write(buf)?;
+        write_tls(&mut session, &self.inner.tcp)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.lock();
+        session.writer().flush()?;
+        write_tls(&mut session, &self.inner.tcp)
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let session = self
+            .session
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        session.send_close_notify();
+        let _ = write_tls(session, &self.tcp);
+    }
+}
+
+fn write_tls(session: &mut ServerConnection, mut tcp: &TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(&mut tcp)?;
+    }
+    Ok(())
+}
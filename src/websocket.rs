@@ -0,0 +1,375 @@
+//! WebSocket connections (RFC 6455).
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::time::Duration;
+
+use super::server::{has_token, Conn};
+use super::{base64, sha1};
+use super::{Method, Request, Response, Status, Version};
+
+// Sec-WebSocket-Key に付けて hash する決まった文字列。
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const VERSION: &str = "13";
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// close の status code (RFC 6455 7.4.1)。
+const NORMAL: u16 = 1000;
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// A message sent or received over a `WebSocket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    /// A ping, of up to 125 bytes. Received pings are answered before
+    /// `recv` returns them.
+    Ping(Vec<u8>),
+    /// A pong, of up to 125 bytes.
+    Pong(Vec<u8>),
+    /// A close frame with an optional status code and reason.
+    Close(Option<(u16, String)>),
+}
+
+/// A WebSocket connection, handed to the function passed to
+/// `WebSocket::upgrade`.
+///
+/// `recv` reads whole messages, reassembling fragmented ones, and answers
+/// pings and close frames from the client. Protocol errors close the
+/// connection with the matching status code and are returned as
+/// `InvalidData` errors. Dropping the socket sends a normal close if none
+/// has been sent.
+pub struct WebSocket {
+    conn: BufReader<Conn>,
+    max_message_size: usize,
+    // 組み立て中の分割された message。
+    partial: Option<(u8, Vec<u8>)>,
+    sent_close: bool,
+    received_close: bool,
+    // frame の途中で読めなくなったら、もう続けられない。
+    broken: bool,
+}
+
+enum Failure {
+    // frame を読み始める前の timeout などで、続けられる。
+    Idle(io::Error),
+    Io(io::Error),
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Failure {
+        Failure::Io(err)
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl WebSocket {
+    /// Answer a WebSocket handshake, calling `f` with the connection once
+    /// the `101 Switching Protocols` response has been sent.
+    ///
+    /// Returns `400 Bad Request` if `request` is not a valid HTTP/1.1
+    /// handshake, and `426 Upgrade Required` if the client speaks another
+    /// version of the protocol. Add a `Sec-WebSocket-Protocol` header to the
+    /// response to pick one of the client's subprotocols.
+    ///
+    /// `f` runs on the pool worker that served the request and keeps it
+    /// until `f` returns, so every open socket takes a worker. Reads time
+    /// out after the server's read timeout unless changed with
+    /// `set_read_timeout`.
+    pub fn upgrade<F>(request: &Request, f: F) -> Response
+    where
+        F: FnOnce(WebSocket) + Send + 'static,
+    {
+        let is_handshake = *request.method() == Method::Get
+            && request.version() == Version::Http11
+            && has_token(request.headers().get_all("Upgrade"), "websocket")
+            && has_token(request.headers().get_all("Connection"), "upgrade");
+        let key = request
+            .header("Sec-WebSocket-Key")
+            .filter(|key| base64::decode(key).is_some_and(|k| k.len() == 16));
+        let key = match key {
+            Some(key) if is_handshake => key,
+            _ => return Response::new(Status::BadRequest),
+        };
+        if request.header("Sec-WebSocket-Version") != Some(VERSION) {
+            return Response::new(Status::UpgradeRequired).header("Sec-WebSocket-Version", VERSION);
+        }
+        let accept = base64::encode(&sha1::digest(format!("{}{}", key, GUID).as_bytes()));
+        Response::new(Status::SwitchingProtocols)
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Accept", accept)
+            .upgrade(move |conn| {
+                f(WebSocket {
+                    conn,
+                    max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+                    partial: None,
+                    sent_close: false,
+                    received_close: false,
+                    broken: false,
+                })
+            })
+    }
+
+    /// Set the largest message in bytes `recv` accepts. Bigger ones close
+    /// the connection with status 1009. Defaults to 16 MiB.
+    pub fn set_max_message_size(&mut self, bytes: usize) {
+        self.max_message_size = bytes;
+    }
+
+    /// Set how long `recv` waits for a message, or `None` to wait forever.
+    ///
+    /// A `recv` that times out before a message starts can be retried, e.g.
+    /// after sending a ping to keep the connection alive.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.conn.get_mut().set_read_timeout(timeout);
+    }
+
+    /// Wait for the next message from the client.
+    ///
+    /// After a `WebSocketMessage::Close` has been returned, or the
+    /// connection has failed, this returns an error.
+    pub fn recv(&mut self) -> io::Result<WebSocketMessage> {
+        if self.received_close || self.broken {
+            return Err(closed());
+        }
+        loop {
+            let frame = match self.read_frame() {
+                Ok(frame) => frame,
+                Err(Failure::Idle(err)) => return Err(err),
+                Err(Failure::Io(err)) => {
+                    self.broken = true;
+                    return Err(err);
+                }
+                Err(Failure::Protocol(code, reason)) => return Err(self.fail(code, reason)),
+            };
+            match frame.opcode {
+                PING => {
+                    self.write_frame(PONG, &frame.payload)?;
+                    return Ok(WebSocketMessage::Ping(frame.payload));
+                }
+                PONG => return Ok(WebSocketMessage::Pong(frame.payload)),
+                CLOSE => return self.received_close(frame.payload),
+                CONTINUATION => match self.partial {
+                    Some((_, ref mut data)) => data.extend_from_slice(&frame.payload),
+                    None => return Err(self.fail(PROTOCOL_ERROR, "unexpected continuation")),
+                },
+                TEXT | BINARY if self.partial.is_none() => {
+                    self.partial = Some((frame.opcode, frame.payload));
+                }
+                TEXT | BINARY => return Err(self.fail(PROTOCOL_ERROR, "expected continuation")),
+                _ => return Err(self.fail(PROTOCOL_ERROR, "unknown opcode")),
+            }
+            if !frame.fin {
+                continue;
+            }
+            // fin の data frame なら、 partial は今入れたか足したところ。
+            let (opcode, data) = self.partial.take().unwrap();
+            if opcode == BINARY {
+                return Ok(WebSocketMessage::Binary(data));
+            }
+            match String::from_utf8(data) {
+                Ok(text) => return Ok(WebSocketMessage::Text(text)),
+                Err(_) => return Err(self.fail(INVALID_DATA, "text is not UTF-8")),
+            }
+        }
+    }
+
+    /// Send a message.
+    ///
+    /// Sending `WebSocketMessage::Close` starts the closing handshake: keep
+    /// calling `recv` until it returns the client's
+    /// `WebSocketMessage::Close`, or drop the socket. Nothing can be sent
+    /// after a close.
+    pub fn send(&mut self, message: WebSocketMessage) -> io::Result<()> {
+        if self.sent_close || self.broken {
+            return Err(closed());
+        }
+        match message {
+            WebSocketMessage::Text(text) => self.write_frame(TEXT, text.as_bytes()),
+            WebSocketMessage::Binary(data) => self.write_frame(BINARY, &data),
+            WebSocketMessage::Ping(data) => self.write_control(PING, &data),
+            WebSocketMessage::Pong(data) => self.write_control(PONG, &data),
+            WebSocketMessage::Close(None) => self.write_close(&[]),
+            WebSocketMessage::Close(Some((code, reason))) => {
+                let mut payload = code.to_be_bytes().to_vec();
+                payload.extend_from_slice(reason.as_bytes());
+                self.write_close(&payload)
+            }
+        }
+    }
+
+    /// Send a close frame with `code` and `reason`. See `send`.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        self.send(WebSocketMessage::Close(Some((code, reason.to_owned()))))
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, Failure> {
+        // 最初の byte を待つ間の error なら、まだ何も読んでいない。
+        if self.conn.fill_buf().map_err(Failure::Idle)?.is_empty() {
+            return Err(Failure::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let mut head = [0; 2];
+        self.conn.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        // extension は使わないので、 RSV bit は立たない。
+        if head[0] & 0x70 != 0 {
+            return Err(Failure::Protocol(PROTOCOL_ERROR, "reserved bits set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(Failure::Protocol(PROTOCOL_ERROR, "client frame not masked"));
+        }
+        let length = match head[1] & 0x7f {
+            126 => {
+                let mut length = [0; 2];
+                self.conn.read_exact(&mut length)?;
+                u64::from(u16::from_be_bytes(length))
+            }
+            127 => {
+                let mut length = [0; 8];
+                self.conn.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => u64::from(length),
+        };
+        if opcode >= CLOSE {
+            if !fin || length > MAX_CONTROL_PAYLOAD as u64 {
+                return Err(Failure::Protocol(PROTOCOL_ERROR, "invalid control frame"));
+            }
+        } else {
+            let buffered = self.partial.as_ref().map_or(0, |(_, data)| data.len());
+            if length > self.max_message_size.saturating_sub(buffered) as u64 {
+                return Err(Failure::Protocol(MESSAGE_TOO_BIG, "message too big"));
+            }
+        }
+        let mut mask = [0; 4];
+        self.conn.read_exact(&mut mask)?;
+        let mut payload = vec![0; length as usize];
+        self.conn.read_exact(&mut payload)?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    fn received_close(&mut self, payload: Vec<u8>) -> io::Result<WebSocketMessage> {
+        let close = match payload.len() {
+            0 => None,
+            1 => return Err(self.fail(PROTOCOL_ERROR, "invalid close frame")),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                if !is_valid_code(code) {
+                    return Err(self.fail(PROTOCOL_ERROR, "invalid close code"));
+                }
+                match String::from_utf8(payload[2..].to_vec()) {
+                    Ok(reason) => Some((code, reason)),
+                    Err(_) => return Err(self.fail(INVALID_DATA, "close reason is not UTF-8")),
+                }
+            }
+        };
+        self.received_close = true;
+        // こちらから閉じ始めていなければ、同じ code を返して閉じる。
+        if !self.sent_close {
+            self.write_close(&payload[..payload.len().min(2)])?;
+        }
+        Ok(WebSocketMessage::Close(close))
+    }
+
+    // 相手の誤りを close で伝え、 error にする。
+    fn fail(&mut self, code: u16, reason: &'static str) -> io::Error {
+        debug!("Closing a WebSocket: {}", reason);
+        self.partial = None;
+        self.received_close = true;
+        if !self.sent_close {
+            let _ = self.write_close(&code.to_be_bytes());
+        }
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+
+    fn write_control(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "control frame payload too long",
+            ));
+        }
+        self.write_frame(opcode, payload)
+    }
+
+    fn write_close(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.sent_close = true;
+        self.write_control(CLOSE, payload)
+    }
+
+    // server からの frame は mask しない。
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            length @ 0..=125 => frame.push(length as u8),
+            length @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        let conn = self.conn.get_mut();
+        let written = conn.write_all(&frame).and_then(|_| conn.flush());
+        if written.is_err() {
+            self.broken = true;
+        }
+        written
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        if !self.sent_close && !self.broken {
+            let _ = self.write_close(&NORMAL.to_be_bytes());
+        }
+    }
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WebSocket")
+            .field("max_message_size", &self.max_message_size)
+            .field("sent_close", &self.sent_close)
+            .field("received_close", &self.received_close)
+            .finish()
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "WebSocket is closed")
+}
+
+// 送られてきてよい close の code (RFC 6455 7.4)。
+fn is_valid_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+}